@@ -1,6 +1,8 @@
+#[allow(unused_imports)]
 use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{Compression, KvStore, KvStoreOptions, KvsEngine, LsmKvsEngine, SledKvsEngine};
 use rand::prelude::*;
+#[allow(clippy::single_component_path_imports)]
 use sled;
 use tempfile::TempDir;
 
@@ -39,9 +41,24 @@ fn set_bench(c: &mut Criterion) {
             BatchSize::SmallInput,
         )
     });
+    group.bench_function("lsm", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (LsmKvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(mut store, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    store.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
     group.finish();
 }
 
+#[allow(clippy::useless_vec)]
 fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get");
 
@@ -80,7 +97,143 @@ fn get_bench(c: &mut Criterion) {
             })
         });
     }
+    for i in &vec![8, 12, 16, 20] {
+        group.bench_with_input(format!("lsm_{}", i), i, |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let mut store = LsmKvsEngine::open(temp_dir.path()).unwrap();
+            for key_i in 1..(1 << i) {
+                store
+                    .set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store
+                    .get(format!("key{}", rng.gen_range(1, 1 << i)))
+                    .unwrap();
+            })
+        });
+    }
+}
+
+/// A JSON document of about 4 KiB, as repetitive as the ones our users store.
+fn json_document(i: u32) -> String {
+    let items: Vec<String> = (0..40)
+        .map(|item| {
+            format!(
+                r#"{{"id":{},"name":"item {}","tags":["alpha","beta","gamma"],"price":{}.99,"in_stock":true}}"#,
+                item,
+                item,
+                (i + item) % 100
+            )
+        })
+        .collect();
+    format!(r#"{{"document":{},"items":[{}]}}"#, i, items.join(","))
+}
+
+fn compression_options(compression: Option<Compression>) -> KvStoreOptions {
+    KvStoreOptions {
+        compression,
+        ..KvStoreOptions::default()
+    }
+}
+
+const COMPRESSIONS: [(&str, Option<Compression>); 3] = [
+    ("none", None),
+    ("lz4", Some(Compression::Lz4)),
+    ("zstd", Some(Compression::Zstd)),
+];
+
+fn set_compressible_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set_compressible");
+    let documents: Vec<String> = (0..(1 << 10)).map(json_document).collect();
+
+    for (name, compression) in COMPRESSIONS.iter() {
+        group.bench_function(*name, |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let options = compression_options(*compression);
+                    (
+                        KvStore::open_with(temp_dir.path(), options).unwrap(),
+                        temp_dir,
+                    )
+                },
+                |(mut store, _temp_dir)| {
+                    for (i, document) in documents.iter().enumerate() {
+                        store.set(format!("key{}", i), document.clone()).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn get_compressible_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_compressible");
+
+    for (name, compression) in COMPRESSIONS.iter() {
+        group.bench_function(*name, |b| {
+            let temp_dir = TempDir::new().unwrap();
+            let options = compression_options(*compression);
+            let mut store = KvStore::open_with(temp_dir.path(), options).unwrap();
+            for i in 0..(1 << 10) {
+                store.set(format!("key{}", i), json_document(i)).unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store
+                    .get(format!("key{}", rng.gen_range(0, 1 << 10)))
+                    .unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Caches of none, 256 KiB and 4 MiB in front of 64 MiB of values.
+const CACHE_CAPACITIES: [(&str, Option<u64>); 3] = [
+    ("uncached", None),
+    ("cache_256k", Some(256 << 10)),
+    ("cache_4m", Some(4 << 20)),
+];
+
+fn get_skewed_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_skewed");
+    let keys: u32 = 1 << 14;
+
+    for (name, cache_capacity) in CACHE_CAPACITIES.iter() {
+        group.bench_function(*name, |b| {
+            let temp_dir = TempDir::new().unwrap();
+            let options = KvStoreOptions {
+                cache_capacity: *cache_capacity,
+                ..KvStoreOptions::default()
+            };
+            let mut store = KvStore::open_with(temp_dir.path(), options).unwrap();
+            for i in 0..keys {
+                store.set(format!("key{}", i), json_document(i)).unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                // Most reads go to a few hot keys: the key of rank r is read about as
+                // often as 1 / r.
+                let uniform: f64 = rng.gen();
+                let key = (f64::from(keys).powf(uniform) - 1.0) as u32;
+                store.get(format!("key{}", key)).unwrap();
+            })
+        });
+    }
+    group.finish();
 }
 
-criterion_group!(benches, set_bench, get_bench);
+criterion_group!(
+    benches,
+    set_bench,
+    get_bench,
+    set_compressible_bench,
+    get_compressible_bench,
+    get_skewed_bench
+);
 criterion_main!(benches);
@@ -0,0 +1,134 @@
+use crate::error::Result;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Access granted to a token on keys with a given prefix.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Access {
+    /// Get keys
+    Read,
+    /// Set and remove keys, but not increment them, which returns their value
+    Write,
+    /// Get, set and remove keys
+    ReadWrite,
+}
+
+impl Access {
+    fn allows(self, write: bool) -> bool {
+        match self {
+            Access::Read => !write,
+            Access::Write => write,
+            Access::ReadWrite => true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Grant {
+    token: String,
+    #[serde(default)]
+    namespace: Option<String>,
+    prefix: String,
+    access: Access,
+}
+
+/// Access control list of a `KvsServer`, mapping client tokens to the key prefixes
+/// they may read or write.
+///
+/// The ACL file is a JSON list of grants:
+///
+/// ```json
+/// [
+///     { "token": "s3cret", "prefix": "team-a/", "access": "read-write" },
+///     { "token": "s3cret", "namespace": "team-a", "prefix": "", "access": "read" },
+///     { "token": "monitoring", "prefix": "", "access": "read" }
+/// ]
+/// ```
+///
+/// A grant applies to keys of the named namespace, or of the default one when it names
+/// none. Administrative requests, such as shutting the server down, need `read-write`
+/// access to the empty prefix, i.e. to every key.
+#[derive(Debug, Default)]
+pub struct Acl {
+    grants: HashMap<String, Vec<(Option<String>, String, Access)>>,
+}
+
+impl Acl {
+    /// Load the ACL from a JSON file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let grants: Vec<Grant> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        Ok(grants.into_iter().fold(Acl::default(), |acl, grant| {
+            acl.push(grant.token, grant.namespace, grant.prefix, grant.access)
+        }))
+    }
+
+    /// Grant `access` to keys of the default namespace starting with `prefix` to the
+    /// holder of `token`.
+    pub fn grant(
+        self,
+        token: impl Into<String>,
+        prefix: impl Into<String>,
+        access: Access,
+    ) -> Self {
+        self.push(token.into(), None, prefix.into(), access)
+    }
+
+    /// Grant `access` to keys of `namespace` starting with `prefix` to the holder of
+    /// `token`.
+    pub fn grant_in_namespace(
+        self,
+        token: impl Into<String>,
+        namespace: impl Into<String>,
+        prefix: impl Into<String>,
+        access: Access,
+    ) -> Self {
+        self.push(token.into(), Some(namespace.into()), prefix.into(), access)
+    }
+
+    fn push(
+        mut self,
+        token: String,
+        namespace: Option<String>,
+        prefix: String,
+        access: Access,
+    ) -> Self {
+        self.grants
+            .entry(token)
+            .or_default()
+            .push((namespace, prefix, access));
+        self
+    }
+
+    pub(crate) fn is_known(&self, token: &str) -> bool {
+        self.grants.contains_key(token)
+    }
+
+    /// Whether `token` may read, or write if `write` is set, the given key of
+    /// `namespace`, the default one if `None`.
+    pub(crate) fn allows(
+        &self,
+        token: &str,
+        namespace: Option<&str>,
+        key: &str,
+        write: bool,
+    ) -> bool {
+        self.grants.get(token).is_some_and(|grants| {
+            grants.iter().any(|(granted, prefix, access)| {
+                granted.as_deref() == namespace
+                    && key.starts_with(prefix.as_str())
+                    && access.allows(write)
+            })
+        })
+    }
+
+    pub(crate) fn allows_admin(&self, token: &str) -> bool {
+        self.grants.get(token).is_some_and(|grants| {
+            grants.iter().any(|(namespace, prefix, access)| {
+                namespace.is_none() && prefix.is_empty() && *access == Access::ReadWrite
+            })
+        })
+    }
+}
@@ -1,16 +1,21 @@
-#![allow(dead_code, unused_variables)]
-
-#[macro_use(slog_o)]
 extern crate slog;
 #[macro_use]
 extern crate slog_scope;
-extern crate slog_term;
 
 use clap::{Parser, Subcommand};
-use kvs::{KvsClient, Result};
-use slog::Drain;
+use kvs::{
+    ClientOptions, ClientTimeouts, ClientTls, DumpFormat, EngineStats, ErrorKind, KvsClient,
+    LogOptions, Result, ServerStats, DEFAULT_BATCH_SIZE, DEFAULT_OVERLOAD_RETRIES,
+};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::Duration;
 
 const DEFAULT_LISTENING_ADDRESS: SocketAddr =
     SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000);
@@ -18,8 +23,65 @@ const DEFAULT_LISTENING_ADDRESS: SocketAddr =
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    #[command(flatten)]
+    log: LogOptions,
+
     #[command(subcommand)]
     command: Command,
+
+    /// Give up on connecting to or waiting for the server after this many seconds
+    #[arg(
+        long,
+        value_name = "SECS",
+        global = true,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    timeout: Option<u64>,
+
+    /// Retry requests refused by an overloaded server this many times, backing off
+    /// exponentially
+    #[arg(long, value_name = "N", global = true, default_value_t = DEFAULT_OVERLOAD_RETRIES)]
+    overload_retries: usize,
+
+    /// Connect over TLS, trusting server certificates signed by CAs from this PEM file
+    #[arg(long, value_name = "FILE", global = true)]
+    tls_ca: Option<PathBuf>,
+
+    /// PEM file with the certificate chain to authenticate to the server with
+    #[arg(long, value_name = "FILE", global = true, requires_all = ["tls_ca", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the client certificate
+    #[arg(long, value_name = "FILE", global = true, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Name expected in the server certificate, instead of the server IP address
+    #[arg(long, value_name = "NAME", global = true, requires = "tls_ca")]
+    tls_server_name: Option<String>,
+
+    /// Token to authenticate to the server with
+    #[arg(long, value_name = "TOKEN", global = true)]
+    token: Option<String>,
+
+    /// Namespace to get, set and remove keys in, instead of the default one
+    #[arg(long, value_name = "NAME", global = true)]
+    namespace: Option<String>,
+}
+
+fn client_tls(cli: &Cli) -> Result<Option<ClientTls>> {
+    let ca = match &cli.tls_ca {
+        Some(ca) => ca,
+        None => return Ok(None),
+    };
+    let identity = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+        _ => None,
+    };
+    let mut tls = ClientTls::from_pem_files(ca, identity)?;
+    if let Some(name) = &cli.tls_server_name {
+        tls = tls.with_server_name(name)?;
+    }
+    Ok(Some(tls))
 }
 
 #[derive(Subcommand, Debug)]
@@ -44,43 +106,493 @@ enum Command {
         #[arg(long, value_name = "ADDR", default_value_t=DEFAULT_LISTENING_ADDRESS)]
         addr: SocketAddr,
     },
+    /// Add DELTA to the integer value of KEY, 0 if it does not exist, and print the
+    /// new value
+    Incr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+        #[arg(long, value_name = "ADDR", default_value_t=DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+    /// Subtract DELTA from the integer value of KEY, 0 if it does not exist, and print
+    /// the new value
+    Decr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+        #[arg(long, value_name = "ADDR", default_value_t=DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+    Shutdown {
+        #[arg(long, value_name = "ADDR", default_value_t=DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+    Replication {
+        #[arg(long, value_name = "ADDR", default_value_t=DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+    Stats {
+        #[arg(long, value_name = "ADDR", default_value_t=DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+    /// Write every key of the namespace with its value to the standard output
+    Export {
+        /// Format to write the pairs in
+        #[arg(long, value_name = "FORMAT", value_enum, default_value_t = DumpFormat::Jsonl)]
+        format: DumpFormat,
+        /// File to write to, instead of the standard output
+        #[arg(long, value_name = "FILE")]
+        output: Option<PathBuf>,
+        #[arg(long, value_name = "ADDR", default_value_t=DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+    /// Set the keys read with their values from the standard input, in batches
+    Import {
+        /// Format to read the pairs in
+        #[arg(long, value_name = "FORMAT", value_enum, default_value_t = DumpFormat::Jsonl)]
+        format: DumpFormat,
+        /// File to read from, instead of the standard input
+        #[arg(long, value_name = "FILE")]
+        input: Option<PathBuf>,
+        /// Pairs sent per request
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = DEFAULT_BATCH_SIZE,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        batch_size: usize,
+        #[arg(long, value_name = "ADDR", default_value_t=DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+    /// Run commands over one connection, typed interactively or read from a script.
+    /// `help` lists the commands
+    Shell {
+        /// Run the commands of this file, one per line, and report the result of each
+        /// line, instead of reading them interactively
+        #[arg(long, value_name = "FILE")]
+        batch: Option<PathBuf>,
+        #[arg(long, value_name = "ADDR", default_value_t=DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+    Namespace {
+        #[command(subcommand)]
+        command: NamespaceCommand,
+        #[arg(long, value_name = "ADDR", global = true, default_value_t=DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
 }
 
-fn run() -> Result<()> {
-    let cli = Cli::parse();
+#[derive(Subcommand, Debug)]
+enum NamespaceCommand {
+    Create { name: String },
+    Drop { name: String },
+    List,
+}
+
+fn engine_stats_line(label: &str, stats: &EngineStats) -> String {
+    format!(
+        "{} keys {} disk_bytes {} live_bytes {} stale_bytes {} compactions {} compaction_ms {} evictions {} cache_hits {} cache_misses {} bloom_false_positive_rate {:.4}",
+        label,
+        stats.keys,
+        stats.disk_bytes,
+        stats.live_bytes,
+        stats.stale_bytes,
+        stats.compactions,
+        stats.compaction_time.as_millis(),
+        stats.evictions,
+        stats.cache_hits,
+        stats.cache_misses,
+        stats.bloom_false_positive_rate
+    )
+}
+
+fn stats_lines(stats: &ServerStats) -> Vec<String> {
+    let mut lines = vec![
+        format!("uptime_secs {}", stats.uptime.as_secs()),
+        format!("connections {}", stats.connections),
+    ];
+    for request in &stats.requests {
+        lines.push(format!(
+            "request {} count {} errors {} p50_us {} p99_us {}",
+            request.kind,
+            request.latency.count(),
+            request.errors,
+            request.latency.quantile(0.5).as_micros(),
+            request.latency.quantile(0.99).as_micros()
+        ));
+    }
+    lines.push(engine_stats_line("engine", &stats.engine));
+    for (name, engine) in &stats.namespaces {
+        lines.push(engine_stats_line(&format!("namespace {}", name), engine));
+    }
+    lines
+}
+
+const SHELL_HELP: &str = "\
+get KEY            print the value of KEY
+set KEY VALUE      set KEY to VALUE
+rm KEY             remove KEY
+incr KEY [DELTA]   add DELTA, or 1, to the integer value of KEY
+decr KEY [DELTA]   subtract DELTA, or 1, from the integer value of KEY
+scan [PREFIX]      print every key starting with PREFIX, with its value
+ping               check that the server responds
+stats              print the statistics of the server
+help               print this help
+exit               leave the shell
+Words containing spaces or quotes are quoted with \" or ', and \\ escapes a character.";
+
+/// Split a shell line into words. Double or single quotes group characters, spaces
+/// included, into a word, and a backslash escapes the next character, except within
+/// single quotes.
+fn split_words(line: &str) -> std::result::Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), c) => word.push(c),
+            (_, '\\') => match chars.next() {
+                Some(escaped) => {
+                    word.push(escaped);
+                    in_word = true;
+                }
+                None => return Err("nothing to escape at the end of the line".to_owned()),
+            },
+            (Some(_), c) => word.push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if let Some(quote) = quote {
+        return Err(format!("unterminated {} quote", quote));
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Quote a word so that `split_words` reads it back, if it needs to be.
+fn quote_word(word: &str) -> String {
+    let plain = !word.is_empty()
+        && !word
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\'' || c == '\\');
+    if plain {
+        return word.to_owned();
+    }
+    let escaped = word.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{}\"", escaped)
+}
+
+/// What a shell command leads to.
+enum Outcome {
+    /// Lines to print
+    Output(Vec<String>),
+    /// The command cannot be run, or the server refused it
+    Failed(String),
+    /// The shell should end
+    Exit,
+}
+
+/// Run one line of the shell. Errors are those that end the session, because the
+/// connection to the server is lost.
+fn run_shell_line(client: &mut KvsClient, line: &str) -> Result<Outcome> {
+    let words = match split_words(line) {
+        Ok(words) => words,
+        Err(e) => return Ok(Outcome::Failed(e)),
+    };
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let result = match words.as_slice() {
+        [] => return Ok(Outcome::Output(Vec::new())),
+        ["exit"] | ["quit"] => return Ok(Outcome::Exit),
+        ["help"] => Ok(SHELL_HELP.lines().map(str::to_owned).collect()),
+        ["ping"] => client.ping().map(|_| vec!["PONG".to_owned()]),
+        ["get", key] => client.get(key.to_string()).map(|value| {
+            vec![value.map_or_else(|| "Key not found".to_owned(), |value| quote_word(&value))]
+        }),
+        ["set", key, value] => client
+            .set(key.to_string(), value.to_string())
+            .map(|_| vec!["OK".to_owned()]),
+        ["rm", key] => client
+            .remove(key.to_string())
+            .map(|_| vec!["OK".to_owned()]),
+        ["incr", key] | ["incr", key, _] | ["decr", key] | ["decr", key, _] => {
+            let delta = match words.get(2).map_or(Ok(1), |delta| delta.parse::<i64>()) {
+                Ok(delta) => delta,
+                Err(_) => return Ok(Outcome::Failed(format!("invalid delta {:?}", words[2]))),
+            };
+            let value = if words[0] == "incr" {
+                client.incr(key.to_string(), delta)
+            } else {
+                client.decr(key.to_string(), delta)
+            };
+            value.map(|value| vec![value.to_string()])
+        }
+        ["scan"] | ["scan", _] => {
+            let prefix = words.get(1).copied().unwrap_or_default();
+            client.scan(prefix.to_owned()).map(|pairs| {
+                if pairs.is_empty() {
+                    return vec!["No keys".to_owned()];
+                }
+                pairs
+                    .iter()
+                    .map(|(key, value)| format!("{} {}", quote_word(key), quote_word(value)))
+                    .collect()
+            })
+        }
+        ["stats"] => client.stats().map(|stats| stats_lines(&stats)),
+        [command, ..] => {
+            let known = [
+                "get", "set", "rm", "incr", "decr", "scan", "ping", "stats", "help", "exit", "quit",
+            ];
+            let message = if known.contains(command) {
+                format!("wrong number of arguments for {}, see help", command)
+            } else {
+                format!("unknown command {:?}, see help", command)
+            };
+            return Ok(Outcome::Failed(message));
+        }
+    };
+    match result {
+        Ok(lines) => Ok(Outcome::Output(lines)),
+        Err(e) if e.is_connection_error() => Err(e),
+        Err(e) => Ok(Outcome::Failed(e.to_string())),
+    }
+}
+
+fn open_input(path: Option<&Path>) -> Result<Box<dyn Read>> {
+    Ok(match path {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(io::stdin()),
+    })
+}
+
+fn open_output(path: Option<&Path>) -> Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    })
+}
+
+fn readline_error(e: ReadlineError) -> ErrorKind {
+    match e {
+        ReadlineError::Io(e) => ErrorKind::Io(e),
+        e => ErrorKind::Io(io::Error::other(e.to_string())),
+    }
+}
+
+/// Read commands from the terminal, with line editing and a history kept in
+/// `~/.kvs_history`.
+fn interactive_shell(client: &mut KvsClient) -> Result<()> {
+    let mut editor = DefaultEditor::new().map_err(readline_error)?;
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(".kvs_history"));
+    if let Some(history) = &history {
+        // There is no history yet the first time.
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        let line = match editor.readline("kvs> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(readline_error(e)),
+        };
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
+        match run_shell_line(client, &line)? {
+            Outcome::Output(lines) => {
+                for line in lines {
+                    println!("{}", line);
+                }
+            }
+            Outcome::Failed(message) => println!("error: {}", message),
+            Outcome::Exit => break,
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(e) = editor.save_history(history) {
+            warn!("Failed to save the shell history: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Run the commands of a script, skipping blank lines and lines starting with `#`.
+/// Every output line is prefixed with the number of the line of the script it comes
+/// from. Returns whether every command succeeded.
+fn batch_shell(client: &mut KvsClient, script: &Path) -> Result<bool> {
+    let mut succeeded = true;
+    for (number, line) in fs::read_to_string(script)?.lines().enumerate() {
+        let number = number + 1;
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        match run_shell_line(client, line)? {
+            Outcome::Output(lines) => {
+                for output in lines {
+                    println!("{}: {}", number, output);
+                }
+            }
+            Outcome::Failed(message) => {
+                println!("{}: error: {}", number, message);
+                succeeded = false;
+            }
+            Outcome::Exit => break,
+        }
+    }
+    Ok(succeeded)
+}
+
+fn run(cli: Cli) -> Result<()> {
     info!("Running kvs-client {}", env!("CARGO_PKG_VERSION"));
     info!("------------------------");
     info!("Config: {:?}", cli);
 
+    let options = ClientOptions {
+        timeouts: cli
+            .timeout
+            .map(|secs| ClientTimeouts::all(Duration::from_secs(secs)))
+            .unwrap_or_default(),
+        tls: client_tls(&cli)?,
+        token: cli.token.clone(),
+        namespace: cli.namespace.clone(),
+        overload_retries: cli.overload_retries,
+    };
+    let connect = |addr| KvsClient::connect_with(addr, &options);
+
     match cli.command {
         Command::Ping { addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = connect(addr)?;
             client.ping()?;
         }
         Command::Get { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
-            client.get(key)?;
+            let mut client = connect(addr)?;
+            match client.get(key)? {
+                Some(value) => println!("{}", value),
+                None => println!("Key not found"),
+            }
         }
         Command::Set { key, value, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = connect(addr)?;
             client.set(key, value)?;
         }
         Command::Rm { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = connect(addr)?;
             client.remove(key)?;
         }
+        Command::Incr { key, delta, addr } => {
+            let mut client = connect(addr)?;
+            println!("{}", client.incr(key, delta)?);
+        }
+        Command::Decr { key, delta, addr } => {
+            let mut client = connect(addr)?;
+            println!("{}", client.decr(key, delta)?);
+        }
+        Command::Shutdown { addr } => {
+            let mut client = connect(addr)?;
+            client.shutdown()?;
+        }
+        Command::Replication { addr } => {
+            let mut client = connect(addr)?;
+            let status = client.replication_status()?;
+            println!("position {}", status.position);
+            for follower in status.followers {
+                println!(
+                    "follower {} applied {} lag {}",
+                    follower.addr, follower.applied, follower.lag
+                );
+            }
+        }
+        Command::Stats { addr } => {
+            let mut client = connect(addr)?;
+            for line in stats_lines(&client.stats()?) {
+                println!("{}", line);
+            }
+        }
+        Command::Export {
+            format,
+            output,
+            addr,
+        } => {
+            // The server scans the whole namespace while holding its engine, so the
+            // pairs are a consistent view of it.
+            let pairs = connect(addr)?.scan(String::new())?;
+            kvs::write_pairs(format, pairs, open_output(output.as_deref())?)?;
+        }
+        Command::Import {
+            format,
+            input,
+            batch_size,
+            addr,
+        } => {
+            let mut client = connect(addr)?;
+            let input = open_input(input.as_deref())?;
+            let imported =
+                kvs::read_batches(format, input, batch_size, |batch| client.set_batch(batch))?;
+            println!("Imported {} keys", imported);
+        }
+        Command::Shell { batch, addr } => {
+            let mut client = connect(addr)?;
+            match batch {
+                Some(script) => {
+                    if !batch_shell(&mut client, &script)? {
+                        exit(1);
+                    }
+                }
+                None => interactive_shell(&mut client)?,
+            }
+        }
+        Command::Namespace { command, addr } => {
+            let mut client = connect(addr)?;
+            match command {
+                NamespaceCommand::Create { name } => client.create_namespace(name)?,
+                NamespaceCommand::Drop { name } => client.drop_namespace(name)?,
+                NamespaceCommand::List => {
+                    for name in client.namespaces()? {
+                        println!("{}", name);
+                    }
+                }
+            }
+        }
     }
 
     Ok(())
 }
 
 fn main() {
-    let plain = slog_term::PlainSyncDecorator::new(std::io::stderr());
-    let log = slog::Logger::root(slog_term::FullFormat::new(plain).build().fuse(), slog_o!());
+    let cli = Cli::parse();
+    let log = match cli.log.logger() {
+        Ok(log) => log,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    };
 
     let _guard = slog_scope::set_global_logger(log);
-    slog_scope::scope(&slog_scope::logger().new(slog_o!("scope" => "1")), || {
-        if let Err(err) = run() {
+    slog_scope::scope(&slog_scope::logger().new(slog::o!("scope" => "1")), || {
+        if let Err(err) = run(cli) {
             eprintln!("{}", err);
             exit(1);
         }
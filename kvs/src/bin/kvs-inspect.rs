@@ -0,0 +1,192 @@
+extern crate slog;
+extern crate slog_scope;
+
+use clap::{Parser, Subcommand};
+use kvs::{
+    ErrorKind, IndexMode, KvStore, KvStoreOptions, KvsEngine, LogInspection, LogOptions,
+    RecordState, Result,
+};
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+/// Inspect and maintain the log of a stopped KvStore.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(flatten)]
+    log: LogOptions,
+
+    /// Directory of the store, holding its data.log
+    #[arg(long, value_name = "DIR", default_value = ".", global = true)]
+    data_dir: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print every record with its offset and whether it is live, then a summary
+    Dump {
+        /// Print only the summary
+        #[arg(long)]
+        summary: bool,
+    },
+    /// Drop the corrupt bytes of the log, keeping every readable record
+    Repair,
+    /// Rewrite the log with only its live records, or merge it into the sorted segment of
+    /// a store with a sparse index
+    Compact,
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let log_path = cli.data_dir.join("data.log");
+    if !log_path.is_file() {
+        return Err(ErrorKind::ConversionError(format!(
+            "no KvStore log at {}",
+            log_path.display()
+        )));
+    }
+
+    match cli.command {
+        Command::Dump { summary } => {
+            let inspection = KvStore::inspect(&cli.data_dir)?;
+            let mut output = BufWriter::new(io::stdout());
+            if !summary {
+                dump(&inspection, &mut output)?;
+            }
+            write_summary(&inspection, &mut output)?;
+            output.flush()?;
+        }
+        Command::Repair => {
+            let inspection = KvStore::repair(&cli.data_dir)?;
+            if inspection.corrupt.is_empty() {
+                println!("Nothing to repair");
+            } else {
+                for range in &inspection.corrupt {
+                    println!(
+                        "Dropped {} bytes at offset {}: {}",
+                        range.length, range.offset, range.error
+                    );
+                }
+                println!(
+                    "Kept {} records, dropped {} corrupt bytes",
+                    inspection.records.len(),
+                    inspection.corrupt_bytes()
+                );
+            }
+        }
+        Command::Compact => {
+            let segment_path = cli.data_dir.join("data.sst");
+            let (index, before) = if segment_path.is_file() {
+                let bytes = file_bytes(&log_path)? + file_bytes(&segment_path)?;
+                (IndexMode::Sparse, bytes)
+            } else {
+                (IndexMode::Hash, file_bytes(&log_path)?)
+            };
+            let options = KvStoreOptions {
+                index,
+                ..KvStoreOptions::default()
+            };
+            let mut store = KvStore::open_with(&cli.data_dir, options)?;
+            store.compact()?;
+            let after = store.stats()?.disk_bytes;
+            println!("Compacted {} bytes into {}", before, after);
+        }
+    }
+    Ok(())
+}
+
+/// One line per record and corrupt range, in log order.
+fn dump(inspection: &LogInspection, output: &mut impl Write) -> Result<()> {
+    let mut records = inspection.records.iter().peekable();
+    let mut corrupt = inspection.corrupt.iter().peekable();
+    loop {
+        let record_first = match (records.peek(), corrupt.peek()) {
+            (Some(record), Some(range)) => record.offset < range.offset,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => return Ok(()),
+        };
+        if record_first {
+            let record = records.next().unwrap();
+            let state = match record.state {
+                RecordState::Live => "live",
+                RecordState::Superseded => "superseded",
+                RecordState::Tombstone => "tombstone",
+            };
+            write!(
+                output,
+                "{:>10} {:>8} {:<10} {:?}",
+                record.offset, record.length, state, record.key
+            )?;
+            if let Some(value) = &record.value {
+                write!(output, " {:?}", value)?;
+            }
+            match record.compression {
+                Some(compression) => writeln!(output, " ({})", compression)?,
+                None => writeln!(output)?,
+            }
+        } else {
+            let range = corrupt.next().unwrap();
+            writeln!(
+                output,
+                "{:>10} {:>8} {:<10} {}",
+                range.offset, range.length, "corrupt", range.error
+            )?;
+        }
+    }
+}
+
+fn write_summary(inspection: &LogInspection, output: &mut impl Write) -> Result<()> {
+    let count = |state| {
+        inspection
+            .records
+            .iter()
+            .filter(|record| record.state == state)
+            .count()
+    };
+    writeln!(
+        output,
+        "records {} live {} superseded {} tombstones {} corrupt_ranges {}",
+        inspection.records.len(),
+        count(RecordState::Live),
+        count(RecordState::Superseded),
+        count(RecordState::Tombstone),
+        inspection.corrupt.len()
+    )?;
+    writeln!(
+        output,
+        "file_bytes {} live_bytes {} corrupt_bytes {} space_amplification {:.2}",
+        inspection.file_bytes,
+        inspection.live_bytes(),
+        inspection.corrupt_bytes(),
+        inspection.space_amplification()
+    )?;
+    Ok(())
+}
+
+fn file_bytes(path: &Path) -> Result<u64> {
+    Ok(fs::metadata(path)?.len())
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let log = match cli.log.logger() {
+        Ok(log) => log,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    };
+
+    let _guard = slog_scope::set_global_logger(log);
+    slog_scope::scope(&slog_scope::logger().new(slog::o!("scope" => "1")), || {
+        if let Err(err) = run(cli) {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    });
+}
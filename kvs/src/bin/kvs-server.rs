@@ -1,50 +1,629 @@
-#![allow(dead_code, unused_variables)]
-
-#[macro_use(slog_o)]
 extern crate slog;
 #[macro_use]
 extern crate slog_scope;
-extern crate slog_term;
 
-use clap::Parser;
-use kvs::{Engine, ErrorKind, KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
-use slog::Drain;
+use clap::{Parser, ValueEnum};
+use kvs::{
+    Acl, ClientOptions, ClientTls, Compression, Durability, Engine, ErrorKind, FollowerConfig,
+    InMemoryEngine, IndexMode, KvStore, KvStoreOptions, KvsEngine, KvsServer, LogOptions,
+    LsmKvsEngine, LsmOptions, NodeId, RaftConfig, RaftEngine, RateLimit, Result, ServerLimits,
+    ServerTimeouts, ServerTls, ShutdownHandle, SledKvsEngine, TcpTransport,
+    DEFAULT_BLOOM_FALSE_POSITIVE_RATE, DEFAULT_COMPACTION_THRESHOLD, DEFAULT_COMPRESSION_THRESHOLD,
+};
+use serde::Deserialize;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::HashMap;
 use std::env::current_dir;
 use std::fs;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::thread;
+use std::time::Duration;
 
 const DEFAULT_LISTENING_ADDRESS: SocketAddr =
     SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000);
+const DEFAULT_MAX_KEY_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_VALUE_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_MAX_REQUEST_SIZE: usize = 64 * 1024 * 1024;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Address to listen to
-    #[arg(long, value_name = "ADDR", default_value_t=DEFAULT_LISTENING_ADDRESS)]
-    addr: SocketAddr,
+    #[command(flatten)]
+    log: LogOptions,
+
+    /// TOML file with the settings of the server. Flags override the settings of the
+    /// file
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Directory holding the data of the server [default: the working directory]
+    #[arg(long, value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Address to listen to [default: 127.0.0.1:4000]
+    #[arg(long, value_name = "ADDR")]
+    addr: Option<SocketAddr>,
+
+    /// Engine to use [default: kvs]
+    #[arg(long, value_name = "ENGINE", value_enum)]
+    engine: Option<Engine>,
+
+    /// When writes are synced to disk before being acknowledged [default: fsync]
+    #[arg(long, value_name = "DURABILITY", value_enum)]
+    durability: Option<Durability>,
+
+    /// Serve at most this many connections at once, each on its own thread
+    /// [default: no limit]
+    #[arg(long, value_name = "N", value_parser = positive_usize())]
+    threads: Option<usize>,
+
+    /// Compact the log of the kvs engine once it holds this many overwritten or removed
+    /// entries [default: 1024]
+    #[arg(long, value_name = "ENTRIES", value_parser = clap::value_parser!(u64).range(1..))]
+    compaction_threshold: Option<u64>,
+
+    /// How the kvs engine indexes keys. A sparse index bounds memory for key sets larger
+    /// than it [default: hash]
+    #[arg(long, value_name = "INDEX", value_enum)]
+    index: Option<IndexMode>,
+
+    /// Compress the values of the kvs engine with this algorithm [default: none]
+    #[arg(long, value_name = "ALGORITHM", value_enum)]
+    compression: Option<Compression>,
+
+    /// Compress only values of at least this many bytes [default: 512]
+    #[arg(long, value_name = "BYTES")]
+    compression_threshold: Option<usize>,
+
+    /// Cache up to this many bytes of recently read keys and values of the kvs engine
+    /// [default: no cache]
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    cache_capacity: Option<u64>,
+
+    /// Rate of lookups of absent keys that bloom filters of the keys let through to the
+    /// disk, between 0 and 1 exclusive [default: 0.01]
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    bloom_false_positive_rate: Option<f64>,
+
+    /// Evict the least recently used keys of the memory engine once its keys and values
+    /// take more than this many bytes [default: no limit]
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    max_memory: Option<u64>,
+
+    /// Close connections that send no request for this many seconds
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout: Option<u64>,
+
+    /// Refuse keys of more than this many bytes [default: 65536]
+    #[arg(long, value_name = "BYTES", value_parser = positive_usize())]
+    max_key_size: Option<usize>,
+
+    /// Refuse values of more than this many bytes [default: 16777216]
+    #[arg(long, value_name = "BYTES", value_parser = positive_usize())]
+    max_value_size: Option<usize>,
+
+    /// Close connections sending a request of more than this many bytes. Raft
+    /// snapshots are sent as a single request, so it bounds their size too
+    /// [default: 67108864]
+    #[arg(long, value_name = "BYTES", value_parser = positive_usize())]
+    max_request_size: Option<usize>,
+
+    /// Refuse connections while this many are open, telling clients to retry later.
+    /// Unlike --threads, connections over the limit do not wait [default: no limit]
+    #[arg(long, value_name = "N", value_parser = positive_usize())]
+    max_connections: Option<usize>,
+
+    /// Allow this many requests per second on each connection, telling clients of
+    /// further requests to retry later [default: no limit]
+    #[arg(long, value_name = "REQS", value_parser = positive_u32())]
+    connection_rate: Option<u32>,
+
+    /// Allow this many requests at once on a connection that was idle [default: the
+    /// connection rate]
+    #[arg(long, value_name = "REQS", value_parser = positive_u32())]
+    connection_burst: Option<u32>,
+
+    /// Allow this many requests per second on all connections together [default: no
+    /// limit]
+    #[arg(long, value_name = "REQS", value_parser = positive_u32())]
+    global_rate: Option<u32>,
+
+    /// Allow this many requests at once on all connections after a quiet period
+    /// [default: the global rate]
+    #[arg(long, value_name = "REQS", value_parser = positive_u32())]
+    global_burst: Option<u32>,
+
+    /// PEM file with the certificate chain to serve TLS with
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the TLS certificate
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM file with CA certificates that client certificates must be signed by
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// JSON file granting client tokens access to key prefixes. Clients must
+    /// authenticate when given
+    #[arg(long, value_name = "FILE")]
+    acl: Option<PathBuf>,
+
+    /// Log writes to `replication.log` in the data directory, so that followers can
+    /// replicate them
+    #[arg(long, conflicts_with_all = ["follow", "cluster"])]
+    replicate: bool,
+
+    /// Replicate the writes of the leader at this address, and serve reads only
+    #[arg(long, value_name = "ADDR", conflicts_with = "cluster")]
+    follow: Option<SocketAddr>,
+
+    /// Token to authenticate to the leader with
+    #[arg(long, value_name = "TOKEN", requires = "follow")]
+    leader_token: Option<String>,
+
+    /// Connect to the leader over TLS, trusting certificates signed by CAs from this
+    /// PEM file
+    #[arg(long, value_name = "FILE", requires = "follow")]
+    leader_tls_ca: Option<PathBuf>,
+
+    /// Serve metrics in the Prometheus format over HTTP at this address
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Replicate writes with Raft across the servers listed in this file, one address
+    /// per line, --addr included. The state of the node is kept in `raft/` in the data
+    /// directory
+    #[arg(long, value_name = "FILE")]
+    cluster: Option<PathBuf>,
+
+    /// Token to authenticate to the other servers of the cluster with
+    #[arg(long, value_name = "TOKEN", requires = "cluster")]
+    peer_token: Option<String>,
+
+    /// Connect to the other servers of the cluster over TLS, trusting certificates
+    /// signed by CAs from this PEM file
+    #[arg(long, value_name = "FILE", requires = "cluster")]
+    peer_tls_ca: Option<PathBuf>,
+}
+
+/// Settings of the file given with `--config`. Each is optional, and overridden by the
+/// flag of the same name.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Relative to the directory of the file
+    data_dir: Option<PathBuf>,
+    addr: Option<SocketAddr>,
+    engine: Option<String>,
+    durability: Option<String>,
+    threads: Option<usize>,
+    index: Option<String>,
+    #[serde(default)]
+    compaction: CompactionConfig,
+    #[serde(default)]
+    compression: CompressionConfig,
+    #[serde(default)]
+    cache: CacheConfig,
+    #[serde(default)]
+    bloom: BloomConfig,
+    #[serde(default)]
+    memory: MemoryConfig,
+    #[serde(default)]
+    limits: LimitsConfig,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct CompactionConfig {
+    threshold: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct CompressionConfig {
+    algorithm: Option<String>,
+    threshold: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct CacheConfig {
+    capacity: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct BloomConfig {
+    false_positive_rate: Option<f64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct MemoryConfig {
+    max_bytes: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct LimitsConfig {
+    idle_timeout: Option<u64>,
+    max_key_size: Option<usize>,
+    max_value_size: Option<usize>,
+    max_request_size: Option<usize>,
+    max_connections: Option<usize>,
+    connection_rate: Option<u32>,
+    connection_burst: Option<u32>,
+    global_rate: Option<u32>,
+    global_burst: Option<u32>,
+}
+
+impl Config {
+    fn read(path: &Path) -> Result<Config> {
+        let invalid = |message: String| {
+            ErrorKind::ConversionError(format!("invalid config {}: {}", path.display(), message))
+        };
+        let content = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let mut config: Config = toml::from_str(&content).map_err(|e| invalid(e.to_string()))?;
+
+        if let Some(engine) = &config.engine {
+            parse_choice::<Engine>("engine", engine).map_err(invalid)?;
+        }
+        if let Some(durability) = &config.durability {
+            parse_choice::<Durability>("durability", durability).map_err(invalid)?;
+        }
+        if let Some(index) = &config.index {
+            parse_choice::<IndexMode>("index", index).map_err(invalid)?;
+        }
+        if let Some(algorithm) = &config.compression.algorithm {
+            parse_choice::<Compression>("compression.algorithm", algorithm).map_err(invalid)?;
+        }
+        if let Some(rate) = config.bloom.false_positive_rate {
+            check_rate(rate)
+                .map_err(|message| invalid(format!("bloom.false_positive_rate {}", message)))?;
+        }
+        let positive = [
+            ("threads", config.threads.map(|threads| threads as u64)),
+            ("compaction.threshold", config.compaction.threshold),
+            ("cache.capacity", config.cache.capacity),
+            ("memory.max_bytes", config.memory.max_bytes),
+            ("limits.idle_timeout", config.limits.idle_timeout),
+            (
+                "limits.max_key_size",
+                config.limits.max_key_size.map(|size| size as u64),
+            ),
+            (
+                "limits.max_value_size",
+                config.limits.max_value_size.map(|size| size as u64),
+            ),
+            (
+                "limits.max_request_size",
+                config.limits.max_request_size.map(|size| size as u64),
+            ),
+            (
+                "limits.max_connections",
+                config.limits.max_connections.map(|max| max as u64),
+            ),
+            (
+                "limits.connection_rate",
+                config.limits.connection_rate.map(u64::from),
+            ),
+            (
+                "limits.connection_burst",
+                config.limits.connection_burst.map(u64::from),
+            ),
+            (
+                "limits.global_rate",
+                config.limits.global_rate.map(u64::from),
+            ),
+            (
+                "limits.global_burst",
+                config.limits.global_burst.map(u64::from),
+            ),
+        ];
+        for (name, value) in positive.iter() {
+            if *value == Some(0) {
+                return Err(invalid(format!("{} must be at least 1", name)));
+            }
+        }
+        if let (Some(data_dir), Some(base)) = (&config.data_dir, path.parent()) {
+            config.data_dir = Some(base.join(data_dir));
+        }
+        Ok(config)
+    }
+}
+
+fn positive_usize() -> clap::builder::RangedU64ValueParser<usize> {
+    clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+}
 
-    /// Engine to use
-    #[arg(long, value_name="ENGINE", value_enum, default_value_t=Engine::kvs)]
+fn positive_u32() -> clap::builder::RangedU64ValueParser<u32> {
+    clap::builder::RangedU64ValueParser::<u32>::new().range(1..)
+}
+
+/// Rate limit of the given rate, bursting up to the rate unless a burst is given.
+fn rate_limit(name: &str, rate: Option<u32>, burst: Option<u32>) -> Result<Option<RateLimit>> {
+    match (rate, burst) {
+        (Some(rate), burst) => Ok(Some(RateLimit {
+            burst: burst.unwrap_or(rate),
+            ..RateLimit::per_second(rate)
+        })),
+        (None, None) => Ok(None),
+        (None, Some(_)) => Err(ErrorKind::ConversionError(format!(
+            "a {} burst needs a {} rate",
+            name, name
+        ))),
+    }
+}
+
+/// Check that a false positive rate is a probability that bloom filters can reach.
+fn check_rate(rate: f64) -> std::result::Result<f64, String> {
+    if rate > 0.0 && rate < 1.0 {
+        Ok(rate)
+    } else {
+        Err(format!("must be between 0 and 1 exclusive, not {}", rate))
+    }
+}
+
+fn parse_rate(value: &str) -> std::result::Result<f64, String> {
+    check_rate(value.parse().map_err(|e| format!("{}", e))?)
+}
+
+/// Parse the value of a setting the way the flag of the same name is parsed.
+fn parse_choice<T: ValueEnum>(name: &str, value: &str) -> std::result::Result<T, String> {
+    T::from_str(value, false).map_err(|_| {
+        let expected: Vec<String> = T::value_variants()
+            .iter()
+            .filter_map(|variant| variant.to_possible_value())
+            .map(|value| value.get_name().to_owned())
+            .collect();
+        format!(
+            "invalid {} {:?}, expected one of: {}",
+            name,
+            value,
+            expected.join(", ")
+        )
+    })
+}
+
+/// Settings of the server, from the flags, else the config file, else the defaults.
+#[derive(Debug)]
+struct Settings {
+    data_dir: PathBuf,
+    addr: SocketAddr,
     engine: Engine,
+    durability: Durability,
+    threads: Option<usize>,
+    compaction_threshold: u64,
+    index: IndexMode,
+    compression: Option<Compression>,
+    compression_threshold: usize,
+    cache_capacity: Option<u64>,
+    bloom_false_positive_rate: f64,
+    max_memory: Option<u64>,
+    idle_timeout: Option<u64>,
+    limits: ServerLimits,
+}
+
+impl Settings {
+    fn resolve(cli: &Cli) -> Result<Settings> {
+        let config = match &cli.config {
+            Some(path) => Config::read(path)?,
+            None => Config::default(),
+        };
+        // Choices of the config were validated when reading it.
+        let engine = config
+            .engine
+            .map(|engine| parse_choice("engine", &engine).unwrap());
+        let durability = config
+            .durability
+            .map(|durability| parse_choice("durability", &durability).unwrap());
+        let index = config
+            .index
+            .map(|index| parse_choice("index", &index).unwrap());
+        let compression = config
+            .compression
+            .algorithm
+            .map(|algorithm| parse_choice("compression.algorithm", &algorithm).unwrap());
+        let data_dir = match cli.data_dir.clone().or(config.data_dir) {
+            Some(data_dir) => data_dir,
+            None => current_dir()?,
+        };
+        Ok(Settings {
+            data_dir,
+            addr: cli
+                .addr
+                .or(config.addr)
+                .unwrap_or(DEFAULT_LISTENING_ADDRESS),
+            engine: cli.engine.or(engine).unwrap_or(Engine::kvs),
+            durability: cli.durability.or(durability).unwrap_or(Durability::Fsync),
+            threads: cli.threads.or(config.threads),
+            compaction_threshold: cli
+                .compaction_threshold
+                .or(config.compaction.threshold)
+                .unwrap_or(DEFAULT_COMPACTION_THRESHOLD),
+            index: cli.index.or(index).unwrap_or(IndexMode::Hash),
+            compression: cli.compression.or(compression),
+            compression_threshold: cli
+                .compression_threshold
+                .or(config.compression.threshold)
+                .unwrap_or(DEFAULT_COMPRESSION_THRESHOLD),
+            cache_capacity: cli.cache_capacity.or(config.cache.capacity),
+            bloom_false_positive_rate: cli
+                .bloom_false_positive_rate
+                .or(config.bloom.false_positive_rate)
+                .unwrap_or(DEFAULT_BLOOM_FALSE_POSITIVE_RATE),
+            max_memory: cli.max_memory.or(config.memory.max_bytes),
+            idle_timeout: cli.idle_timeout.or(config.limits.idle_timeout),
+            limits: ServerLimits {
+                max_key: Some(
+                    cli.max_key_size
+                        .or(config.limits.max_key_size)
+                        .unwrap_or(DEFAULT_MAX_KEY_SIZE),
+                ),
+                max_value: Some(
+                    cli.max_value_size
+                        .or(config.limits.max_value_size)
+                        .unwrap_or(DEFAULT_MAX_VALUE_SIZE),
+                ),
+                max_request: Some(
+                    cli.max_request_size
+                        .or(config.limits.max_request_size)
+                        .unwrap_or(DEFAULT_MAX_REQUEST_SIZE),
+                ),
+                max_connections: cli.max_connections.or(config.limits.max_connections),
+                connection_rate: rate_limit(
+                    "connection",
+                    cli.connection_rate.or(config.limits.connection_rate),
+                    cli.connection_burst.or(config.limits.connection_burst),
+                )?,
+                global_rate: rate_limit(
+                    "global",
+                    cli.global_rate.or(config.limits.global_rate),
+                    cli.global_burst.or(config.limits.global_burst),
+                )?,
+            },
+        })
+    }
 }
 
-fn set_currently_used_engine(engine: Engine) -> Result<()> {
-    let engine_file = current_dir()?.join("engine");
+fn set_currently_used_engine(data_dir: &Path, engine: Engine) -> Result<()> {
+    let engine_file = data_dir.join("engine");
     fs::write(engine_file, format!("{:?}", engine))?;
     Ok(())
 }
 
-fn run_on_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    set_currently_used_engine(engine.as_type())?;
-    let mut server = KvsServer::new(engine, addr)?;
+/// Read the cluster file: node ids are the line numbers of the addresses, starting
+/// at 1. Blank lines and lines starting with `#` are skipped.
+fn read_cluster(path: &Path, addr: SocketAddr) -> Result<(NodeId, HashMap<NodeId, SocketAddr>)> {
+    let mut peers = HashMap::new();
+    let mut id = None;
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let peer: SocketAddr = line.parse().map_err(|_| {
+            ErrorKind::ConversionError(format!(
+                "invalid address {:?} on line {} of {}",
+                line,
+                number + 1,
+                path.display()
+            ))
+        })?;
+        let peer_id = number as NodeId + 1;
+        if peer == addr {
+            id = Some(peer_id);
+        }
+        peers.insert(peer_id, peer);
+    }
+    let id = id.ok_or_else(|| {
+        ErrorKind::ConversionError(format!("{} is not listed in {}", addr, path.display()))
+    })?;
+    Ok((id, peers))
+}
+
+fn run_on_engine<E: KvsEngine + Send + 'static>(
+    engine: E,
+    cli: &Cli,
+    settings: &Settings,
+) -> Result<()> {
+    // The memory engine leaves nothing in the data directory to guard.
+    if engine.as_type() != Engine::memory {
+        set_currently_used_engine(&settings.data_dir, engine.as_type())?;
+    }
+    let cluster = match &cli.cluster {
+        Some(cluster) => cluster,
+        None => return serve(KvsServer::new(engine, settings.addr)?, cli, settings),
+    };
+
+    let (id, peers) = read_cluster(cluster, settings.addr)?;
+    info!("Raft node {} of a cluster of {}", id, peers.len());
+    let tls = match &cli.peer_tls_ca {
+        Some(ca) => Some(ClientTls::from_pem_files(ca, None)?),
+        None => None,
+    };
+    let options = ClientOptions {
+        tls,
+        token: cli.peer_token.clone(),
+        ..ClientOptions::default()
+    };
+    let transport = TcpTransport::new(&peers, &options);
+    let config = RaftConfig::new(id, peers, settings.data_dir.join("raft"));
+    let engine = RaftEngine::start(engine, config, transport)?;
+    let inbox = engine.inbox();
+    let server = KvsServer::new(engine, settings.addr)?.with_raft(inbox);
+    serve(server, cli, settings)
+}
+
+fn serve<E: KvsEngine + Send + 'static>(
+    server: KvsServer<E>,
+    cli: &Cli,
+    settings: &Settings,
+) -> Result<()> {
+    let timeouts = ServerTimeouts {
+        idle: settings.idle_timeout.map(Duration::from_secs),
+        ..ServerTimeouts::default()
+    };
+    let mut server = server
+        .with_timeouts(timeouts)
+        .with_limits(settings.limits)
+        .with_durability(settings.durability);
+    if let Some(threads) = settings.threads {
+        server = server.with_threads(threads);
+    }
+    if let (Some(cert), Some(key)) = (&cli.tls_cert, &cli.tls_key) {
+        let tls = ServerTls::from_pem_files(cert, key, cli.tls_client_ca.as_deref())?;
+        server = server.with_tls(tls);
+    }
+    if let Some(addr) = cli.metrics_addr {
+        server = server.with_metrics_addr(addr);
+    }
+    if let Some(acl) = &cli.acl {
+        server = server.with_acl(Acl::from_file(acl)?);
+    }
+    if cli.replicate {
+        server = server.with_replication_log(settings.data_dir.join("replication.log"))?;
+    }
+    if let Some(leader) = cli.follow {
+        let tls = match &cli.leader_tls_ca {
+            Some(ca) => Some(ClientTls::from_pem_files(ca, None)?),
+            None => None,
+        };
+        server = server.following(FollowerConfig {
+            leader,
+            options: ClientOptions {
+                tls,
+                token: cli.leader_token.clone(),
+                ..ClientOptions::default()
+            },
+            position_file: settings.data_dir.join("replication.pos"),
+        });
+    }
+    shutdown_on_signals(server.shutdown_handle())?;
     server.listen()?;
 
     Ok(())
 }
 
-fn currently_used_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join("engine");
+/// Shut the server down gracefully on SIGINT or SIGTERM.
+fn shutdown_on_signals(handle: ShutdownHandle) -> Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!("Received signal {}", signal);
+            handle.shutdown();
+        }
+    });
+    Ok(())
+}
+
+fn currently_used_engine(data_dir: &Path) -> Result<Option<Engine>> {
+    let engine = data_dir.join("engine");
 
     if !engine.exists() {
         return Ok(None);
@@ -53,6 +632,7 @@ fn currently_used_engine() -> Result<Option<Engine>> {
     match fs::read_to_string(engine)?.as_str() {
         "kvs" => Ok(Some(Engine::kvs)),
         "sled" => Ok(Some(Engine::sled)),
+        "lsm" => Ok(Some(Engine::lsm)),
         x => {
             warn!("Invalid engine defined in the file: {}", x);
             Ok(None)
@@ -60,36 +640,79 @@ fn currently_used_engine() -> Result<Option<Engine>> {
     }
 }
 
-fn run() -> Result<()> {
-    let cli = Cli::parse();
-
+fn run(cli: Cli) -> Result<()> {
+    let settings = Settings::resolve(&cli)?;
     info!("Running kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("------------------------");
-    info!("Database engine: {:?}", cli.engine);
-    info!("Listening address {}", cli.addr);
-
-    match currently_used_engine()? {
-        None => {}
-        Some(used_engine) => {
-            if used_engine != cli.engine {
-                return Err(ErrorKind::WrongEngineUsed);
-            }
+    info!("Data directory: {}", settings.data_dir.display());
+    info!("Database engine: {:?}", settings.engine);
+    info!("Listening address {}", settings.addr);
+    info!("Durability: {:?}", settings.durability);
+    info!("TLS: {}", cli.tls_cert.is_some());
+    if let Some(leader) = cli.follow {
+        info!("Following leader at {}", leader);
+    }
+
+    fs::create_dir_all(&settings.data_dir)?;
+    match currently_used_engine(&settings.data_dir)? {
+        Some(used_engine)
+            if used_engine != settings.engine && settings.engine != Engine::memory =>
+        {
+            return Err(ErrorKind::WrongEngineUsed);
         }
+        _ => {}
     }
 
-    match cli.engine {
-        Engine::kvs => run_on_engine(KvStore::open(".")?, cli.addr),
-        Engine::sled => run_on_engine(SledKvsEngine::new(sled::open(".")?), cli.addr),
+    let data_dir = &settings.data_dir;
+    match settings.engine {
+        Engine::kvs => {
+            let options = KvStoreOptions {
+                index: settings.index,
+                compaction_threshold: settings.compaction_threshold,
+                compression: settings.compression,
+                compression_threshold: settings.compression_threshold,
+                cache_capacity: settings.cache_capacity,
+                bloom_false_positive_rate: settings.bloom_false_positive_rate,
+            };
+            let engine = KvStore::open_with(data_dir, options)?;
+            run_on_engine(engine, &cli, &settings)
+        }
+        Engine::sled => {
+            let engine = SledKvsEngine::new(sled::open(data_dir)?)
+                .with_bloom_false_positive_rate(settings.bloom_false_positive_rate);
+            run_on_engine(engine, &cli, &settings)
+        }
+        Engine::lsm => {
+            let options = LsmOptions {
+                bloom_false_positive_rate: settings.bloom_false_positive_rate,
+                ..LsmOptions::default()
+            };
+            let engine = LsmKvsEngine::open_with(data_dir, options)?;
+            run_on_engine(engine, &cli, &settings)
+        }
+        Engine::memory => {
+            let engine = match settings.max_memory {
+                Some(max_bytes) => InMemoryEngine::with_max_bytes(max_bytes),
+                None => InMemoryEngine::new(),
+            };
+            run_on_engine(engine, &cli, &settings)
+        }
     }
 }
 
 fn main() {
-    let plain = slog_term::PlainSyncDecorator::new(std::io::stderr());
-    let log = slog::Logger::root(slog_term::FullFormat::new(plain).build().fuse(), slog_o!());
+    let cli = Cli::parse();
+    let log = match cli.log.logger() {
+        Ok(log) => log,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    };
 
     let _guard = slog_scope::set_global_logger(log);
-    slog_scope::scope(&slog_scope::logger().new(slog_o!("scope" => "1")), || {
-        if let Err(err) = run() {
+    slog_scope::scope(&slog_scope::logger().new(slog::o!("scope" => "1")), || {
+        if let Err(err) = run(cli) {
             eprintln!("{}", err);
             exit(1);
         }
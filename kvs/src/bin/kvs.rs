@@ -1,31 +1,119 @@
-#![allow(dead_code, unused_variables)]
-
-#[macro_use(slog_o)]
 extern crate slog;
 extern crate slog_scope;
-extern crate slog_term;
 
 use clap::{Parser, Subcommand};
-use kvs::{KvsEngine, Result};
-use slog::Drain;
+use kvs::{
+    ClientOptions, DumpFormat, KvsEngine, LogOptions, Result, ShardedKvsClient, DEFAULT_BATCH_SIZE,
+    DEFAULT_VIRTUAL_NODES,
+};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    #[command(flatten)]
+    log: LogOptions,
+
     #[command(subcommand)]
     command: Command,
 }
 
 #[derive(Subcommand, Debug)]
 enum Command {
-    Get { key: String },
-    Set { key: String, value: String },
-    Rm { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Rm {
+        key: String,
+    },
+    /// Write every key with its value to the standard output
+    Export {
+        /// Format to write the pairs in
+        #[arg(long, value_name = "FORMAT", value_enum, default_value_t = DumpFormat::Jsonl)]
+        format: DumpFormat,
+
+        /// File to write to, instead of the standard output
+        #[arg(long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Set the keys read with their values from the standard input
+    Import {
+        /// Format to read the pairs in
+        #[arg(long, value_name = "FORMAT", value_enum, default_value_t = DumpFormat::Jsonl)]
+        format: DumpFormat,
+
+        /// File to read from, instead of the standard input
+        #[arg(long, value_name = "FILE")]
+        input: Option<PathBuf>,
+
+        /// Pairs written between two syncs to disk
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = DEFAULT_BATCH_SIZE,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        batch_size: usize,
+    },
+    /// Move keys between sharded servers to where consistent hashing places them, after
+    /// servers were added or removed
+    Rebalance {
+        /// Every server keys are sharded across, separated by commas
+        #[arg(long, value_name = "ADDR", value_delimiter = ',', required = true)]
+        servers: Vec<SocketAddr>,
+
+        /// Servers being removed, whose keys all move to the others
+        #[arg(long, value_name = "ADDR", value_delimiter = ',')]
+        drain: Vec<SocketAddr>,
+
+        /// Points each server gets on the hash ring, as configured in the clients
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = DEFAULT_VIRTUAL_NODES,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        virtual_nodes: usize,
+
+        /// Token to authenticate to the servers with
+        #[arg(long, value_name = "TOKEN")]
+        token: Option<String>,
+
+        /// Namespace to rebalance, instead of the default one
+        #[arg(long, value_name = "NAME")]
+        namespace: Option<String>,
+    },
 }
 
-fn run() -> Result<()> {
-    let cli = Cli::parse();
+fn run(cli: Cli) -> Result<()> {
+    if let Command::Rebalance {
+        servers,
+        drain,
+        virtual_nodes,
+        token,
+        namespace,
+    } = cli.command
+    {
+        let options = ClientOptions {
+            token,
+            namespace,
+            ..ClientOptions::default()
+        };
+        let mut client = ShardedKvsClient::new(servers)
+            .with_virtual_nodes(virtual_nodes)
+            .with_options(options);
+        let moved = client.rebalance(&drain)?;
+        println!("Moved {} keys", moved);
+        return Ok(());
+    }
 
     let mut kv_store = kvs::KvStore::open(".")?;
 
@@ -59,16 +147,59 @@ fn run() -> Result<()> {
             }
             Ok(_) => exit(0),
         },
+        Command::Export { format, output } => {
+            // The store is opened by this process only, so the pairs are a consistent
+            // view of it.
+            let pairs = kv_store.scan("")?;
+            kvs::write_pairs(format, pairs, open_output(output.as_deref())?)?;
+            Ok(())
+        }
+        Command::Import {
+            format,
+            input,
+            batch_size,
+        } => {
+            let imported =
+                kvs::read_batches(format, open_input(input.as_deref())?, batch_size, |batch| {
+                    for (key, value) in batch {
+                        kv_store.set(key, value)?;
+                    }
+                    kv_store.flush()
+                })?;
+            println!("Imported {} keys", imported);
+            Ok(())
+        }
+        Command::Rebalance { .. } => unreachable!(),
     }
 }
 
+fn open_input(path: Option<&Path>) -> Result<Box<dyn Read>> {
+    Ok(match path {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(io::stdin()),
+    })
+}
+
+fn open_output(path: Option<&Path>) -> Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    })
+}
+
 fn main() {
-    let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
-    let log = slog::Logger::root(slog_term::FullFormat::new(plain).build().fuse(), slog_o!());
+    let cli = Cli::parse();
+    let log = match cli.log.logger() {
+        Ok(log) => log,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    };
 
     let _guard = slog_scope::set_global_logger(log);
-    slog_scope::scope(&slog_scope::logger().new(slog_o!("scope" => "1")), || {
-        if let Err(err) = run() {
+    slog_scope::scope(&slog_scope::logger().new(slog::o!("scope" => "1")), || {
+        if let Err(err) = run(cli) {
             eprintln!("{}", err);
             exit(1);
         }
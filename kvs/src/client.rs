@@ -1,110 +1,411 @@
-use crate::error::Result;
+use crate::error::{ErrorKind, Result};
+use crate::metrics::ServerStats;
+use crate::raft::RaftMessage;
+use crate::replication::{Entry, ReplicationStatus};
 use crate::requests::{Request, Response};
+use crate::stream::Stream;
+use crate::tls::ClientTls;
 use serde::Deserialize;
 use serde_json::de::{Deserializer, IoRead};
 use slog_scope::debug;
 use std::io::{BufReader, BufWriter, Write};
 use std::net::SocketAddr;
 use std::net::TcpStream;
-use std::process::exit;
+use std::thread;
+use std::time::Duration;
+
+/// Most redirects followed by one request before giving up.
+const MAX_REDIRECTS: usize = 5;
+/// Pause before retrying a request on a node that does not know the leader yet.
+const ELECTION_WAIT: Duration = Duration::from_millis(100);
+/// Shortest pause before retrying a request refused by an overloaded server. Each
+/// further retry waits twice as long.
+const OVERLOAD_BACKOFF: Duration = Duration::from_millis(50);
+/// Retries of a request refused by an overloaded server, by default.
+pub const DEFAULT_OVERLOAD_RETRIES: usize = 5;
+
+/// Socket timeouts of a client connection. `None` waits forever.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClientTimeouts {
+    /// Limit on establishing the connection
+    pub connect: Option<Duration>,
+    /// Limit on waiting for a response from the server
+    pub read: Option<Duration>,
+    /// Limit on sending a request to the server
+    pub write: Option<Duration>,
+}
+
+impl ClientTimeouts {
+    /// Use the same timeout for connecting, reading and writing.
+    pub fn all(timeout: Duration) -> Self {
+        ClientTimeouts {
+            connect: Some(timeout),
+            read: Some(timeout),
+            write: Some(timeout),
+        }
+    }
+}
+
+/// Options of a client connection.
+#[derive(Clone)]
+pub struct ClientOptions {
+    /// Socket timeouts
+    pub timeouts: ClientTimeouts,
+    /// Encrypt the connection with TLS
+    pub tls: Option<ClientTls>,
+    /// Authenticate with this token right after connecting
+    pub token: Option<String>,
+    /// Namespace to get, set and remove keys in, instead of the default one
+    pub namespace: Option<String>,
+    /// Retries of a request refused by an overloaded server, before failing with
+    /// `ErrorKind::Overloaded`
+    pub overload_retries: usize,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            timeouts: ClientTimeouts::default(),
+            tls: None,
+            token: None,
+            namespace: None,
+            overload_retries: DEFAULT_OVERLOAD_RETRIES,
+        }
+    }
+}
+
+type Reader = Deserializer<IoRead<BufReader<Stream>>>;
 
 /// The client of the key/value store.
+///
+/// Gets, sets and removes sent to a node of a Raft cluster that is not the leader are
+/// retried on the leader. Requests refused by an overloaded server are retried after
+/// the time it asks for, backing off exponentially.
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    reader: Reader,
+    writer: BufWriter<Stream>,
+    addr: SocketAddr,
+    namespace: Option<String>,
+    options: ClientOptions,
 }
 
 impl KvsClient {
     /// Connect to the server at the given socket address.
     pub fn connect(addr: SocketAddr) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
+        Self::connect_with(addr, &ClientOptions::default())
+    }
+
+    /// Connect to the server at the given socket address, giving up on operations
+    /// that exceed the given timeouts with `ErrorKind::Timeout`.
+    pub fn connect_with_timeouts(addr: SocketAddr, timeouts: ClientTimeouts) -> Result<Self> {
+        Self::connect_with(
+            addr,
+            &ClientOptions {
+                timeouts,
+                ..ClientOptions::default()
+            },
+        )
+    }
+
+    /// Connect to the server at the given socket address with the given options.
+    pub fn connect_with(addr: SocketAddr, options: &ClientOptions) -> Result<Self> {
+        let (reader, writer) = open(addr, options)?;
+        let mut client = KvsClient {
+            reader,
+            writer,
+            addr,
+            namespace: options.namespace.clone(),
+            options: options.clone(),
+        };
+        if let Some(token) = &options.token {
+            client.authenticate(token.clone())?;
+        }
+        Ok(client)
+    }
 
-        Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: BufWriter::new(tcp_writer),
-        })
+    /// Authenticate to the server with the given token
+    pub fn authenticate(&mut self, token: String) -> Result<()> {
+        match self.call(&Request::Auth { token })? {
+            Response::Success => Ok(()),
+            response => Err(error_from(response)),
+        }
     }
 
     /// Send ping command to the server
     pub fn ping(&mut self) -> Result<()> {
-        self.send_request(Request::Ping)?;
-        match self.get_response()? {
-            Response::Pong => {}
-            Response::Error(msg) => {
-                eprintln!("{}", msg);
-                exit(1);
-            }
-            _ => {
-                eprintln!("Unexpected response");
-                exit(1);
-            }
+        match self.call(&Request::Ping)? {
+            Response::Pong => Ok(()),
+            response => Err(error_from(response)),
         }
-        Ok(())
     }
 
     /// Get the value of a given string key
-    pub fn get(&mut self, key: String) -> Result<()> {
-        self.send_request(Request::Get { key })?;
-
-        match self.get_response()? {
-            Response::Value(Some(value)) => {
-                println!("{}", value);
-            }
-            Response::Value(None) => {
-                println!("Key not found");
-            }
-            Response::Error(msg) => {
-                eprintln!("{}", msg);
-                exit(1);
-            }
-            _ => {
-                eprintln!("Unexpected response");
-                exit(1);
-            }
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let request = Request::Get {
+            namespace: self.namespace.clone(),
+            key,
+        };
+        match self.request_leader(request)? {
+            Response::Value(value) => Ok(value),
+            response => Err(error_from(response)),
         }
-        Ok(())
     }
 
     /// Set key to hold the string value
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.send_request(Request::Set { key, value })?;
+        let request = Request::Set {
+            namespace: self.namespace.clone(),
+            key,
+            value,
+        };
+        match self.request_leader(request)? {
+            Response::Success => Ok(()),
+            response => Err(error_from(response)),
+        }
+    }
 
-        match self.get_response()? {
-            Response::Success => {}
-            Response::Error(msg) => {
-                eprintln!("{}", msg);
-                exit(1);
-            }
-            _ => {
-                eprintln!("Unexpected response");
-                exit(1);
-            }
+    /// Set every key to hold its value, in one request. The server applies the pairs in
+    /// order, and stops at the first that fails
+    pub fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let request = Request::SetBatch {
+            namespace: self.namespace.clone(),
+            pairs,
+        };
+        match self.request_leader(request)? {
+            Response::Success => Ok(()),
+            response => Err(error_from(response)),
         }
-        Ok(())
     }
 
     /// Remove key from the store
     pub fn remove(&mut self, key: String) -> Result<()> {
-        self.send_request(Request::Remove { key })?;
+        let request = Request::Remove {
+            namespace: self.namespace.clone(),
+            key,
+        };
+        match self.request_leader(request)? {
+            Response::Success => Ok(()),
+            response => Err(error_from(response)),
+        }
+    }
+
+    /// Add `delta` to the integer value of key, taken as 0 if the key does not exist,
+    /// and return the new value. Fails with `ErrorKind::NotAnInteger` if the value is
+    /// not an integer
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let request = Request::Incr {
+            namespace: self.namespace.clone(),
+            key,
+            delta,
+        };
+        match self.request_leader(request)? {
+            Response::Integer(value) => Ok(value),
+            response => Err(error_from(response)),
+        }
+    }
+
+    /// Subtract `delta` from the integer value of key, taken as 0 if the key does not
+    /// exist, and return the new value
+    pub fn decr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let request = Request::Decr {
+            namespace: self.namespace.clone(),
+            key,
+            delta,
+        };
+        match self.request_leader(request)? {
+            Response::Integer(value) => Ok(value),
+            response => Err(error_from(response)),
+        }
+    }
+
+    /// Get every key starting with `prefix`, with its value, in key order
+    pub fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.scan_request(prefix, None, None)
+    }
+
+    /// Get at most `limit` keys starting with `prefix`, following the key `after` if
+    /// given, with their values, in key order
+    pub fn scan_page(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.scan_request(prefix, after, Some(limit))
+    }
+
+    fn scan_request(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        let request = Request::Scan {
+            namespace: self.namespace.clone(),
+            prefix,
+            after,
+            limit,
+        };
+        match self.request_leader(request)? {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(error_from(response)),
+        }
+    }
 
+    /// Get, set and remove keys in the given namespace, or in the default one if `None`
+    pub fn use_namespace(&mut self, namespace: Option<String>) {
+        self.namespace = namespace;
+    }
+
+    /// Create a namespace on the server
+    pub fn create_namespace(&mut self, name: String) -> Result<()> {
+        match self.call(&Request::CreateNamespace { name })? {
+            Response::Success => Ok(()),
+            response => Err(error_from(response)),
+        }
+    }
+
+    /// Drop a namespace with all of its keys from the server
+    pub fn drop_namespace(&mut self, name: String) -> Result<()> {
+        match self.call(&Request::DropNamespace { name })? {
+            Response::Success => Ok(()),
+            response => Err(error_from(response)),
+        }
+    }
+
+    /// List the namespaces on the server, besides the default one
+    pub fn namespaces(&mut self) -> Result<Vec<String>> {
+        match self.call(&Request::ListNamespaces)? {
+            Response::Namespaces(names) => Ok(names),
+            response => Err(error_from(response)),
+        }
+    }
+
+    /// Get the replication log position of the server and the progress of its
+    /// followers
+    pub fn replication_status(&mut self) -> Result<ReplicationStatus> {
+        match self.call(&Request::ReplicationStatus)? {
+            Response::Replication(status) => Ok(status),
+            response => Err(error_from(response)),
+        }
+    }
+
+    /// Get request metrics of the server and statistics of its engines
+    pub fn stats(&mut self) -> Result<ServerStats> {
+        match self.call(&Request::Stats)? {
+            Response::Stats(stats) => Ok(stats),
+            response => Err(error_from(response)),
+        }
+    }
+
+    /// Turn the connection into a stream of the writes logged by the server after
+    /// position `from`.
+    pub(crate) fn start_replication(&mut self, from: u64) -> Result<()> {
+        match self.call(&Request::Replicate { from })? {
+            Response::Success => Ok(()),
+            response => Err(error_from(response)),
+        }
+    }
+
+    pub(crate) fn next_entry(&mut self) -> Result<Entry> {
         match self.get_response()? {
-            Response::Success => {}
-            Response::Error(msg) => {
-                eprintln!("{}", msg);
-                exit(1);
+            Response::Entry(entry) => Ok(entry),
+            response => Err(error_from(response)),
+        }
+    }
+
+    /// Tell the leader that writes up to `position` are applied.
+    pub(crate) fn acknowledge(&mut self, position: u64) -> Result<()> {
+        self.send_request(&Request::Ack { position })
+    }
+
+    /// Hand a message to the Raft node served by the server.
+    pub(crate) fn send_raft(&mut self, message: RaftMessage) -> Result<()> {
+        match self.call(&Request::Raft(message))? {
+            Response::Success => Ok(()),
+            response => Err(error_from(response)),
+        }
+    }
+
+    /// Ask the server to shut down gracefully
+    pub fn shutdown(&mut self) -> Result<()> {
+        match self.call(&Request::Shutdown)? {
+            Response::Success => Ok(()),
+            response => Err(error_from(response)),
+        }
+    }
+
+    /// Send a request, following redirects to the leader of a Raft cluster. The
+    /// connection is replaced by one to the leader.
+    fn request_leader(&mut self, request: Request) -> Result<Response> {
+        for _ in 0..MAX_REDIRECTS {
+            match self.call(&request)? {
+                Response::Redirect(Some(leader)) => {
+                    debug!("Redirected to the leader at {}", leader);
+                    let options = ClientOptions {
+                        namespace: self.namespace.clone(),
+                        ..self.options.clone()
+                    };
+                    *self = KvsClient::connect_with(leader, &options)?;
+                }
+                Response::Redirect(None) => thread::sleep(ELECTION_WAIT),
+                response => return Ok(response),
+            }
+        }
+        Err(ErrorKind::NotLeader(None))
+    }
+
+    /// Send a request and read its response. While the server is overloaded, the
+    /// request is retried up to `ClientOptions::overload_retries` times, waiting as long
+    /// as the server asks, and at least twice as long as before.
+    fn call(&mut self, request: &Request) -> Result<Response> {
+        self.send_request(request)?;
+        let mut response = self.get_response()?;
+        let mut backoff = OVERLOAD_BACKOFF;
+        for _ in 0..self.options.overload_retries {
+            let (retry_after, reconnect) = match response {
+                Response::Overloaded {
+                    retry_after,
+                    reconnect,
+                } => (retry_after, reconnect),
+                response => return Ok(response),
+            };
+            let wait = retry_after.max(backoff);
+            debug!("Server is overloaded, retrying in {:?}", wait);
+            thread::sleep(wait);
+            backoff *= 2;
+            if reconnect {
+                if let Some(refused) = self.reconnect()? {
+                    response = refused;
+                    continue;
+                }
             }
-            _ => {
-                eprintln!("Unexpected response");
-                exit(1);
+            self.send_request(request)?;
+            response = self.get_response()?;
+        }
+        Ok(response)
+    }
+
+    /// Replace the connection by a new one to the same server, authenticating again.
+    /// Returns the response of the server if it refuses the new connection too.
+    fn reconnect(&mut self) -> Result<Option<Response>> {
+        let (reader, writer) = open(self.addr, &self.options)?;
+        self.reader = reader;
+        self.writer = writer;
+        if let Some(token) = self.options.token.clone() {
+            self.send_request(&Request::Auth { token })?;
+            match self.get_response()? {
+                Response::Success => {}
+                response @ Response::Overloaded { .. } => return Ok(Some(response)),
+                response => return Err(error_from(response)),
             }
         }
-        Ok(())
+        Ok(None)
     }
 
-    fn send_request(&mut self, request: Request) -> Result<()> {
+    fn send_request(&mut self, request: &Request) -> Result<()> {
         debug!("Sending: {:?}", request);
-        serde_json::to_writer(&mut self.writer, &request)?;
+        serde_json::to_writer(&mut self.writer, request)?;
         self.writer.flush()?;
         Ok(())
     }
@@ -115,3 +416,35 @@ impl KvsClient {
         Ok(response)
     }
 }
+
+/// Open a connection to the server at `addr`.
+fn open(addr: SocketAddr, options: &ClientOptions) -> Result<(Reader, BufWriter<Stream>)> {
+    let timeouts = options.timeouts;
+    let tcp = match timeouts.connect {
+        Some(timeout) => TcpStream::connect_timeout(&addr, timeout)?,
+        None => TcpStream::connect(addr)?,
+    };
+    tcp.set_read_timeout(timeouts.read)?;
+    tcp.set_write_timeout(timeouts.write)?;
+    let reader = match &options.tls {
+        Some(tls) => Stream::connect_tls(tcp, tls)?,
+        None => Stream::Plain(tcp),
+    };
+    let writer = reader.try_clone()?;
+    Ok((
+        Deserializer::from_reader(BufReader::new(reader)),
+        BufWriter::new(writer),
+    ))
+}
+
+/// Convert a response that does not answer the request into an error.
+fn error_from(response: Response) -> ErrorKind {
+    match response {
+        Response::Error(msg) => ErrorKind::Server(msg),
+        Response::PermissionDenied(reason) => ErrorKind::PermissionDenied(reason),
+        Response::NotAnInteger(key) => ErrorKind::NotAnInteger(key),
+        Response::TooLarge(what) => ErrorKind::TooLarge(what),
+        Response::Overloaded { retry_after, .. } => ErrorKind::Overloaded(retry_after),
+        _ => ErrorKind::UnexpectedResponse,
+    }
+}
@@ -0,0 +1,158 @@
+use crate::error::{ErrorKind, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Lines, Read, Write};
+
+/// Pairs imported per batch unless configured otherwise.
+pub const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// Format of the key/value pairs exported from or imported into a store.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum DumpFormat {
+    /// One `{"key": ..., "value": ...}` JSON object per line
+    Jsonl,
+    /// A `key,value` header line, then one record per pair
+    Csv,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+/// Write `pairs` to `writer` in the given format. Returns the number of pairs written.
+pub fn write_pairs<W: Write>(
+    format: DumpFormat,
+    pairs: impl IntoIterator<Item = (String, String)>,
+    mut writer: W,
+) -> Result<u64> {
+    let mut written = 0;
+    match format {
+        DumpFormat::Jsonl => {
+            for (key, value) in pairs {
+                serde_json::to_writer(&mut writer, &Pair { key, value })?;
+                writer.write_all(b"\n")?;
+                written += 1;
+            }
+            writer.flush()?;
+        }
+        DumpFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            writer.write_record(["key", "value"]).map_err(csv_error)?;
+            for (key, value) in pairs {
+                writer.write_record([key, value]).map_err(csv_error)?;
+                written += 1;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(written)
+}
+
+/// Read the pairs of `reader`, in the given format, and hand them to `apply` in batches
+/// of at most `batch_size` pairs. Returns the number of pairs read.
+///
+/// Reading stops at the first malformed line, once the pairs before it are applied.
+pub fn read_batches<R: Read>(
+    format: DumpFormat,
+    reader: R,
+    batch_size: usize,
+    mut apply: impl FnMut(Vec<(String, String)>) -> Result<()>,
+) -> Result<u64> {
+    assert!(batch_size > 0, "batches hold at least one pair");
+    let mut read = 0;
+    let mut batch = Vec::with_capacity(batch_size);
+    for pair in PairReader::new(format, reader) {
+        let pair = match pair {
+            Ok(pair) => pair,
+            Err(e) => {
+                if !batch.is_empty() {
+                    apply(batch)?;
+                }
+                return Err(e);
+            }
+        };
+        batch.push(pair);
+        read += 1;
+        if batch.len() == batch_size {
+            apply(std::mem::replace(
+                &mut batch,
+                Vec::with_capacity(batch_size),
+            ))?;
+        }
+    }
+    if !batch.is_empty() {
+        apply(batch)?;
+    }
+    Ok(read)
+}
+
+/// Iterator over the pairs of a reader in a `DumpFormat`.
+enum PairReader<R: Read> {
+    Jsonl {
+        lines: Lines<BufReader<R>>,
+        line: u64,
+    },
+    Csv(csv::StringRecordsIntoIter<R>),
+}
+
+impl<R: Read> PairReader<R> {
+    fn new(format: DumpFormat, reader: R) -> Self {
+        match format {
+            DumpFormat::Jsonl => PairReader::Jsonl {
+                lines: BufReader::new(reader).lines(),
+                line: 0,
+            },
+            DumpFormat::Csv => PairReader::Csv(csv::Reader::from_reader(reader).into_records()),
+        }
+    }
+}
+
+impl<R: Read> Iterator for PairReader<R> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            PairReader::Jsonl { lines, line } => loop {
+                let text = match lines.next()? {
+                    Ok(text) => text,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                *line += 1;
+                if text.trim().is_empty() {
+                    continue;
+                }
+                return Some(
+                    serde_json::from_str::<Pair>(&text)
+                        .map(|pair| (pair.key, pair.value))
+                        .map_err(|e| ErrorKind::ConversionError(format!("line {}: {}", line, e))),
+                );
+            },
+            PairReader::Csv(records) => {
+                let record = match records.next()? {
+                    Ok(record) => record,
+                    Err(e) => return Some(Err(csv_error(e))),
+                };
+                match (record.get(0), record.get(1), record.len()) {
+                    (Some(key), Some(value), 2) => Some(Ok((key.to_owned(), value.to_owned()))),
+                    _ => {
+                        let line = record.position().map_or(0, |position| position.line());
+                        Some(Err(ErrorKind::ConversionError(format!(
+                            "line {}: expected a key and a value",
+                            line
+                        ))))
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn csv_error(e: csv::Error) -> ErrorKind {
+    if e.is_io_error() {
+        ErrorKind::Io(e.into())
+    } else {
+        ErrorKind::ConversionError(e.to_string())
+    }
+}
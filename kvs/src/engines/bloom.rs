@@ -0,0 +1,116 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Rate of lookups of absent keys that bloom filters let through, by default.
+pub const DEFAULT_BLOOM_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// Keys a `KeyFilter` is sized for at least.
+const MIN_FILTER_KEYS: u64 = 1024;
+
+/// A set of keys answering "maybe" or "certainly not", in a few bits per key.
+///
+/// Filters are kept in memory only and rebuilt from the keys when engines open, so their
+/// hashes need not be stable across builds.
+#[derive(Debug, Clone)]
+pub(super) struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// An empty filter sized for `keys` keys at the given false positive rate. More keys
+    /// may be inserted, at a higher false positive rate.
+    pub(super) fn new(keys: u64, false_positive_rate: f64) -> Self {
+        let keys = keys.max(1) as f64;
+        let rate = false_positive_rate.clamp(1e-9, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-keys * rate.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let hashes = (bits / keys * ln2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: vec![0; (bits as usize).div_ceil(64)],
+            hashes,
+        }
+    }
+
+    pub(super) fn insert(&mut self, key: &str) {
+        for bit in self.bit_indexes(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// False if `key` was certainly never inserted.
+    pub(super) fn may_contain(&self, key: &str) -> bool {
+        self.bit_indexes(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Rate of absent keys the filter would let through, estimated from the share of
+    /// its bits that are set.
+    pub(super) fn false_positive_rate(&self) -> f64 {
+        let set: u32 = self.bits.iter().map(|word| word.count_ones()).sum();
+        let share = f64::from(set) / (self.bits.len() * 64) as f64;
+        share.powi(self.hashes as i32)
+    }
+
+    /// Memory taken by the bits of the filter.
+    pub(super) fn bytes(&self) -> u64 {
+        self.bits.len() as u64 * 8
+    }
+
+    /// Bits of `key`, from two hashes combined as in Kirsch and Mitzenmacher's
+    /// "Less Hashing, Same Performance".
+    fn bit_indexes(&self, key: &str) -> impl Iterator<Item = usize> {
+        let hash = |seed: u64| {
+            let mut hasher = DefaultHasher::new();
+            seed.hash(&mut hasher);
+            key.hash(&mut hasher);
+            hasher.finish()
+        };
+        let (first, second) = (hash(0), hash(1) | 1);
+        let bits = self.bits.len() as u64 * 64;
+        (0..u64::from(self.hashes))
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % bits) as usize)
+    }
+}
+
+/// A bloom filter over the keys of a store that keys are inserted into as they are
+/// written. It is sized for twice the keys it is built with, and full once as many
+/// keys again were inserted: the store then builds a new one, which also forgets
+/// removed keys.
+#[derive(Debug, Clone)]
+pub(super) struct KeyFilter {
+    bloom: BloomFilter,
+    capacity: u64,
+    inserted: u64,
+}
+
+impl KeyFilter {
+    /// An empty filter for a store of `keys` keys, to insert them into.
+    pub(super) fn new(keys: u64, false_positive_rate: f64) -> Self {
+        let capacity = (keys * 2).max(MIN_FILTER_KEYS);
+        KeyFilter {
+            bloom: BloomFilter::new(capacity, false_positive_rate),
+            capacity,
+            inserted: 0,
+        }
+    }
+
+    pub(super) fn insert(&mut self, key: &str) {
+        self.bloom.insert(key);
+        self.inserted += 1;
+    }
+
+    /// False if `key` was certainly never inserted.
+    pub(super) fn may_contain(&self, key: &str) -> bool {
+        self.bloom.may_contain(key)
+    }
+
+    /// Whether more keys were inserted than the filter was sized for.
+    pub(super) fn is_full(&self) -> bool {
+        self.inserted > self.capacity
+    }
+
+    pub(super) fn false_positive_rate(&self) -> f64 {
+        self.bloom.false_positive_rate()
+    }
+}
@@ -1,60 +1,165 @@
-use super::{Engine, KvsEngine};
+use super::bloom::{KeyFilter, DEFAULT_BLOOM_FALSE_POSITIVE_RATE};
+use super::lru::LruMap;
+use super::segment::{Segment, SegmentWriter};
+use super::{check_namespace_name, Durability, Engine, EngineStats, KvsEngine};
 use crate::error::{ErrorKind, Result};
-use crate::log::LogEntry;
+use crate::log::{Compression, LogEntry};
+use clap::ValueEnum;
 use serde_json::Deserializer;
 use slog_scope::info;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::OpenOptions;
 use std::fs::{self, File};
 use std::io::prelude::*;
 use std::io::{self, BufReader, BufWriter, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-const COMPACTNESS_THRESHOLD: u64 = 1024;
+/// Overwritten or removed entries the log holds before it is compacted, by default.
+pub const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024;
+/// Size in bytes from which values are compressed, by default.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 512;
+const NAMESPACES_DIR: &str = "namespaces";
 
 type Position = u64;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Location {
     position: Position,
     length: u64,
 }
 type Key = String;
 
+/// How a `KvStore` finds the entries of its keys.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum IndexMode {
+    /// Every key is held in memory with the location of its entry in the log. Memory
+    /// grows with the number of keys
+    Hash,
+    /// Compaction merges the log into a segment of entries sorted by key, of which one
+    /// key every 4 KiB is held in memory. Only keys written since the last compaction
+    /// are held one by one, so memory is bounded by the compaction threshold
+    Sparse,
+}
+
+/// Options of a `KvStore`, given when opening it.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    /// How keys are indexed. A store written with a sparse index is converted when opened
+    /// with a hash index, and the other way around
+    pub index: IndexMode,
+    /// Overwritten or removed entries the log may hold before it is compacted. With a
+    /// sparse index, entries the log may hold before it is merged into the segment
+    pub compaction_threshold: u64,
+    /// Algorithm compressing values of at least `compression_threshold` bytes, if any.
+    /// Values written compressed are read back whatever the current setting
+    pub compression: Option<Compression>,
+    /// Size in bytes from which values are compressed
+    pub compression_threshold: usize,
+    /// Bytes of recently read keys and values held in memory, if any. Each namespace has
+    /// a cache of its own
+    pub cache_capacity: Option<u64>,
+    /// Rate of lookups of absent keys that the bloom filter of the keys lets through to
+    /// the index and the disk
+    pub bloom_false_positive_rate: f64,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            index: IndexMode::Hash,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            compression: None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            cache_capacity: None,
+            bloom_false_positive_rate: DEFAULT_BLOOM_FALSE_POSITIVE_RATE,
+        }
+    }
+}
+
 /// A key-value store.
 #[derive(Debug)]
 pub struct KvStore {
     writer: BufWriterWithPos<File>,
     reader: BufReader<File>,
     index: HashMap<String, Location>,
+    /// Sorted entries of the keys written before the last compaction, with a sparse index
+    segment: Option<Segment>,
+    /// Keys of the segment removed since the last compaction
+    removed: HashSet<Key>,
+    /// Bytes of the log entries the index references
+    live_log_bytes: u64,
+    /// Number of keys of the segment written or removed since the last compaction, and
+    /// bytes of their entries in the segment
+    superseded_keys: u64,
+    superseded_bytes: u64,
     to_compact: u64,
     dir: PathBuf,
+    durability: Durability,
+    compactions: u64,
+    compaction_time: Duration,
+    /// Values of recently read keys, if `cache_capacity` is set
+    cache: Option<LruMap>,
+    cache_hits: u64,
+    cache_misses: u64,
+    /// Bloom filter of the keys of the log and the segment
+    filter: KeyFilter,
+    options: KvStoreOptions,
 }
 
 impl KvsEngine for KvStore {
     /// Set the value of a string key to a string. Return an error if the value is not
     /// written successfully.
     fn set(&mut self, key: Key, value: String) -> Result<()> {
-        let log_entry = LogEntry::add(key.clone(), value);
+        self.invalidate(&key);
+        let superseding = !self.index.contains_key(&key)
+            && !self.removed.contains(&key)
+            && self.filter.may_contain(&key);
+        let superseded = match &self.segment {
+            Some(segment) if superseding => segment.get(&key)?.map(|(_, length)| length),
+            _ => None,
+        };
+        let log_entry = match self.options.compression {
+            Some(compression) if value.len() >= self.options.compression_threshold => {
+                LogEntry::add_compressed(key.clone(), value, compression)?
+            }
+            _ => LogEntry::add(key.clone(), value),
+        };
         let writing_start_position = self.writer.position;
         serde_json::to_writer(&mut self.writer, &log_entry)?;
-        self.writer.flush()?;
+        self.flush_log()?;
         let writing_end_position = self.writer.position;
-        if self
-            .index
-            .insert(
-                key,
-                Location {
-                    position: writing_start_position,
-                    length: writing_end_position - writing_start_position,
-                },
-            )
-            .is_some()
-        {
+        self.removed.remove(&key);
+        if !self.filter.may_contain(&key) {
+            self.filter.insert(&key);
+        }
+        if let Some(length) = superseded {
+            self.superseded_keys += 1;
+            self.superseded_bytes += length;
+        }
+        let length = writing_end_position - writing_start_position;
+        self.live_log_bytes += length;
+        let overwritten = match self.index.insert(
+            key,
+            Location {
+                position: writing_start_position,
+                length,
+            },
+        ) {
+            Some(old) => {
+                self.live_log_bytes -= old.length;
+                true
+            }
+            None => false,
+        };
+        // Every entry of the log is merged into the segment, if there is one.
+        if overwritten || self.segment.is_some() {
             self.to_compact += 1;
         }
-        if self.to_compact > COMPACTNESS_THRESHOLD {
+        if self.to_compact > self.options.compaction_threshold {
             self.compact()?;
+        } else if self.filter.is_full() {
+            self.rebuild_filter()?;
         }
         Ok(())
     }
@@ -62,35 +167,165 @@ impl KvsEngine for KvStore {
     /// Get the string value of a string key. If the key does not exist, return None.
     /// Return an error if the value is not read successfully.
     fn get(&mut self, key: Key) -> Result<Option<String>> {
-        match self.index.get(&key) {
-            Some(&Location { position, length }) => {
-                self.reader.seek(SeekFrom::Start(position))?;
-                let length_bound_reader = self.reader.get_mut().take(length);
-                let log_entry: LogEntry = serde_json::from_reader(length_bound_reader)?;
-                Ok(Some(log_entry.value))
+        if !self.filter.may_contain(&key) {
+            return Ok(None);
+        }
+        if let Some(cache) = &mut self.cache {
+            if let Some(value) = cache.get(&key) {
+                self.cache_hits += 1;
+                return Ok(Some(value.clone()));
             }
-            None => Ok(None),
+            self.cache_misses += 1;
         }
+        let value = self.lookup(&key)?;
+        if let (Some(cache), Some(value), Some(capacity)) =
+            (&mut self.cache, &value, self.options.cache_capacity)
+        {
+            cache.insert(key, value.clone());
+            cache.evict(capacity);
+        }
+        Ok(value)
     }
 
     /// Remove a given key. Return an error if the key does not exist or is not removed
     /// successfully.
     fn remove(&mut self, key: Key) -> Result<()> {
+        if !self.filter.may_contain(&key) {
+            return Err(ErrorKind::KeyNotFound);
+        }
+        self.invalidate(&key);
+        let in_segment = match &self.segment {
+            Some(segment) if !self.removed.contains(&key) => {
+                segment.get(&key)?.map(|(_, length)| length)
+            }
+            _ => None,
+        };
         let log_entry = LogEntry::remove(key.clone());
         serde_json::to_writer(&mut self.writer, &log_entry)?;
-        self.writer.flush()?;
+        self.flush_log()?;
         self.to_compact += 1;
-        self.index
-            .remove(&key)
-            .ok_or(ErrorKind::KeyNotFound)
-            .map(|_| ())?;
+        let in_log = match self.index.remove(&key) {
+            Some(location) => {
+                self.live_log_bytes -= location.length;
+                true
+            }
+            None => false,
+        };
+        if let Some(length) = in_segment {
+            // A key written since the last compaction already counts as superseded.
+            if !in_log {
+                self.superseded_keys += 1;
+                self.superseded_bytes += length;
+            }
+            self.removed.insert(key);
+        } else if !in_log {
+            return Err(ErrorKind::KeyNotFound);
+        }
 
-        if self.to_compact > COMPACTNESS_THRESHOLD {
+        if self.to_compact > self.options.compaction_threshold {
             self.compact()?;
         }
         Ok(())
     }
 
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut keys: Vec<Key> = self
+            .index
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.sort();
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.lookup(&key)? {
+                pairs.push((key, value));
+            }
+        }
+        if let Some(segment) = &self.segment {
+            for entry in segment.scan(prefix)? {
+                if !self.index.contains_key(&entry.key) && !self.removed.contains(&entry.key) {
+                    let key = entry.key.clone();
+                    pairs.push((key, entry.into_value()?));
+                }
+            }
+            pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        }
+        Ok(pairs)
+    }
+
+    /// Flush the log and make sure it reaches the disk.
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    /// Namespaces are stores in subdirectories of `namespaces/` in the store directory,
+    /// opened with the options of this store.
+    fn open_namespace(&mut self, name: &str) -> Result<Self> {
+        check_namespace_name(name)?;
+        let path = self.dir.join(NAMESPACES_DIR).join(name);
+        let mut store = KvStore::open_with(path, self.options.clone())?;
+        store.set_durability(self.durability);
+        Ok(store)
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        check_namespace_name(name)?;
+        let path = self.dir.join(NAMESPACES_DIR).join(name);
+        if !path.is_dir() {
+            return Err(ErrorKind::NamespaceNotFound(name.to_owned()));
+        }
+        fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let path = self.dir.join(NAMESPACES_DIR);
+        if !path.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut names = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                if let Some(name) = entry.file_name().to_str() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Bytes of the log not referenced by the index are stale, and so are entries of the
+    /// segment of keys written or removed since.
+    fn stats(&self) -> Result<EngineStats> {
+        let segment_keys = self.segment.as_ref().map_or(0, Segment::keys);
+        let segment_bytes = self.segment.as_ref().map_or(0, Segment::bytes);
+        let keys = self.index.len() as u64 + segment_keys - self.superseded_keys;
+        let live_bytes = self.live_log_bytes + segment_bytes - self.superseded_bytes;
+        let disk_bytes = self.writer.position + segment_bytes;
+        Ok(EngineStats {
+            keys,
+            disk_bytes,
+            live_bytes,
+            stale_bytes: disk_bytes - live_bytes,
+            compactions: self.compactions,
+            compaction_time: self.compaction_time,
+            cache_hits: self.cache_hits,
+            cache_misses: self.cache_misses,
+            bloom_false_positive_rate: self.filter.false_positive_rate(),
+            ..EngineStats::default()
+        })
+    }
+
     fn as_type(&self) -> Engine {
         Engine::kvs
     }
@@ -98,13 +333,30 @@ impl KvsEngine for KvStore {
 
 impl KvStore {
     /// Open the KvStore at a given path. Return the KvStore.
+    ///
+    /// Writes are handed to the operating system without syncing them to disk, unless
+    /// configured otherwise with `set_durability`.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Open the KvStore at a given path with the given options.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let path = path.into();
         fs::create_dir_all(&path)?;
         let log_path = path.join("data.log");
+        let segment_path = path.join("data.sst");
+        let segment = match options.index {
+            IndexMode::Hash => {
+                if segment_path.exists() {
+                    unmerge_segment(&segment_path, &log_path)?;
+                }
+                None
+            }
+            IndexMode::Sparse => Some(Segment::open(&segment_path)?),
+        };
 
         let writer_file = OpenOptions::new()
-            .write(true)
             .append(true)
             .create(true)
             .open(&log_path)?;
@@ -114,18 +366,98 @@ impl KvStore {
             writer: BufWriterWithPos::new(writer_file)?,
             reader: BufReader::new(reader_file),
             index: HashMap::new(),
+            segment,
+            removed: HashSet::new(),
+            live_log_bytes: 0,
+            superseded_keys: 0,
+            superseded_bytes: 0,
             dir: path,
             to_compact: 0,
+            durability: Durability::Buffered,
+            compactions: 0,
+            compaction_time: Duration::default(),
+            cache: options.cache_capacity.map(|_| LruMap::default()),
+            cache_hits: 0,
+            cache_misses: 0,
+            filter: KeyFilter::new(0, options.bloom_false_positive_rate),
+            options,
         };
         let position = store.read_all()?;
         store.writer.update_position(position)?;
+        store.rebuild_filter()?;
+        store.count_live()?;
 
-        if store.to_compact > COMPACTNESS_THRESHOLD {
+        if store.to_compact > store.options.compaction_threshold {
             store.compact()?;
         }
         Ok(store)
     }
 
+    /// Value of `key`, read from the log or the segment.
+    fn lookup(&mut self, key: &str) -> Result<Option<String>> {
+        if let Some(location) = self.index.get(key).cloned() {
+            return Ok(Some(self.read_entry(&location)?.into_value()?));
+        }
+        match &self.segment {
+            Some(segment) if !self.removed.contains(key) => segment
+                .get(key)?
+                .map(|(entry, _)| entry.into_value())
+                .transpose(),
+            _ => Ok(None),
+        }
+    }
+
+    /// Build a new bloom filter from the keys of the log and the segment, forgetting
+    /// removed keys.
+    fn rebuild_filter(&mut self) -> Result<()> {
+        let keys = self.segment.as_ref().map_or(0, Segment::keys) + self.index.len() as u64;
+        let mut filter = KeyFilter::new(keys, self.options.bloom_false_positive_rate);
+        for key in self.index.keys() {
+            filter.insert(key);
+        }
+        if let Some(segment) = &self.segment {
+            for entry in segment.entries()? {
+                filter.insert(&entry?.key);
+            }
+        }
+        self.filter = filter;
+        Ok(())
+    }
+
+    /// Count the live bytes of the log, and the entries of the segment its keys
+    /// supersede, looking each up on disk.
+    fn count_live(&mut self) -> Result<()> {
+        self.live_log_bytes = self.index.values().map(|location| location.length).sum();
+        self.superseded_keys = 0;
+        self.superseded_bytes = 0;
+        if let Some(segment) = &self.segment {
+            for key in self.index.keys().chain(&self.removed) {
+                if let Some((_, length)) = segment.get(key)? {
+                    self.superseded_keys += 1;
+                    self.superseded_bytes += length;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the cached value of `key`, about to be overwritten or removed.
+    fn invalidate(&mut self, key: &str) {
+        if let Some(cache) = &mut self.cache {
+            cache.remove(key);
+        }
+    }
+
+    /// Hand the written entries over to the OS, so that the reader can see them, and
+    /// sync them to disk if every write has to be durable.
+    fn flush_log(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if self.durability == Durability::Fsync {
+            self.writer.writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
     fn read_all(&mut self) -> Result<Position> {
         // To make sure we read from the beginning of the file
         let mut current_pos = self.reader.seek(SeekFrom::Start(0))?;
@@ -135,37 +467,53 @@ impl KvStore {
             let next_pos = stream.byte_offset() as u64;
 
             let log_entry = log_entry?;
-            if log_entry.is_tombstone() {
-                self.index.remove(&log_entry.key);
-                self.to_compact += 1;
-            } else if self
-                .index
-                .insert(
-                    log_entry.key,
-                    Location {
-                        position: current_pos,
-                        length: next_pos - current_pos,
-                    },
-                )
-                .is_some()
-            {
+            if self.segment.is_some() {
+                if log_entry.is_tombstone() {
+                    self.removed.insert(log_entry.key.clone());
+                } else {
+                    self.removed.remove(&log_entry.key);
+                }
                 self.to_compact += 1;
             }
+            let stale = index_entry(
+                &mut self.index,
+                log_entry,
+                Location {
+                    position: current_pos,
+                    length: next_pos - current_pos,
+                },
+            );
+            if self.segment.is_none() {
+                self.to_compact += stale;
+            }
             current_pos = next_pos;
         }
         Ok(current_pos)
     }
 
-    fn compact(&mut self) -> Result<()> {
+    fn read_entry(&mut self, location: &Location) -> Result<LogEntry> {
+        self.reader.seek(SeekFrom::Start(location.position))?;
+        let length_bound_reader = self.reader.get_mut().take(location.length);
+        Ok(serde_json::from_reader(length_bound_reader)?)
+    }
+
+    /// Rewrite the log with only the entries the index references. With a sparse index,
+    /// merge the log into the segment instead.
+    ///
+    /// `kvs-inspect compact` runs this on a stopped store.
+    pub fn compact(&mut self) -> Result<()> {
+        if self.segment.is_some() {
+            return self.merge_log();
+        }
         /* Compaction algorithm. The current strategy is to create a new file and copy
          * over all the entries in the index to the new file. Then, we replace the old file
          * with the new file and update the index.
          */
         info!("Compacting...");
+        let started = Instant::now();
         let compacted_log_path = self.dir.join("data--compacted.log");
         let original_log_path = self.dir.join("data.log");
         let writer_file = OpenOptions::new()
-            .write(true)
             .append(true)
             .create(true)
             .open(&compacted_log_path)?;
@@ -190,9 +538,306 @@ impl KvStore {
         self.to_compact = 0;
         self.reader = BufReader::new(OpenOptions::new().read(true).open(&original_log_path)?);
         self.writer.update_position(new_position)?;
+        self.rebuild_filter()?;
+        self.compactions += 1;
+        self.compaction_time += started.elapsed();
 
         Ok(())
     }
+
+    /// Merge the entries of the log into a new segment, then empty the log. Should the
+    /// process stop before the log is emptied, replaying it over the new segment when
+    /// opening the store again gives the same keys.
+    fn merge_log(&mut self) -> Result<()> {
+        info!("Merging the log into the segment...");
+        let started = Instant::now();
+        let mut log_keys: Vec<(Key, Option<Location>)> = self
+            .index
+            .iter()
+            .map(|(key, location)| (key.clone(), Some(location.clone())))
+            .chain(self.removed.iter().map(|key| (key.clone(), None)))
+            .collect();
+        log_keys.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let segment_path = self.dir.join("data.sst");
+        let mut writer = SegmentWriter::create(&self.dir.join("data--merged.sst"))?;
+        let mut old_entries = match &self.segment {
+            Some(segment) => segment.entries()?,
+            None => unreachable!("only stores with a segment merge their log"),
+        };
+        let keys = self.segment.as_ref().map_or(0, Segment::keys) + self.index.len() as u64;
+        let mut filter = KeyFilter::new(keys, self.options.bloom_false_positive_rate);
+        let mut old_entry = old_entries.next().transpose()?;
+        for (key, location) in log_keys {
+            while let Some(entry) = old_entry.as_ref().filter(|entry| entry.key < key) {
+                writer.push(entry)?;
+                filter.insert(&entry.key);
+                old_entry = old_entries.next().transpose()?;
+            }
+            if old_entry.as_ref().is_some_and(|entry| entry.key == key) {
+                old_entry = old_entries.next().transpose()?;
+            }
+            if let Some(location) = location {
+                let entry = self.read_entry(&location)?;
+                writer.push(&entry)?;
+                filter.insert(&entry.key);
+            }
+        }
+        while let Some(entry) = old_entry {
+            writer.push(&entry)?;
+            filter.insert(&entry.key);
+            old_entry = old_entries.next().transpose()?;
+        }
+        self.segment = Some(writer.finish(&segment_path)?);
+        self.filter = filter;
+
+        let log_path = self.dir.join("data.log");
+        File::create(&log_path)?.sync_all()?;
+        self.writer = BufWriterWithPos::new(OpenOptions::new().append(true).open(&log_path)?)?;
+        self.reader = BufReader::new(File::open(&log_path)?);
+        self.index.clear();
+        self.removed.clear();
+        self.live_log_bytes = 0;
+        self.superseded_keys = 0;
+        self.superseded_bytes = 0;
+        self.to_compact = 0;
+        self.compactions += 1;
+        self.compaction_time += started.elapsed();
+        Ok(())
+    }
+}
+
+/// Turn the store at the directory of `segment_path` back into a single log, for a hash
+/// index: the entries of the segment, older than those of the log, go before them.
+/// Should the process stop before the segment is removed, doing it again puts the
+/// entries of the segment before the log twice, which gives the same keys.
+fn unmerge_segment(segment_path: &Path, log_path: &Path) -> Result<()> {
+    info!(
+        "Converting the segment of {} back into its log",
+        log_path.display()
+    );
+    let unmerged_log_path = log_path.with_file_name("data--unmerged.log");
+    let mut writer = BufWriter::new(File::create(&unmerged_log_path)?);
+    io::copy(&mut File::open(segment_path)?, &mut writer)?;
+    if log_path.exists() {
+        io::copy(&mut File::open(log_path)?, &mut writer)?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::rename(&unmerged_log_path, log_path)?;
+    fs::remove_file(segment_path)?;
+    Ok(())
+}
+
+/// Record `entry`, found at `location` of the log, in `index`. Return the number of
+/// entries it makes stale: the previous entry of its key, or the tombstone itself.
+fn index_entry(index: &mut HashMap<Key, Location>, entry: LogEntry, location: Location) -> u64 {
+    if entry.is_tombstone() {
+        index.remove(&entry.key);
+        1
+    } else if index.insert(entry.key, location).is_some() {
+        1
+    } else {
+        0
+    }
+}
+
+/// Whether a record of the log still holds the value of its key.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RecordState {
+    /// The current value of the key
+    Live,
+    /// A value overwritten or removed by a later record
+    Superseded,
+    /// The removal of the key
+    Tombstone,
+}
+
+/// A record of the log of a `KvStore`.
+#[derive(Clone, Debug)]
+pub struct LogRecord {
+    /// Offset of the record in the log
+    pub offset: u64,
+    /// Length of the record in bytes
+    pub length: u64,
+    /// Key of the record
+    pub key: String,
+    /// Value of the record, decompressed, `None` for a tombstone
+    pub value: Option<String>,
+    /// How the value is compressed in the log, if it is
+    pub compression: Option<Compression>,
+    /// Whether the record still holds the value of its key
+    pub state: RecordState,
+}
+
+/// Bytes of the log of a `KvStore` that do not hold a record.
+#[derive(Clone, Debug)]
+pub struct CorruptRange {
+    /// Offset of the first corrupt byte
+    pub offset: u64,
+    /// Number of corrupt bytes, up to the next readable record or the end of the log
+    pub length: u64,
+    /// Why the bytes could not be read as a record
+    pub error: String,
+}
+
+/// The records of the log of a `KvStore`, as read by `KvStore::inspect`.
+#[derive(Clone, Debug, Default)]
+pub struct LogInspection {
+    /// Readable records, in log order
+    pub records: Vec<LogRecord>,
+    /// Unreadable ranges, in log order
+    pub corrupt: Vec<CorruptRange>,
+    /// Size of the log in bytes
+    pub file_bytes: u64,
+}
+
+impl LogInspection {
+    /// Bytes of the records holding current values.
+    pub fn live_bytes(&self) -> u64 {
+        self.records
+            .iter()
+            .filter(|record| record.state == RecordState::Live)
+            .map(|record| record.length)
+            .sum()
+    }
+
+    /// Bytes of corrupt ranges.
+    pub fn corrupt_bytes(&self) -> u64 {
+        self.corrupt.iter().map(|range| range.length).sum()
+    }
+
+    /// Size of the log relative to the bytes of its live records. 1 means compaction
+    /// would not reclaim anything; an empty log counts as 1 too.
+    pub fn space_amplification(&self) -> f64 {
+        match self.live_bytes() {
+            0 if self.file_bytes == 0 => 1.0,
+            0 => f64::INFINITY,
+            live_bytes => self.file_bytes as f64 / live_bytes as f64,
+        }
+    }
+}
+
+impl KvStore {
+    /// Read the log of the KvStore at a given path without opening the store. Records are
+    /// told live or superseded the way `open` builds its index. Unlike `open`, reading goes
+    /// on past corrupt bytes, from the next readable record.
+    pub fn inspect(path: impl Into<PathBuf>) -> Result<LogInspection> {
+        let data = fs::read(path.into().join("data.log"))?;
+        Ok(inspect_log(&data))
+    }
+
+    /// Drop the corrupt bytes of the log of the stopped KvStore at a given path, keeping
+    /// every readable record. Return the inspection of the log before the repair.
+    pub fn repair(path: impl Into<PathBuf>) -> Result<LogInspection> {
+        let dir = path.into();
+        let log_path = dir.join("data.log");
+        let data = fs::read(&log_path)?;
+        let inspection = inspect_log(&data);
+        if inspection.corrupt.is_empty() {
+            return Ok(inspection);
+        }
+
+        let repaired_log_path = dir.join("data--repaired.log");
+        let mut writer = BufWriter::new(File::create(&repaired_log_path)?);
+        for record in &inspection.records {
+            let start = record.offset as usize;
+            writer.write_all(&data[start..start + record.length as usize])?;
+        }
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&repaired_log_path, &log_path)?;
+        info!(
+            "Dropped {} corrupt bytes from {}",
+            inspection.corrupt_bytes(),
+            log_path.display()
+        );
+        Ok(inspection)
+    }
+}
+
+fn inspect_log(data: &[u8]) -> LogInspection {
+    let mut inspection = LogInspection {
+        file_bytes: data.len() as u64,
+        ..LogInspection::default()
+    };
+    let mut index = HashMap::new();
+    let mut position = 0;
+    while position < data.len() {
+        let mut stream = Deserializer::from_slice(&data[position..]).into_iter::<LogEntry>();
+        let mut current_pos = position;
+        let error = loop {
+            let entry = match stream.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) => break Some(e.to_string()),
+                None => break None,
+            };
+            let next_pos = position + stream.byte_offset();
+            let location = Location {
+                position: current_pos as u64,
+                length: (next_pos - current_pos) as u64,
+            };
+            let compression = entry.compression;
+            let value = if entry.is_tombstone() {
+                None
+            } else {
+                match entry.decompressed_value() {
+                    Ok(value) => Some(value),
+                    Err(e) => break Some(e.to_string()),
+                }
+            };
+            inspection.records.push(LogRecord {
+                offset: location.position,
+                length: location.length,
+                key: entry.key.clone(),
+                value,
+                compression,
+                state: RecordState::Superseded,
+            });
+            index_entry(&mut index, entry, location);
+            current_pos = next_pos;
+        };
+        position = current_pos;
+        let error = match error {
+            Some(error) => error,
+            None => break,
+        };
+        let resume = next_record(data, position + 1);
+        inspection.corrupt.push(CorruptRange {
+            offset: position as u64,
+            length: (resume - position) as u64,
+            error,
+        });
+        position = resume;
+    }
+
+    for record in &mut inspection.records {
+        record.state = match (&record.value, index.get(&record.key)) {
+            (None, _) => RecordState::Tombstone,
+            (Some(_), Some(location)) if location.position == record.offset => RecordState::Live,
+            (Some(_), _) => RecordState::Superseded,
+        };
+    }
+    inspection
+}
+
+/// Offset of the first readable record at or after `from`, or the end of `data`.
+/// Records start with `{"key":`, which cannot appear inside a serialized string.
+fn next_record(data: &[u8], from: usize) -> usize {
+    const RECORD_START: &[u8] = b"{\"key\":";
+    (from..data.len())
+        .find(|&offset| {
+            data[offset..].starts_with(RECORD_START)
+                && matches!(
+                    Deserializer::from_slice(&data[offset..])
+                        .into_iter::<LogEntry>()
+                        .next(),
+                    Some(Ok(_))
+                )
+        })
+        .unwrap_or(data.len())
 }
 
 #[derive(Debug)]
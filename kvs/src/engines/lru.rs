@@ -0,0 +1,82 @@
+use std::collections::BTreeMap;
+
+/// Values by key, with the order in which they were last used so that the least
+/// recently used can be evicted once they take too much memory.
+#[derive(Debug, Default)]
+pub(super) struct LruMap {
+    /// Value of each key and the tick of its last use
+    values: BTreeMap<String, (String, u64)>,
+    /// Key last used at each tick, least recently used first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: u64,
+    evictions: u64,
+}
+
+impl LruMap {
+    /// Value of `key`, which becomes the most recently used.
+    pub(super) fn get(&mut self, key: &str) -> Option<&String> {
+        let tick = self.tick + 1;
+        let (value, used) = self.values.get_mut(key)?;
+        let key = self.recency.remove(used).expect("recency of a held key");
+        *used = tick;
+        self.recency.insert(tick, key);
+        self.tick = tick;
+        Some(value)
+    }
+
+    /// Set the value of `key`, which becomes the most recently used.
+    pub(super) fn insert(&mut self, key: String, value: String) {
+        self.tick += 1;
+        self.bytes += (key.len() + value.len()) as u64;
+        self.recency.insert(self.tick, key.clone());
+        if let Some((old, used)) = self.values.insert(key.clone(), (value, self.tick)) {
+            self.recency.remove(&used);
+            self.bytes -= (key.len() + old.len()) as u64;
+        }
+    }
+
+    pub(super) fn remove(&mut self, key: &str) -> Option<String> {
+        let (value, used) = self.values.remove(key)?;
+        self.recency.remove(&used);
+        self.bytes -= (key.len() + value.len()) as u64;
+        Some(value)
+    }
+
+    /// Drop least recently used keys until the keys and values take at most
+    /// `max_bytes`.
+    pub(super) fn evict(&mut self, max_bytes: u64) {
+        while self.bytes > max_bytes {
+            let (_, key) = self.recency.pop_first().expect("recency of held bytes");
+            let (value, _) = self.values.remove(&key).expect("value of a used key");
+            self.bytes -= (key.len() + value.len()) as u64;
+            self.evictions += 1;
+        }
+    }
+
+    /// Keys starting with `prefix` with their values, in key order, leaving the order of
+    /// use as it is.
+    pub(super) fn scan<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a String, &'a String)> {
+        self.values
+            .range(prefix.to_owned()..)
+            .take_while(move |(key, _)| key.starts_with(prefix))
+            .map(|(key, (value, _))| (key, value))
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Bytes of the keys and values held.
+    pub(super) fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Keys evicted so far.
+    pub(super) fn evictions(&self) -> u64 {
+        self.evictions
+    }
+}
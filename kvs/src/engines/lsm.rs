@@ -0,0 +1,670 @@
+use super::bloom::{BloomFilter, DEFAULT_BLOOM_FALSE_POSITIVE_RATE};
+use super::segment::{Segment, SegmentWriter};
+use super::{check_namespace_name, Durability, Engine, EngineStats, KvsEngine};
+use crate::error::{ErrorKind, Result};
+use crate::log::LogEntry;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use slog_scope::{info, warn};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const NAMESPACES_DIR: &str = "namespaces";
+const RUNS_DIR: &str = "runs";
+const WAL_FILE: &str = "wal.log";
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Options of an `LsmKvsEngine`, given when opening it.
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// Bytes written to the write-ahead log after which the memtable is flushed to a
+    /// sorted run of level 0
+    pub memtable_bytes: u64,
+    /// Runs level 0 may hold before they are merged into level 1
+    pub level0_runs: usize,
+    /// Bytes level 1 may hold before it is merged into level 2
+    pub level1_bytes: u64,
+    /// How many times more bytes each level may hold than the one above it
+    pub level_ratio: u64,
+    /// Rate of lookups of absent keys the bloom filter of a run lets through
+    pub bloom_false_positive_rate: f64,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_bytes: 4 << 20,
+            level0_runs: 4,
+            level1_bytes: 16 << 20,
+            level_ratio: 10,
+            bloom_false_positive_rate: DEFAULT_BLOOM_FALSE_POSITIVE_RATE,
+        }
+    }
+}
+
+/// Runs making up each level, saved whenever it changes so that runs are added and
+/// removed at once.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    next_run: u64,
+    /// Runs of level 0, oldest first. Their keys may overlap.
+    level0: Vec<u64>,
+    /// The run of each level from level 1 on, if it holds one.
+    levels: Vec<Option<u64>>,
+}
+
+/// Number of live keys and bytes of their entries.
+#[derive(Debug, Default, Clone, Copy)]
+struct Live {
+    keys: u64,
+    bytes: u64,
+}
+
+impl Live {
+    /// Count a write replacing the live entry of a key of `old` bytes, if there is one,
+    /// by one of `new` bytes, unless it removes the key.
+    fn replace(&mut self, old: Option<u64>, new: Option<u64>) {
+        self.keys = self.keys + new.is_some() as u64 - old.is_some() as u64;
+        self.bytes = self.bytes + new.unwrap_or(0) - old.unwrap_or(0);
+    }
+}
+
+/// Bytes of the entry setting `key` to `value` in a run.
+fn entry_bytes(key: &str, value: &str) -> Result<u64> {
+    Ok(serde_json::to_vec(&LogEntry::add(key.to_owned(), value.to_owned()))?.len() as u64)
+}
+
+/// A sorted run with the bloom filter of its keys.
+#[derive(Debug)]
+struct Run {
+    id: u64,
+    segment: Segment,
+    bloom: BloomFilter,
+}
+
+/// A log-structured merge tree. Writes go to a write-ahead log and to a sorted map in
+/// memory, the memtable, which is flushed to a sorted run on disk once the log grows
+/// large. Runs are merged into levels of growing size, each level from level 1 on being
+/// a single run, and are looked up through bloom filters.
+#[derive(Debug)]
+pub struct LsmKvsEngine {
+    dir: PathBuf,
+    options: LsmOptions,
+    durability: Durability,
+    /// `None` values are removed keys.
+    memtable: BTreeMap<String, Option<String>>,
+    wal: BufWriter<File>,
+    wal_bytes: u64,
+    manifest: Manifest,
+    /// Runs of level 0, oldest first
+    level0: Vec<Run>,
+    /// The run of each level from level 1 on
+    levels: Vec<Option<Run>>,
+    /// Live keys of the runs with the memtable laid over them, counted on opening and
+    /// kept as writes happen. Flushing the memtable and merging runs do not change them.
+    live: Live,
+    compactions: u64,
+    compaction_time: Duration,
+}
+
+impl LsmKvsEngine {
+    /// Open the LsmKvsEngine at a given path, creating it if it does not exist.
+    ///
+    /// Writes are handed to the operating system without syncing them to disk, unless
+    /// configured otherwise with `set_durability`.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmKvsEngine> {
+        LsmKvsEngine::open_with(path, LsmOptions::default())
+    }
+
+    /// Open the LsmKvsEngine at a given path with the given options.
+    pub fn open_with(path: impl Into<PathBuf>, options: LsmOptions) -> Result<LsmKvsEngine> {
+        let dir = path.into();
+        fs::create_dir_all(dir.join(RUNS_DIR))?;
+        let manifest_path = dir.join(MANIFEST_FILE);
+        let manifest: Manifest = if manifest_path.exists() {
+            serde_json::from_slice(&fs::read(&manifest_path)?)?
+        } else {
+            Manifest::default()
+        };
+        remove_unlisted_runs(&dir, &manifest)?;
+
+        let open_run = |id| Run::open(&dir, id, options.bloom_false_positive_rate);
+        let level0 = manifest
+            .level0
+            .iter()
+            .map(|&id| open_run(id))
+            .collect::<Result<_>>()?;
+        let levels = manifest
+            .levels
+            .iter()
+            .map(|id| id.map(open_run).transpose())
+            .collect::<Result<_>>()?;
+
+        let wal_path = dir.join(WAL_FILE);
+        let mut memtable = BTreeMap::new();
+        let mut wal_bytes = 0;
+        if wal_path.exists() {
+            let reader = BufReader::new(File::open(&wal_path)?);
+            let mut stream = Deserializer::from_reader(reader).into_iter::<LogEntry>();
+            while let Some(entry) = stream.next() {
+                // A crash may leave the last entry incomplete, which is cut off. Any
+                // other entry that cannot be read is corrupt.
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) if !e.is_eof() => {
+                        return Err(ErrorKind::ConversionError(format!(
+                            "write-ahead log of {} is corrupt at byte {}: {}",
+                            dir.display(),
+                            wal_bytes,
+                            e
+                        )))
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Cutting off the write-ahead log of {} at byte {}: {}",
+                            dir.display(),
+                            wal_bytes,
+                            e
+                        );
+                        OpenOptions::new()
+                            .write(true)
+                            .open(&wal_path)?
+                            .set_len(wal_bytes)?;
+                        break;
+                    }
+                };
+                let key = entry.key.clone();
+                let value = if entry.is_tombstone() {
+                    None
+                } else {
+                    Some(entry.into_value()?)
+                };
+                memtable.insert(key, value);
+                wal_bytes = stream.byte_offset() as u64;
+            }
+        }
+        let wal = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&wal_path)?;
+
+        let mut engine = LsmKvsEngine {
+            dir,
+            options,
+            durability: Durability::Buffered,
+            memtable,
+            wal: BufWriter::new(wal),
+            wal_bytes,
+            manifest,
+            level0,
+            levels,
+            live: Live::default(),
+            compactions: 0,
+            compaction_time: Duration::default(),
+        };
+        let mut live = engine.count_runs_live()?;
+        for (key, value) in &engine.memtable {
+            let new = value
+                .as_deref()
+                .map(|value| entry_bytes(key, value))
+                .transpose()?;
+            live.replace(engine.live_in_runs(key)?, new);
+        }
+        engine.live = live;
+        if engine.wal_bytes >= engine.options.memtable_bytes {
+            engine.flush_memtable()?;
+        }
+        Ok(engine)
+    }
+
+    /// Append an entry to the write-ahead log, returning its length.
+    fn append_to_wal(&mut self, entry: &LogEntry) -> Result<u64> {
+        let bytes = serde_json::to_vec(entry)?;
+        self.wal.write_all(&bytes)?;
+        self.wal.flush()?;
+        if self.durability == Durability::Fsync {
+            self.wal.get_ref().sync_data()?;
+        }
+        self.wal_bytes += bytes.len() as u64;
+        Ok(bytes.len() as u64)
+    }
+
+    /// Runs in the order they are looked up in: newest first.
+    fn runs(&self) -> impl Iterator<Item = &Run> {
+        self.level0.iter().rev().chain(self.levels.iter().flatten())
+    }
+
+    /// Entry of `key` in the memtable or the newest run holding it, tombstones included.
+    fn lookup(&self, key: &str) -> Result<Option<Option<String>>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(Some(value.clone()));
+        }
+        for run in self.runs() {
+            if !run.bloom.may_contain(key) {
+                continue;
+            }
+            if let Some((entry, _)) = run.segment.get(key)? {
+                return Ok(Some(if entry.is_tombstone() {
+                    None
+                } else {
+                    Some(entry.into_value()?)
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Length of the entry of `key` in the newest run holding it, unless it removes it.
+    fn live_in_runs(&self, key: &str) -> Result<Option<u64>> {
+        for run in self.runs() {
+            if !run.bloom.may_contain(key) {
+                continue;
+            }
+            if let Some((entry, length)) = run.segment.get(key)? {
+                return Ok(Some(length).filter(|_| !entry.is_tombstone()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Live keys of the runs, found by merging them.
+    fn count_runs_live(&self) -> Result<Live> {
+        let sources = self
+            .runs()
+            .map(|run| run.segment.entries().map(boxed))
+            .collect::<Result<Vec<_>>>()?;
+        let mut live = Live::default();
+        for entry in MergedEntries::new(sources) {
+            let entry = entry?;
+            if !entry.is_tombstone() {
+                live.keys += 1;
+                live.bytes += serde_json::to_vec(&entry)?.len() as u64;
+            }
+        }
+        Ok(live)
+    }
+
+    /// Length of the live entry of `key`, from the memtable if it holds the key.
+    fn live_entry(&self, key: &str) -> Result<Option<u64>> {
+        match self.memtable.get(key) {
+            Some(value) => value
+                .as_deref()
+                .map(|value| entry_bytes(key, value))
+                .transpose(),
+            None => self.live_in_runs(key),
+        }
+    }
+
+    /// Write the memtable to a new run of level 0 and empty the write-ahead log, then
+    /// compact the levels that outgrew their size.
+    fn flush_memtable(&mut self) -> Result<()> {
+        if !self.memtable.is_empty() {
+            let started = Instant::now();
+            let id = self.manifest.next_run;
+            // Tombstones only matter if a run may hold the keys they remove.
+            let keep_tombstones = self.runs().next().is_some();
+            let mut writer =
+                RunWriter::create(&self.dir, id, self.memtable.len() as u64, &self.options)?;
+            for (key, value) in &self.memtable {
+                match value {
+                    Some(value) => writer.push(&LogEntry::add(key.clone(), value.clone()))?,
+                    None if keep_tombstones => writer.push(&LogEntry::remove(key.clone()))?,
+                    None => {}
+                }
+            }
+            self.level0.push(writer.finish()?);
+            self.manifest.next_run += 1;
+            self.manifest.level0.push(id);
+            self.save_manifest()?;
+            self.compaction_time += started.elapsed();
+        }
+        self.wal.flush()?;
+        File::create(self.dir.join(WAL_FILE))?.sync_all()?;
+        self.wal = BufWriter::new(
+            OpenOptions::new()
+                .append(true)
+                .open(self.dir.join(WAL_FILE))?,
+        );
+        self.wal_bytes = 0;
+        self.memtable.clear();
+        self.compact_levels()
+    }
+
+    /// Merge level 0 into level 1 once it holds too many runs, then each level into the
+    /// next one while it holds too many bytes.
+    fn compact_levels(&mut self) -> Result<()> {
+        if self.level0.len() >= self.options.level0_runs {
+            self.merge_into(0)?;
+        }
+        let mut limit = self.options.level1_bytes;
+        let mut level = 1;
+        while level <= self.levels.len() {
+            let bytes = self.levels[level - 1]
+                .as_ref()
+                .map_or(0, |run| run.segment.bytes());
+            if bytes > limit {
+                self.merge_into(level)?;
+            }
+            limit = limit.saturating_mul(self.options.level_ratio);
+            level += 1;
+        }
+        Ok(())
+    }
+
+    /// Merge the runs of `level` with the run of the level below it into a new run of
+    /// the level below. Tombstones are dropped when no deeper level holds runs.
+    fn merge_into(&mut self, level: usize) -> Result<()> {
+        info!("Merging level {} into level {}", level, level + 1);
+        let started = Instant::now();
+        if self.levels.len() <= level {
+            self.levels.resize_with(level + 1, || None);
+            self.manifest.levels.resize(level + 1, None);
+        }
+        let mut inputs: Vec<Run> = if level == 0 {
+            self.level0.drain(..).rev().collect()
+        } else {
+            self.levels[level - 1].take().into_iter().collect()
+        };
+        inputs.extend(self.levels[level].take());
+        let keep_tombstones = self.levels[level + 1..].iter().any(Option::is_some);
+
+        let id = self.manifest.next_run;
+        let keys = inputs.iter().map(|run| run.segment.keys()).sum();
+        let mut writer = RunWriter::create(&self.dir, id, keys, &self.options)?;
+        let sources = inputs
+            .iter()
+            .map(|run| run.segment.entries().map(boxed))
+            .collect::<Result<Vec<_>>>()?;
+        for entry in MergedEntries::new(sources) {
+            let entry = entry?;
+            if keep_tombstones || !entry.is_tombstone() {
+                writer.push(&entry)?;
+            }
+        }
+        self.levels[level] = Some(writer.finish()?);
+
+        self.manifest.next_run += 1;
+        if level == 0 {
+            self.manifest.level0.clear();
+        } else {
+            self.manifest.levels[level - 1] = None;
+        }
+        self.manifest.levels[level] = Some(id);
+        self.save_manifest()?;
+        for run in inputs {
+            fs::remove_file(run_path(&self.dir, run.id))?;
+        }
+        self.compactions += 1;
+        self.compaction_time += started.elapsed();
+        Ok(())
+    }
+
+    fn save_manifest(&self) -> Result<()> {
+        let path = self.dir.join(MANIFEST_FILE);
+        let temp_path = self.dir.join("MANIFEST--new");
+        let file = File::create(&temp_path)?;
+        serde_json::to_writer(&file, &self.manifest)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    /// Entries of the memtable and of every run whose key starts with `prefix`, newest
+    /// entry of each key only, tombstones included.
+    fn merged(&self, prefix: &str) -> Result<MergedEntries<'static>> {
+        let memtable: Vec<LogEntry> = self
+            .memtable
+            .range(prefix.to_owned()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| match value {
+                Some(value) => LogEntry::add(key.clone(), value.clone()),
+                None => LogEntry::remove(key.clone()),
+            })
+            .collect();
+        let mut sources = vec![boxed(memtable.into_iter().map(Ok))];
+        for run in self.runs() {
+            sources.push(boxed(run.segment.scan(prefix)?.into_iter().map(Ok)));
+        }
+        Ok(MergedEntries::new(sources))
+    }
+}
+
+impl KvsEngine for LsmKvsEngine {
+    /// Keys new to the memtable are looked up in the runs, to keep count of live keys.
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let old = self.live_entry(&key)?;
+        let new = self.append_to_wal(&LogEntry::add(key.clone(), value.clone()))?;
+        self.live.replace(old, Some(new));
+        self.memtable.insert(key, Some(value));
+        if self.wal_bytes >= self.options.memtable_bytes {
+            self.flush_memtable()?;
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.lookup(&key)?.flatten())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        let old = self.live_entry(&key)?;
+        if old.is_none() {
+            return Err(ErrorKind::KeyNotFound);
+        }
+        self.append_to_wal(&LogEntry::remove(key.clone()))?;
+        self.live.replace(old, None);
+        self.memtable.insert(key, None);
+        if self.wal_bytes >= self.options.memtable_bytes {
+            self.flush_memtable()?;
+        }
+        Ok(())
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for entry in self.merged(prefix)? {
+            let entry = entry?;
+            if !entry.is_tombstone() {
+                let key = entry.key.clone();
+                pairs.push((key, entry.into_value()?));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.wal.flush()?;
+        self.wal.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    /// Namespaces are engines in subdirectories of `namespaces/` in the engine directory,
+    /// opened with the options of this engine.
+    fn open_namespace(&mut self, name: &str) -> Result<Self> {
+        check_namespace_name(name)?;
+        let path = self.dir.join(NAMESPACES_DIR).join(name);
+        let mut engine = LsmKvsEngine::open_with(path, self.options.clone())?;
+        engine.set_durability(self.durability);
+        Ok(engine)
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        check_namespace_name(name)?;
+        let path = self.dir.join(NAMESPACES_DIR).join(name);
+        if !path.is_dir() {
+            return Err(ErrorKind::NamespaceNotFound(name.to_owned()));
+        }
+        fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let path = self.dir.join(NAMESPACES_DIR);
+        if !path.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut names = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                if let Some(name) = entry.file_name().to_str() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Live bytes are those of the newest entry of each key, as it is or would be
+    /// written to a run.
+    fn stats(&self) -> Result<EngineStats> {
+        let Live {
+            keys,
+            bytes: live_bytes,
+        } = self.live;
+        let disk_bytes = self.wal_bytes + self.runs().map(|run| run.segment.bytes()).sum::<u64>();
+        // An absent key is read from disk unless every filter rules it out.
+        let passed_by_none: f64 = self
+            .runs()
+            .map(|run| 1.0 - run.bloom.false_positive_rate())
+            .product();
+        Ok(EngineStats {
+            keys,
+            disk_bytes,
+            live_bytes,
+            stale_bytes: disk_bytes.saturating_sub(live_bytes),
+            compactions: self.compactions,
+            compaction_time: self.compaction_time,
+            bloom_false_positive_rate: 1.0 - passed_by_none,
+            ..EngineStats::default()
+        })
+    }
+
+    fn as_type(&self) -> Engine {
+        Engine::lsm
+    }
+}
+
+impl Run {
+    /// Open run `id` of the engine in `dir`, building its bloom filter from its keys.
+    fn open(dir: &Path, id: u64, false_positive_rate: f64) -> Result<Run> {
+        let segment = Segment::open(&run_path(dir, id))?;
+        let mut bloom = BloomFilter::new(segment.keys(), false_positive_rate);
+        for entry in segment.entries()? {
+            bloom.insert(&entry?.key);
+        }
+        Ok(Run { id, segment, bloom })
+    }
+}
+
+/// Writer of a new run, building its bloom filter along.
+struct RunWriter {
+    id: u64,
+    path: PathBuf,
+    writer: SegmentWriter,
+    bloom: BloomFilter,
+}
+
+impl RunWriter {
+    /// Start writing run `id` of the engine in `dir`, of at most `keys` keys.
+    fn create(dir: &Path, id: u64, keys: u64, options: &LsmOptions) -> Result<RunWriter> {
+        let path = run_path(dir, id);
+        Ok(RunWriter {
+            id,
+            writer: SegmentWriter::create(&path.with_extension("run--new"))?,
+            path,
+            bloom: BloomFilter::new(keys, options.bloom_false_positive_rate),
+        })
+    }
+
+    fn push(&mut self, entry: &LogEntry) -> Result<()> {
+        self.bloom.insert(&entry.key);
+        self.writer.push(entry)
+    }
+
+    fn finish(self) -> Result<Run> {
+        Ok(Run {
+            id: self.id,
+            segment: self.writer.finish(&self.path)?,
+            bloom: self.bloom,
+        })
+    }
+}
+
+fn run_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(RUNS_DIR).join(format!("{:08}.run", id))
+}
+
+/// Remove the files of `runs/` the manifest does not list: runs being written or merged
+/// away when the engine last stopped.
+fn remove_unlisted_runs(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let listed: Vec<PathBuf> = manifest
+        .level0
+        .iter()
+        .chain(manifest.levels.iter().flatten())
+        .map(|&id| run_path(dir, id))
+        .collect();
+    for entry in fs::read_dir(dir.join(RUNS_DIR))? {
+        let path = entry?.path();
+        if !listed.contains(&path) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+type Entries<'a> = Box<dyn Iterator<Item = Result<LogEntry>> + 'a>;
+
+fn boxed<'a>(entries: impl Iterator<Item = Result<LogEntry>> + 'a) -> Entries<'a> {
+    Box::new(entries)
+}
+
+/// Entries of sources sorted by key, merged into one sequence sorted by key holding the
+/// entry of each key from the first source that has it.
+struct MergedEntries<'a> {
+    sources: Vec<Peekable<Entries<'a>>>,
+}
+
+impl<'a> MergedEntries<'a> {
+    /// `sources` go from the newest to the oldest.
+    fn new(sources: Vec<Entries<'a>>) -> Self {
+        MergedEntries {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl Iterator for MergedEntries<'_> {
+    type Item = Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The first source holding the smallest key, whose entry is the newest of it.
+        let mut newest: Option<(usize, String)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Ok(entry)) if newest.as_ref().is_none_or(|(_, key)| entry.key < *key) => {
+                    newest = Some((i, entry.key.clone()));
+                }
+                Some(Err(_)) => return source.next(),
+                _ => {}
+            }
+        }
+        let (newest, key) = newest?;
+        for source in &mut self.sources[newest + 1..] {
+            if matches!(source.peek(), Some(Ok(entry)) if entry.key == key) {
+                source.next();
+            }
+        }
+        self.sources[newest].next()
+    }
+}
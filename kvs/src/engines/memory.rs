@@ -0,0 +1,127 @@
+use super::lru::LruMap;
+use super::{check_namespace_name, incremented, Durability, Engine, EngineStats, KvsEngine};
+use crate::error::{ErrorKind, Result};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// An engine keeping its keys in memory only, losing them when dropped.
+///
+/// With a maximum size, setting a key evicts the least recently set or read keys
+/// until the keys and values of the keyspace fit in it again. Each namespace has a
+/// maximum size of its own.
+#[derive(Debug, Clone)]
+pub struct InMemoryEngine {
+    entries: Arc<Mutex<LruMap>>,
+    namespaces: Arc<Mutex<BTreeMap<String, Arc<Mutex<LruMap>>>>>,
+    max_bytes: Option<u64>,
+}
+
+impl InMemoryEngine {
+    /// Create an empty engine without a maximum size.
+    pub fn new() -> Self {
+        InMemoryEngine {
+            entries: Arc::default(),
+            namespaces: Arc::default(),
+            max_bytes: None,
+        }
+    }
+
+    /// Create an empty engine evicting keys once its keys and values take more than
+    /// `max_bytes`.
+    pub fn with_max_bytes(max_bytes: u64) -> Self {
+        InMemoryEngine {
+            max_bytes: Some(max_bytes),
+            ..InMemoryEngine::new()
+        }
+    }
+}
+
+impl Default for InMemoryEngine {
+    fn default() -> Self {
+        InMemoryEngine::new()
+    }
+}
+
+impl KvsEngine for InMemoryEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(key, value);
+        if let Some(max_bytes) = self.max_bytes {
+            entries.evict(max_bytes);
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.entries.lock().unwrap().get(&key).cloned())
+    }
+
+    fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let mut entries = self.entries.lock().unwrap();
+        let value = incremented(&key, entries.get(&key).map(String::as_str), delta)?;
+        entries.insert(key, value.to_string());
+        if let Some(max_bytes) = self.max_bytes {
+            entries.evict(max_bytes);
+        }
+        Ok(value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        match self.entries.lock().unwrap().remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(ErrorKind::KeyNotFound),
+        }
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries
+            .scan(prefix)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Writes are never durable, so there is nothing to configure.
+    fn set_durability(&mut self, _durability: Durability) {}
+
+    fn open_namespace(&mut self, name: &str) -> Result<Self> {
+        check_namespace_name(name)?;
+        let mut namespaces = self.namespaces.lock().unwrap();
+        Ok(InMemoryEngine {
+            entries: Arc::clone(namespaces.entry(name.to_owned()).or_default()),
+            namespaces: Arc::clone(&self.namespaces),
+            max_bytes: self.max_bytes,
+        })
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        check_namespace_name(name)?;
+        match self.namespaces.lock().unwrap().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(ErrorKind::NamespaceNotFound(name.to_owned())),
+        }
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        Ok(self.namespaces.lock().unwrap().keys().cloned().collect())
+    }
+
+    /// The live bytes are those of the keys and values held in memory.
+    fn stats(&self) -> Result<EngineStats> {
+        let entries = self.entries.lock().unwrap();
+        Ok(EngineStats {
+            keys: entries.len() as u64,
+            live_bytes: entries.bytes(),
+            evictions: entries.evictions(),
+            ..EngineStats::default()
+        })
+    }
+
+    fn as_type(&self) -> Engine {
+        Engine::memory
+    }
+}
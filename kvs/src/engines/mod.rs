@@ -1,9 +1,22 @@
-use crate::error::Result;
+use crate::error::{ErrorKind, Result};
 use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
+mod bloom;
 mod kvs;
+mod lru;
+mod lsm;
+mod memory;
+mod segment;
 mod sled;
-pub use self::kvs::KvStore;
+pub use self::bloom::DEFAULT_BLOOM_FALSE_POSITIVE_RATE;
+pub use self::kvs::{
+    CorruptRange, IndexMode, KvStore, KvStoreOptions, LogInspection, LogRecord, RecordState,
+    DEFAULT_COMPACTION_THRESHOLD, DEFAULT_COMPRESSION_THRESHOLD,
+};
+pub use self::lsm::{LsmKvsEngine, LsmOptions};
+pub use self::memory::InMemoryEngine;
 pub use self::sled::SledKvsEngine;
 
 /// The engine of the key/value store.
@@ -14,6 +27,51 @@ pub enum Engine {
     kvs,
     /// Sled embedded database engine
     sled,
+    /// Log-structured merge tree engine
+    lsm,
+    /// In-memory engine, losing its keys when the server stops
+    memory,
+}
+
+/// How durable a write is once the engine or the server acknowledges it.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum Durability {
+    /// Every write is synced to disk before it is acknowledged
+    Fsync,
+    /// Writes are synced to disk in batches by explicit `KvsEngine::flush` calls.
+    /// `KvsServer` acknowledges a write once a flush covering it completes.
+    GroupCommit,
+    /// Writes are not synced before they are acknowledged. KvStore and the LSM engine
+    /// hand them to the operating system, while sled keeps them in memory until it
+    /// flushes its cache, so a crash may lose them
+    Buffered,
+}
+
+/// Statistics of an engine. Figures an engine does not track are zero.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// Number of keys
+    pub keys: u64,
+    /// Bytes the engine takes on disk
+    pub disk_bytes: u64,
+    /// Bytes of the log holding current values
+    pub live_bytes: u64,
+    /// Bytes of the log holding overwritten or removed values, until compaction
+    /// reclaims them
+    pub stale_bytes: u64,
+    /// Compactions since the engine was opened
+    pub compactions: u64,
+    /// Time spent compacting since the engine was opened
+    pub compaction_time: Duration,
+    /// Keys evicted to keep the engine within its maximum size
+    pub evictions: u64,
+    /// Reads answered from the cache of values
+    pub cache_hits: u64,
+    /// Reads that missed the cache of values and went to disk
+    pub cache_misses: u64,
+    /// Rate of lookups of absent keys that the bloom filter of the keys lets through,
+    /// estimated from its bits
+    pub bloom_false_positive_rate: f64,
 }
 
 /// Storage interface for key-value store.
@@ -30,6 +88,73 @@ pub trait KvsEngine {
     /// Returns an error if the key does not exit or value is not read successfully.
     fn remove(&mut self, key: String) -> Result<()>;
 
+    /// Adds `delta` to the integer value of a key, taken as 0 if the key does not
+    /// exist, and returns the new value. Returns `ErrorKind::NotAnInteger` if the value
+    /// is not an integer.
+    ///
+    /// Reading then setting the value is atomic as the engine is borrowed mutably.
+    /// Engines whose keys may be written through other handles override it.
+    fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let value = self.get(key.clone())?;
+        let value = incremented(&key, value.as_deref(), delta)?;
+        self.set(key, value.to_string())?;
+        Ok(value)
+    }
+
+    /// Returns every key starting with `prefix` with its value, ordered by key.
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>>;
+
+    /// Flushes buffered writes to durable storage.
+    fn flush(&mut self) -> Result<()>;
+
+    /// Sets how durable `set` and `remove` are when they return.
+    fn set_durability(&mut self, durability: Durability);
+
+    /// Opens the named namespace stored alongside this engine, creating it if it does
+    /// not exist. A namespace holds its keys apart from this engine and from other
+    /// namespaces.
+    fn open_namespace(&mut self, name: &str) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Removes the named namespace with all of its keys.
+    fn drop_namespace(&mut self, name: &str) -> Result<()>;
+
+    /// Lists the namespaces stored alongside this engine.
+    fn namespaces(&self) -> Result<Vec<String>>;
+
+    /// Returns statistics about the data held by the engine.
+    fn stats(&self) -> Result<EngineStats>;
+
     /// As Engine type
     fn as_type(&self) -> Engine;
 }
+
+/// The value of counter `key` once `delta` is added to it, from its current value if
+/// the key exists.
+pub(crate) fn incremented(key: &str, value: Option<&str>, delta: i64) -> Result<i64> {
+    let value = match value {
+        Some(value) => value
+            .parse::<i64>()
+            .map_err(|_| ErrorKind::NotAnInteger(key.to_owned()))?,
+        None => 0,
+    };
+    value.checked_add(delta).ok_or_else(|| {
+        ErrorKind::ConversionError(format!("adding {} to {:?} overflows", delta, key))
+    })
+}
+
+/// Check that a namespace name is non-empty and made of ASCII letters, digits, `-` and
+/// `_`, so that engines can use it as a file or tree name.
+pub(crate) fn check_namespace_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(ErrorKind::InvalidNamespace(name.to_owned()))
+    }
+}
@@ -0,0 +1,182 @@
+use crate::error::Result;
+use crate::log::LogEntry;
+use serde_json::Deserializer;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Bytes from the start of a block after which the next entry starts a new block. A
+/// block spans at most this many bytes plus one entry.
+const BLOCK_BYTES: u64 = 4096;
+
+/// First key and position of each block of a segment, in key order.
+#[derive(Debug, Default)]
+struct SparseIndex {
+    blocks: Vec<(String, u64)>,
+    keys: u64,
+}
+
+impl SparseIndex {
+    /// Account for the entry of `key` at `position`, the next one of the segment.
+    fn add(&mut self, key: &str, position: u64) {
+        let new_block = match self.blocks.last() {
+            Some(&(_, start)) => position - start >= BLOCK_BYTES,
+            None => true,
+        };
+        if new_block {
+            self.blocks.push((key.to_owned(), position));
+        }
+        self.keys += 1;
+    }
+}
+
+/// An immutable file of entries sorted by key, each key at most once, found through a
+/// sparse index of its blocks held in memory.
+#[derive(Debug)]
+pub(super) struct Segment {
+    path: PathBuf,
+    file: File,
+    index: SparseIndex,
+    length: u64,
+}
+
+impl Segment {
+    /// Open the segment at `path`, creating an empty one if there is none, and build
+    /// its index with one pass over it.
+    pub(super) fn open(path: &Path) -> Result<Segment> {
+        if !path.exists() {
+            File::create(path)?.sync_all()?;
+        }
+        let mut index = SparseIndex::default();
+        let mut stream =
+            Deserializer::from_reader(BufReader::new(File::open(path)?)).into_iter::<LogEntry>();
+        let mut position = 0;
+        while let Some(entry) = stream.next() {
+            index.add(&entry?.key, position);
+            position = stream.byte_offset() as u64;
+        }
+        Ok(Segment {
+            path: path.to_owned(),
+            file: File::open(path)?,
+            index,
+            length: position,
+        })
+    }
+
+    /// Number of keys of the segment.
+    pub(super) fn keys(&self) -> u64 {
+        self.index.keys
+    }
+
+    /// Size of the segment in bytes.
+    pub(super) fn bytes(&self) -> u64 {
+        self.length
+    }
+
+    /// Entry of `key` and its length in bytes, read from the disk with one read of the
+    /// block that would hold it.
+    pub(super) fn get(&self, key: &str) -> Result<Option<(LogEntry, u64)>> {
+        let blocks = &self.index.blocks;
+        let block = blocks.partition_point(|(first, _)| first.as_str() <= key);
+        if block == 0 {
+            return Ok(None);
+        }
+        let start = blocks[block - 1].1;
+        let end = blocks.get(block).map_or(self.length, |&(_, end)| end);
+        let mut data = vec![0; (end - start) as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut data)?;
+
+        let mut stream = Deserializer::from_slice(&data).into_iter::<LogEntry>();
+        let mut position = 0;
+        while let Some(entry) = stream.next() {
+            let entry = entry?;
+            let next_position = stream.byte_offset();
+            if entry.key == key {
+                return Ok(Some((entry, (next_position - position) as u64)));
+            } else if entry.key.as_str() > key {
+                break;
+            }
+            position = next_position;
+        }
+        Ok(None)
+    }
+
+    /// Entries whose key starts with `prefix`, in key order.
+    pub(super) fn scan(&self, prefix: &str) -> Result<Vec<LogEntry>> {
+        let blocks = &self.index.blocks;
+        // Keys with the prefix may start in the block before the first one whose first
+        // key comes after the prefix.
+        let block = blocks.partition_point(|(first, _)| first.as_str() < prefix);
+        let start = match block {
+            0 => 0,
+            block => blocks[block - 1].1,
+        };
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(start))?;
+        let reader = BufReader::new(file.take(self.length - start));
+
+        let mut entries = Vec::new();
+        for entry in Deserializer::from_reader(reader).into_iter::<LogEntry>() {
+            let entry = entry?;
+            if entry.key.starts_with(prefix) {
+                entries.push(entry);
+            } else if entry.key.as_str() > prefix {
+                break;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Every entry of the segment in key order, read from a handle of its own.
+    pub(super) fn entries(&self) -> Result<impl Iterator<Item = Result<LogEntry>>> {
+        let reader = BufReader::new(File::open(&self.path)?.take(self.length));
+        Ok(Deserializer::from_reader(reader)
+            .into_iter::<LogEntry>()
+            .map(|entry| entry.map_err(Into::into)))
+    }
+}
+
+/// Writer of a new segment, given its entries in key order.
+pub(super) struct SegmentWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    index: SparseIndex,
+    position: u64,
+}
+
+impl SegmentWriter {
+    /// Start writing a segment to `path`, replacing any file there.
+    pub(super) fn create(path: &Path) -> Result<SegmentWriter> {
+        Ok(SegmentWriter {
+            path: path.to_owned(),
+            writer: BufWriter::new(File::create(path)?),
+            index: SparseIndex::default(),
+            position: 0,
+        })
+    }
+
+    /// Append `entry`, whose key comes after the key of every entry appended so far.
+    pub(super) fn push(&mut self, entry: &LogEntry) -> Result<()> {
+        let bytes = serde_json::to_vec(entry)?;
+        self.writer.write_all(&bytes)?;
+        self.index.add(&entry.key, self.position);
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Sync the segment to disk and move it to `path`, replacing the segment there.
+    pub(super) fn finish(self, path: &Path) -> Result<Segment> {
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&self.path, path)?;
+        Ok(Segment {
+            path: path.to_owned(),
+            file: File::open(path)?,
+            index: self.index,
+            length: self.position,
+        })
+    }
+}
@@ -1,40 +1,201 @@
-use super::{Engine, KvsEngine};
+use super::bloom::{KeyFilter, DEFAULT_BLOOM_FALSE_POSITIVE_RATE};
+use super::{check_namespace_name, incremented, Durability, Engine, EngineStats, KvsEngine};
 use crate::error::{ErrorKind, Result};
+use slog_scope::warn;
 
 /// Sled engine wrapper
+///
+/// The engine must be the only writer to its tree. Lookups go through a bloom filter of
+/// the keys of the tree, built on opening it and kept up to date by the writes of the
+/// engine.
 pub struct SledKvsEngine {
     db: sled::Db,
+    tree: sled::Tree,
+    durability: Durability,
+    /// None if building the filter failed, in which case lookups go to the tree.
+    filter: Option<KeyFilter>,
+    bloom_false_positive_rate: f64,
+}
+
+/// Bloom filter of the keys of `tree`.
+fn build_filter(tree: &sled::Tree, false_positive_rate: f64) -> Result<KeyFilter> {
+    let mut filter = KeyFilter::new(tree.len() as u64, false_positive_rate);
+    for key in tree.iter().keys() {
+        filter.insert(&String::from_utf8(key?.to_vec())?);
+    }
+    Ok(filter)
 }
 
 impl SledKvsEngine {
-    /// Create a new SledKvsEngine
+    /// Create a new SledKvsEngine. Every write is flushed to disk unless configured
+    /// otherwise with `set_durability`.
     pub fn new(db: sled::Db) -> Self {
-        SledKvsEngine { db }
+        let tree = (*db).clone();
+        let mut engine = SledKvsEngine {
+            db,
+            tree,
+            durability: Durability::Fsync,
+            filter: None,
+            bloom_false_positive_rate: DEFAULT_BLOOM_FALSE_POSITIVE_RATE,
+        };
+        engine.reset_filter();
+        engine
+    }
+
+    /// Set the rate of lookups of absent keys that the bloom filter lets through to the
+    /// tree.
+    pub fn with_bloom_false_positive_rate(mut self, false_positive_rate: f64) -> Self {
+        self.bloom_false_positive_rate = false_positive_rate;
+        self.reset_filter();
+        self
+    }
+
+    /// Build the bloom filter of the keys of the tree, or go without one if the tree
+    /// cannot be read.
+    fn reset_filter(&mut self) {
+        self.filter = match build_filter(&self.tree, self.bloom_false_positive_rate) {
+            Ok(filter) => Some(filter),
+            Err(e) => {
+                warn!("Looking up keys without a bloom filter: {}", e);
+                None
+            }
+        };
+    }
+
+    /// Whether the tree may hold `key`.
+    fn may_contain(&self, key: &str) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| filter.may_contain(key))
+    }
+
+    /// Add a key written to the tree to the bloom filter, building a larger one once it
+    /// holds more keys than it was sized for.
+    fn add_to_filter(&mut self, key: &str) -> Result<()> {
+        if let Some(filter) = &mut self.filter {
+            if filter.is_full() {
+                *filter = build_filter(&self.tree, self.bloom_false_positive_rate)?;
+            } else if !filter.may_contain(key) {
+                filter.insert(key);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_if_required(&mut self) -> Result<()> {
+        if self.durability == Durability::Fsync {
+            self.db.flush()?;
+        }
+        Ok(())
     }
 }
 
 impl KvsEngine for SledKvsEngine {
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.db.insert(key, value.into_bytes())?;
-        self.db.flush()?;
-        Ok(())
+        self.tree.insert(key.as_str(), value.into_bytes())?;
+        self.add_to_filter(&key)?;
+        self.flush_if_required()
     }
 
     fn get(&mut self, key: String) -> Result<Option<String>> {
+        if !self.may_contain(&key) {
+            return Ok(None);
+        }
         Ok(self
-            .db
+            .tree
             .get(key)?
             .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
             .map(String::from_utf8)
             .transpose()?)
     }
 
+    fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let old = self.get(key.clone())?;
+        let value = incremented(&key, old.as_deref(), delta)?;
+        self.tree
+            .insert(key.as_str(), value.to_string().into_bytes())?;
+        self.add_to_filter(&key)?;
+        self.flush_if_required()?;
+        Ok(value)
+    }
+
     fn remove(&mut self, key: String) -> Result<()> {
-        self.db.remove(key)?.ok_or(ErrorKind::KeyNotFound)?;
+        if !self.may_contain(&key) {
+            return Err(ErrorKind::KeyNotFound);
+        }
+        self.tree.remove(key)?.ok_or(ErrorKind::KeyNotFound)?;
+        self.flush_if_required()
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for pair in self.tree.scan_prefix(prefix) {
+            let (key, value) = pair?;
+            pairs.push((
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            ));
+        }
+        Ok(pairs)
+    }
+
+    fn flush(&mut self) -> Result<()> {
         self.db.flush()?;
         Ok(())
     }
 
+    fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    /// Namespaces are trees of the same database.
+    fn open_namespace(&mut self, name: &str) -> Result<Self> {
+        check_namespace_name(name)?;
+        let tree = self.db.open_tree(name)?;
+        Ok(SledKvsEngine {
+            db: self.db.clone(),
+            filter: Some(build_filter(&tree, self.bloom_false_positive_rate)?),
+            tree,
+            durability: self.durability,
+            bloom_false_positive_rate: self.bloom_false_positive_rate,
+        })
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        check_namespace_name(name)?;
+        if self.db.drop_tree(name)? {
+            Ok(())
+        } else {
+            Err(ErrorKind::NamespaceNotFound(name.to_owned()))
+        }
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let default = self.db.name();
+        let mut names = Vec::new();
+        for name in self.db.tree_names() {
+            if name != default {
+                names.push(String::from_utf8(name.to_vec())?);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Sled does not expose its garbage, so only the keys and the size of the whole
+    /// database are known.
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: self.tree.len() as u64,
+            disk_bytes: self.db.size_on_disk()?,
+            bloom_false_positive_rate: self
+                .filter
+                .as_ref()
+                .map_or(0.0, KeyFilter::false_positive_rate),
+            ..EngineStats::default()
+        })
+    }
+
     fn as_type(&self) -> Engine {
         Engine::sled
     }
@@ -2,6 +2,8 @@
 
 use std::error::Error;
 use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddr;
+use std::time::Duration;
 
 /// The error type for this crate.
 #[derive(Debug)]
@@ -18,6 +20,34 @@ pub enum ErrorKind {
     DatabaseEngine(sled::Error),
     /// Wrong engine used error
     WrongEngineUsed,
+    /// Error reported by the server
+    Server(String),
+    /// Server responded with a message that does not match the request
+    UnexpectedResponse,
+    /// Peer did not respond within the configured timeout
+    Timeout,
+    /// TLS configuration or session error
+    Tls(rustls::Error),
+    /// Server refused the request for lack of credentials or permissions
+    PermissionDenied(String),
+    /// Namespace name that cannot be used
+    InvalidNamespace(String),
+    /// Namespace does not exist
+    NamespaceNotFound(String),
+    /// Namespace to create exists already
+    NamespaceExists(String),
+    /// Operation is not supported by the engine
+    Unsupported(String),
+    /// Request was sent to a cluster node that is not the leader. Holds the address of
+    /// the leader, if one is known
+    NotLeader(Option<SocketAddr>),
+    /// Value of a key to increment is not an integer. Holds the key
+    NotAnInteger(String),
+    /// Key, value or request is larger than the server accepts
+    TooLarge(String),
+    /// Server is too busy to handle requests. Holds the time after which the server
+    /// asked for the request to be sent again
+    Overloaded(Duration),
 }
 
 impl Display for ErrorKind {
@@ -29,6 +59,24 @@ impl Display for ErrorKind {
             ErrorKind::KeyNotFound => write!(f, "Key not found"),
             ErrorKind::DatabaseEngine(ref err) => err.fmt(f),
             ErrorKind::WrongEngineUsed => write!(f, "Wrong engine used!"),
+            ErrorKind::Server(msg) => write!(f, "{}", msg),
+            ErrorKind::UnexpectedResponse => write!(f, "Unexpected response"),
+            ErrorKind::Timeout => write!(f, "Operation timed out"),
+            ErrorKind::Tls(ref err) => write!(f, "TLS error: {}", err),
+            ErrorKind::PermissionDenied(reason) => write!(f, "Permission denied: {}", reason),
+            ErrorKind::InvalidNamespace(name) => write!(f, "Invalid namespace name: {:?}", name),
+            ErrorKind::NamespaceNotFound(name) => write!(f, "Namespace not found: {}", name),
+            ErrorKind::NamespaceExists(name) => write!(f, "Namespace already exists: {}", name),
+            ErrorKind::Unsupported(what) => write!(f, "Unsupported: {}", what),
+            ErrorKind::NotLeader(Some(leader)) => {
+                write!(f, "Not the leader, the leader is {}", leader)
+            }
+            ErrorKind::NotLeader(None) => write!(f, "No leader is elected"),
+            ErrorKind::NotAnInteger(key) => write!(f, "Value of key {:?} is not an integer", key),
+            ErrorKind::TooLarge(what) => write!(f, "Too large: {}", what),
+            ErrorKind::Overloaded(retry_after) => {
+                write!(f, "Server is overloaded, retry after {:?}", retry_after)
+            }
         }
     }
 }
@@ -39,20 +87,48 @@ impl Error for ErrorKind {
             ErrorKind::Io(ref err) => Some(err),
             ErrorKind::Serialization(ref err) => Some(err),
             ErrorKind::DatabaseEngine(ref err) => Some(err),
+            ErrorKind::Tls(ref err) => Some(err),
             _ => None,
         }
     }
 }
 
+impl ErrorKind {
+    /// Whether the error means the connection to the server is no longer usable.
+    pub fn is_connection_error(&self) -> bool {
+        match self {
+            ErrorKind::Io(_) | ErrorKind::Timeout => true,
+            ErrorKind::Serialization(err) => err.is_io() || err.is_eof(),
+            _ => false,
+        }
+    }
+}
+
+/// Sockets with a read or write timeout report `WouldBlock` on Unix and `TimedOut`
+/// on Windows once it expires.
+fn is_timeout(kind: std::io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
 impl From<std::io::Error> for ErrorKind {
     fn from(err: std::io::Error) -> Self {
-        ErrorKind::Io(err)
+        if is_timeout(err.kind()) {
+            ErrorKind::Timeout
+        } else {
+            ErrorKind::Io(err)
+        }
     }
 }
 
 impl From<serde_json::Error> for ErrorKind {
     fn from(err: serde_json::Error) -> Self {
-        ErrorKind::Serialization(err)
+        match err.io_error_kind() {
+            Some(kind) if is_timeout(kind) => ErrorKind::Timeout,
+            _ => ErrorKind::Serialization(err),
+        }
     }
 }
 
@@ -62,6 +138,12 @@ impl From<sled::Error> for ErrorKind {
     }
 }
 
+impl From<rustls::Error> for ErrorKind {
+    fn from(err: rustls::Error) -> Self {
+        ErrorKind::Tls(err)
+    }
+}
+
 impl From<std::string::FromUtf8Error> for ErrorKind {
     fn from(err: std::string::FromUtf8Error) -> Self {
         ErrorKind::ConversionError(err.to_string())
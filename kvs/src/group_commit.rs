@@ -0,0 +1,63 @@
+use crate::engines::KvsEngine;
+use crate::error::Result;
+use crate::namespaces::lock_engine;
+use slog_scope::debug;
+use std::sync::{Condvar, Mutex};
+
+/// Coordinates group commit of writes made by concurrent connections.
+///
+/// Every write registers a sequence number while still holding the engine lock. A
+/// writer waiting for durability either sees its write covered by a completed flush,
+/// or becomes the one flushing on behalf of every write registered so far. Writes
+/// arriving while a flush is in progress are covered by the next one.
+#[derive(Default)]
+pub(crate) struct GroupCommit {
+    state: Mutex<CommitState>,
+    flushed: Condvar,
+}
+
+#[derive(Default)]
+struct CommitState {
+    written: u64,
+    durable: u64,
+    flushing: bool,
+}
+
+impl GroupCommit {
+    /// Register a write applied to the engine. Must be called with the engine locked,
+    /// so that sequence numbers follow the order of writes.
+    pub(crate) fn register(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        state.written
+    }
+
+    /// Block until the write with the given sequence number is durable.
+    pub(crate) fn wait_durable<E: KvsEngine>(&self, seq: u64, engine: &Mutex<E>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.durable >= seq {
+                return Ok(());
+            }
+            if !state.flushing {
+                break;
+            }
+            state = self.flushed.wait(state).unwrap();
+        }
+
+        state.flushing = true;
+        let target = state.written;
+        drop(state);
+
+        debug!("Flushing writes up to {}", target);
+        let result = lock_engine(engine).flush();
+
+        let mut state = self.state.lock().unwrap();
+        state.flushing = false;
+        if result.is_ok() {
+            state.durable = target;
+        }
+        self.flushed.notify_all();
+        result
+    }
+}
@@ -3,15 +3,49 @@
 
 //! A simple key/value store library.
 
-pub use client::KvsClient;
-pub use engines::{Engine, KvStore, KvsEngine, SledKvsEngine};
+pub use acl::{Access, Acl};
+pub use client::{ClientOptions, ClientTimeouts, KvsClient, DEFAULT_OVERLOAD_RETRIES};
+pub use dump::{read_batches, write_pairs, DumpFormat, DEFAULT_BATCH_SIZE};
+pub use engines::{
+    CorruptRange, Durability, Engine, EngineStats, InMemoryEngine, IndexMode, KvStore,
+    KvStoreOptions, KvsEngine, LogInspection, LogRecord, LsmKvsEngine, LsmOptions, RecordState,
+    SledKvsEngine, DEFAULT_BLOOM_FALSE_POSITIVE_RATE, DEFAULT_COMPACTION_THRESHOLD,
+    DEFAULT_COMPRESSION_THRESHOLD,
+};
 pub use error::{ErrorKind, Result};
-pub use server::KvsServer;
+pub use log::Compression;
+pub use logging::{LogFormat, LogLevel, LogOptions};
+pub use metrics::{Histogram, RequestStats, ServerStats};
+pub use pool::KvsClientPool;
+pub use raft::{
+    NodeId, RaftConfig, RaftEngine, RaftInbox, RaftMessage, SimulatedNetwork, SimulatedTransport,
+    TcpTransport, Transport,
+};
+pub use rate_limit::RateLimit;
+pub use replication::{FollowerConfig, FollowerStatus, ReplicationStatus};
+pub use server::{KvsServer, ServerLimits, ServerTimeouts};
+pub use sharding::{ShardedKvsClient, DEFAULT_VIRTUAL_NODES, REBALANCE_PAGE};
+pub use shutdown::ShutdownHandle;
+pub use tls::{ClientTls, ServerTls};
 
+mod acl;
 mod client;
+mod dump;
 mod engines;
 mod error;
+mod group_commit;
 mod log;
+mod logging;
+mod metrics;
+mod namespaces;
+mod pool;
+mod raft;
+mod rate_limit;
+mod replication;
 mod requests;
 mod sandbox;
 mod server;
+mod sharding;
+mod shutdown;
+mod stream;
+mod tls;
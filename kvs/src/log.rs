@@ -1,4 +1,9 @@
+use crate::error::{ErrorKind, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::time::SystemTime;
 
 const TOMBSTONE: &str = "__tombstone__";
@@ -11,9 +16,34 @@ fn get_sys_time_in_secs() -> u64 {
     }
 }
 
+/// Algorithm compressing the values of log entries.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// LZ4, fast with a fair ratio
+    Lz4,
+    /// Zstandard, slower with a better ratio
+    Zstd,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+/// Zstandard level of compressed values, its default one.
+const ZSTD_LEVEL: i32 = 3;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LogEntry {
     pub key: String,
+    /// How `value` is compressed, if it is. Compressed values are base64-encoded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
     pub value: String,
 }
 
@@ -21,17 +51,74 @@ impl LogEntry {
     pub fn add(key: String, value: String) -> Self {
         assert!(value != TOMBSTONE);
 
-        Self { key, value }
+        Self {
+            key,
+            compression: None,
+            value,
+        }
+    }
+
+    /// Entry setting `key` to `value` compressed with `compression`, or to `value` as
+    /// is when compressing does not make the entry smaller.
+    pub fn add_compressed(key: String, value: String, compression: Compression) -> Result<Self> {
+        let compressed = match compression {
+            Compression::Lz4 => lz4_flex::compress_prepend_size(value.as_bytes()),
+            Compression::Zstd => zstd::encode_all(value.as_bytes(), ZSTD_LEVEL)?,
+        };
+        let encoded = BASE64.encode(compressed);
+        // Values are JSON-escaped when written, so their plain length is a lower bound.
+        if encoded.len() >= value.len() {
+            return Ok(Self::add(key, value));
+        }
+        Ok(Self {
+            key,
+            compression: Some(compression),
+            value: encoded,
+        })
     }
 
     pub fn remove(key: String) -> Self {
         Self {
             key,
+            compression: None,
             value: TOMBSTONE.to_string(),
         }
     }
 
     pub fn is_tombstone(&self) -> bool {
-        self.value == TOMBSTONE
+        self.compression.is_none() && self.value == TOMBSTONE
+    }
+
+    /// The value set by the entry, decompressed.
+    pub fn into_value(self) -> Result<String> {
+        match self.compression {
+            Some(_) => self.decompressed_value(),
+            None => Ok(self.value),
+        }
+    }
+
+    /// The value set by the entry, decompressed, leaving the entry as is.
+    pub fn decompressed_value(&self) -> Result<String> {
+        let compression = match self.compression {
+            Some(compression) => compression,
+            None => return Ok(self.value.clone()),
+        };
+        let corrupt = |e: String| {
+            ErrorKind::ConversionError(format!(
+                "corrupt {} value of key {:?}: {}",
+                compression, self.key, e
+            ))
+        };
+        let compressed = BASE64
+            .decode(&self.value)
+            .map_err(|e| corrupt(e.to_string()))?;
+        let value = match compression {
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(&compressed)
+                .map_err(|e| corrupt(e.to_string()))?,
+            Compression::Zstd => {
+                zstd::decode_all(compressed.as_slice()).map_err(|e| corrupt(e.to_string()))?
+            }
+        };
+        String::from_utf8(value).map_err(|e| corrupt(e.to_string()))
     }
 }
@@ -0,0 +1,113 @@
+use crate::error::Result;
+use clap::{Args, ValueEnum};
+use slog::{Drain, Level, LevelFilter, Logger};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Least severe level of the records that get logged.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum LogLevel {
+    /// Only failures the program cannot recover from
+    Critical,
+    /// Failed requests and operations
+    Error,
+    /// Unexpected conditions the program recovers from
+    Warning,
+    /// Startup, shutdown and other notable events
+    Info,
+    /// Every request and what it leads to
+    Debug,
+    /// Everything, including internal details
+    Trace,
+}
+
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Critical => Level::Critical,
+            LogLevel::Error => Level::Error,
+            LogLevel::Warning => Level::Warning,
+            LogLevel::Info => Level::Info,
+            LogLevel::Debug => Level::Debug,
+            LogLevel::Trace => Level::Trace,
+        }
+    }
+}
+
+/// How log records are written.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum LogFormat {
+    /// One human readable line per record
+    Text,
+    /// One JSON object per line, with the message, level, time and every key/value pair
+    /// of the record
+    Json,
+}
+
+/// Logging options of the command lines of the kvs binaries.
+#[derive(Args, Clone, Debug)]
+pub struct LogOptions {
+    /// Least severe level of the records to log
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        value_enum,
+        global = true,
+        default_value_t = LogLevel::Info
+    )]
+    pub log_level: LogLevel,
+
+    /// Format of the log records
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        value_enum,
+        global = true,
+        default_value_t = LogFormat::Text
+    )]
+    pub log_format: LogFormat,
+
+    /// File to append log records to, instead of the standard error
+    #[arg(long, value_name = "FILE", global = true)]
+    pub log_file: Option<PathBuf>,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        LogOptions {
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Text,
+            log_file: None,
+        }
+    }
+}
+
+impl LogOptions {
+    /// Build the root logger these options describe. Records never go to the standard
+    /// output, which is left to the output of the commands.
+    pub fn logger(&self) -> Result<Logger> {
+        let output: Box<dyn Write + Send> = match &self.log_file {
+            Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+            None => Box::new(io::stderr()),
+        };
+        let level = Level::from(self.log_level);
+        let logger = match self.log_format {
+            LogFormat::Text => {
+                let decorator = slog_term::PlainSyncDecorator::new(output);
+                let drain = slog_term::FullFormat::new(decorator).build().fuse();
+                Logger::root(LevelFilter::new(drain, level).fuse(), slog::o!())
+            }
+            LogFormat::Json => {
+                let drain = slog_json::Json::new(output)
+                    .add_default_keys()
+                    .set_flush(true)
+                    .build();
+                let drain = Mutex::new(drain).fuse();
+                Logger::root(LevelFilter::new(drain, level).fuse(), slog::o!())
+            }
+        };
+        Ok(logger)
+    }
+}
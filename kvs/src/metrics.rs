@@ -0,0 +1,384 @@
+use crate::engines::EngineStats;
+use crate::shutdown::ShutdownHandle;
+use serde::{Deserialize, Serialize};
+use slog_scope::{debug, error, info};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the latency histogram buckets, in microseconds.
+const BUCKETS_MICROS: [u64; 16] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 10_000_000,
+];
+
+/// How often the metrics endpoint checks for shutdown while no one scrapes it.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Name, type and help of an engine metric, with how to get its value.
+type EngineMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&EngineStats) -> f64,
+);
+
+/// Distribution of request latencies over fixed buckets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Histogram {
+    /// Upper bound of each bucket
+    pub bounds: Vec<Duration>,
+    /// Number of samples in each bucket, not cumulative. The last one counts samples
+    /// above every bound.
+    pub counts: Vec<u64>,
+    /// Sum of all samples
+    pub sum: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            bounds: BUCKETS_MICROS
+                .iter()
+                .map(|&micros| Duration::from_micros(micros))
+                .collect(),
+            counts: vec![0; BUCKETS_MICROS.len() + 1],
+            sum: Duration::default(),
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| latency <= bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += latency;
+    }
+
+    /// Number of samples.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile, `q` being between 0 and 1.
+    /// Samples above every bound are reported as the largest bound.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let rank = (q * self.count() as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (count, bound) in self.counts.iter().zip(&self.bounds) {
+            seen += count;
+            if seen >= rank {
+                return *bound;
+            }
+        }
+        self.bounds.last().copied().unwrap_or_default()
+    }
+}
+
+/// Counters and latencies of one type of request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RequestStats {
+    /// Type of the request, such as `get` or `set`
+    pub kind: String,
+    /// Requests that failed, were denied or redirected
+    pub errors: u64,
+    /// Time taken to handle the requests and send the responses
+    pub latency: Histogram,
+}
+
+/// Statistics of a running `KvsServer`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerStats {
+    /// Time since the server started listening
+    pub uptime: Duration,
+    /// Connections being served
+    pub connections: u64,
+    /// Statistics of each type of request received, ordered by type
+    pub requests: Vec<RequestStats>,
+    /// Statistics of the engine of the default namespace
+    pub engine: EngineStats,
+    /// Statistics of the engine of each named namespace, ordered by name
+    pub namespaces: Vec<(String, EngineStats)>,
+}
+
+/// Metrics collected by a server while it runs.
+pub(crate) struct Metrics {
+    started: Instant,
+    connections: AtomicU64,
+    requests: Mutex<BTreeMap<&'static str, RequestStats>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            requests: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Metrics {
+    pub(crate) fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn record(&self, kind: &'static str, latency: Duration, failed: bool) {
+        let mut requests = self.requests.lock().unwrap();
+        let stats = requests.entry(kind).or_insert_with(|| RequestStats {
+            kind: kind.to_owned(),
+            ..RequestStats::default()
+        });
+        stats.latency.record(latency);
+        if failed {
+            stats.errors += 1;
+        }
+    }
+
+    /// Statistics of the server, besides those of its engines.
+    pub(crate) fn snapshot(&self) -> ServerStats {
+        ServerStats {
+            uptime: self.started.elapsed(),
+            connections: self.connections.load(Ordering::SeqCst),
+            requests: self.requests.lock().unwrap().values().cloned().collect(),
+            ..ServerStats::default()
+        }
+    }
+}
+
+/// Render statistics in the Prometheus text exposition format.
+pub(crate) fn to_prometheus(stats: &ServerStats) -> String {
+    let mut out = String::new();
+    let seconds = |duration: Duration| duration.as_secs_f64();
+
+    metric_header(
+        &mut out,
+        "kvs_uptime_seconds",
+        "gauge",
+        "Time since the server started",
+    );
+    let _ = writeln!(out, "kvs_uptime_seconds {}", seconds(stats.uptime));
+    metric_header(
+        &mut out,
+        "kvs_connections",
+        "gauge",
+        "Connections being served",
+    );
+    let _ = writeln!(out, "kvs_connections {}", stats.connections);
+
+    metric_header(
+        &mut out,
+        "kvs_requests_total",
+        "counter",
+        "Requests handled",
+    );
+    for request in &stats.requests {
+        let _ = writeln!(
+            out,
+            "kvs_requests_total{{type=\"{}\"}} {}",
+            request.kind,
+            request.latency.count()
+        );
+    }
+    metric_header(
+        &mut out,
+        "kvs_request_errors_total",
+        "counter",
+        "Requests that failed, were denied or redirected",
+    );
+    for request in &stats.requests {
+        let _ = writeln!(
+            out,
+            "kvs_request_errors_total{{type=\"{}\"}} {}",
+            request.kind, request.errors
+        );
+    }
+    metric_header(
+        &mut out,
+        "kvs_request_duration_seconds",
+        "histogram",
+        "Time taken to handle requests",
+    );
+    for request in &stats.requests {
+        let latency = &request.latency;
+        let mut cumulative = 0;
+        for (count, bound) in latency.counts.iter().zip(&latency.bounds) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_bucket{{type=\"{}\",le=\"{}\"}} {}",
+                request.kind,
+                seconds(*bound),
+                cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "kvs_request_duration_seconds_bucket{{type=\"{}\",le=\"+Inf\"}} {}",
+            request.kind,
+            latency.count()
+        );
+        let _ = writeln!(
+            out,
+            "kvs_request_duration_seconds_sum{{type=\"{}\"}} {}",
+            request.kind,
+            seconds(latency.sum)
+        );
+        let _ = writeln!(
+            out,
+            "kvs_request_duration_seconds_count{{type=\"{}\"}} {}",
+            request.kind,
+            latency.count()
+        );
+    }
+
+    let engines: Vec<(&str, &EngineStats)> = std::iter::once(("", &stats.engine))
+        .chain(
+            stats
+                .namespaces
+                .iter()
+                .map(|(name, engine)| (name.as_str(), engine)),
+        )
+        .collect();
+    let gauges: [EngineMetric; 10] = [
+        ("kvs_engine_keys", "gauge", "Number of keys", |e| {
+            e.keys as f64
+        }),
+        (
+            "kvs_engine_disk_bytes",
+            "gauge",
+            "Bytes the engine takes on disk",
+            |e| e.disk_bytes as f64,
+        ),
+        (
+            "kvs_engine_live_bytes",
+            "gauge",
+            "Bytes of the log holding current values",
+            |e| e.live_bytes as f64,
+        ),
+        (
+            "kvs_engine_stale_bytes",
+            "gauge",
+            "Bytes of the log holding overwritten or removed values",
+            |e| e.stale_bytes as f64,
+        ),
+        (
+            "kvs_engine_compactions_total",
+            "counter",
+            "Compactions since the engine was opened",
+            |e| e.compactions as f64,
+        ),
+        (
+            "kvs_engine_compaction_seconds_total",
+            "counter",
+            "Time spent compacting",
+            |e| e.compaction_time.as_secs_f64(),
+        ),
+        (
+            "kvs_engine_evictions_total",
+            "counter",
+            "Keys evicted to keep the engine within its maximum size",
+            |e| e.evictions as f64,
+        ),
+        (
+            "kvs_engine_cache_hits_total",
+            "counter",
+            "Reads answered from the cache of values",
+            |e| e.cache_hits as f64,
+        ),
+        (
+            "kvs_engine_cache_misses_total",
+            "counter",
+            "Reads that missed the cache of values",
+            |e| e.cache_misses as f64,
+        ),
+        (
+            "kvs_engine_bloom_false_positive_rate",
+            "gauge",
+            "Estimated rate of lookups of absent keys the bloom filter lets through",
+            |e| e.bloom_false_positive_rate,
+        ),
+    ];
+    for (name, kind, help, value) in gauges.iter() {
+        metric_header(&mut out, name, kind, help);
+        for (namespace, engine) in &engines {
+            let _ = writeln!(
+                out,
+                "{}{{namespace=\"{}\"}} {}",
+                name,
+                namespace,
+                value(engine)
+            );
+        }
+    }
+    out
+}
+
+fn metric_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Serve statistics over HTTP in the Prometheus format on `listener`, whatever the
+/// path requested, until shutdown is requested.
+pub(crate) fn serve_http(
+    listener: TcpListener,
+    stats: impl Fn() -> ServerStats,
+    shutdown: &ShutdownHandle,
+) {
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!("Metrics endpoint disabled: {}", e);
+        return;
+    }
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving metrics on http://{}/metrics", addr);
+    }
+    while !shutdown.is_requested() {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                if let Err(e) = respond(stream, &stats) {
+                    debug!("Failed to serve metrics to {}: {}", peer, e);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL_INTERVAL)
+            }
+            Err(e) => debug!("Failed to accept a metrics connection: {}", e),
+        }
+    }
+}
+
+fn respond(stream: TcpStream, stats: &impl Fn() -> ServerStats) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    // The request itself does not matter, but its headers are read so that the client
+    // does not see the connection reset.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+        line.clear();
+    }
+
+    let body = to_prometheus(&stats());
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
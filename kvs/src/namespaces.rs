@@ -0,0 +1,148 @@
+use crate::engines::{Durability, EngineStats, KvsEngine};
+use crate::error::{ErrorKind, Result};
+use crate::group_commit::GroupCommit;
+use crate::requests::Response;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+
+/// Lock an engine shared between connections. A request that panicked while holding
+/// the lock, such as a write tripping an assertion before it changed anything, leaves
+/// the engine usable by the others.
+pub(crate) fn lock_engine<E>(engine: &Mutex<E>) -> MutexGuard<'_, E> {
+    engine.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The engine of a single namespace, with the group commit of its writes.
+pub(crate) struct Keyspace<E: KvsEngine> {
+    engine: Arc<Mutex<E>>,
+    group_commit: Option<GroupCommit>,
+}
+
+impl<E: KvsEngine> Keyspace<E> {
+    fn new(engine: Arc<Mutex<E>>, durability: Option<Durability>) -> Self {
+        if let Some(durability) = durability {
+            lock_engine(&engine).set_durability(durability);
+        }
+        Keyspace {
+            engine,
+            group_commit: match durability {
+                Some(Durability::GroupCommit) => Some(GroupCommit::default()),
+                _ => None,
+            },
+        }
+    }
+
+    /// Run an operation on the engine. If it is a `write` and writes are group
+    /// committed, return only once a flush covering it completes.
+    pub(crate) fn execute(
+        &self,
+        write: bool,
+        op: impl FnOnce(&mut E) -> Result<Response>,
+    ) -> Result<Response> {
+        let (response, commit_seq) = {
+            let mut engine = lock_engine(&self.engine);
+            let response = op(&mut engine)?;
+            let commit_seq = match &self.group_commit {
+                Some(group_commit) if write => Some(group_commit.register()),
+                _ => None,
+            };
+            (response, commit_seq)
+        };
+
+        if let (Some(group_commit), Some(seq)) = (&self.group_commit, commit_seq) {
+            group_commit.wait_durable(seq, &self.engine)?;
+        }
+        Ok(response)
+    }
+}
+
+/// The default namespace of a server, which is its engine, and the named namespaces
+/// stored alongside it.
+pub(crate) struct Namespaces<E: KvsEngine> {
+    default: Arc<Keyspace<E>>,
+    named: RwLock<HashMap<String, Arc<Keyspace<E>>>>,
+    durability: Option<Durability>,
+}
+
+impl<E: KvsEngine> Namespaces<E> {
+    /// Open every namespace stored alongside `engine`, applying `durability` to each
+    /// unless it is `None`.
+    pub(crate) fn open(engine: Arc<Mutex<E>>, durability: Option<Durability>) -> Result<Self> {
+        let mut named = HashMap::new();
+        {
+            let mut engine = lock_engine(&engine);
+            for name in engine.namespaces()? {
+                let namespace = engine.open_namespace(&name)?;
+                let keyspace = Keyspace::new(Arc::new(Mutex::new(namespace)), durability);
+                named.insert(name, Arc::new(keyspace));
+            }
+        }
+
+        Ok(Namespaces {
+            default: Arc::new(Keyspace::new(engine, durability)),
+            named: RwLock::new(named),
+            durability,
+        })
+    }
+
+    /// Get the named namespace, or the default one if `name` is `None`.
+    pub(crate) fn get(&self, name: Option<&str>) -> Result<Arc<Keyspace<E>>> {
+        match name {
+            None => Ok(Arc::clone(&self.default)),
+            Some(name) => self
+                .named
+                .read()
+                .unwrap()
+                .get(name)
+                .cloned()
+                .ok_or_else(|| ErrorKind::NamespaceNotFound(name.to_owned())),
+        }
+    }
+
+    pub(crate) fn create(&self, name: &str) -> Result<()> {
+        let mut named = self.named.write().unwrap();
+        if named.contains_key(name) {
+            return Err(ErrorKind::NamespaceExists(name.to_owned()));
+        }
+        let namespace = lock_engine(&self.default.engine).open_namespace(name)?;
+        let keyspace = Keyspace::new(Arc::new(Mutex::new(namespace)), self.durability);
+        named.insert(name.to_owned(), Arc::new(keyspace));
+        Ok(())
+    }
+
+    /// Drop the named namespace. Requests already holding it finish on the dropped
+    /// engine.
+    pub(crate) fn remove(&self, name: &str) -> Result<()> {
+        let mut named = self.named.write().unwrap();
+        if named.remove(name).is_none() {
+            return Err(ErrorKind::NamespaceNotFound(name.to_owned()));
+        }
+        lock_engine(&self.default.engine).drop_namespace(name)
+    }
+
+    pub(crate) fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.named.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Statistics of the default engine and of each named namespace, ordered by name.
+    pub(crate) fn stats(&self) -> Result<(EngineStats, Vec<(String, EngineStats)>)> {
+        let default = lock_engine(&self.default.engine).stats()?;
+        let mut named = Vec::new();
+        for (name, keyspace) in self.named.read().unwrap().iter() {
+            named.push((name.clone(), lock_engine(&keyspace.engine).stats()?));
+        }
+        named.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok((default, named))
+    }
+
+    /// Flush the engines of all namespaces.
+    pub(crate) fn flush(&self) -> Result<()> {
+        lock_engine(&self.default.engine).flush()?;
+        for keyspace in self.named.read().unwrap().values() {
+            lock_engine(&keyspace.engine).flush()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use crate::client::{ClientOptions, ClientTimeouts, KvsClient};
+use crate::error::Result;
+use crate::tls::ClientTls;
+use slog_scope::debug;
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A bounded pool of connections to a single key/value server.
+///
+/// Idle connections are health-checked with a ping before being reused. Requests that
+/// fail because the connection dropped are retried on a fresh connection with
+/// exponential backoff, but only when they are idempotent (`get` and `ping`).
+pub struct KvsClientPool {
+    addr: SocketAddr,
+    max_size: usize,
+    max_retries: u32,
+    backoff: Duration,
+    health_check_interval: Duration,
+    options: ClientOptions,
+    state: Mutex<PoolState>,
+    released: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<IdleClient>,
+    open: usize,
+}
+
+struct IdleClient {
+    client: KvsClient,
+    since: Instant,
+}
+
+impl KvsClientPool {
+    /// Create a pool keeping at most `max_size` connections to the server at `addr`.
+    ///
+    /// Connections are opened lazily, on first use.
+    pub fn new(addr: SocketAddr, max_size: usize) -> Self {
+        assert!(max_size > 0, "pool must allow at least one connection");
+
+        KvsClientPool {
+            addr,
+            max_size,
+            max_retries: DEFAULT_MAX_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            health_check_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+            options: ClientOptions::default(),
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Set how many times a failed request is retried and the initial backoff
+    /// between attempts. The backoff doubles after every attempt.
+    pub fn with_retries(mut self, max_retries: u32, backoff: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Set how long a connection may stay idle before it is pinged on checkout.
+    pub fn with_health_check_interval(mut self, interval: Duration) -> Self {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// Set the socket timeouts of connections opened by the pool.
+    pub fn with_timeouts(mut self, timeouts: ClientTimeouts) -> Self {
+        self.options.timeouts = timeouts;
+        self
+    }
+
+    /// Encrypt connections opened by the pool with TLS.
+    pub fn with_tls(mut self, tls: ClientTls) -> Self {
+        self.options.tls = Some(tls);
+        self
+    }
+
+    /// Authenticate connections opened by the pool with the given token.
+    pub fn with_token(mut self, token: String) -> Self {
+        self.options.token = Some(token);
+        self
+    }
+
+    /// Get, set and remove keys in the given namespace on connections opened by the pool.
+    pub fn with_namespace(mut self, namespace: String) -> Self {
+        self.options.namespace = Some(namespace);
+        self
+    }
+
+    /// Number of connections currently held open by the pool.
+    pub fn open_connections(&self) -> usize {
+        self.state.lock().unwrap().open
+    }
+
+    /// Send ping command to the server
+    pub fn ping(&self) -> Result<()> {
+        self.with_retries_on(true, |client| client.ping())
+    }
+
+    /// Get the value of a given string key
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.with_retries_on(true, |client| client.get(key.clone()))
+    }
+
+    /// Set key to hold the string value
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.with_retries_on(false, |client| client.set(key.clone(), value.clone()))
+    }
+
+    /// Remove key from the store
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.with_retries_on(false, |client| client.remove(key.clone()))
+    }
+
+    /// Run `request` on a pooled connection.
+    ///
+    /// Failing to connect is always retried, as nothing has reached the server yet.
+    /// Failures after the request was sent are retried only if it is `idempotent`.
+    fn with_retries_on<T>(
+        &self,
+        idempotent: bool,
+        mut request: impl FnMut(&mut KvsClient) -> Result<T>,
+    ) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            let (result, sent) = match self.checkout() {
+                Ok(mut conn) => {
+                    let result = request(&mut conn);
+                    if let Err(ref err) = result {
+                        if err.is_connection_error() {
+                            conn.discard();
+                        }
+                    }
+                    (result, true)
+                }
+                Err(err) => (Err(err), false),
+            };
+
+            match result {
+                Err(ref err)
+                    if err.is_connection_error()
+                        && (idempotent || !sent)
+                        && attempt < self.max_retries =>
+                {
+                    let backoff = self.backoff_for(attempt);
+                    debug!("Connection error: {}, retrying in {:?}", err, backoff);
+                    thread::sleep(backoff);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn backoff_for(&self, attempt: u32) -> Duration {
+        self.backoff
+            .checked_mul(1 << attempt.min(16))
+            .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF))
+    }
+
+    /// Take an idle connection, open a new one if below the limit, or wait for one
+    /// to be released.
+    fn checkout(&self) -> Result<PooledClient<'_>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(idle) = state.idle.pop() {
+                drop(state);
+                let mut conn = PooledClient {
+                    pool: self,
+                    client: Some(idle.client),
+                };
+                if idle.since.elapsed() < self.health_check_interval || conn.ping().is_ok() {
+                    return Ok(conn);
+                }
+                debug!("Dropping unhealthy connection to {}", self.addr);
+                conn.discard();
+                state = self.state.lock().unwrap();
+            } else if state.open < self.max_size {
+                state.open += 1;
+                drop(state);
+                return match KvsClient::connect_with(self.addr, &self.options) {
+                    Ok(client) => Ok(PooledClient {
+                        pool: self,
+                        client: Some(client),
+                    }),
+                    Err(err) => {
+                        self.release_slot();
+                        Err(err)
+                    }
+                };
+            } else {
+                state = self.released.wait(state).unwrap();
+            }
+        }
+    }
+
+    fn release_slot(&self) {
+        self.state.lock().unwrap().open -= 1;
+        self.released.notify_one();
+    }
+}
+
+/// A connection checked out of the pool. Returned to the pool on drop, unless
+/// it was discarded.
+struct PooledClient<'a> {
+    pool: &'a KvsClientPool,
+    client: Option<KvsClient>,
+}
+
+impl PooledClient<'_> {
+    fn discard(&mut self) {
+        if self.client.take().is_some() {
+            self.pool.release_slot();
+        }
+    }
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().expect("connection already discarded")
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().expect("connection already discarded")
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.state.lock().unwrap().idle.push(IdleClient {
+                client,
+                since: Instant::now(),
+            });
+            self.pool.released.notify_one();
+        }
+    }
+}
@@ -0,0 +1,699 @@
+use super::storage::{HardState, Storage};
+use super::transport::Transport;
+use super::{Body, Command, Entry, NodeId, RaftConfig, RaftMessage, Snapshot};
+use crate::error::Result;
+use rand::Rng;
+use slog_scope::{debug, info};
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+/// Most entries sent in one `AppendEntries` message.
+const MAX_BATCH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Outcome of a proposed command, sent once its index is applied.
+pub(crate) enum Applied {
+    /// The command was applied to the engine, with this result
+    Done(Result<()>),
+    /// Another entry was committed at its index, the leader having changed
+    Superseded(Option<NodeId>),
+}
+
+/// A command proposed on this node, waiting to be applied.
+struct Waiter {
+    term: u64,
+    sender: Sender<Applied>,
+}
+
+/// A read waiting on this node for its leadership to be confirmed.
+struct ReadWaiter {
+    /// First heartbeat round sent after the read arrived
+    round: u64,
+    /// Commit index when the read arrived, to be applied before answering
+    index: u64,
+    sender: Sender<()>,
+}
+
+/// State machine of the Raft protocol for one node. It handles messages and timeouts
+/// and decides what is committed. Applying entries to the engine is left to the
+/// caller, so that it can happen without holding the lock on the core.
+pub(crate) struct Core {
+    id: NodeId,
+    /// Every other node of the cluster
+    peers: Vec<NodeId>,
+    election_timeout: Duration,
+    heartbeat_interval: Duration,
+    snapshot_threshold: u64,
+
+    role: Role,
+    hard_state: HardState,
+    leader: Option<NodeId>,
+    storage: Storage,
+    transport: Box<dyn Transport>,
+
+    /// Entries after the snapshot
+    log: Vec<Entry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    commit_index: u64,
+    last_applied: u64,
+    /// A snapshot received from the leader, to be restored into the engine
+    pending_snapshot: Option<Snapshot>,
+
+    election_deadline: Instant,
+    heartbeat_deadline: Instant,
+    votes: HashSet<NodeId>,
+    next_index: HashMap<NodeId, u64>,
+    match_index: HashMap<NodeId, u64>,
+    /// When a leader last heard from each follower in its term
+    last_heard: HashMap<NodeId, Instant>,
+    /// Last heartbeat round sent by this node as a leader
+    round: u64,
+    /// Last heartbeat round each follower answered in the term of the leader
+    acked_round: HashMap<NodeId, u64>,
+    /// Index of the entry appended when this node became leader. Reads are served
+    /// only once it is applied, as earlier entries may be committed but not applied.
+    leader_start: u64,
+    waiters: HashMap<u64, Waiter>,
+    reads: Vec<ReadWaiter>,
+}
+
+impl Core {
+    pub(crate) fn new(config: &RaftConfig, transport: Box<dyn Transport>) -> Result<Self> {
+        let (storage, restored) = Storage::open(&config.dir)?;
+        let (snapshot_index, snapshot_term) = restored
+            .snapshot
+            .as_ref()
+            .map_or((0, 0), |snapshot| (snapshot.last_index, snapshot.last_term));
+        let mut peers: Vec<NodeId> = config
+            .peers
+            .keys()
+            .copied()
+            .filter(|&id| id != config.id)
+            .collect();
+        peers.sort_unstable();
+        let now = Instant::now();
+        info!(
+            "Raft node {} starting at term {} with {} log entries after index {}",
+            config.id,
+            restored.hard_state.term,
+            restored.entries.len(),
+            snapshot_index
+        );
+
+        let mut core = Core {
+            id: config.id,
+            peers,
+            election_timeout: config.election_timeout,
+            heartbeat_interval: config.heartbeat_interval,
+            snapshot_threshold: config.snapshot_threshold,
+            role: Role::Follower,
+            hard_state: restored.hard_state,
+            leader: None,
+            storage,
+            transport,
+            log: restored.entries,
+            snapshot_index,
+            snapshot_term,
+            // The engine was flushed when the snapshot was taken, and entries after it
+            // are applied again once known to be committed.
+            commit_index: snapshot_index,
+            last_applied: snapshot_index,
+            pending_snapshot: None,
+            election_deadline: now,
+            heartbeat_deadline: now,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            last_heard: HashMap::new(),
+            round: 0,
+            acked_round: HashMap::new(),
+            leader_start: 0,
+            waiters: HashMap::new(),
+            reads: Vec::new(),
+        };
+        core.reset_election_deadline(now);
+        Ok(core)
+    }
+
+    pub(crate) fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    pub(crate) fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub(crate) fn term(&self) -> u64 {
+        self.hard_state.term
+    }
+
+    /// Wait to serve a read until a majority of the nodes answered a heartbeat sent
+    /// after it, which confirms that no other node took over, and every entry committed
+    /// before it is applied. `sender` is then told, or dropped if the node steps down.
+    /// Fails with the known leader on any other node.
+    pub(crate) fn read(
+        &mut self,
+        sender: Sender<()>,
+    ) -> std::result::Result<Result<()>, Option<NodeId>> {
+        if self.role != Role::Leader {
+            return Err(self.leader);
+        }
+        self.reads.push(ReadWaiter {
+            round: self.round + 1,
+            // Entries of earlier terms may be committed without this node knowing yet.
+            index: self.commit_index.max(self.leader_start),
+            sender,
+        });
+        let result = self.broadcast_append();
+        self.answer_reads();
+        Ok(result)
+    }
+
+    /// Append a command to the log of the leader. Fails with the known leader on any
+    /// other node.
+    pub(crate) fn propose(
+        &mut self,
+        command: Command,
+        sender: Sender<Applied>,
+    ) -> std::result::Result<Result<()>, Option<NodeId>> {
+        if self.role != Role::Leader {
+            return Err(self.leader);
+        }
+        Ok(self.append_command(command).map(|index| {
+            let term = self.hard_state.term;
+            self.waiters.insert(index, Waiter { term, sender });
+        }))
+    }
+
+    pub(crate) fn step(&mut self, message: RaftMessage) -> Result<()> {
+        if message.to != self.id {
+            return Ok(());
+        }
+        let term = message.term;
+        if term > self.hard_state.term {
+            self.become_follower(term, None)?;
+        }
+        let now = Instant::now();
+        let from = message.from;
+
+        match message.body {
+            Body::RequestVote {
+                last_log_index,
+                last_log_term,
+            } => {
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let free = match self.hard_state.voted_for {
+                    None => true,
+                    Some(candidate) => candidate == from,
+                };
+                let granted = term == self.hard_state.term && free && up_to_date;
+                if granted {
+                    self.hard_state.voted_for = Some(from);
+                    self.storage.save_hard_state(self.hard_state)?;
+                    self.reset_election_deadline(now);
+                }
+                self.send(from, Body::Vote { granted });
+            }
+            Body::Vote { granted } => {
+                if self.role == Role::Candidate && term == self.hard_state.term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader(now)?;
+                    }
+                }
+            }
+            Body::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                round,
+            } => {
+                if term < self.hard_state.term {
+                    let match_index = self.commit_index;
+                    self.send(
+                        from,
+                        Body::AppendResult {
+                            success: false,
+                            match_index,
+                            round,
+                        },
+                    );
+                    return Ok(());
+                }
+                self.follow(from, now);
+                let matched =
+                    self.append_entries(prev_log_index, prev_log_term, &entries, leader_commit)?;
+                let body = match matched {
+                    Some(match_index) => Body::AppendResult {
+                        success: true,
+                        match_index,
+                        round,
+                    },
+                    None => Body::AppendResult {
+                        success: false,
+                        match_index: self.commit_index,
+                        round,
+                    },
+                };
+                self.send(from, body);
+            }
+            Body::AppendResult {
+                success,
+                match_index,
+                round,
+            } => {
+                if self.role != Role::Leader || term != self.hard_state.term {
+                    return Ok(());
+                }
+                self.last_heard.insert(from, now);
+                let acked = self.acked_round.entry(from).or_insert(0);
+                *acked = (*acked).max(round);
+                self.answer_reads();
+                if success {
+                    self.acknowledged(from, match_index);
+                } else {
+                    let next = self.next_index.get(&from).copied().unwrap_or(1);
+                    let next = (match_index + 1).min(next.saturating_sub(1)).max(1);
+                    self.next_index.insert(from, next);
+                    self.send_append(from)?;
+                }
+            }
+            Body::InstallSnapshot { snapshot } => {
+                if term < self.hard_state.term {
+                    return Ok(());
+                }
+                self.follow(from, now);
+                let last_index = snapshot.last_index;
+                self.install_snapshot(snapshot)?;
+                self.send(from, Body::SnapshotInstalled { last_index });
+            }
+            Body::SnapshotInstalled { last_index } => {
+                if self.role == Role::Leader && term == self.hard_state.term {
+                    self.last_heard.insert(from, now);
+                    self.acknowledged(from, last_index);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Start an election when no leader was heard from in time, and send heartbeats
+    /// as a leader. A leader that loses touch with a majority steps down, so that
+    /// clients of a partitioned leader are not kept waiting.
+    pub(crate) fn tick(&mut self, now: Instant) -> Result<()> {
+        match self.role {
+            Role::Leader => {
+                let window = self.election_timeout * 2;
+                let reachable = self
+                    .last_heard
+                    .values()
+                    .filter(|&&heard| now.duration_since(heard) < window)
+                    .count();
+                if reachable + 1 < self.quorum() {
+                    info!(
+                        "Raft node {} lost touch with a majority, stepping down",
+                        self.id
+                    );
+                    let term = self.hard_state.term;
+                    return self.become_follower(term, None);
+                }
+                if now >= self.heartbeat_deadline {
+                    self.broadcast_append()?;
+                    self.heartbeat_deadline = now + self.heartbeat_interval;
+                }
+            }
+            Role::Follower | Role::Candidate => {
+                if now >= self.election_deadline {
+                    self.start_election(now)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn take_pending_snapshot(&mut self) -> Option<Snapshot> {
+        self.pending_snapshot.take()
+    }
+
+    pub(crate) fn snapshot_applied(&mut self, snapshot: &Snapshot) {
+        self.last_applied = self.last_applied.max(snapshot.last_index);
+        self.answer_reads();
+    }
+
+    /// Entries committed but not yet applied.
+    pub(crate) fn committed_entries(&self) -> Vec<Entry> {
+        if self.pending_snapshot.is_some() || self.commit_index <= self.last_applied {
+            return Vec::new();
+        }
+        let start = (self.last_applied - self.snapshot_index) as usize;
+        let end = (self.commit_index - self.snapshot_index) as usize;
+        self.log[start..end].to_vec()
+    }
+
+    pub(crate) fn entry_applied(&mut self, index: u64, term: u64, result: Result<()>) {
+        self.last_applied = index;
+        self.answer_reads();
+        if let Some(waiter) = self.waiters.remove(&index) {
+            let applied = if waiter.term == term {
+                Applied::Done(result)
+            } else {
+                Applied::Superseded(self.leader)
+            };
+            let _ = waiter.sender.send(applied);
+        }
+    }
+
+    /// Index and term of the last applied entry, once enough entries were applied
+    /// since the last snapshot to take a new one.
+    pub(crate) fn snapshot_due(&self) -> Option<(u64, u64)> {
+        if self.last_applied.saturating_sub(self.snapshot_index) < self.snapshot_threshold {
+            return None;
+        }
+        self.term_at(self.last_applied)
+            .map(|term| (self.last_applied, term))
+    }
+
+    /// Save a snapshot of the engine and drop the log entries it covers.
+    pub(crate) fn compact(&mut self, snapshot: &Snapshot) -> Result<()> {
+        if snapshot.last_index <= self.snapshot_index {
+            return Ok(());
+        }
+        self.storage.save_snapshot(snapshot)?;
+        let covered = (snapshot.last_index - self.snapshot_index) as usize;
+        self.log.drain(..covered.min(self.log.len()));
+        self.snapshot_index = snapshot.last_index;
+        self.snapshot_term = snapshot.last_term;
+        self.storage.rewrite_log(&self.log)?;
+        debug!(
+            "Raft node {} compacted its log up to index {}",
+            self.id, snapshot.last_index
+        );
+        Ok(())
+    }
+
+    /// Tell the reads whose leadership is confirmed and whose entries are applied.
+    fn answer_reads(&mut self) {
+        let quorum = self.quorum();
+        let (acked_round, last_applied) = (&self.acked_round, self.last_applied);
+        self.reads.retain(|read| {
+            let confirmed = acked_round
+                .values()
+                .filter(|&&round| round >= read.round)
+                .count()
+                + 1
+                >= quorum;
+            if confirmed && last_applied >= read.index {
+                let _ = read.sender.send(());
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn quorum(&self) -> usize {
+        let size = self.peers.len() + 1;
+        size / 2 + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.log
+            .last()
+            .map_or(self.snapshot_index, |entry| entry.index)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    /// Term of the entry at `index`, unless it was compacted or is beyond the log.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            Some(self.snapshot_term)
+        } else if index < self.snapshot_index {
+            None
+        } else {
+            self.log
+                .get((index - self.snapshot_index - 1) as usize)
+                .map(|entry| entry.term)
+        }
+    }
+
+    fn send(&self, to: NodeId, body: Body) {
+        self.transport.send(RaftMessage {
+            from: self.id,
+            to,
+            term: self.hard_state.term,
+            body,
+        });
+    }
+
+    fn reset_election_deadline(&mut self, now: Instant) {
+        let min = self.election_timeout.as_millis() as u64;
+        let timeout = rand::thread_rng().gen_range(min, min * 2 + 1);
+        self.election_deadline = now + Duration::from_millis(timeout);
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term > self.hard_state.term {
+            self.hard_state = HardState {
+                term,
+                voted_for: None,
+            };
+            self.storage.save_hard_state(self.hard_state)?;
+        }
+        if self.role != Role::Follower {
+            debug!("Raft node {} follows at term {}", self.id, term);
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.votes.clear();
+        self.reads.clear();
+        Ok(())
+    }
+
+    /// Recognize `leader` as the leader of the current term.
+    fn follow(&mut self, leader: NodeId, now: Instant) {
+        if self.leader != Some(leader) {
+            info!(
+                "Raft node {} follows node {} at term {}",
+                self.id, leader, self.hard_state.term
+            );
+        }
+        self.role = Role::Follower;
+        self.leader = Some(leader);
+        self.votes.clear();
+        self.reads.clear();
+        self.reset_election_deadline(now);
+    }
+
+    fn start_election(&mut self, now: Instant) -> Result<()> {
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.hard_state = HardState {
+            term: self.hard_state.term + 1,
+            voted_for: Some(self.id),
+        };
+        self.storage.save_hard_state(self.hard_state)?;
+        self.votes.clear();
+        self.votes.insert(self.id);
+        self.reset_election_deadline(now);
+        debug!(
+            "Raft node {} starts an election at term {}",
+            self.id, self.hard_state.term
+        );
+
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader(now);
+        }
+        let (last_log_index, last_log_term) = (self.last_index(), self.last_term());
+        for &peer in &self.peers {
+            self.send(
+                peer,
+                Body::RequestVote {
+                    last_log_index,
+                    last_log_term,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self, now: Instant) -> Result<()> {
+        info!(
+            "Raft node {} became leader at term {}",
+            self.id, self.hard_state.term
+        );
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.votes.clear();
+        let next = self.last_index() + 1;
+        self.next_index = self.peers.iter().map(|&peer| (peer, next)).collect();
+        self.match_index = self.peers.iter().map(|&peer| (peer, 0)).collect();
+        self.last_heard = self.peers.iter().map(|&peer| (peer, now)).collect();
+        self.acked_round.clear();
+        self.heartbeat_deadline = now + self.heartbeat_interval;
+        // Entries of earlier terms only count as committed once an entry of the
+        // current term is, so a new leader appends one right away.
+        self.leader_start = self.append_command(Command::Noop)?;
+        Ok(())
+    }
+
+    /// Append a command to the log of the leader and send it to the followers.
+    fn append_command(&mut self, command: Command) -> Result<u64> {
+        let entry = Entry {
+            index: self.last_index() + 1,
+            term: self.hard_state.term,
+            command,
+        };
+        let index = entry.index;
+        self.storage.append(std::slice::from_ref(&entry))?;
+        self.log.push(entry);
+        self.advance_commit();
+        self.broadcast_append()?;
+        Ok(index)
+    }
+
+    fn broadcast_append(&mut self) -> Result<()> {
+        self.round += 1;
+        for peer in self.peers.clone() {
+            self.send_append(peer)?;
+        }
+        Ok(())
+    }
+
+    /// Send a follower the entries it is missing, or the snapshot if they were
+    /// compacted.
+    fn send_append(&mut self, peer: NodeId) -> Result<()> {
+        let next = self.next_index.get(&peer).copied().unwrap_or(1);
+        if next <= self.snapshot_index {
+            if let Some(snapshot) = self.storage.load_snapshot()? {
+                self.next_index.insert(peer, snapshot.last_index + 1);
+                self.send(peer, Body::InstallSnapshot { snapshot });
+            }
+            return Ok(());
+        }
+
+        let prev_log_index = next - 1;
+        let prev_log_term = self.term_at(prev_log_index).unwrap_or(0);
+        let start = (next - self.snapshot_index - 1) as usize;
+        let end = self.log.len().min(start + MAX_BATCH);
+        let entries = self.log[start.min(end)..end].to_vec();
+        self.send(
+            peer,
+            Body::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit: self.commit_index,
+                round: self.round,
+            },
+        );
+        Ok(())
+    }
+
+    /// Record that a follower stored the log up to `match_index`.
+    fn acknowledged(&mut self, peer: NodeId, match_index: u64) {
+        let matched = self.match_index.entry(peer).or_insert(0);
+        *matched = (*matched).max(match_index);
+        let next = self.next_index.entry(peer).or_insert(1);
+        *next = (*next).max(match_index + 1);
+        self.advance_commit();
+    }
+
+    /// Commit the highest entry of the current term stored by a majority.
+    fn advance_commit(&mut self) {
+        let mut matched: Vec<u64> = self.match_index.values().copied().collect();
+        matched.push(self.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let majority = matched[self.quorum() - 1];
+        if majority > self.commit_index && self.term_at(majority) == Some(self.hard_state.term) {
+            self.commit_index = majority;
+        }
+    }
+
+    /// Append entries from the leader after checking that the log matches at
+    /// `prev_log_index`. Returns the index of the last entry known to match the
+    /// leader, or `None` if the log does not match.
+    fn append_entries(
+        &mut self,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: &[Entry],
+        leader_commit: u64,
+    ) -> Result<Option<u64>> {
+        if prev_log_index > self.last_index() {
+            return Ok(None);
+        }
+        // Compacted entries were committed, so they match the leader.
+        if prev_log_index >= self.snapshot_index
+            && self.term_at(prev_log_index) != Some(prev_log_term)
+        {
+            return Ok(None);
+        }
+
+        let mut appended = Vec::new();
+        for entry in entries {
+            if entry.index <= self.snapshot_index {
+                continue;
+            }
+            if appended.is_empty() && entry.index <= self.last_index() {
+                if self.term_at(entry.index) == Some(entry.term) {
+                    continue;
+                }
+                // A conflicting entry and everything after it were never committed.
+                self.log
+                    .truncate((entry.index - self.snapshot_index - 1) as usize);
+                self.storage.rewrite_log(&self.log)?;
+            }
+            appended.push(entry.clone());
+        }
+        if !appended.is_empty() {
+            self.storage.append(&appended)?;
+            self.log.extend(appended);
+        }
+
+        let last_new = prev_log_index + entries.len() as u64;
+        if leader_commit > self.commit_index {
+            self.commit_index = leader_commit.min(last_new).max(self.commit_index);
+        }
+        Ok(Some(last_new.max(self.snapshot_index)))
+    }
+
+    /// Replace the state of this node with a snapshot from the leader.
+    fn install_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+        if snapshot.last_index <= self.commit_index {
+            return Ok(());
+        }
+        self.storage.save_snapshot(&snapshot)?;
+        // Entries following the snapshot are kept if the log agrees with it.
+        if self.term_at(snapshot.last_index) == Some(snapshot.last_term) {
+            let covered = (snapshot.last_index - self.snapshot_index) as usize;
+            self.log.drain(..covered);
+        } else {
+            self.log.clear();
+        }
+        self.storage.rewrite_log(&self.log)?;
+        self.snapshot_index = snapshot.last_index;
+        self.snapshot_term = snapshot.last_term;
+        self.commit_index = snapshot.last_index;
+        info!(
+            "Raft node {} installed a snapshot up to index {}",
+            self.id, snapshot.last_index
+        );
+        self.pending_snapshot = Some(snapshot);
+        Ok(())
+    }
+}
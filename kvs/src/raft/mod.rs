@@ -0,0 +1,431 @@
+//! Raft consensus, replicating the writes of a `KvsEngine` across a cluster.
+
+use crate::engines::{incremented, Durability, Engine, EngineStats, KvsEngine};
+use crate::error::{ErrorKind, Result};
+use crate::namespaces::lock_engine;
+use serde::{Deserialize, Serialize};
+use slog_scope::error;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod core;
+mod storage;
+mod transport;
+
+use self::core::{Applied, Core};
+pub use self::transport::{SimulatedNetwork, SimulatedTransport, TcpTransport, Transport};
+
+/// Identifier of a node in a Raft cluster.
+pub type NodeId = u64;
+
+/// A change to the replicated engine.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Command {
+    /// Appended by every new leader, so that it can commit entries of earlier terms
+    Noop,
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub(crate) index: u64,
+    pub(crate) term: u64,
+    pub(crate) command: Command,
+}
+
+/// Every key and value of the engine once the entry at `last_index` is applied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub(crate) last_index: u64,
+    pub(crate) last_term: u64,
+    pub(crate) data: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Body {
+    RequestVote {
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        granted: bool,
+    },
+    /// `round` numbers the heartbeats of the leader, and is sent back in the result
+    AppendEntries {
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+        round: u64,
+    },
+    /// On failure, `match_index` is an index up to which the logs are known to match
+    AppendResult {
+        success: bool,
+        match_index: u64,
+        round: u64,
+    },
+    InstallSnapshot {
+        snapshot: Snapshot,
+    },
+    SnapshotInstalled {
+        last_index: u64,
+    },
+}
+
+/// A message between two nodes of a Raft cluster.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RaftMessage {
+    pub(crate) from: NodeId,
+    pub(crate) to: NodeId,
+    pub(crate) term: u64,
+    pub(crate) body: Body,
+}
+
+impl RaftMessage {
+    /// The node that sent the message.
+    pub fn from(&self) -> NodeId {
+        self.from
+    }
+
+    /// The node the message is addressed to.
+    pub fn to(&self) -> NodeId {
+        self.to
+    }
+}
+
+/// Delivers messages from other nodes to a `RaftEngine`.
+#[derive(Clone)]
+pub struct RaftInbox {
+    sender: Sender<RaftMessage>,
+}
+
+impl RaftInbox {
+    /// Hand a message over to the node. Messages to a stopped node are dropped.
+    pub fn deliver(&self, message: RaftMessage) {
+        let _ = self.sender.send(message);
+    }
+}
+
+/// Configuration of a node in a Raft cluster.
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    /// Identifier of this node
+    pub id: NodeId,
+    /// Every node of the cluster, this one included, with the address its `KvsServer`
+    /// listens on. Clients are redirected to these addresses.
+    pub peers: HashMap<NodeId, SocketAddr>,
+    /// Directory keeping the term, vote, log and snapshot of the node
+    pub dir: PathBuf,
+    /// Shortest time without hearing from a leader before starting an election. The
+    /// actual timeout is randomized up to twice as long.
+    pub election_timeout: Duration,
+    /// Interval between heartbeats of a leader
+    pub heartbeat_interval: Duration,
+    /// Number of applied entries after which the log is replaced by a snapshot
+    pub snapshot_threshold: u64,
+    /// Limit on waiting for a write to be committed
+    pub propose_timeout: Duration,
+}
+
+impl RaftConfig {
+    /// Configure node `id` of the cluster of `peers`, storing its state in `dir`.
+    pub fn new(id: NodeId, peers: HashMap<NodeId, SocketAddr>, dir: impl Into<PathBuf>) -> Self {
+        RaftConfig {
+            id,
+            peers,
+            dir: dir.into(),
+            election_timeout: Duration::from_millis(300),
+            heartbeat_interval: Duration::from_millis(50),
+            snapshot_threshold: 1000,
+            propose_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A `KvsEngine` replicated with Raft across the nodes of a cluster.
+///
+/// Writes are accepted by the leader only, and return once a majority of the nodes
+/// stored them and the leader applied them to the wrapped engine. Writes through a
+/// node wait for each other. Reads are served by the leader from its engine, once a
+/// majority of the nodes confirmed that it still leads, so that a deposed leader does
+/// not serve stale values. Other nodes fail both with `ErrorKind::NotLeader`, naming
+/// the leader when they know it.
+///
+/// The node runs on a background thread, which stops once every clone of the engine is
+/// dropped.
+pub struct RaftEngine<E: KvsEngine> {
+    shared: Arc<Shared<E>>,
+}
+
+impl<E: KvsEngine> Clone for RaftEngine<E> {
+    fn clone(&self) -> Self {
+        RaftEngine {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+struct Shared<E: KvsEngine> {
+    core: Mutex<Core>,
+    engine: Mutex<E>,
+    /// Held while a write is proposed and applied
+    writes: Mutex<()>,
+    config: RaftConfig,
+    inbox: RaftInbox,
+}
+
+impl<E: KvsEngine + Send + 'static> RaftEngine<E> {
+    /// Start a node replicating writes into `engine`, exchanging messages with the
+    /// other nodes through `transport`. Messages for this node have to be handed to
+    /// its `inbox`.
+    ///
+    /// The engine must hold the writes applied before the node was last stopped, or
+    /// be empty on a first start.
+    pub fn start(
+        engine: E,
+        config: RaftConfig,
+        transport: impl Transport + 'static,
+    ) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let core = Core::new(&config, Box::new(transport))?;
+        let shared = Arc::new(Shared {
+            core: Mutex::new(core),
+            engine: Mutex::new(engine),
+            writes: Mutex::new(()),
+            config,
+            inbox: RaftInbox { sender },
+        });
+
+        let weak = Arc::downgrade(&shared);
+        let tick = shared.config.heartbeat_interval / 5;
+        thread::spawn(move || run(weak, receiver, tick));
+        Ok(RaftEngine { shared })
+    }
+
+    /// Get the inbox that messages from other nodes are delivered to.
+    pub fn inbox(&self) -> RaftInbox {
+        self.shared.inbox.clone()
+    }
+
+    /// Whether this node is the leader of its cluster.
+    pub fn is_leader(&self) -> bool {
+        self.shared.core.lock().unwrap().is_leader()
+    }
+
+    /// The leader this node knows of, if any.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.shared.core.lock().unwrap().leader()
+    }
+
+    /// The current term of this node.
+    pub fn term(&self) -> u64 {
+        self.shared.core.lock().unwrap().term()
+    }
+
+    fn not_leader(&self, leader: Option<NodeId>) -> ErrorKind {
+        ErrorKind::NotLeader(leader.and_then(|id| self.shared.config.peers.get(&id).copied()))
+    }
+
+    /// Replicate a command and wait until it is applied.
+    fn propose(&self, command: Command) -> Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        self.shared
+            .core
+            .lock()
+            .unwrap()
+            .propose(command, sender)
+            .map_err(|leader| self.not_leader(leader))??;
+        match receiver.recv_timeout(self.shared.config.propose_timeout) {
+            Ok(Applied::Done(result)) => result,
+            Ok(Applied::Superseded(leader)) => Err(self.not_leader(leader)),
+            Err(_) => Err(ErrorKind::Timeout),
+        }
+    }
+
+    /// Wait until a majority of the nodes confirmed that this node leads, and every
+    /// write committed until then is applied. Fails if another node leads.
+    fn check_leader(&self) -> Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        self.shared
+            .core
+            .lock()
+            .unwrap()
+            .read(sender)
+            .map_err(|leader| self.not_leader(leader))??;
+        match receiver.recv_timeout(self.shared.config.propose_timeout) {
+            Ok(()) => Ok(()),
+            Err(RecvTimeoutError::Disconnected) => Err(self.not_leader(self.leader())),
+            Err(RecvTimeoutError::Timeout) => Err(ErrorKind::Timeout),
+        }
+    }
+}
+
+impl<E: KvsEngine + Send + 'static> KvsEngine for RaftEngine<E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let _writing = lock_engine(&self.shared.writes);
+        self.propose(Command::Set { key, value })
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.check_leader()?;
+        lock_engine(&self.shared.engine).get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        let _writing = lock_engine(&self.shared.writes);
+        self.propose(Command::Remove { key })
+    }
+
+    /// The leader replicates the new value rather than the delta, as entries after the
+    /// last snapshot are applied again when a node restarts. No other write of the
+    /// node lands between reading the counter and setting it.
+    fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let _writing = lock_engine(&self.shared.writes);
+        self.check_leader()?;
+        let old = lock_engine(&self.shared.engine).get(key.clone())?;
+        let value = incremented(&key, old.as_deref(), delta)?;
+        self.propose(Command::Set {
+            key,
+            value: value.to_string(),
+        })?;
+        Ok(value)
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.check_leader()?;
+        lock_engine(&self.shared.engine).scan(prefix)
+    }
+
+    /// Writes are durable once committed, as the Raft log is synced on every append.
+    /// This flushes the wrapped engine.
+    fn flush(&mut self) -> Result<()> {
+        lock_engine(&self.shared.engine).flush()
+    }
+
+    fn set_durability(&mut self, durability: Durability) {
+        lock_engine(&self.shared.engine).set_durability(durability)
+    }
+
+    fn open_namespace(&mut self, _name: &str) -> Result<Self> {
+        Err(ErrorKind::Unsupported(
+            "namespaces are not replicated by Raft".to_owned(),
+        ))
+    }
+
+    fn drop_namespace(&mut self, _name: &str) -> Result<()> {
+        Err(ErrorKind::Unsupported(
+            "namespaces are not replicated by Raft".to_owned(),
+        ))
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        lock_engine(&self.shared.engine).stats()
+    }
+
+    fn as_type(&self) -> Engine {
+        lock_engine(&self.shared.engine).as_type()
+    }
+}
+
+impl<E: KvsEngine> Shared<E> {
+    /// Apply committed entries, or a snapshot received from the leader, to the engine,
+    /// and take a snapshot once enough entries were applied since the last one.
+    fn apply(&self) -> Result<()> {
+        let snapshot = self.core.lock().unwrap().take_pending_snapshot();
+        if let Some(snapshot) = snapshot {
+            let mut engine = lock_engine(&self.engine);
+            let keep: HashMap<&str, &str> = snapshot
+                .data
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect();
+            for (key, _) in engine.scan("")? {
+                if !keep.contains_key(key.as_str()) {
+                    engine.remove(key)?;
+                }
+            }
+            for (key, value) in &snapshot.data {
+                engine.set(key.clone(), value.clone())?;
+            }
+            engine.flush()?;
+            self.core.lock().unwrap().snapshot_applied(&snapshot);
+        }
+
+        let entries = self.core.lock().unwrap().committed_entries();
+        for entry in entries {
+            let result = {
+                let mut engine = lock_engine(&self.engine);
+                match entry.command {
+                    Command::Noop => Ok(()),
+                    Command::Set { key, value } => engine.set(key, value),
+                    Command::Remove { key } => engine.remove(key),
+                }
+            };
+            self.core
+                .lock()
+                .unwrap()
+                .entry_applied(entry.index, entry.term, result);
+        }
+
+        let due = self.core.lock().unwrap().snapshot_due();
+        if let Some((last_index, last_term)) = due {
+            let data = {
+                let mut engine = lock_engine(&self.engine);
+                // The snapshot replaces log entries, so what it covers must be on disk.
+                engine.flush()?;
+                engine.scan("")?
+            };
+            let snapshot = Snapshot {
+                last_index,
+                last_term,
+                data,
+            };
+            self.core.lock().unwrap().compact(&snapshot)?;
+        }
+        Ok(())
+    }
+}
+
+/// Drive a node: handle incoming messages and timeouts, and apply what gets committed.
+fn run<E: KvsEngine>(shared: Weak<Shared<E>>, inbox: Receiver<RaftMessage>, tick: Duration) {
+    loop {
+        let received = inbox.recv_timeout(tick);
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        let result = {
+            let mut core = shared.core.lock().unwrap();
+            let mut result = Ok(());
+            match received {
+                Ok(message) => {
+                    result = core.step(message);
+                    while let (Ok(()), Ok(message)) = (&result, inbox.try_recv()) {
+                        result = core.step(message);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            result.and_then(|_| core.tick(Instant::now()))
+        };
+        if let Err(e) = result.and_then(|_| shared.apply()) {
+            error!("Raft node {} failed: {}", shared.config.id, e);
+        }
+    }
+}
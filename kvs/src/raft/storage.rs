@@ -0,0 +1,139 @@
+use super::{Entry, NodeId, Snapshot};
+use crate::error::{ErrorKind, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Term and vote of a node, which must survive restarts for elections to be safe.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct HardState {
+    pub(crate) term: u64,
+    pub(crate) voted_for: Option<NodeId>,
+}
+
+/// Durable state of a Raft node in a directory: its hard state, the latest snapshot
+/// and the log entries that follow it.
+///
+/// Every change is synced to disk before it returns, since a node must not forget a
+/// vote or an entry it acknowledged.
+pub(crate) struct Storage {
+    dir: PathBuf,
+    log: BufWriter<File>,
+}
+
+/// Everything read back from storage when a node starts.
+pub(crate) struct Restored {
+    pub(crate) hard_state: HardState,
+    pub(crate) snapshot: Option<Snapshot>,
+    pub(crate) entries: Vec<Entry>,
+}
+
+impl Storage {
+    pub(crate) fn open(dir: impl Into<PathBuf>) -> Result<(Self, Restored)> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let hard_state: HardState = read_json(&dir.join("state.json"))?.unwrap_or_default();
+        let snapshot: Option<Snapshot> = read_json(&dir.join("snapshot.json"))?;
+        let first_index = snapshot.as_ref().map_or(0, |s| s.last_index) + 1;
+
+        let log_path = dir.join("log.jsonl");
+        let mut entries = Vec::new();
+        if log_path.exists() {
+            let reader = BufReader::new(File::open(&log_path)?);
+            // An entry cut short by a crash was never acknowledged, so it is dropped.
+            for entry in Deserializer::from_reader(reader).into_iter::<Entry>() {
+                match entry {
+                    Ok(entry) => entries.push(entry),
+                    Err(_) => break,
+                }
+            }
+        }
+        entries.retain(|entry| entry.index >= first_index);
+        for (i, entry) in entries.iter().enumerate() {
+            if entry.index != first_index + i as u64 {
+                return Err(ErrorKind::Server(format!(
+                    "Raft log in {} has a gap at index {}",
+                    dir.display(),
+                    entry.index
+                )));
+            }
+        }
+
+        let mut storage = Storage {
+            log: BufWriter::new(File::create(dir.join("log.tmp"))?),
+            dir,
+        };
+        storage.rewrite_log(&entries)?;
+        Ok((
+            storage,
+            Restored {
+                hard_state,
+                snapshot,
+                entries,
+            },
+        ))
+    }
+
+    pub(crate) fn save_hard_state(&self, state: HardState) -> Result<()> {
+        write_json(&self.dir.join("state.json"), &state)
+    }
+
+    pub(crate) fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        for entry in entries {
+            serde_json::to_writer(&mut self.log, entry)?;
+            self.log.write_all(b"\n")?;
+        }
+        self.log.flush()?;
+        self.log.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Replace the log with the given entries, after a conflict or a snapshot.
+    pub(crate) fn rewrite_log(&mut self, entries: &[Entry]) -> Result<()> {
+        let tmp = self.dir.join("log.tmp");
+        let mut log = BufWriter::new(File::create(&tmp)?);
+        for entry in entries {
+            serde_json::to_writer(&mut log, entry)?;
+            log.write_all(b"\n")?;
+        }
+        log.flush()?;
+        log.get_ref().sync_data()?;
+        drop(log);
+
+        let path = self.dir.join("log.jsonl");
+        fs::rename(&tmp, &path)?;
+        self.log = BufWriter::new(OpenOptions::new().append(true).open(&path)?);
+        Ok(())
+    }
+
+    pub(crate) fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        write_json(&self.dir.join("snapshot.json"), snapshot)
+    }
+
+    pub(crate) fn load_snapshot(&self) -> Result<Option<Snapshot>> {
+        read_json(&self.dir.join("snapshot.json"))
+    }
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Option<T>> {
+    match File::open(path) {
+        Ok(file) => Ok(Some(serde_json::from_reader(BufReader::new(file))?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replace the file atomically, so that a crash leaves either version.
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer(&mut file, value)?;
+    file.flush()?;
+    file.get_ref().sync_data()?;
+    drop(file);
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use super::{NodeId, RaftInbox, RaftMessage};
+use crate::client::{ClientOptions, KvsClient};
+use rand::Rng;
+use slog_scope::debug;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Messages waiting to be sent to a peer. Further messages are dropped, as Raft
+/// retries whatever gets lost.
+const QUEUE_LENGTH: usize = 1024;
+
+/// Carries messages between the nodes of a Raft cluster.
+pub trait Transport: Send {
+    /// Send a message to the node it is addressed to, without blocking. Delivery is
+    /// best effort: messages may be lost, and Raft sends them again as needed.
+    fn send(&self, message: RaftMessage);
+}
+
+/// Sends messages to the `KvsServer` of each peer, over one connection per peer.
+pub struct TcpTransport {
+    queues: HashMap<NodeId, SyncSender<RaftMessage>>,
+}
+
+impl TcpTransport {
+    /// Connect to `peers` with the given options, on a thread per peer. Connecting
+    /// gives up after a second unless a connect timeout is set.
+    pub fn new(peers: &HashMap<NodeId, SocketAddr>, options: &ClientOptions) -> Self {
+        let mut options = options.clone();
+        options.timeouts.connect = options.timeouts.connect.or(Some(Duration::from_secs(1)));
+        options.namespace = None;
+        let queues = peers
+            .iter()
+            .map(|(&id, &addr)| {
+                let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+                let options = options.clone();
+                thread::spawn(move || send_to_peer(addr, &options, receiver));
+                (id, sender)
+            })
+            .collect();
+        TcpTransport { queues }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, message: RaftMessage) {
+        if let Some(queue) = self.queues.get(&message.to) {
+            let _ = queue.try_send(message);
+        }
+    }
+}
+
+/// Send queued messages to a peer until the transport is dropped, reconnecting after
+/// failures.
+fn send_to_peer(addr: SocketAddr, options: &ClientOptions, queue: Receiver<RaftMessage>) {
+    let mut client: Option<KvsClient> = None;
+    for message in queue {
+        let result = match &mut client {
+            Some(client) => client.send_raft(message),
+            None => KvsClient::connect_with(addr, options).and_then(|mut connected| {
+                connected.send_raft(message)?;
+                client = Some(connected);
+                Ok(())
+            }),
+        };
+        if let Err(e) = result {
+            debug!("Failed to send a Raft message to {}: {}", addr, e);
+            client = None;
+        }
+    }
+}
+
+/// An in-process network between Raft nodes, which can lose messages and isolate
+/// nodes. It lets a cluster be tested without sockets.
+#[derive(Clone, Default)]
+pub struct SimulatedNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+#[derive(Default)]
+struct NetworkState {
+    inboxes: HashMap<NodeId, RaftInbox>,
+    isolated: HashSet<NodeId>,
+    loss: f64,
+}
+
+impl SimulatedNetwork {
+    /// Create a network that delivers every message.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Deliver messages addressed to node `id` to `inbox`.
+    pub fn register(&self, id: NodeId, inbox: RaftInbox) {
+        self.state.lock().unwrap().inboxes.insert(id, inbox);
+    }
+
+    /// Get a transport sending messages over this network.
+    pub fn transport(&self) -> SimulatedTransport {
+        SimulatedTransport {
+            network: self.clone(),
+        }
+    }
+
+    /// Drop every message from or to node `id`.
+    pub fn isolate(&self, id: NodeId) {
+        self.state.lock().unwrap().isolated.insert(id);
+    }
+
+    /// Deliver messages from and to node `id` again.
+    pub fn heal(&self, id: NodeId) {
+        self.state.lock().unwrap().isolated.remove(&id);
+    }
+
+    /// Drop each message with probability `loss`, between 0 and 1.
+    pub fn set_loss(&self, loss: f64) {
+        self.state.lock().unwrap().loss = loss;
+    }
+}
+
+/// Sends messages over a `SimulatedNetwork`.
+pub struct SimulatedTransport {
+    network: SimulatedNetwork,
+}
+
+impl Transport for SimulatedTransport {
+    fn send(&self, message: RaftMessage) {
+        let state = self.network.state.lock().unwrap();
+        if state.isolated.contains(&message.from) || state.isolated.contains(&message.to) {
+            return;
+        }
+        if state.loss > 0.0 && rand::thread_rng().gen::<f64>() < state.loss {
+            return;
+        }
+        if let Some(inbox) = state.inboxes.get(&message.to) {
+            inbox.deliver(message);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::time::{Duration, Instant};
+
+/// Rate of requests allowed by a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Requests allowed per second on average. Must be positive
+    pub per_second: f64,
+    /// Requests allowed at once after a quiet period. Must be at least 1
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Allow `per_second` requests per second, in bursts of as many.
+    pub fn per_second(per_second: u32) -> Self {
+        RateLimit {
+            per_second: f64::from(per_second),
+            burst: per_second,
+        }
+    }
+}
+
+/// A bucket holding up to `burst` tokens, refilled at `per_second`. Each request takes
+/// a token.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            limit,
+            tokens: f64::from(limit.burst),
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Take a token, or tell how long until there is one.
+    pub(crate) fn take(&mut self) -> Result<(), Duration> {
+        self.check()?;
+        self.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Tell whether there is a token without taking it, or how long until there is one.
+    pub(crate) fn check(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * self.limit.per_second;
+        self.tokens = (self.tokens + refill).min(f64::from(self.limit.burst));
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.limit.per_second,
+            ))
+        }
+    }
+}
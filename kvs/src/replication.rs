@@ -0,0 +1,463 @@
+use crate::client::{ClientOptions, KvsClient};
+use crate::engines::{check_namespace_name, incremented, KvsEngine};
+use crate::error::{ErrorKind, Result};
+use crate::namespaces::Namespaces;
+use crate::requests::Response;
+use crate::shutdown::ShutdownHandle;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use slog_scope::{debug, info, warn};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long a follower waits for the leader before checking for shutdown, and a
+/// leader waits for new writes before checking whether the follower is still there.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(200);
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// A write applied by the leader, replayed by its followers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Mutation {
+    Set {
+        namespace: Option<String>,
+        key: String,
+        value: String,
+    },
+    SetBatch {
+        namespace: Option<String>,
+        pairs: Vec<(String, String)>,
+    },
+    Remove {
+        namespace: Option<String>,
+        key: String,
+    },
+    /// Replicated as the `Set` of the new value
+    Incr {
+        namespace: Option<String>,
+        key: String,
+        delta: i64,
+    },
+    CreateNamespace {
+        name: String,
+    },
+    DropNamespace {
+        name: String,
+    },
+}
+
+/// A write with its position in the replication log. Positions start at 1.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub(crate) position: u64,
+    pub(crate) write: Mutation,
+}
+
+/// Replication progress of a follower, as seen by its leader.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FollowerStatus {
+    /// Address the follower connected from
+    pub addr: SocketAddr,
+    /// Position of the last write the follower acknowledged
+    pub applied: u64,
+    /// Number of writes the follower has yet to acknowledge
+    pub lag: u64,
+}
+
+/// Replication state of a leader.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationStatus {
+    /// Position of the last write in the replication log
+    pub position: u64,
+    /// Followers currently connected
+    pub followers: Vec<FollowerStatus>,
+}
+
+/// Where a follower replicates writes from.
+#[derive(Clone)]
+pub struct FollowerConfig {
+    /// Address of the leader
+    pub leader: SocketAddr,
+    /// Options of the connection to the leader. The read timeout is replaced by a
+    /// short one, so that the follower notices shutdown requests, and connecting
+    /// gives up after a few seconds unless a connect timeout is set.
+    pub options: ClientOptions,
+    /// File keeping the position of the last applied write across restarts
+    pub position_file: PathBuf,
+}
+
+/// Log of the writes applied by a leader, kept in a file so that followers can resume
+/// from any position.
+///
+/// Entries are handed to the operating system as they are appended and synced to disk
+/// when the server shuts down.
+pub(crate) struct ReplicationLog {
+    path: PathBuf,
+    state: Mutex<LogState>,
+    appended: Condvar,
+}
+
+struct LogState {
+    writer: BufWriter<File>,
+    /// Byte offset of every entry. The entry at position `n` starts at `offsets[n - 1]`
+    offsets: Vec<u64>,
+    /// Byte length of the log
+    end: u64,
+    followers: HashMap<u64, FollowerStatus>,
+    next_follower: u64,
+}
+
+impl ReplicationLog {
+    /// Open the log at `path`, creating it if needed. An entry left incomplete by a
+    /// crash, which is the last one and lacks its newline, is cut off. Any other entry
+    /// that cannot be read fails opening the log.
+    pub(crate) fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let mut offsets = Vec::new();
+        let mut end = 0;
+        let mut reader = BufReader::new(file.try_clone()?);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line)? > 0 && line.ends_with(b"\n") {
+            let position = offsets.len() as u64 + 1;
+            let entry: Entry = serde_json::from_slice(&line).map_err(|e| {
+                ErrorKind::Server(format!(
+                    "replication log {} is corrupt at position {}: {}",
+                    path.display(),
+                    position,
+                    e
+                ))
+            })?;
+            if entry.position != position {
+                return Err(ErrorKind::Server(format!(
+                    "replication log {} is out of order at position {}",
+                    path.display(),
+                    entry.position
+                )));
+            }
+            offsets.push(end);
+            end += line.len() as u64;
+            line.clear();
+        }
+        if !line.is_empty() {
+            warn!(
+                "Cutting off an incomplete entry at the end of replication log {}",
+                path.display()
+            );
+            file.set_len(end)?;
+        }
+        info!("Replication log at position {}", offsets.len());
+
+        Ok(ReplicationLog {
+            path,
+            state: Mutex::new(LogState {
+                writer: BufWriter::new(file),
+                offsets,
+                end,
+                followers: HashMap::new(),
+                next_follower: 0,
+            }),
+            appended: Condvar::new(),
+        })
+    }
+
+    /// Position of the last entry.
+    pub(crate) fn position(&self) -> u64 {
+        self.state.lock().unwrap().offsets.len() as u64
+    }
+
+    pub(crate) fn append(&self, write: Mutation) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let entry = Entry {
+            position: state.offsets.len() as u64 + 1,
+            write,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        state.writer.write_all(&line)?;
+        state.writer.flush()?;
+
+        let offset = state.end;
+        state.offsets.push(offset);
+        state.end += line.len() as u64;
+        self.appended.notify_all();
+        Ok(())
+    }
+
+    pub(crate) fn sync(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.writer.flush()?;
+        state.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    pub(crate) fn status(&self) -> ReplicationStatus {
+        let state = self.state.lock().unwrap();
+        let position = state.offsets.len() as u64;
+        let mut followers: Vec<FollowerStatus> = state
+            .followers
+            .values()
+            .map(|follower| FollowerStatus {
+                lag: position.saturating_sub(follower.applied),
+                ..follower.clone()
+            })
+            .collect();
+        followers.sort_by_key(|follower| follower.addr);
+        ReplicationStatus {
+            position,
+            followers,
+        }
+    }
+
+    pub(crate) fn register_follower(&self, addr: SocketAddr, applied: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_follower;
+        state.next_follower += 1;
+        let lag = state.offsets.len() as u64 - applied;
+        state
+            .followers
+            .insert(id, FollowerStatus { addr, applied, lag });
+        id
+    }
+
+    pub(crate) fn acknowledge(&self, id: u64, applied: u64) {
+        if let Some(follower) = self.state.lock().unwrap().followers.get_mut(&id) {
+            follower.applied = applied;
+        }
+    }
+
+    pub(crate) fn unregister_follower(&self, id: u64) {
+        self.state.lock().unwrap().followers.remove(&id);
+    }
+
+    /// Wait up to `timeout` for entries after position `after`. Returns the position
+    /// of the last entry and the byte range of the entries after `after`.
+    fn wait_after(&self, after: u64, timeout: Duration) -> (u64, u64, u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.offsets.len() as u64 <= after {
+            state = self.appended.wait_timeout(state, timeout).unwrap().0;
+        }
+        let position = state.offsets.len() as u64;
+        let start = state
+            .offsets
+            .get(after as usize)
+            .copied()
+            .unwrap_or(state.end);
+        (position, start, state.end)
+    }
+
+    /// Send every entry after position `from` to a follower, then each new one as it
+    /// is appended, until `stop` returns true or sending fails.
+    pub(crate) fn stream_to(
+        &self,
+        from: u64,
+        mut send: impl FnMut(Entry) -> Result<()>,
+        stop: impl Fn() -> bool,
+    ) -> Result<()> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut sent = from;
+        while !stop() {
+            let (position, start, end) = self.wait_after(sent, POLL_INTERVAL);
+            if position <= sent {
+                continue;
+            }
+            reader.seek(SeekFrom::Start(start))?;
+            let entries = Deserializer::from_reader((&mut reader).take(end - start));
+            for entry in entries.into_iter::<Entry>() {
+                let entry = entry?;
+                sent = entry.position;
+                send(entry)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Apply a write to the namespaces of a server, returning the response to it. If there
+/// is a replication log, the write is appended to it before it is applied, while the
+/// engine it applies to is locked, so that the log follows the order of writes to each
+/// key and holds every write a client was told succeeded. Writes bound to fail, such as
+/// removing a missing key, are refused before they reach the log.
+pub(crate) fn apply<E: KvsEngine>(
+    namespaces: &Namespaces<E>,
+    write: Mutation,
+    log: Option<&ReplicationLog>,
+) -> Result<Response> {
+    let replicate = |write: Mutation| match log {
+        Some(log) => log.append(write),
+        None => Ok(()),
+    };
+    let response =
+        match &write {
+            Mutation::Set {
+                namespace,
+                key,
+                value,
+            } => namespaces
+                .get(namespace.as_deref())?
+                .execute(true, |engine| {
+                    replicate(write.clone())?;
+                    engine.set(key.clone(), value.clone())?;
+                    Ok(Response::Success)
+                })?,
+            Mutation::SetBatch { namespace, pairs } => namespaces
+                .get(namespace.as_deref())?
+                .execute(true, |engine| {
+                    replicate(write.clone())?;
+                    for (key, value) in pairs {
+                        engine.set(key.clone(), value.clone())?;
+                    }
+                    Ok(Response::Success)
+                })?,
+            Mutation::Remove { namespace, key } => {
+                namespaces
+                    .get(namespace.as_deref())?
+                    .execute(true, |engine| {
+                        if log.is_some() && engine.get(key.clone())?.is_none() {
+                            return Err(ErrorKind::KeyNotFound);
+                        }
+                        replicate(write.clone())?;
+                        engine.remove(key.clone())?;
+                        Ok(Response::Success)
+                    })?
+            }
+            Mutation::Incr {
+                namespace,
+                key,
+                delta,
+            } => namespaces
+                .get(namespace.as_deref())?
+                .execute(true, |engine| {
+                    if log.is_none() {
+                        return engine.incr(key.clone(), *delta).map(Response::Integer);
+                    }
+                    let value = engine.get(key.clone())?;
+                    let value = incremented(key, value.as_deref(), *delta)?;
+                    // Followers set the value rather than add to theirs, should they have
+                    // applied the write before a restart.
+                    replicate(Mutation::Set {
+                        namespace: namespace.clone(),
+                        key: key.clone(),
+                        value: value.to_string(),
+                    })?;
+                    engine.set(key.clone(), value.to_string())?;
+                    Ok(Response::Integer(value))
+                })?,
+            Mutation::CreateNamespace { name } => {
+                check_namespace_name(name)?;
+                if namespaces.names().contains(name) {
+                    return Err(ErrorKind::NamespaceExists(name.clone()));
+                }
+                replicate(write.clone())?;
+                namespaces.create(name)?;
+                Response::Success
+            }
+            Mutation::DropNamespace { name } => {
+                if !namespaces.names().contains(name) {
+                    return Err(ErrorKind::NamespaceNotFound(name.clone()));
+                }
+                replicate(write.clone())?;
+                namespaces.remove(name)?;
+                Response::Success
+            }
+        };
+    Ok(response)
+}
+
+/// Replicate writes from the leader into `namespaces` until shutdown is requested,
+/// reconnecting with backoff whenever the connection fails.
+pub(crate) fn follow<E: KvsEngine>(
+    config: FollowerConfig,
+    namespaces: &Namespaces<E>,
+    shutdown: &ShutdownHandle,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    while !shutdown.is_requested() {
+        match follow_once(&config, namespaces, shutdown, &mut backoff) {
+            Ok(()) => return,
+            Err(e) => {
+                warn!("Replication from {} failed: {}", config.leader, e);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+fn follow_once<E: KvsEngine>(
+    config: &FollowerConfig,
+    namespaces: &Namespaces<E>,
+    shutdown: &ShutdownHandle,
+    backoff: &mut Duration,
+) -> Result<()> {
+    let mut applied = read_position(config)?;
+    let mut options = config.options.clone();
+    options.timeouts.read = Some(POLL_INTERVAL);
+    options.timeouts.connect = options.timeouts.connect.or(Some(MAX_BACKOFF));
+    let mut client = KvsClient::connect_with(config.leader, &options)?;
+    client.start_replication(applied)?;
+    info!(
+        "Replicating from {} after position {}",
+        config.leader, applied
+    );
+    *backoff = INITIAL_BACKOFF;
+
+    loop {
+        let entry = match client.next_entry() {
+            Err(ErrorKind::Timeout) if shutdown.is_requested() => return Ok(()),
+            Err(ErrorKind::Timeout) => continue,
+            entry => entry?,
+        };
+        if entry.position != applied + 1 {
+            return Err(ErrorKind::Server(format!(
+                "expected replicated write {}, got {}",
+                applied + 1,
+                entry.position
+            )));
+        }
+        debug!("Applying replicated write {}", entry.position);
+        // A replayed write may find its effect already applied before a restart.
+        match apply(namespaces, entry.write, None) {
+            Ok(_)
+            | Err(ErrorKind::KeyNotFound)
+            | Err(ErrorKind::NamespaceExists(_))
+            | Err(ErrorKind::NamespaceNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+        applied = entry.position;
+        write_position(config, applied)?;
+        client.acknowledge(applied)?;
+    }
+}
+
+fn read_position(config: &FollowerConfig) -> Result<u64> {
+    match fs::read_to_string(&config.position_file) {
+        Ok(position) => position.trim().parse().map_err(|_| {
+            ErrorKind::ConversionError(format!(
+                "invalid replication position in {}",
+                config.position_file.display()
+            ))
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replace the position file atomically, so that a crash leaves either position.
+fn write_position(config: &FollowerConfig, position: u64) -> Result<()> {
+    let tmp = config.position_file.with_extension("tmp");
+    fs::write(&tmp, position.to_string())?;
+    fs::rename(&tmp, &config.position_file)?;
+    Ok(())
+}
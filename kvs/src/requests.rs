@@ -1,11 +1,106 @@
+use crate::metrics::ServerStats;
+use crate::raft::RaftMessage;
+use crate::replication::{Entry, ReplicationStatus};
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
     Ping,
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Auth {
+        token: String,
+    },
+    Get {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+    },
+    Set {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+        value: String,
+    },
+    /// Set every key to its value at once
+    SetBatch {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        pairs: Vec<(String, String)>,
+    },
+    Remove {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+    },
+    /// Keys starting with `prefix`, in order. Pages of at most `limit` keys each start
+    /// after the last key of the previous one, `after`.
+    Scan {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        prefix: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        after: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+    },
+    /// Add `delta` to the integer value of the key, 0 if it does not exist
+    Incr {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+        delta: i64,
+    },
+    /// Subtract `delta` from the integer value of the key, 0 if it does not exist
+    Decr {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+        delta: i64,
+    },
+    Shutdown,
+    CreateNamespace {
+        name: String,
+    },
+    DropNamespace {
+        name: String,
+    },
+    ListNamespaces,
+    Replicate {
+        from: u64,
+    },
+    Ack {
+        position: u64,
+    },
+    ReplicationStatus,
+    Raft(RaftMessage),
+    Stats,
+}
+
+impl Request {
+    /// Name of the type of request, to label metrics with.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Request::Ping => "ping",
+            Request::Auth { .. } => "auth",
+            Request::Get { .. } => "get",
+            Request::Set { .. } => "set",
+            Request::SetBatch { .. } => "set_batch",
+            Request::Remove { .. } => "remove",
+            Request::Scan { .. } => "scan",
+            Request::Incr { .. } => "incr",
+            Request::Decr { .. } => "decr",
+            Request::Shutdown => "shutdown",
+            Request::CreateNamespace { .. } => "create_namespace",
+            Request::DropNamespace { .. } => "drop_namespace",
+            Request::ListNamespaces => "list_namespaces",
+            Request::Replicate { .. } => "replicate",
+            Request::Ack { .. } => "ack",
+            Request::ReplicationStatus => "replication_status",
+            Request::Raft(_) => "raft",
+            Request::Stats => "stats",
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -14,4 +109,25 @@ pub enum Response {
     Error(String),
     Value(Option<String>),
     Pong,
+    PermissionDenied(String),
+    Namespaces(Vec<String>),
+    Pairs(Vec<(String, String)>),
+    /// New value of an incremented or decremented key
+    Integer(i64),
+    /// Value of the key to increment or decrement is not an integer
+    NotAnInteger(String),
+    /// Key, value or request is larger than the server accepts
+    TooLarge(String),
+    /// Server is too busy to handle the request, which may be sent again after
+    /// `retry_after`. With `reconnect`, the server closes the connection, and the
+    /// request must be sent on a new one
+    Overloaded {
+        retry_after: Duration,
+        reconnect: bool,
+    },
+    Entry(Entry),
+    Replication(ReplicationStatus),
+    /// Not the leader of a Raft cluster, send the request to the given one instead
+    Redirect(Option<SocketAddr>),
+    Stats(ServerStats),
 }
@@ -1,89 +1,902 @@
-use crate::engines::KvsEngine;
-use crate::error::Result;
+use crate::acl::Acl;
+use crate::engines::{Durability, KvsEngine};
+use crate::error::{ErrorKind, Result};
+use crate::metrics::{self, Metrics, ServerStats};
+use crate::namespaces::Namespaces;
+use crate::raft::RaftInbox;
+use crate::rate_limit::{RateLimit, TokenBucket};
+use crate::replication::{self, FollowerConfig, Mutation, ReplicationLog};
 use crate::requests::{Request, Response};
+use crate::shutdown::ShutdownHandle;
+use crate::stream::Stream;
+use crate::tls::ServerTls;
+use serde::Deserialize;
 use serde_json::Deserializer;
-use slog_scope::debug;
+use slog_scope::{debug, error, info};
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::io::prelude::*;
-use std::io::BufWriter;
+use std::io::{self, BufReader, BufWriter};
 use std::net::SocketAddr;
-use std::net::{TcpListener, TcpStream};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REPLICATION_DISABLED: &str = "Replication is not enabled on this server";
+const RAFT_DISABLED: &str = "Raft is not enabled on this server";
+
+/// How often the accept loop checks for shutdown while every thread is busy.
+const BUSY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Time that clients of refused connections are told to wait before connecting again.
+const REFUSED_RETRY_AFTER: Duration = Duration::from_millis(100);
+/// Limit on waiting for the request of a refused connection.
+const REFUSED_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Socket timeouts of connections accepted by the server. `None` waits forever.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServerTimeouts {
+    /// Limit on waiting for the next request before the connection is closed
+    pub idle: Option<Duration>,
+    /// Limit on sending a response to the client
+    pub write: Option<Duration>,
+}
+
+/// Limits on what clients may send to the server. `None` sets no limit.
+///
+/// Sizes are in bytes. Keys and values are checked before the engine is touched. A
+/// request larger than `max_request` is not read any further, and the connection is
+/// closed after the client is told.
+///
+/// Requests over a rate limit, and connections over `max_connections`, are answered
+/// with `Response::Overloaded`, telling the client when to retry. Messages between
+/// nodes of a cluster are not rate limited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServerLimits {
+    /// Limit on keys, and on prefixes to scan
+    pub max_key: Option<usize>,
+    /// Limit on values to set
+    pub max_value: Option<usize>,
+    /// Limit on a whole request as sent by the client
+    pub max_request: Option<usize>,
+    /// Limit on connections open at once. Further connections are refused, unlike
+    /// those over `KvsServer::with_threads` which wait to be served
+    pub max_connections: Option<usize>,
+    /// Rate of requests allowed on each connection
+    pub connection_rate: Option<RateLimit>,
+    /// Rate of requests allowed on all connections together
+    pub global_rate: Option<RateLimit>,
+}
 
 /// The server of the key/value store.
 pub struct KvsServer<E: KvsEngine> {
-    engine: E,
+    engine: Arc<Mutex<E>>,
     addr: SocketAddr,
+    timeouts: ServerTimeouts,
+    limits: ServerLimits,
+    shutdown: ShutdownHandle,
+    durability: Option<Durability>,
+    tls: Option<ServerTls>,
+    acl: Option<Arc<Acl>>,
+    replication: Option<Arc<ReplicationLog>>,
+    follower: Option<FollowerConfig>,
+    raft: Option<RaftInbox>,
+    metrics_addr: Option<SocketAddr>,
+    threads: Option<usize>,
 }
 
-impl<E: KvsEngine> KvsServer<E> {
+impl<E: KvsEngine + Send + 'static> KvsServer<E> {
     /// Create a `KvsServer` with a given storage engine and socket address.
     pub fn new(engine: E, addr: SocketAddr) -> Result<Self> {
-        Ok(KvsServer { engine, addr })
+        Ok(KvsServer {
+            engine: Arc::new(Mutex::new(engine)),
+            addr,
+            timeouts: ServerTimeouts::default(),
+            limits: ServerLimits::default(),
+            shutdown: ShutdownHandle::default(),
+            durability: None,
+            tls: None,
+            acl: None,
+            replication: None,
+            follower: None,
+            raft: None,
+            metrics_addr: None,
+            threads: None,
+        })
+    }
+
+    /// Log every write to the file at `path`, so that followers can replicate them.
+    pub fn with_replication_log(mut self, path: impl Into<PathBuf>) -> Result<Self> {
+        self.replication = Some(Arc::new(ReplicationLog::open(path)?));
+        Ok(self)
+    }
+
+    /// Make the server a follower of a leader: it applies the writes logged by the
+    /// leader, and rejects writes from its own clients.
+    pub fn following(mut self, config: FollowerConfig) -> Self {
+        self.follower = Some(config);
+        self
+    }
+
+    /// Make the server a node of a Raft cluster, whose engine is a `RaftEngine`.
+    /// Messages from other nodes are handed to its `inbox`, and clients are redirected
+    /// to the leader.
+    pub fn with_raft(mut self, inbox: RaftInbox) -> Self {
+        self.raft = Some(inbox);
+        self
+    }
+
+    /// Require clients to authenticate with a token, and restrict the keys they may
+    /// access to those granted by the ACL.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Accept only TLS-encrypted connections.
+    pub fn with_tls(mut self, tls: ServerTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Set how durable a write is when the server acknowledges it, in every namespace.
+    /// By default the engine's own durability is kept.
+    ///
+    /// With `Durability::GroupCommit` writes from concurrent connections are synced to
+    /// disk together, and each is acknowledged once a sync covering it completes.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = Some(durability);
+        self
+    }
+
+    /// Serve metrics over HTTP at `addr`, in the Prometheus text format. They are also
+    /// available to clients with `Request::Stats`.
+    pub fn with_metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Set the socket timeouts of accepted connections.
+    pub fn with_timeouts(mut self, timeouts: ServerTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Set the largest keys, values and requests that clients may send, and how many
+    /// requests and connections the server accepts.
+    pub fn with_limits(mut self, limits: ServerLimits) -> Self {
+        self.limits = limits;
+        self
     }
 
-    /// Listen to the given socket address.
+    /// Serve at most `threads` connections at once. Further connections wait to be
+    /// accepted until one of those is closed. By default there is no limit.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        assert!(threads > 0, "the server needs at least one thread");
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Get a handle that stops the server.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Listen to the given socket address. Every connection is served on its own thread.
+    ///
+    /// Returns once shutdown is requested through a `ShutdownHandle` or a
+    /// `Request::Shutdown`, after all connections are closed and the engine is flushed.
+    /// By then the threads serving connections released the engine, which the server
+    /// holds alone. Without an ACL, only clients on the local host may request shutdown.
     pub fn listen(&mut self) -> Result<()> {
+        let namespaces = Arc::new(Namespaces::open(Arc::clone(&self.engine), self.durability)?);
         let listener = TcpListener::bind(self.addr)?;
+        let connections = Arc::new(Connections::default());
+        let metrics = Arc::new(Metrics::default());
+        let request_ids = Arc::new(AtomicU64::new(1));
+        let bucket = self
+            .limits
+            .global_rate
+            .map(|limit| Arc::new(Mutex::new(TokenBucket::new(limit))));
+        let metrics_endpoint = match self.metrics_addr {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr)?;
+                let namespaces = Arc::clone(&namespaces);
+                let metrics = Arc::clone(&metrics);
+                let shutdown = self.shutdown.clone();
+                Some(thread::spawn(move || {
+                    let stats = || {
+                        server_stats(&metrics, &namespaces).unwrap_or_else(|e| {
+                            error!("Failed to collect engine stats: {}", e);
+                            metrics.snapshot()
+                        })
+                    };
+                    metrics::serve_http(listener, stats, &shutdown)
+                }))
+            }
+            None => None,
+        };
+        let follower = self.follower.clone().map(|config| {
+            let namespaces = Arc::clone(&namespaces);
+            let shutdown = self.shutdown.clone();
+            thread::spawn(move || replication::follow(config, &namespaces, &shutdown))
+        });
+
+        if !self.shutdown.listening_on(listener.local_addr()?) {
+            for stream in listener.incoming() {
+                if self.shutdown.is_requested() {
+                    break;
+                }
+                // A connection failing before it is served must not stop the server.
+                let tcp = match stream {
+                    Ok(tcp) => tcp,
+                    Err(e) => {
+                        error!("Failed to accept connection: {}", e);
+                        continue;
+                    }
+                };
+                // Every record logged while serving the connection names the client.
+                let peer = tcp
+                    .peer_addr()
+                    .map_or_else(|_| "unknown".to_owned(), |addr| addr.to_string());
+                let logger = slog_scope::logger().new(slog::o!("peer" => peer));
+                let timeouts = tcp
+                    .set_read_timeout(self.timeouts.idle)
+                    .and_then(|_| tcp.set_write_timeout(self.timeouts.write));
+                if let Err(e) = timeouts {
+                    slog_scope::scope(&logger, || error!("Failed to set up connection: {}", e));
+                    continue;
+                }
+                if let Some(max) = self.limits.max_connections {
+                    if connections.len() >= max {
+                        self.refuse_connection(tcp);
+                        continue;
+                    }
+                }
+                let id = match connections.register(&tcp) {
+                    Ok(id) => id,
+                    Err(e) => {
+                        slog_scope::scope(&logger, || error!("Failed to set up connection: {}", e));
+                        continue;
+                    }
+                };
+                let tls = self.tls.clone();
+                let handler = Handler {
+                    namespaces: Arc::clone(&namespaces),
+                    shutdown: self.shutdown.clone(),
+                    acl: self.acl.clone(),
+                    replication: self.replication.clone(),
+                    read_only: self.follower.is_some(),
+                    limits: self.limits,
+                    bucket: bucket.clone(),
+                    raft: self.raft.clone(),
+                    metrics: Arc::clone(&metrics),
+                    request_ids: Arc::clone(&request_ids),
+                };
+                let registration = Registration {
+                    connections: Arc::clone(&connections),
+                    id,
+                };
 
-        for stream in listener.incoming() {
-            let stream = stream?;
-            let reader = Deserializer::from_reader(&stream).into_iter::<Request>();
+                thread::spawn(move || {
+                    // Release the engine before the server may consider itself drained,
+                    // even when serving the connection panics.
+                    let _registration = registration;
+                    let handler = handler;
+                    slog_scope::scope(&logger, || {
+                        debug!("Accepted connection");
+                        handler.metrics.connection_opened();
+                        let stream = match &tls {
+                            Some(tls) => Stream::accept_tls(tcp, tls),
+                            None => Ok(Stream::Plain(tcp)),
+                        };
+                        if let Err(e) = stream.and_then(|stream| handler.serve(stream)) {
+                            error!("Error while serving connection: {}", e);
+                        }
+                        handler.metrics.connection_closed();
+                        debug!("Closed connection");
+                    });
+                });
+                if let Some(threads) = self.threads {
+                    connections.wait_for_slot(threads, &self.shutdown);
+                }
+            }
+        }
 
-            for request in reader {
-                let request = request?;
-                let writer = BufWriter::new(&stream);
+        info!("Stopped accepting connections, draining open ones");
+        connections.close_all();
+        if let Some(follower) = follower {
+            let _ = follower.join();
+        }
+        if let Some(metrics_endpoint) = metrics_endpoint {
+            let _ = metrics_endpoint.join();
+        }
+        namespaces.flush()?;
+        if let Some(replication) = &self.replication {
+            replication.sync()?;
+        }
+        info!("Shutdown complete");
+        Ok(())
+    }
 
-                self.handle_request(writer, request)?;
+    /// Answer the first request of a connection over `max_connections` with
+    /// `Response::Overloaded`, on a thread of its own so that accepting connections
+    /// goes on.
+    fn refuse_connection(&self, tcp: TcpStream) {
+        debug!("Refusing connection over the limit of open connections");
+        let tls = self.tls.clone();
+        let max_request = self.limits.max_request;
+        thread::spawn(move || {
+            let refuse = || -> Result<()> {
+                tcp.set_read_timeout(Some(REFUSED_READ_TIMEOUT))?;
+                let stream = match &tls {
+                    Some(tls) => Stream::accept_tls(tcp, tls)?,
+                    None => Stream::Plain(tcp),
+                };
+                let mut reader =
+                    Deserializer::from_reader(FrameReader::new(stream.try_clone()?, max_request));
+                Request::deserialize(&mut reader)?;
+                let mut writer = BufWriter::new(stream);
+                let response = Response::Overloaded {
+                    retry_after: REFUSED_RETRY_AFTER,
+                    reconnect: true,
+                };
+                serde_json::to_writer(&mut writer, &response)?;
+                writer.flush()?;
+                Ok(())
+            };
+            if let Err(e) = refuse() {
+                debug!("Error while refusing connection: {}", e);
             }
+        });
+    }
+}
+
+/// Connections being served, so they can be drained on shutdown.
+#[derive(Default)]
+struct Connections {
+    streams: Mutex<(u64, HashMap<u64, TcpStream>)>,
+    closed: Condvar,
+}
+
+impl Connections {
+    fn register(&self, stream: &TcpStream) -> Result<u64> {
+        let mut streams = self.streams.lock().unwrap();
+        let (next_id, open) = &mut *streams;
+        let id = *next_id;
+        *next_id += 1;
+        open.insert(id, stream.try_clone()?);
+        Ok(id)
+    }
+
+    fn len(&self) -> usize {
+        self.streams.lock().unwrap().1.len()
+    }
+
+    fn unregister(&self, id: u64) {
+        self.streams.lock().unwrap().1.remove(&id);
+        self.closed.notify_all();
+    }
+
+    /// Wait until fewer than `limit` connections are open, or shutdown is requested.
+    fn wait_for_slot(&self, limit: usize, shutdown: &ShutdownHandle) {
+        let mut streams = self.streams.lock().unwrap();
+        while streams.1.len() >= limit && !shutdown.is_requested() {
+            streams = self
+                .closed
+                .wait_timeout(streams, BUSY_POLL_INTERVAL)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Stop reading new requests from every connection and wait until each finished
+    /// the request it was handling.
+    fn close_all(&self) {
+        let mut streams = self.streams.lock().unwrap();
+        for stream in streams.1.values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        while !streams.1.is_empty() {
+            streams = self.closed.wait(streams).unwrap();
         }
+    }
+}
+
+/// A connection registered in `Connections` until its thread ends.
+struct Registration {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.unregister(self.id);
+    }
+}
+
+struct Handler<E: KvsEngine> {
+    namespaces: Arc<Namespaces<E>>,
+    shutdown: ShutdownHandle,
+    acl: Option<Arc<Acl>>,
+    replication: Option<Arc<ReplicationLog>>,
+    read_only: bool,
+    limits: ServerLimits,
+    /// Tokens of the requests of all connections
+    bucket: Option<Arc<Mutex<TokenBucket>>>,
+    raft: Option<RaftInbox>,
+    metrics: Arc<Metrics>,
+    /// Source of the ids tagging the log records of each request
+    request_ids: Arc<AtomicU64>,
+}
+
+/// State of a single client connection.
+#[derive(Default)]
+struct Session {
+    /// Token the client authenticated with
+    token: Option<String>,
+    /// Tokens of the requests of the connection
+    bucket: Option<TokenBucket>,
+    /// Whether the client connects from the host of the server
+    local: bool,
+}
+
+impl<E: KvsEngine> Handler<E> {
+    fn serve(&self, stream: Stream) -> Result<()> {
+        let frame = FrameReader::new(stream.try_clone()?, self.limits.max_request);
+        let read = Rc::clone(&frame.read);
+        let reader = Deserializer::from_reader(frame);
+        let mut writer = BufWriter::new(stream);
+        let mut session = Session {
+            bucket: self.limits.connection_rate.map(TokenBucket::new),
+            local: writer
+                .get_ref()
+                .tcp()
+                .peer_addr()
+                .is_ok_and(|addr| addr.ip().is_loopback()),
+            ..Session::default()
+        };
+
+        for request in reader.into_iter::<Request>() {
+            let request = match request.map_err(ErrorKind::from) {
+                Err(ErrorKind::Timeout) => {
+                    debug!("Closing idle connection");
+                    return Ok(());
+                }
+                Err(_) if frame_exceeded(read.get(), self.limits.max_request) => {
+                    return self.refuse_request(writer);
+                }
+                request => request?,
+            };
+            read.set(0);
+            if let Request::Replicate { from } = request {
+                return self.replicate(writer, &session, from);
+            }
+            self.handle_request(&mut writer, &mut session, request)?;
+        }
+        Ok(())
+    }
+
+    /// Tell the client its request is too large, and discard the rest of what it sends
+    /// so that it gets to read the response.
+    fn refuse_request(&self, mut writer: BufWriter<Stream>) -> Result<()> {
+        let limit = self.limits.max_request.unwrap_or_default();
+        debug!("Closing connection sending a request over {} bytes", limit);
+        let response = Response::TooLarge(format!("request over the limit of {} bytes", limit));
+        self.send_response(&mut writer, response)?;
+        let mut stream = writer.into_inner().map_err(|e| e.into_error())?;
+        let _ = stream.tcp().shutdown(Shutdown::Write);
+        let _ = io::copy(&mut stream, &mut io::sink());
         Ok(())
     }
 
-    fn handle_request(&mut self, writer: BufWriter<&TcpStream>, request: Request) -> Result<()> {
-        debug!("Received: {:?}", request);
+    fn handle_request(
+        &self,
+        writer: &mut BufWriter<Stream>,
+        session: &mut Session,
+        request: Request,
+    ) -> Result<()> {
+        let request_id = self.request_ids.fetch_add(1, Ordering::Relaxed);
+        let logger = slog_scope::logger().new(slog::o!("request_id" => request_id));
+        slog_scope::scope(&logger, || {
+            debug!("Received: {:?}", request);
+            let started = Instant::now();
+            let kind = request.kind();
+
+            let response = self.respond(session, request);
+            let failed = matches!(
+                response,
+                Response::Error(_)
+                    | Response::PermissionDenied(_)
+                    | Response::Redirect(_)
+                    | Response::NotAnInteger(_)
+                    | Response::TooLarge(_)
+                    | Response::Overloaded { .. }
+            );
+            let result = self.send_response(writer, response);
+            self.metrics.record(kind, started.elapsed(), failed);
+            result
+        })
+    }
+
+    fn respond(&self, session: &mut Session, request: Request) -> Response {
+        if let Err(retry_after) = self.throttle(session, &request) {
+            debug!("Overloaded, retry after {:?}", retry_after);
+            return Response::Overloaded {
+                retry_after,
+                reconnect: false,
+            };
+        }
+        if let Err(reason) = self.authorize(session, &request) {
+            debug!("Permission denied: {}", reason);
+            return Response::PermissionDenied(reason);
+        }
+        if let Err(reason) = self.check_sizes(&request) {
+            debug!("Request too large: {}", reason);
+            return Response::TooLarge(reason);
+        }
 
         match request {
-            Request::Ping => {
-                self.send_response(writer, Response::Pong)?;
+            Request::Ping => Response::Pong,
+            Request::Auth { token } => self.authenticate(session, token),
+            Request::Shutdown => {
+                self.shutdown.shutdown();
+                Response::Success
             }
-            Request::Get { key } => {
-                match self.engine.get(key) {
-                    Ok(value) => {
-                        self.send_response(writer, Response::Value(value))?;
-                    }
-                    Err(e) => {
-                        self.send_response(writer, Response::Error(e.to_string()))?;
-                    }
-                };
+            Request::ListNamespaces => Response::Namespaces(self.namespaces.names()),
+            Request::ReplicationStatus => match &self.replication {
+                Some(log) => Response::Replication(log.status()),
+                None => Response::Error(REPLICATION_DISABLED.to_owned()),
+            },
+            Request::Get { namespace, key } => {
+                let result = self
+                    .namespaces
+                    .get(namespace.as_deref())
+                    .and_then(|keyspace| {
+                        keyspace.execute(false, |engine| engine.get(key).map(Response::Value))
+                    });
+                result.unwrap_or_else(error_response)
+            }
+            Request::Scan {
+                namespace,
+                prefix,
+                after,
+                limit,
+            } => self
+                .namespaces
+                .get(namespace.as_deref())
+                .and_then(|keyspace| {
+                    keyspace.execute(false, |engine| {
+                        let pairs = engine.scan(&prefix)?;
+                        Ok(Response::Pairs(page(pairs, after.as_deref(), limit)))
+                    })
+                })
+                .unwrap_or_else(error_response),
+            Request::Set {
+                namespace,
+                key,
+                value,
+            } => self.write(Mutation::Set {
+                namespace,
+                key,
+                value,
+            }),
+            Request::SetBatch { namespace, pairs } => {
+                self.write(Mutation::SetBatch { namespace, pairs })
             }
-            Request::Set { key, value } => match self.engine.set(key, value) {
-                Ok(_) => self.send_response(writer, Response::Success)?,
-                Err(e) => {
-                    self.send_response(writer, Response::Error(e.to_string()))?;
+            Request::Remove { namespace, key } => self.write(Mutation::Remove { namespace, key }),
+            Request::Incr {
+                namespace,
+                key,
+                delta,
+            } => self.write(Mutation::Incr {
+                namespace,
+                key,
+                delta,
+            }),
+            Request::Decr {
+                namespace,
+                key,
+                delta,
+            } => match delta.checked_neg() {
+                Some(delta) => self.write(Mutation::Incr {
+                    namespace,
+                    key,
+                    delta,
+                }),
+                None => Response::Error(format!("cannot subtract {}", delta)),
+            },
+            Request::CreateNamespace { name } => self.write(Mutation::CreateNamespace { name }),
+            Request::DropNamespace { name } => self.write(Mutation::DropNamespace { name }),
+            Request::Raft(message) => match &self.raft {
+                Some(inbox) => {
+                    inbox.deliver(message);
+                    Response::Success
                 }
+                None => Response::Error(RAFT_DISABLED.to_owned()),
             },
-            Request::Remove { key } => {
-                match self.engine.remove(key) {
-                    Ok(_) => {
-                        self.send_response(writer, Response::Success)?;
-                    }
-                    Err(e) => {
-                        self.send_response(writer, Response::Error(e.to_string()))?;
+            Request::Stats => match server_stats(&self.metrics, &self.namespaces) {
+                Ok(stats) => Response::Stats(stats),
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::Replicate { .. } | Request::Ack { .. } => {
+                Response::Error("Unexpected request".to_owned())
+            }
+        }
+    }
+
+    fn write(&self, write: Mutation) -> Response {
+        if self.read_only {
+            return Response::Error("Followers are read-only, write to the leader".to_owned());
+        }
+        replication::apply(&self.namespaces, write, self.replication.as_deref())
+            .unwrap_or_else(error_response)
+    }
+
+    /// Stream the replication log after position `from` to a follower, and record the
+    /// positions it acknowledges, until either side closes the connection.
+    fn replicate(&self, mut writer: BufWriter<Stream>, session: &Session, from: u64) -> Result<()> {
+        if let Err(reason) = self.authorize(session, &Request::Replicate { from }) {
+            return self.send_response(&mut writer, Response::PermissionDenied(reason));
+        }
+        let log = match &self.replication {
+            Some(log) => Arc::clone(log),
+            None => {
+                let response = Response::Error(REPLICATION_DISABLED.to_owned());
+                return self.send_response(&mut writer, response);
+            }
+        };
+        if from > log.position() {
+            let response = Response::Error(format!(
+                "Follower is at position {}, ahead of the leader at {}",
+                from,
+                log.position()
+            ));
+            return self.send_response(&mut writer, response);
+        }
+        self.send_response(&mut writer, Response::Success)?;
+
+        let stream = writer.get_ref().try_clone()?;
+        let peer = stream.tcp().peer_addr()?;
+        info!("Follower {} replicating after position {}", peer, from);
+        let id = log.register_follower(peer, from);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let acks = {
+            let log = Arc::clone(&log);
+            let stopped = Arc::clone(&stopped);
+            thread::spawn(move || {
+                let mut reader = Deserializer::from_reader(BufReader::new(stream));
+                loop {
+                    match Request::deserialize(&mut reader).map_err(ErrorKind::from) {
+                        Ok(Request::Ack { position }) => log.acknowledge(id, position),
+                        Err(ErrorKind::Timeout) => continue,
+                        _ => break,
                     }
-                };
+                }
+                stopped.store(true, Ordering::SeqCst);
+            })
+        };
+
+        let result = log.stream_to(
+            from,
+            |entry| {
+                serde_json::to_writer(&mut writer, &Response::Entry(entry))?;
+                writer.flush()?;
+                Ok(())
+            },
+            || stopped.load(Ordering::SeqCst) || self.shutdown.is_requested(),
+        );
+        let _ = writer.get_ref().tcp().shutdown(Shutdown::Both);
+        let _ = acks.join();
+        log.unregister_follower(id);
+        info!("Follower {} disconnected", peer);
+        result
+    }
+
+    fn authenticate(&self, session: &mut Session, token: String) -> Response {
+        match &self.acl {
+            Some(acl) if !acl.is_known(&token) => {
+                Response::PermissionDenied("Invalid token".to_owned())
+            }
+            _ => {
+                session.token = Some(token);
+                Response::Success
             }
+        }
+    }
+
+    /// Check the request against the ACL, if the server has one. Without one, only
+    /// clients on the local host may shut the server down.
+    fn authorize(&self, session: &Session, request: &Request) -> std::result::Result<(), String> {
+        let acl = match &self.acl {
+            Some(acl) => acl,
+            None if matches!(request, Request::Shutdown) && !session.local => {
+                return Err("Shutdown is only accepted from the local host".to_owned())
+            }
+            None => return Ok(()),
         };
+        let allowed = match (request, &session.token) {
+            (Request::Ping, _) | (Request::Auth { .. }, _) => true,
+            (_, None) => return Err("Authentication required".to_owned()),
+            (Request::ListNamespaces, Some(_))
+            | (Request::ReplicationStatus, Some(_))
+            | (Request::Stats, Some(_))
+            | (Request::Ack { .. }, Some(_)) => true,
+            (Request::Get { namespace, key }, Some(token))
+            | (
+                Request::Scan {
+                    namespace,
+                    prefix: key,
+                    ..
+                },
+                Some(token),
+            ) => acl.allows(token, namespace.as_deref(), key, false),
+            (Request::Set { namespace, key, .. }, Some(token))
+            | (Request::Remove { namespace, key }, Some(token)) => {
+                acl.allows(token, namespace.as_deref(), key, true)
+            }
+            // The new value of a counter is sent back, so changing it reads it too.
+            (Request::Incr { namespace, key, .. }, Some(token))
+            | (Request::Decr { namespace, key, .. }, Some(token)) => {
+                acl.allows(token, namespace.as_deref(), key, false)
+                    && acl.allows(token, namespace.as_deref(), key, true)
+            }
+            (Request::SetBatch { namespace, pairs }, Some(token)) => pairs
+                .iter()
+                .all(|(key, _)| acl.allows(token, namespace.as_deref(), key, true)),
+            (Request::Shutdown, Some(token))
+            | (Request::CreateNamespace { .. }, Some(token))
+            | (Request::DropNamespace { .. }, Some(token))
+            | (Request::Replicate { .. }, Some(token))
+            | (Request::Raft(_), Some(token)) => acl.allows_admin(token),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err("Operation not permitted".to_owned())
+        }
+    }
+
+    /// Take a token for a client request from the buckets of the connection and of the
+    /// server, or tell how long until the client may retry. A request refused by one
+    /// bucket takes no token from the other.
+    fn throttle(
+        &self,
+        session: &mut Session,
+        request: &Request,
+    ) -> std::result::Result<(), Duration> {
+        if matches!(request, Request::Raft(_) | Request::Ack { .. }) {
+            return Ok(());
+        }
+        let mut server = self.bucket.as_ref().map(|bucket| bucket.lock().unwrap());
+        if let Some(bucket) = &mut session.bucket {
+            bucket.check()?;
+        }
+        if let Some(bucket) = &mut server {
+            bucket.check()?;
+        }
+        if let Some(bucket) = &mut session.bucket {
+            bucket.take()?;
+        }
+        if let Some(bucket) = &mut server {
+            bucket.take()?;
+        }
         Ok(())
     }
 
-    fn send_response(
-        &mut self,
-        mut writer: BufWriter<&TcpStream>,
-        response: Response,
-    ) -> Result<()> {
+    /// Check the keys and values of the request against the limits of the server.
+    fn check_sizes(&self, request: &Request) -> std::result::Result<(), String> {
+        let check = |what: &str, bytes: &str, limit: Option<usize>| match limit {
+            Some(limit) if bytes.len() > limit => Err(format!(
+                "{} of {} bytes, the limit is {} bytes",
+                what,
+                bytes.len(),
+                limit
+            )),
+            _ => Ok(()),
+        };
+        let (max_key, max_value) = (self.limits.max_key, self.limits.max_value);
+        match request {
+            Request::Get { key, .. }
+            | Request::Remove { key, .. }
+            | Request::Incr { key, .. }
+            | Request::Decr { key, .. } => check("key", key, max_key),
+            Request::Scan { prefix, .. } => check("prefix", prefix, max_key),
+            Request::Set { key, value, .. } => {
+                check("key", key, max_key)?;
+                check("value", value, max_value)
+            }
+            Request::SetBatch { pairs, .. } => pairs.iter().try_for_each(|(key, value)| {
+                check("key", key, max_key)?;
+                check("value", value, max_value)
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn send_response(&self, writer: &mut BufWriter<Stream>, response: Response) -> Result<()> {
         debug!("Sending to client: {:?}", response);
 
-        serde_json::to_writer(&mut writer, &response)?;
+        serde_json::to_writer(&mut *writer, &response)?;
         writer.flush()?;
         Ok(())
     }
 }
+
+/// Reader of the requests of a connection, counting the bytes read since the start of
+/// the current request and failing once there are more than `limit`.
+struct FrameReader<R> {
+    inner: BufReader<R>,
+    read: Rc<Cell<usize>>,
+    limit: Option<usize>,
+}
+
+impl<R: Read> FrameReader<R> {
+    fn new(inner: R, limit: Option<usize>) -> Self {
+        FrameReader {
+            inner: BufReader::new(inner),
+            read: Rc::new(Cell::new(0)),
+            limit,
+        }
+    }
+}
+
+impl<R: Read> Read for FrameReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.read.get();
+        if frame_exceeded(read, self.limit) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request is too large",
+            ));
+        }
+        let n = self.inner.read(buf)?;
+        self.read.set(read + n);
+        Ok(n)
+    }
+}
+
+fn frame_exceeded(read: usize, limit: Option<usize>) -> bool {
+    limit.is_some_and(|limit| read > limit)
+}
+
+/// Statistics of the server and of the engines of its namespaces.
+fn server_stats<E: KvsEngine>(
+    metrics: &Metrics,
+    namespaces: &Namespaces<E>,
+) -> Result<ServerStats> {
+    let (engine, named) = namespaces.stats()?;
+    Ok(ServerStats {
+        engine,
+        namespaces: named,
+        ..metrics.snapshot()
+    })
+}
+
+/// Turn an error into a response, redirecting clients of a Raft node to the leader.
+fn error_response(e: ErrorKind) -> Response {
+    match e {
+        ErrorKind::NotLeader(leader) => Response::Redirect(leader),
+        ErrorKind::NotAnInteger(key) => Response::NotAnInteger(key),
+        e => Response::Error(e.to_string()),
+    }
+}
+
+/// The scanned pairs following the key `after`, at most `limit` of them.
+fn page(
+    pairs: Vec<(String, String)>,
+    after: Option<&str>,
+    limit: Option<usize>,
+) -> Vec<(String, String)> {
+    let start = after.map_or(0, |after| {
+        pairs.partition_point(|(key, _)| key.as_str() <= after)
+    });
+    pairs
+        .into_iter()
+        .skip(start)
+        .take(limit.unwrap_or(usize::MAX))
+        .collect()
+}
@@ -0,0 +1,202 @@
+use crate::client::{ClientOptions, KvsClient};
+use crate::error::Result;
+use slog_scope::{debug, info};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+
+/// Points each server gets on the hash ring unless configured otherwise.
+pub const DEFAULT_VIRTUAL_NODES: usize = 128;
+
+/// Keys read from a server at a time while rebalancing.
+pub const REBALANCE_PAGE: usize = 100;
+
+/// Consistent hash ring placing keys on servers. Every server owns many points of the
+/// ring, its virtual nodes, so that keys spread evenly and adding or removing a server
+/// only moves the keys of the points it gains or loses.
+#[derive(Debug, Clone)]
+struct HashRing {
+    points: BTreeMap<u64, SocketAddr>,
+}
+
+impl HashRing {
+    fn new(servers: &[SocketAddr], virtual_nodes: usize) -> Self {
+        let mut points = BTreeMap::new();
+        for server in servers {
+            for i in 0..virtual_nodes {
+                points.insert(hash(&format!("{}#{}", server, i)), *server);
+            }
+        }
+        HashRing { points }
+    }
+
+    /// The server owning the first point at or after the hash of the key.
+    fn owner(&self, key: &str) -> SocketAddr {
+        let hash = hash(key);
+        let (_, server) = self
+            .points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .expect("hash ring has no servers");
+        *server
+    }
+}
+
+/// FNV-1a, mixed with the SplitMix64 finalizer so that similar strings, like the
+/// names of virtual nodes, land far apart. Placement must not change between runs or
+/// builds, which rules out the hashers of the standard library.
+fn hash(data: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in data.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// A client spreading keys across several key/value servers with consistent hashing.
+///
+/// Every request goes to the server owning its key. Connections are opened on first
+/// use, and reopened on the next request after one fails.
+pub struct ShardedKvsClient {
+    servers: Vec<SocketAddr>,
+    virtual_nodes: usize,
+    ring: HashRing,
+    options: ClientOptions,
+    clients: HashMap<SocketAddr, KvsClient>,
+}
+
+impl ShardedKvsClient {
+    /// Create a client sharding keys across `servers`.
+    pub fn new(servers: Vec<SocketAddr>) -> Self {
+        assert!(!servers.is_empty(), "at least one server is needed");
+
+        ShardedKvsClient {
+            ring: HashRing::new(&servers, DEFAULT_VIRTUAL_NODES),
+            servers,
+            virtual_nodes: DEFAULT_VIRTUAL_NODES,
+            options: ClientOptions::default(),
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Set how many points of the hash ring each server gets. Every client of the same
+    /// servers must use the same number to agree on where keys are.
+    pub fn with_virtual_nodes(mut self, virtual_nodes: usize) -> Self {
+        assert!(virtual_nodes > 0, "servers need at least one virtual node");
+        self.virtual_nodes = virtual_nodes;
+        self.ring = HashRing::new(&self.servers, virtual_nodes);
+        self
+    }
+
+    /// Set the options of the connections to the servers.
+    pub fn with_options(mut self, options: ClientOptions) -> Self {
+        self.options = options;
+        self.clients.clear();
+        self
+    }
+
+    /// The servers keys are spread across.
+    pub fn servers(&self) -> &[SocketAddr] {
+        &self.servers
+    }
+
+    /// The server that owns the given key.
+    pub fn owner(&self, key: &str) -> SocketAddr {
+        self.ring.owner(key)
+    }
+
+    /// Get the value of a given string key from the server owning it
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let owner = self.owner(&key);
+        self.with_client(owner, |client| client.get(key))
+    }
+
+    /// Set key to hold the string value on the server owning it
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let owner = self.owner(&key);
+        self.with_client(owner, |client| client.set(key, value))
+    }
+
+    /// Remove key from the server owning it
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let owner = self.owner(&key);
+        self.with_client(owner, |client| client.remove(key))
+    }
+
+    /// Add `delta` to the integer value of key on the server owning it
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let owner = self.owner(&key);
+        self.with_client(owner, |client| client.incr(key, delta))
+    }
+
+    /// Move keys to the servers that own them, after servers were added to or removed
+    /// from the list. Every server of the list is checked for keys it does not own,
+    /// as well as the `drained` servers, which are being removed and give up all their
+    /// keys. Returns the number of keys moved.
+    ///
+    /// Keys are read in pages of `REBALANCE_PAGE`. A key is set on its owner before it
+    /// is removed from where it was, so an interrupted rebalance leaves keys duplicated
+    /// but never lost, and can be run again. A key the owner already holds was written
+    /// there since the servers changed, and its value is kept.
+    ///
+    /// Writes must stop during a rebalance: one made to the old server of a key after
+    /// the key was read from it is lost.
+    pub fn rebalance(&mut self, drained: &[SocketAddr]) -> Result<u64> {
+        let mut sources = self.servers.clone();
+        sources.extend(drained.iter().filter(|addr| !self.servers.contains(addr)));
+
+        let mut moved = 0;
+        for source in sources {
+            let mut after = None;
+            loop {
+                let pairs = self.with_client(source, |client| {
+                    client.scan_page(String::new(), after.take(), REBALANCE_PAGE)
+                })?;
+                after = pairs.last().map(|(key, _)| key.clone());
+                let last_page = pairs.len() < REBALANCE_PAGE;
+                for (key, value) in pairs {
+                    let owner = self.owner(&key);
+                    if owner == source {
+                        continue;
+                    }
+                    debug!("Moving {:?} from {} to {}", key, source, owner);
+                    if self
+                        .with_client(owner, |client| client.get(key.clone()))?
+                        .is_none()
+                    {
+                        self.with_client(owner, |client| client.set(key.clone(), value))?;
+                    }
+                    self.with_client(source, |client| client.remove(key))?;
+                    moved += 1;
+                }
+                if last_page {
+                    break;
+                }
+            }
+        }
+        info!("Rebalancing moved {} keys", moved);
+        Ok(moved)
+    }
+
+    fn with_client<T>(
+        &mut self,
+        addr: SocketAddr,
+        request: impl FnOnce(&mut KvsClient) -> Result<T>,
+    ) -> Result<T> {
+        if !self.clients.contains_key(&addr) {
+            let client = KvsClient::connect_with(addr, &self.options)?;
+            self.clients.insert(addr, client);
+        }
+        let client = self.clients.get_mut(&addr).unwrap();
+        let result = request(client);
+        if let Err(e) = &result {
+            if e.is_connection_error() {
+                self.clients.remove(&addr);
+            }
+        }
+        result
+    }
+}
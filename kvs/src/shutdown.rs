@@ -0,0 +1,58 @@
+use slog_scope::info;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+
+/// A handle stopping a running `KvsServer`.
+///
+/// Once shutdown is requested the server stops accepting connections, lets every open
+/// connection finish the request it is handling, flushes the engine and returns from
+/// `KvsServer::listen`.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    state: Arc<Mutex<ShutdownState>>,
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    requested: bool,
+    local_addr: Option<SocketAddr>,
+}
+
+impl ShutdownHandle {
+    /// Request the server to shut down. Does not wait for the shutdown to complete.
+    pub fn shutdown(&self) {
+        let local_addr = {
+            let mut state = self.state.lock().unwrap();
+            if state.requested {
+                return;
+            }
+            state.requested = true;
+            state.local_addr
+        };
+        info!("Shutdown requested");
+
+        // Wake up the listener blocked on accepting a connection.
+        if let Some(mut addr) = local_addr {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => [127, 0, 0, 1].into(),
+                    SocketAddr::V6(_) => [0, 0, 0, 0, 0, 0, 0, 1].into(),
+                });
+            }
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    /// Whether shutdown has been requested.
+    pub fn is_requested(&self) -> bool {
+        self.state.lock().unwrap().requested
+    }
+
+    /// Register the address the server listens on. Returns whether shutdown was
+    /// requested before the listener was ready.
+    pub(crate) fn listening_on(&self, addr: SocketAddr) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.local_addr = Some(addr);
+        state.requested
+    }
+}
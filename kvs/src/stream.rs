@@ -0,0 +1,146 @@
+use crate::error::Result;
+use crate::tls::{ClientTls, ServerTls};
+use rustls::{ClientConnection, Connection, ServerConnection};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+/// A connection between a client and a server, either plaintext or encrypted.
+///
+/// Clones share the connection, so that reading and writing can be done through
+/// separate handles, each on its own thread.
+pub(crate) enum Stream {
+    Plain(TcpStream),
+    Tls {
+        tcp: TcpStream,
+        session: Arc<TlsSession>,
+    },
+}
+
+/// The TLS state of a connection, shared by the handles of a `Stream`.
+///
+/// The state is only locked to move bytes in and out of it, never while waiting on the
+/// socket, so that a handle blocked reading does not keep another from writing.
+pub(crate) struct TlsSession {
+    conn: Mutex<Connection>,
+    /// Held while sending records, so that they reach the socket in order.
+    sending: Mutex<()>,
+}
+
+impl TlsSession {
+    fn new(conn: impl Into<Connection>) -> Arc<Self> {
+        Arc::new(TlsSession {
+            conn: Mutex::new(conn.into()),
+            sending: Mutex::new(()),
+        })
+    }
+
+    /// Send the records waiting in the session.
+    fn send(&self, mut tcp: &TcpStream) -> io::Result<()> {
+        let _sending = self.sending.lock().unwrap();
+        let mut records = Vec::new();
+        {
+            let mut conn = self.conn.lock().unwrap();
+            while conn.wants_write() {
+                conn.write_tls(&mut records)?;
+            }
+        }
+        tcp.write_all(&records)
+    }
+
+    fn read(&self, mut tcp: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                // Peers close connections without a TLS close_notify, which rustls
+                // reports as an error. Messages are self-delimiting, so truncation is
+                // detected by the deserializer anyway.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                result => return result,
+            }
+
+            // Wait for records without holding the session, honoring the read timeout
+            // of the socket. Once some arrived, reading them does not block.
+            tcp.peek(&mut [0])?;
+            let processed = {
+                let mut conn = self.conn.lock().unwrap();
+                conn.read_tls(&mut tcp)?;
+                conn.process_new_packets()
+            };
+            // Handshake messages and alerts may need an answer.
+            self.send(tcp)?;
+            if let Err(e) = processed {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        }
+    }
+
+    fn write(&self, tcp: &TcpStream, buf: &[u8]) -> io::Result<usize> {
+        let written = self.conn.lock().unwrap().writer().write(buf)?;
+        self.send(tcp)?;
+        Ok(written)
+    }
+}
+
+impl Stream {
+    /// Start a TLS session as a client over the given TCP stream.
+    pub(crate) fn connect_tls(tcp: TcpStream, tls: &ClientTls) -> Result<Self> {
+        let server_name = tls.server_name_for(tcp.peer_addr()?);
+        let conn = ClientConnection::new(Arc::clone(&tls.config), server_name)?;
+        Ok(Stream::Tls {
+            tcp,
+            session: TlsSession::new(conn),
+        })
+    }
+
+    /// Start a TLS session as a server over the given TCP stream.
+    pub(crate) fn accept_tls(tcp: TcpStream, tls: &ServerTls) -> Result<Self> {
+        let conn = ServerConnection::new(Arc::clone(&tls.config))?;
+        Ok(Stream::Tls {
+            tcp,
+            session: TlsSession::new(conn),
+        })
+    }
+
+    /// The underlying TCP stream.
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(tcp) | Stream::Tls { tcp, .. } => tcp,
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> Result<Self> {
+        Ok(match self {
+            Stream::Plain(tcp) => Stream::Plain(tcp.try_clone()?),
+            Stream::Tls { tcp, session } => Stream::Tls {
+                tcp: tcp.try_clone()?,
+                session: Arc::clone(session),
+            },
+        })
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.read(buf),
+            Stream::Tls { tcp, session } => session.read(tcp, buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.write(buf),
+            Stream::Tls { tcp, session } => session.write(tcp, buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(tcp) => tcp.flush(),
+            Stream::Tls { tcp, session } => session.send(tcp),
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use crate::error::{ErrorKind, Result};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// TLS settings of a `KvsServer`.
+#[derive(Clone)]
+pub struct ServerTls {
+    pub(crate) config: Arc<ServerConfig>,
+}
+
+impl ServerTls {
+    /// Serve TLS with the certificate chain and private key read from PEM files.
+    ///
+    /// If `client_ca` is given, clients must authenticate with a certificate signed by
+    /// one of the CA certificates in that PEM file.
+    pub fn from_pem_files(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Self> {
+        let provider = provider();
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()?;
+        let builder = match client_ca {
+            Some(client_ca) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(load_roots(client_ca)?),
+                    provider,
+                )
+                .build()
+                .map_err(|e| rustls::Error::General(e.to_string()))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+
+        Ok(ServerTls {
+            config: Arc::new(config),
+        })
+    }
+}
+
+/// TLS settings of a `KvsClient`.
+#[derive(Clone)]
+pub struct ClientTls {
+    pub(crate) config: Arc<ClientConfig>,
+    pub(crate) server_name: Option<ServerName<'static>>,
+}
+
+impl ClientTls {
+    /// Trust servers with certificates signed by one of the CA certificates in the
+    /// given PEM file.
+    ///
+    /// If `identity` is given, the client authenticates itself with the certificate
+    /// chain and private key read from that pair of PEM files.
+    pub fn from_pem_files(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<Self> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(load_roots(ca)?);
+        let config = match identity {
+            Some((cert, key)) => {
+                builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(ClientTls {
+            config: Arc::new(config),
+            server_name: None,
+        })
+    }
+
+    /// Name expected in the server certificate. Defaults to the IP address the client
+    /// connects to.
+    pub fn with_server_name(mut self, name: &str) -> Result<Self> {
+        let name = ServerName::try_from(name.to_owned())
+            .map_err(|e| ErrorKind::ConversionError(e.to_string()))?;
+        self.server_name = Some(name);
+        Ok(self)
+    }
+
+    pub(crate) fn server_name_for(&self, addr: SocketAddr) -> ServerName<'static> {
+        match &self.server_name {
+            Some(name) => name.clone(),
+            None => ServerName::IpAddress(addr.ip().into()),
+        }
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(path, e))?;
+    if certs.is_empty() {
+        return Err(rustls::Error::General(format!("no certificate in {}", path.display())).into());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| pem_error(path, e))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+fn pem_error(path: &Path, err: rustls::pki_types::pem::Error) -> ErrorKind {
+    rustls::Error::General(format!("{}: {}", path.display(), err)).into()
+}
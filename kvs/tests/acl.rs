@@ -0,0 +1,264 @@
+use assert_cmd::prelude::*;
+use kvs::{Access, Acl, ClientOptions, ErrorKind, KvStore, KvsClient, KvsClientPool, KvsServer};
+use predicates::str::contains;
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server(addr: &str, acl: Acl) -> (SocketAddr, TempDir) {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr)
+        .unwrap()
+        .with_acl(acl);
+    thread::spawn(move || server.listen().unwrap());
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    (addr, temp_dir)
+}
+
+fn connect(addr: SocketAddr, token: &str) -> kvs::Result<KvsClient> {
+    KvsClient::connect_with(
+        addr,
+        &ClientOptions {
+            token: Some(token.to_owned()),
+            ..ClientOptions::default()
+        },
+    )
+}
+
+fn assert_denied<T>(result: kvs::Result<T>) {
+    match result {
+        Err(ErrorKind::PermissionDenied(_)) => {}
+        Err(e) => panic!("expected permission denied, got {:?}", e),
+        Ok(_) => panic!("expected permission denied, got success"),
+    }
+}
+
+fn test_acl() -> Acl {
+    Acl::default()
+        .grant("admin", "", Access::ReadWrite)
+        .grant("team-a", "a/", Access::ReadWrite)
+        .grant("team-a", "shared/", Access::Read)
+        .grant("ingest", "shared/", Access::Write)
+}
+
+#[test]
+fn unauthenticated_requests_are_denied() {
+    let (addr, _temp_dir) = start_server("127.0.0.1:4601", test_acl());
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.ping().unwrap();
+    assert_denied(client.get("a/key".to_owned()));
+    assert_denied(client.set("a/key".to_owned(), "value".to_owned()));
+    assert_denied(client.remove("a/key".to_owned()));
+    assert_denied(client.shutdown());
+
+    assert_denied(connect(addr, "unknown"));
+}
+
+#[test]
+fn access_is_limited_to_granted_prefixes() {
+    let (addr, _temp_dir) = start_server("127.0.0.1:4602", test_acl());
+
+    let mut team_a = connect(addr, "team-a").unwrap();
+    team_a.set("a/key".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(
+        team_a.get("a/key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    team_a.remove("a/key".to_owned()).unwrap();
+    assert_denied(team_a.get("b/key".to_owned()));
+    assert_denied(team_a.set("b/key".to_owned(), "value".to_owned()));
+    assert_denied(team_a.set("shared/key".to_owned(), "value".to_owned()));
+    assert_denied(team_a.shutdown());
+    assert_denied(team_a.create_namespace("team-a".to_owned()));
+
+    let mut ingest = connect(addr, "ingest").unwrap();
+    ingest
+        .set("shared/key".to_owned(), "value".to_owned())
+        .unwrap();
+    assert_denied(ingest.get("shared/key".to_owned()));
+    // Incrementing a counter returns its value, so it takes read access as well.
+    assert_denied(ingest.incr("shared/key".to_owned(), 0));
+    assert_denied(ingest.decr("shared/key".to_owned(), 0));
+    assert_denied(team_a.incr("shared/key".to_owned(), 0));
+    assert_eq!(team_a.incr("a/counter".to_owned(), 2).unwrap(), 2);
+    assert_eq!(
+        team_a.get("shared/key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+
+    let mut admin = connect(addr, "admin").unwrap();
+    assert_eq!(
+        admin.get("shared/key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    admin.shutdown().unwrap();
+}
+
+#[test]
+fn access_is_limited_to_granted_namespaces() {
+    let acl = test_acl()
+        .grant_in_namespace("team-b", "team-b", "", Access::ReadWrite)
+        .grant_in_namespace("team-a", "team-b", "shared/", Access::Read);
+    let (addr, _temp_dir) = start_server("127.0.0.1:4606", acl);
+    let connect_to = |token: &str, namespace: &str| {
+        KvsClient::connect_with(
+            addr,
+            &ClientOptions {
+                token: Some(token.to_owned()),
+                namespace: Some(namespace.to_owned()),
+                ..ClientOptions::default()
+            },
+        )
+        .unwrap()
+    };
+
+    let mut admin = connect(addr, "admin").unwrap();
+    admin.create_namespace("team-b".to_owned()).unwrap();
+
+    // Grants on the default namespace do not carry over to named ones.
+    let mut team_a = connect_to("team-a", "team-b");
+    assert_denied(team_a.set("a/key".to_owned(), "value".to_owned()));
+    assert_denied(team_a.get("a/key".to_owned()));
+    assert_denied(connect_to("admin", "team-b").get("a/key".to_owned()));
+
+    let mut team_b = connect_to("team-b", "team-b");
+    team_b
+        .set("shared/key".to_owned(), "value".to_owned())
+        .unwrap();
+    assert_eq!(
+        team_a.get("shared/key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    assert_denied(team_a.set("shared/key".to_owned(), "value".to_owned()));
+
+    // Nor do grants on a named namespace carry over to the default one.
+    let mut team_b = connect(addr, "team-b").unwrap();
+    assert_denied(team_b.get("shared/key".to_owned()));
+    assert_denied(team_b.shutdown());
+    admin.shutdown().unwrap();
+}
+
+#[test]
+fn pool_authenticates_connections() {
+    let (addr, _temp_dir) = start_server("127.0.0.1:4603", test_acl());
+
+    let pool = KvsClientPool::new(addr, 2).with_token("team-a".to_owned());
+    pool.set("a/key".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(
+        pool.get("a/key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    assert_denied(pool.get("b/key".to_owned()));
+}
+
+#[test]
+fn server_without_acl_accepts_any_token() {
+    let addr: SocketAddr = "127.0.0.1:4604".parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr).unwrap();
+    thread::spawn(move || server.listen().unwrap());
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut client = connect(addr, "anything").unwrap();
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+}
+
+#[test]
+fn acl_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("acl.json");
+
+    fs::write(
+        &path,
+        r#"[{ "token": "t", "prefix": "p/", "access": "none" }]"#,
+    )
+    .unwrap();
+    assert!(Acl::from_file(&path).is_err());
+    assert!(Acl::from_file(temp_dir.path().join("missing.json")).is_err());
+
+    fs::write(
+        &path,
+        r#"[
+            { "token": "admin", "prefix": "", "access": "read-write" },
+            { "token": "reader", "prefix": "p/", "access": "read" },
+            { "token": "reader", "namespace": "n", "prefix": "", "access": "read" }
+        ]"#,
+    )
+    .unwrap();
+    let addr = "127.0.0.1:4605";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--acl"])
+        .arg(&path)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "p/key", "value", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Permission denied"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "p/key", "value", "--addr", addr, "--token", "admin"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "p/key", "--addr", addr, "--token", "reader"])
+        .assert()
+        .success()
+        .stdout("value\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "p/key", "--addr", addr, "--token", "reader"])
+        .assert()
+        .failure()
+        .stderr(contains("Permission denied"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "namespace",
+            "create",
+            "n",
+            "--addr",
+            addr,
+            "--token",
+            "admin",
+        ])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "q/key", "--addr", addr, "--token", "reader"])
+        .args(["--namespace", "n"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "q/key", "--addr", addr, "--token", "reader"])
+        .assert()
+        .failure()
+        .stderr(contains("Permission denied"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["shutdown", "--addr", addr, "--token", "admin"])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+}
@@ -0,0 +1,136 @@
+use kvs::{
+    ErrorKind, IndexMode, KvStore, KvStoreOptions, KvsEngine, Result, SledKvsEngine,
+    DEFAULT_BLOOM_FALSE_POSITIVE_RATE,
+};
+use std::fs;
+use tempfile::TempDir;
+
+fn check_keys(engine: &mut impl KvsEngine, present: u32, absent: u32) -> Result<()> {
+    for i in 0..present {
+        let key = format!("key{}", i);
+        assert_eq!(
+            engine.get(key.clone())?,
+            Some(format!("value{}", i)),
+            "{}",
+            key
+        );
+    }
+    for i in present..present + absent {
+        assert_eq!(engine.get(format!("key{}", i))?, None);
+    }
+    Ok(())
+}
+
+// Filters grow with the keys and are rebuilt without losing any.
+#[test]
+fn filters_never_hide_keys() -> Result<()> {
+    for index in &[IndexMode::Hash, IndexMode::Sparse] {
+        let temp_dir = TempDir::new().unwrap();
+        let options = KvStoreOptions {
+            index: *index,
+            compaction_threshold: 500,
+            ..KvStoreOptions::default()
+        };
+        let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        for i in 0..5000 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        check_keys(&mut store, 5000, 1000)?;
+        drop(store);
+
+        let mut store = KvStore::open_with(temp_dir.path(), options)?;
+        check_keys(&mut store, 5000, 1000)?;
+        store.compact()?;
+        check_keys(&mut store, 5000, 1000)?;
+        let rate = store.stats()?.bloom_false_positive_rate;
+        assert!(
+            rate > 0.0 && rate < 2.0 * DEFAULT_BLOOM_FALSE_POSITIVE_RATE,
+            "{}",
+            rate
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn absent_keys_skip_the_index_and_the_disk() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions {
+        cache_capacity: Some(1 << 20),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // Lookups the filter rules out do not even reach the cache.
+    for i in 1000..2000 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    assert!(store.stats()?.cache_misses < 50);
+
+    // Nor do removals of absent keys write to the log.
+    let log = temp_dir.path().join("data.log");
+    let log_bytes = fs::metadata(&log)?.len();
+    assert!(matches!(
+        store.remove("missing".to_owned()),
+        Err(ErrorKind::KeyNotFound)
+    ));
+    assert_eq!(fs::metadata(&log)?.len(), log_bytes);
+    Ok(())
+}
+
+#[test]
+fn lower_rates_take_more_bits() -> Result<()> {
+    let mut rates = Vec::new();
+    for rate in &[0.1, 0.001] {
+        let temp_dir = TempDir::new().unwrap();
+        let options = KvStoreOptions {
+            bloom_false_positive_rate: *rate,
+            ..KvStoreOptions::default()
+        };
+        let mut store = KvStore::open_with(temp_dir.path(), options)?;
+        for i in 0..2000 {
+            store.set(format!("key{}", i), "value".to_owned())?;
+        }
+        rates.push(store.stats()?.bloom_false_positive_rate);
+    }
+    assert!(rates[0] > rates[1] * 10.0, "{:?}", rates);
+    Ok(())
+}
+
+#[test]
+fn sled_filters_its_lookups() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+    for i in 0..2000 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // The filter is built on opening, then kept up to date by writes.
+    check_keys(&mut engine, 2000, 500)?;
+    for i in 2000..4000 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    check_keys(&mut engine, 4000, 500)?;
+    engine.remove("key0".to_owned())?;
+    assert_eq!(engine.get("key0".to_owned())?, None);
+    assert!(matches!(
+        engine.remove("key0".to_owned()),
+        Err(ErrorKind::KeyNotFound)
+    ));
+    let rate = engine.stats()?.bloom_false_positive_rate;
+    assert!(
+        rate > 0.0 && rate < 2.0 * DEFAULT_BLOOM_FALSE_POSITIVE_RATE,
+        "{}",
+        rate
+    );
+    drop(engine);
+
+    let mut engine =
+        SledKvsEngine::new(sled::open(temp_dir.path())?).with_bloom_false_positive_rate(0.001);
+    assert_eq!(
+        engine.get("key3999".to_owned())?,
+        Some("value3999".to_owned())
+    );
+    Ok(())
+}
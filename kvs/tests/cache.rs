@@ -0,0 +1,120 @@
+use assert_cmd::prelude::*;
+use kvs::{IndexMode, KvStore, KvStoreOptions, KvsClient, KvsEngine, Result};
+use std::net::TcpStream;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn cached(capacity: u64) -> KvStoreOptions {
+    KvStoreOptions {
+        cache_capacity: Some(capacity),
+        ..KvStoreOptions::default()
+    }
+}
+
+#[test]
+fn repeated_reads_hit_the_cache() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with(temp_dir.path(), cached(1024))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for _ in 0..3 {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    // Missing keys are not cached. One removed since the bloom filter was built gets
+    // past it.
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    let stats = store.stats()?;
+    assert_eq!(stats.cache_hits, 2);
+    assert_eq!(stats.cache_misses, 3);
+
+    // Scans neither fill nor count against the cache.
+    store.scan("")?;
+    assert_eq!(store.stats()?.cache_misses, 3);
+    Ok(())
+}
+
+#[test]
+fn writes_invalidate_cached_values() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with(temp_dir.path(), cached(1024))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.stats()?.cache_hits, 0);
+
+    // Values survive compactions, which move them in the log.
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.get("key2".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.stats()?.cache_hits, 1);
+    Ok(())
+}
+
+#[test]
+fn cache_holds_at_most_its_capacity() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    // Room for two keys of 4 bytes with values of 6 bytes.
+    let options = KvStoreOptions {
+        index: IndexMode::Sparse,
+        ..cached(20)
+    };
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 1..=3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    for key in &["key1", "key2", "key3", "key3", "key2", "key1"] {
+        store.get(key.to_string())?;
+    }
+    // key1 was evicted by key3, then read again.
+    let stats = store.stats()?;
+    assert_eq!(stats.cache_hits, 2);
+    assert_eq!(stats.cache_misses, 4);
+    Ok(())
+}
+
+#[test]
+fn stores_without_a_cache_count_nothing() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key1".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.cache_hits, 0);
+    assert_eq!(stats.cache_misses, 0);
+    Ok(())
+}
+
+#[test]
+fn server_reports_cache_hits() {
+    let addr = "127.0.0.1:5603";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--cache-capacity", "1048576", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut client = KvsClient::connect(addr.parse().unwrap()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    for _ in 0..3 {
+        client.get("key1".to_owned()).unwrap();
+    }
+    let stats = client.stats().unwrap().engine;
+    assert_eq!((stats.cache_hits, stats.cache_misses), (2, 1));
+    drop(client);
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
@@ -0,0 +1,135 @@
+use kvs::{KvStore, KvsClientPool, KvsServer};
+use serde_json::{json, Deserializer, Value};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server(addr: &str) -> (SocketAddr, TempDir) {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr).unwrap();
+    thread::spawn(move || server.listen().unwrap());
+
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    (addr, temp_dir)
+}
+
+// A server answering only the first `requests_per_conn` requests of every connection
+// before hanging up. Returns its address and a counter of accepted connections.
+fn flaky_server(requests_per_conn: usize) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&accepted);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let requests = Deserializer::from_reader(&stream).into_iter::<Value>();
+            for request in requests.take(requests_per_conn) {
+                let response = match request.unwrap() {
+                    Value::String(ref s) if s == "Ping" => json!("Pong"),
+                    Value::Object(ref o) if o.contains_key("Get") => json!({ "Value": "value" }),
+                    _ => json!("Success"),
+                };
+                serde_json::to_writer(&stream, &response).unwrap();
+            }
+        }
+    });
+    (addr, accepted)
+}
+
+#[test]
+fn pool_shared_between_threads() {
+    let (addr, _temp_dir) = start_server("127.0.0.1:4101");
+    let pool = Arc::new(KvsClientPool::new(addr, 2));
+
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || {
+                for j in 0..20 {
+                    let key = format!("key{}_{}", i, j);
+                    pool.set(key.clone(), format!("value{}", j)).unwrap();
+                    assert_eq!(pool.get(key).unwrap(), Some(format!("value{}", j)));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert!(pool.open_connections() <= 2);
+    assert_eq!(pool.get("missing".to_owned()).unwrap(), None);
+    assert!(pool.remove("missing".to_owned()).is_err());
+}
+
+#[test]
+fn pool_retries_idempotent_request_on_dropped_connection() {
+    let (addr, accepted) = flaky_server(1);
+    let pool = KvsClientPool::new(addr, 1)
+        .with_retries(3, Duration::from_millis(1))
+        .with_health_check_interval(Duration::from_secs(60));
+
+    assert_eq!(
+        pool.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    // The idle connection was closed by the server, the request is resent on a new one.
+    assert_eq!(
+        pool.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn pool_does_not_retry_writes_on_dropped_connection() {
+    let (addr, accepted) = flaky_server(1);
+    let pool = KvsClientPool::new(addr, 1)
+        .with_retries(3, Duration::from_millis(1))
+        .with_health_check_interval(Duration::from_secs(60));
+
+    pool.set("key".to_owned(), "value".to_owned()).unwrap();
+    let err = pool
+        .set("key".to_owned(), "value".to_owned())
+        .expect_err("write must not be retried");
+    assert!(err.is_connection_error());
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+    // The broken connection was discarded, the next write reconnects.
+    pool.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn pool_health_checks_idle_connections() {
+    let (addr, accepted) = flaky_server(1);
+    let pool = KvsClientPool::new(addr, 1)
+        .with_retries(0, Duration::from_millis(1))
+        .with_health_check_interval(Duration::from_secs(0));
+
+    pool.set("key".to_owned(), "value".to_owned()).unwrap();
+    // The ping on checkout detects the closed connection before the write is sent.
+    pool.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn pool_gives_up_when_server_is_unreachable() {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let pool = KvsClientPool::new(addr, 1).with_retries(2, Duration::from_millis(1));
+
+    assert!(pool.ping().is_err());
+    assert_eq!(pool.open_connections(), 0);
+}
@@ -0,0 +1,104 @@
+use assert_cmd::prelude::*;
+use kvs::{Compression, KvStore, KvStoreOptions, KvsEngine};
+use predicates::str::contains;
+use rand::distributions::Alphanumeric;
+use rand::prelude::*;
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn document(i: usize) -> String {
+    let items: Vec<String> = (0..50)
+        .map(|item| format!(r#"{{"id":{},"name":"item {}","tags":["a","b"]}}"#, item, i))
+        .collect();
+    format!("[{}]", items.join(","))
+}
+
+fn options(compression: Option<Compression>) -> KvStoreOptions {
+    KvStoreOptions {
+        compression,
+        compression_threshold: 100,
+        ..KvStoreOptions::default()
+    }
+}
+
+fn log_bytes(dir: &TempDir) -> u64 {
+    fs::metadata(dir.path().join("data.log")).unwrap().len()
+}
+
+#[test]
+fn compressed_values_read_back_transparently() {
+    let plain_dir = TempDir::new().unwrap();
+    let mut plain = KvStore::open_with(plain_dir.path(), options(None)).unwrap();
+    for i in 0..20 {
+        plain.set(format!("key{}", i), document(i)).unwrap();
+    }
+
+    for compression in [Compression::Lz4, Compression::Zstd].iter() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open_with(temp_dir.path(), options(Some(*compression))).unwrap();
+        for i in 0..20 {
+            store.set(format!("key{}", i), document(i)).unwrap();
+        }
+        store.set("small".to_owned(), "value".to_owned()).unwrap();
+        store.remove("key19".to_owned()).unwrap();
+        assert!(log_bytes(&temp_dir) * 4 < log_bytes(&plain_dir));
+        assert_eq!(store.get("key3".to_owned()).unwrap(), Some(document(3)));
+        assert_eq!(store.get("key19".to_owned()).unwrap(), None);
+        drop(store);
+
+        // Values stay readable whatever the compression the store is reopened with.
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        let pairs = store.scan("key").unwrap();
+        assert_eq!(pairs.len(), 19);
+        assert_eq!(pairs[0], ("key0".to_owned(), document(0)));
+        assert_eq!(
+            store.get("small".to_owned()).unwrap(),
+            Some("value".to_owned())
+        );
+        store.compact().unwrap();
+        assert_eq!(store.get("key7".to_owned()).unwrap(), Some(document(7)));
+
+        let inspection = KvStore::inspect(temp_dir.path()).unwrap();
+        for record in &inspection.records {
+            let expected = if record.key == "small" {
+                None
+            } else {
+                Some(*compression)
+            };
+            assert_eq!(record.compression, expected, "{}", record.key);
+        }
+    }
+}
+
+#[test]
+fn incompressible_values_are_written_as_is() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with(temp_dir.path(), options(Some(Compression::Zstd))).unwrap();
+    let value: String = SmallRng::from_seed([0; 16])
+        .sample_iter(&Alphanumeric)
+        .take(200)
+        .collect();
+    store.set("random".to_owned(), value.clone()).unwrap();
+    assert_eq!(store.get("random".to_owned()).unwrap(), Some(value));
+
+    let inspection = KvStore::inspect(temp_dir.path()).unwrap();
+    assert_eq!(inspection.records[0].compression, None);
+}
+
+#[test]
+fn inspect_shows_decompressed_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with(temp_dir.path(), options(Some(Compression::Lz4))).unwrap();
+    store.set("doc".to_owned(), document(1)).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .arg("dump")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("live       \"doc\" \"[{\\\"id\\\":0,"))
+        .stdout(contains("}]\" (lz4)\n"));
+}
@@ -0,0 +1,212 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvStoreOptions, KvsClient, KvsEngine, KvsServer};
+use predicates::str::contains;
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::process::{Child, Command};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn wait_for(addr: &str) {
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn stop(mut server: Child) {
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn config_file_sets_data_dir_and_addr() {
+    let addr = "127.0.0.1:5301";
+    let temp_dir = TempDir::new().unwrap();
+    let working_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    let content = r#"
+data_dir = "data"
+addr = "127.0.0.1:5301"
+engine = "sled"
+durability = "group-commit"
+threads = 4
+
+[compaction]
+threshold = 100
+
+[limits]
+idle_timeout = 30
+"#;
+    fs::write(&config, content).unwrap();
+    let server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .current_dir(&working_dir)
+        .spawn()
+        .unwrap();
+    wait_for(addr);
+    let mut client = KvsClient::connect(addr.parse().unwrap()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(client);
+    stop(server);
+
+    // Relative paths of the config file are relative to the file, not to the working
+    // directory.
+    let data_dir = temp_dir.path().join("data");
+    assert_eq!(fs::read_to_string(data_dir.join("engine")).unwrap(), "sled");
+    assert_eq!(fs::read_dir(&working_dir).unwrap().count(), 0);
+}
+
+#[test]
+fn flags_override_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(&config, "addr = \"127.0.0.1:5302\"\nengine = \"sled\"\n").unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .args(["--addr", "127.0.0.1:5303", "--engine", "kvs"])
+        .arg("--data-dir")
+        .arg(&data_dir)
+        .spawn()
+        .unwrap();
+    wait_for("127.0.0.1:5303");
+    KvsClient::connect("127.0.0.1:5303".parse().unwrap())
+        .unwrap()
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap();
+    assert!(TcpStream::connect("127.0.0.1:5302").is_err());
+    stop(server);
+    assert_eq!(fs::read_to_string(data_dir.join("engine")).unwrap(), "kvs");
+    assert!(data_dir.join("data.log").exists());
+}
+
+#[test]
+fn invalid_config_files_are_reported() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    let cases = [
+        (
+            "engine = \"rocks\"\n",
+            "invalid engine \"rocks\", expected one of: kvs, sled",
+        ),
+        ("durability = \"never\"\n", "invalid durability \"never\""),
+        ("threads = 0\n", "threads must be at least 1"),
+        (
+            "[compaction]\nthreshold = 0\n",
+            "compaction.threshold must be at least 1",
+        ),
+        (
+            "[bloom]\nfalse_positive_rate = 1.5\n",
+            "bloom.false_positive_rate must be between 0 and 1 exclusive, not 1.5",
+        ),
+        (
+            "[cache]\ncapacity = 0\n",
+            "cache.capacity must be at least 1",
+        ),
+        (
+            "[memory]\nmax_bytes = 0\n",
+            "memory.max_bytes must be at least 1",
+        ),
+        (
+            "[limits]\nmax_value_size = 0\n",
+            "limits.max_value_size must be at least 1",
+        ),
+        (
+            "[limits]\nglobal_rate = 0\n",
+            "limits.global_rate must be at least 1",
+        ),
+        (
+            "index = \"btree\"\n",
+            "invalid index \"btree\", expected one of: hash, sparse",
+        ),
+        (
+            "[compression]\nalgorithm = \"gzip\"\n",
+            "invalid compression.algorithm \"gzip\", expected one of: lz4, zstd",
+        ),
+        ("addr = \"localhost\"\n", "addr"),
+        ("port = 4000\n", "unknown field `port`"),
+        ("addr = \n", "kvs.toml"),
+    ];
+    for (content, message) in cases.iter() {
+        fs::write(&config, content).unwrap();
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .arg("--config")
+            .arg(&config)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains(*message));
+    }
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(temp_dir.path().join("missing.toml"))
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("missing.toml"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn thread_limit_queues_connections() {
+    let addr: SocketAddr = "127.0.0.1:5304".parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr)
+        .unwrap()
+        .with_threads(1);
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.listen());
+    wait_for("127.0.0.1:5304");
+
+    let mut first = KvsClient::connect(addr).unwrap();
+    first.ping().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    let second = thread::spawn(move || {
+        let mut second = KvsClient::connect(addr).unwrap();
+        second.ping().unwrap();
+        sender.send(()).unwrap();
+    });
+    // The only thread is busy with the first connection.
+    assert!(receiver.recv_timeout(Duration::from_millis(300)).is_err());
+    drop(first);
+    receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    second.join().unwrap();
+
+    handle.shutdown();
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn compaction_threshold_is_configurable() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions {
+        compaction_threshold: 10,
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with(temp_dir.path(), options).unwrap();
+    for i in 0..25 {
+        store.set("key".to_owned(), format!("value{}", i)).unwrap();
+    }
+    assert_eq!(store.stats().unwrap().compactions, 2);
+
+    let mut namespace = store.open_namespace("team-a").unwrap();
+    for i in 0..12 {
+        namespace
+            .set("key".to_owned(), format!("value{}", i))
+            .unwrap();
+    }
+    assert_eq!(namespace.stats().unwrap().compactions, 1);
+}
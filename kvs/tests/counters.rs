@@ -0,0 +1,165 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    ErrorKind, InMemoryEngine, KvStore, KvsClient, KvsEngine, KvsServer, LsmKvsEngine, Result,
+    SledKvsEngine,
+};
+use predicates::str::contains;
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn counts_on(engine: &mut impl KvsEngine) -> Result<()> {
+    assert_eq!(engine.incr("counter".to_owned(), 1)?, 1);
+    assert_eq!(engine.incr("counter".to_owned(), 41)?, 42);
+    assert_eq!(engine.incr("counter".to_owned(), -50)?, -8);
+    assert_eq!(engine.get("counter".to_owned())?, Some("-8".to_owned()));
+
+    engine.set("text".to_owned(), "value".to_owned())?;
+    assert!(matches!(
+        engine.incr("text".to_owned(), 1),
+        Err(ErrorKind::NotAnInteger(key)) if key == "text"
+    ));
+    assert_eq!(engine.get("text".to_owned())?, Some("value".to_owned()));
+
+    engine.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        engine.incr("max".to_owned(), 1),
+        Err(ErrorKind::ConversionError(_))
+    ));
+    assert_eq!(engine.get("max".to_owned())?, Some(i64::MAX.to_string()));
+    Ok(())
+}
+
+#[test]
+fn kvs_counts() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    counts_on(&mut KvStore::open(temp_dir.path())?)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.incr("counter".to_owned(), 10)?, 2);
+    Ok(())
+}
+
+#[test]
+fn lsm_counts() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    counts_on(&mut LsmKvsEngine::open(temp_dir.path())?)?;
+    let mut engine = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.incr("counter".to_owned(), 10)?, 2);
+    Ok(())
+}
+
+/// Sled releases the lock of its directory a moment after the last handle is dropped.
+fn reopen_sled(path: &Path) -> sled::Result<sled::Db> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(_)) if Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            result => return result,
+        }
+    }
+}
+
+#[test]
+fn sled_counts() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    counts_on(&mut SledKvsEngine::new(sled::open(temp_dir.path())?))?;
+    let mut engine = SledKvsEngine::new(reopen_sled(temp_dir.path())?);
+    assert_eq!(engine.incr("counter".to_owned(), 10)?, 2);
+    Ok(())
+}
+
+#[test]
+fn memory_counts() -> Result<()> {
+    counts_on(&mut InMemoryEngine::new())
+}
+
+#[test]
+fn concurrent_increments_are_not_lost() {
+    let addr: SocketAddr = "127.0.0.1:5604".parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr).unwrap();
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.listen());
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let clients: Vec<_> = (0..8)
+        .map(|_| {
+            thread::spawn(move || {
+                let mut client = KvsClient::connect(addr).unwrap();
+                for _ in 0..50 {
+                    client.incr("counter".to_owned(), 3).unwrap();
+                    client.decr("counter".to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        client.get("counter".to_owned()).unwrap(),
+        Some("800".to_owned())
+    );
+
+    client.set("text".to_owned(), "value".to_owned()).unwrap();
+    assert!(matches!(
+        client.incr("text".to_owned(), 1),
+        Err(ErrorKind::NotAnInteger(key)) if key == "text"
+    ));
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn client_cli_increments() {
+    let addr = "127.0.0.1:5605";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(["--addr", addr]);
+        command
+    };
+
+    client(&["incr", "counter"])
+        .assert()
+        .success()
+        .stdout("1\n");
+    client(&["incr", "counter", "9"])
+        .assert()
+        .success()
+        .stdout("10\n");
+    client(&["decr", "counter", "-5"])
+        .assert()
+        .success()
+        .stdout("15\n");
+    client(&["decr", "counter"])
+        .assert()
+        .success()
+        .stdout("14\n");
+    client(&["set", "text", "value"]).assert().success();
+    client(&["incr", "text"])
+        .assert()
+        .failure()
+        .stderr(contains("is not an integer"));
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
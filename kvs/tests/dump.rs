@@ -0,0 +1,190 @@
+use assert_cmd::prelude::*;
+use kvs::{DumpFormat, KvStore, KvsClient, KvsEngine, KvsServer, Result, ShutdownHandle};
+use predicates::str::contains;
+use std::net::{SocketAddr, TcpStream};
+use std::process::Command;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+struct RunningServer {
+    handle: ShutdownHandle,
+    thread: JoinHandle<Result<()>>,
+    _dir: TempDir,
+}
+
+impl RunningServer {
+    fn start(addr: SocketAddr) -> Self {
+        let dir = TempDir::new().unwrap();
+        let mut server = KvsServer::new(KvStore::open(dir.path()).unwrap(), addr).unwrap();
+        let handle = server.shutdown_handle();
+        let thread = thread::spawn(move || server.listen());
+        while TcpStream::connect(addr).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        RunningServer {
+            handle,
+            thread,
+            _dir: dir,
+        }
+    }
+
+    fn stop(self) {
+        self.handle.shutdown();
+        self.thread.join().unwrap().unwrap();
+    }
+}
+
+/// Pairs with values that need escaping in both formats.
+fn tricky_pairs() -> Vec<(String, String)> {
+    vec![
+        ("comma".to_owned(), "a,b".to_owned()),
+        ("empty".to_owned(), String::new()),
+        ("multiline".to_owned(), "line 1\nline 2".to_owned()),
+        ("quotes".to_owned(), "say \"hi\"".to_owned()),
+        ("unicode".to_owned(), "héllo wörld ✓".to_owned()),
+    ]
+}
+
+#[test]
+fn write_and_read_pairs_round_trip() {
+    for format in [DumpFormat::Jsonl, DumpFormat::Csv].iter() {
+        let mut dump = Vec::new();
+        assert_eq!(
+            kvs::write_pairs(*format, tricky_pairs(), &mut dump).unwrap(),
+            5
+        );
+
+        let mut batches = Vec::new();
+        let read = kvs::read_batches(*format, dump.as_slice(), 2, |batch| {
+            batches.push(batch);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(read, 5);
+        assert_eq!(
+            batches.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+        assert_eq!(batches.concat(), tricky_pairs());
+    }
+}
+
+#[test]
+fn malformed_input_stops_after_applying_previous_pairs() {
+    let input =
+        "{\"key\":\"a\",\"value\":\"1\"}\n\n{\"key\":\"b\"}\n{\"key\":\"c\",\"value\":\"3\"}\n";
+    let mut applied = Vec::new();
+    let err = kvs::read_batches(DumpFormat::Jsonl, input.as_bytes(), 10, |batch| {
+        applied.extend(batch);
+        Ok(())
+    })
+    .unwrap_err();
+    assert!(err.to_string().contains("line 3"), "{}", err);
+    assert_eq!(applied, vec![("a".to_owned(), "1".to_owned())]);
+
+    let input = "key,value\na,1\nb\n";
+    let err = kvs::read_batches(DumpFormat::Csv, input.as_bytes(), 10, |_| Ok(())).unwrap_err();
+    assert!(err.to_string().contains("line: 3"), "{}", err);
+}
+
+#[test]
+fn cli_export_and_import_local_store() {
+    let source = TempDir::new().unwrap();
+    let mut store = KvStore::open(source.path()).unwrap();
+    for (key, value) in tricky_pairs() {
+        store.set(key, value).unwrap();
+    }
+    store.set("removed".to_owned(), "value".to_owned()).unwrap();
+    store.remove("removed".to_owned()).unwrap();
+    drop(store);
+
+    for format in ["jsonl", "csv"].iter() {
+        let dump = source.path().join(format!("dump.{}", format));
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["export", "--format", format, "--output"])
+            .arg(&dump)
+            .current_dir(&source)
+            .assert()
+            .success()
+            .stdout("");
+
+        let target = TempDir::new().unwrap();
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["import", "--format", format, "--batch-size", "2", "--input"])
+            .arg(&dump)
+            .current_dir(&target)
+            .assert()
+            .success()
+            .stdout("Imported 5 keys\n");
+        let mut store = KvStore::open(target.path()).unwrap();
+        assert_eq!(store.scan("").unwrap(), tricky_pairs());
+    }
+
+    // Export writes to the standard output by default.
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export"])
+        .current_dir(&source)
+        .assert()
+        .success()
+        .stdout(contains("{\"key\":\"comma\",\"value\":\"a,b\"}\n"));
+}
+
+#[test]
+fn cli_export_and_import_over_the_wire() {
+    let source_addr: SocketAddr = "127.0.0.1:5501".parse().unwrap();
+    let target_addr: SocketAddr = "127.0.0.1:5502".parse().unwrap();
+    let source = RunningServer::start(source_addr);
+    let target = RunningServer::start(target_addr);
+    let mut client = KvsClient::connect(source_addr).unwrap();
+    let pairs: Vec<(String, String)> = (0..2500)
+        .map(|i| (format!("key{:04}", i), format!("value {}", i)))
+        .collect();
+    client.set_batch(pairs.clone()).unwrap();
+
+    let temp_dir = TempDir::new().unwrap();
+    let dump = temp_dir.path().join("dump.csv");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "export",
+            "--format",
+            "csv",
+            "--addr",
+            &source_addr.to_string(),
+        ])
+        .arg("--output")
+        .arg(&dump)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "import",
+            "--format",
+            "csv",
+            "--addr",
+            &target_addr.to_string(),
+        ])
+        .arg("--input")
+        .arg(&dump)
+        .assert()
+        .success()
+        .stdout("Imported 2500 keys\n");
+
+    let mut target_client = KvsClient::connect(target_addr).unwrap();
+    assert_eq!(target_client.scan(String::new()).unwrap(), pairs);
+    let stats = target_client.stats().unwrap();
+    let batches = stats
+        .requests
+        .iter()
+        .find(|request| request.kind == "set_batch")
+        .unwrap();
+    assert_eq!(batches.latency.count(), 3);
+
+    source.stop();
+    target.stop();
+}
@@ -0,0 +1,180 @@
+use assert_cmd::prelude::*;
+use kvs::{Durability, Engine, EngineStats, KvStore, KvsClient, KvsEngine, KvsServer, Result};
+use std::net::{SocketAddr, TcpStream};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Wraps a `KvStore`, counting durable flushes. Flushes are slowed down so that
+// concurrent writes pile up behind them.
+struct CountingEngine {
+    store: KvStore,
+    flushes: Arc<AtomicUsize>,
+}
+
+impl KvsEngine for CountingEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.store.set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.store.remove(key)
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.store.scan(prefix)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        thread::sleep(Duration::from_millis(5));
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+        self.store.flush()
+    }
+
+    fn set_durability(&mut self, durability: Durability) {
+        self.store.set_durability(durability)
+    }
+
+    fn open_namespace(&mut self, name: &str) -> Result<Self> {
+        Ok(CountingEngine {
+            store: self.store.open_namespace(name)?,
+            flushes: Arc::clone(&self.flushes),
+        })
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        self.store.drop_namespace(name)
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        self.store.namespaces()
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.store.stats()
+    }
+
+    fn as_type(&self) -> Engine {
+        self.store.as_type()
+    }
+}
+
+// Runs concurrent writers against a server with the given durability. Returns the
+// number of flushes made while serving them.
+fn concurrent_writes(addr: &str, durability: Durability) -> usize {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let flushes = Arc::new(AtomicUsize::new(0));
+    let engine = CountingEngine {
+        store: KvStore::open(temp_dir.path()).unwrap(),
+        flushes: Arc::clone(&flushes),
+    };
+    let mut server = KvsServer::new(engine, addr)
+        .unwrap()
+        .with_durability(durability);
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.listen());
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let writers: Vec<_> = (0..8)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut client = KvsClient::connect(addr).unwrap();
+                for j in 0..25 {
+                    client
+                        .set(format!("key{}_{}", i, j), format!("value{}", j))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    let flushes_while_serving = flushes.load(Ordering::SeqCst);
+
+    handle.shutdown();
+    server.join().unwrap().unwrap();
+
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..8 {
+        for j in 0..25 {
+            assert_eq!(
+                store.get(format!("key{}_{}", i, j)).unwrap(),
+                Some(format!("value{}", j))
+            );
+        }
+    }
+    flushes_while_serving
+}
+
+#[test]
+fn group_commit_batches_flushes() {
+    let flushes = concurrent_writes("127.0.0.1:4401", Durability::GroupCommit);
+    assert!(flushes > 0);
+    assert!(
+        flushes < 8 * 25,
+        "{} flushes for {} writes",
+        flushes,
+        8 * 25
+    );
+}
+
+#[test]
+fn buffered_writes_are_not_flushed() {
+    assert_eq!(concurrent_writes("127.0.0.1:4402", Durability::Buffered), 0);
+}
+
+#[test]
+fn fsync_writes_do_not_rely_on_flush() {
+    assert_eq!(concurrent_writes("127.0.0.1:4403", Durability::Fsync), 0);
+}
+
+#[test]
+fn cli_durability_values() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--durability", "sometimes"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    for (durability, addr) in [
+        ("fsync", "127.0.0.1:4404"),
+        ("group-commit", "127.0.0.1:4405"),
+        ("buffered", "127.0.0.1:4406"),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--durability", durability, "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        while TcpStream::connect(addr).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key", "value", "--addr", addr])
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["shutdown", "--addr", addr])
+            .assert()
+            .success();
+        assert!(child.wait().unwrap().success());
+    }
+}
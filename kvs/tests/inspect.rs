@@ -0,0 +1,167 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine, RecordState};
+use predicates::str::contains;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::process::Command;
+use tempfile::TempDir;
+
+/// A store whose log holds a live, a superseded and a removed key.
+fn store_with_history() -> TempDir {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "old".to_owned()).unwrap();
+    store.set("key1".to_owned(), "new".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    store.remove("key2".to_owned()).unwrap();
+    drop(store);
+    temp_dir
+}
+
+fn append(dir: &TempDir, bytes: &[u8]) {
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(dir.path().join("data.log"))
+        .unwrap();
+    log.write_all(bytes).unwrap();
+}
+
+#[test]
+fn inspect_tells_live_records_from_superseded_ones() {
+    let temp_dir = store_with_history();
+    let inspection = KvStore::inspect(temp_dir.path()).unwrap();
+    let states: Vec<(&str, RecordState)> = inspection
+        .records
+        .iter()
+        .map(|record| (record.key.as_str(), record.state))
+        .collect();
+    assert_eq!(
+        states,
+        vec![
+            ("key1", RecordState::Superseded),
+            ("key1", RecordState::Live),
+            ("key2", RecordState::Superseded),
+            ("key2", RecordState::Tombstone),
+        ]
+    );
+    assert!(inspection.corrupt.is_empty());
+    assert_eq!(inspection.records[0].offset, 0);
+    assert_eq!(inspection.records[1].offset, inspection.records[0].length);
+    let live = &inspection.records[1];
+    assert_eq!(inspection.live_bytes(), live.length);
+    assert_eq!(
+        inspection.file_bytes,
+        inspection
+            .records
+            .iter()
+            .map(|record| record.length)
+            .sum::<u64>()
+    );
+    assert!(inspection.space_amplification() > 1.0);
+
+    // The store agrees on what is live.
+    let stats = KvStore::open(temp_dir.path()).unwrap().stats().unwrap();
+    assert_eq!(stats.live_bytes, inspection.live_bytes());
+}
+
+#[test]
+fn repair_drops_corrupt_bytes_and_keeps_later_records() {
+    let temp_dir = store_with_history();
+    append(&temp_dir, b"{\"key\":\"broken\",\"va");
+    append(&temp_dir, b"garbage{\"key\":\"key3\",\"value\":\"value3\"}");
+    append(&temp_dir, b"{\"key\":\"trunc");
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let inspection = KvStore::inspect(temp_dir.path()).unwrap();
+    assert_eq!(inspection.records.len(), 5);
+    assert_eq!(inspection.corrupt.len(), 2);
+    assert_eq!(inspection.corrupt_bytes(), 26 + 13);
+
+    let repaired = KvStore::repair(temp_dir.path()).unwrap();
+    assert_eq!(repaired.corrupt.len(), 2);
+    let inspection = KvStore::inspect(temp_dir.path()).unwrap();
+    assert!(inspection.corrupt.is_empty());
+    assert_eq!(inspection.records.len(), 5);
+
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("new".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).unwrap(), None);
+    assert_eq!(
+        store.get("key3".to_owned()).unwrap(),
+        Some("value3".to_owned())
+    );
+}
+
+#[test]
+fn cli_dump_repair_and_compact() {
+    let temp_dir = store_with_history();
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .arg("dump")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(
+            "         0       28 superseded \"key1\" \"old\"\n",
+        ))
+        .stdout(contains(
+            "        28       28 live       \"key1\" \"new\"\n",
+        ))
+        .stdout(contains("tombstone  \"key2\"\n"))
+        .stdout(contains(
+            "records 4 live 1 superseded 2 tombstones 1 corrupt_ranges 0\n",
+        ))
+        .stdout(contains("space_amplification 4.46\n"));
+
+    append(&temp_dir, b"{\"key\":");
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .args(["dump", "--summary", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("corrupt_ranges 1\n"))
+        .stdout(contains("corrupt_bytes 7 "));
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .arg("repair")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Dropped 7 bytes at offset 125"))
+        .stdout(contains("Kept 4 records, dropped 7 corrupt bytes\n"));
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .arg("repair")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Nothing to repair\n");
+
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .arg("compact")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Compacted 125 bytes into 28\n");
+    let inspection = KvStore::inspect(temp_dir.path()).unwrap();
+    assert_eq!(inspection.records.len(), 1);
+    assert_eq!(inspection.space_amplification(), 1.0);
+}
+
+#[test]
+fn cli_requires_a_log() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .arg("compact")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("no KvStore log at"));
+    assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+}
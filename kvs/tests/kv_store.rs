@@ -1,12 +1,54 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvStore, KvsEngine, LsmKvsEngine, Result};
+use std::path::Path;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+/// Run each test below against every engine built in this crate, opened by `$open`.
+macro_rules! engine_tests {
+    ($engine:ident, $open:expr) => {
+        mod $engine {
+            use super::*;
+
+            #[test]
+            fn get_stored_value() -> Result<()> {
+                super::get_stored_value($open)
+            }
+
+            #[test]
+            fn overwrite_value() -> Result<()> {
+                super::overwrite_value($open)
+            }
+
+            #[test]
+            fn get_non_existent_value() -> Result<()> {
+                super::get_non_existent_value($open)
+            }
+
+            #[test]
+            fn remove_non_existent_key() -> Result<()> {
+                super::remove_non_existent_key($open)
+            }
+
+            #[test]
+            fn remove_key() -> Result<()> {
+                super::remove_key($open)
+            }
+
+            #[test]
+            fn compaction() -> Result<()> {
+                super::compaction($open)
+            }
+        }
+    };
+}
+
+engine_tests!(kv_store, |path: &Path| KvStore::open(path));
+engine_tests!(lsm_engine, |path: &Path| LsmKvsEngine::open(path));
+
 // Should get previously stored value
-#[test]
-fn get_stored_value() -> Result<()> {
+fn get_stored_value<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +58,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -24,10 +66,9 @@ fn get_stored_value() -> Result<()> {
 }
 
 // Should overwrite existent value
-#[test]
-fn overwrite_value() -> Result<()> {
+fn overwrite_value<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +77,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -45,34 +86,31 @@ fn overwrite_value() -> Result<()> {
 }
 
 // Should get `None` when getting a non-existent key
-#[test]
-fn get_non_existent_value() -> Result<()> {
+fn get_non_existent_value<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
 }
 
-#[test]
-fn remove_non_existent_key() -> Result<()> {
+fn remove_non_existent_key<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
 
-#[test]
-fn remove_key() -> Result<()> {
+fn remove_key<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -81,10 +119,9 @@ fn remove_key() -> Result<()> {
 
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
-#[test]
-fn compaction() -> Result<()> {
+fn compaction<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +151,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let mut store = open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
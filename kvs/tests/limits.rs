@@ -0,0 +1,313 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    ClientOptions, ErrorKind, KvStore, KvsClient, KvsServer, RateLimit, Result, ServerLimits,
+    ShutdownHandle,
+};
+use predicates::str::contains;
+use std::net::{SocketAddr, TcpStream};
+use std::process::Command;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn wait_for(addr: SocketAddr) {
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+struct RunningServer {
+    handle: ShutdownHandle,
+    thread: JoinHandle<Result<()>>,
+    _dir: TempDir,
+}
+
+impl RunningServer {
+    fn start(addr: SocketAddr, limits: ServerLimits) -> Self {
+        let dir = TempDir::new().unwrap();
+        let mut server = KvsServer::new(KvStore::open(dir.path()).unwrap(), addr)
+            .unwrap()
+            .with_limits(limits);
+        let handle = server.shutdown_handle();
+        let thread = thread::spawn(move || server.listen());
+        wait_for(addr);
+        RunningServer {
+            handle,
+            thread,
+            _dir: dir,
+        }
+    }
+
+    fn stop(self) {
+        self.handle.shutdown();
+        self.thread.join().unwrap().unwrap();
+    }
+}
+
+/// Connect with a client that fails at once when the server is overloaded.
+fn impatient_client(addr: SocketAddr) -> KvsClient {
+    let options = ClientOptions {
+        overload_retries: 0,
+        ..ClientOptions::default()
+    };
+    KvsClient::connect_with(addr, &options).unwrap()
+}
+
+fn overloaded<T: std::fmt::Debug>(result: kvs::Result<T>) -> Duration {
+    match result {
+        Err(ErrorKind::Overloaded(retry_after)) => retry_after,
+        result => panic!("expected an overloaded error, got {:?}", result),
+    }
+}
+
+fn too_large<T: std::fmt::Debug>(result: kvs::Result<T>, expected: &str) {
+    match result {
+        Err(ErrorKind::TooLarge(what)) => assert!(what.contains(expected), "{}", what),
+        result => panic!("expected a too large error, got {:?}", result),
+    }
+}
+
+#[test]
+fn oversized_requests_are_refused() {
+    let addr: SocketAddr = "127.0.0.1:5606".parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let limits = ServerLimits {
+        max_key: Some(8),
+        max_value: Some(16),
+        max_request: Some(1024),
+        ..ServerLimits::default()
+    };
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr)
+        .unwrap()
+        .with_limits(limits);
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.listen());
+    wait_for(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key".to_owned(), "v".repeat(16)).unwrap();
+    too_large(
+        client.set("k".repeat(9), "value".to_owned()),
+        "key of 9 bytes, the limit is 8 bytes",
+    );
+    too_large(
+        client.set("key".to_owned(), "v".repeat(17)),
+        "value of 17 bytes, the limit is 16 bytes",
+    );
+    too_large(
+        client.set_batch(vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "v".repeat(17)),
+        ]),
+        "value of 17 bytes",
+    );
+    too_large(client.get("k".repeat(9)), "key of 9 bytes");
+    too_large(client.scan("k".repeat(9)), "prefix of 9 bytes");
+    too_large(client.incr("k".repeat(9), 1), "key of 9 bytes");
+
+    // Refused writes leave the store as it was, and the connection usable.
+    assert_eq!(client.get("key".to_owned()).unwrap(), Some("v".repeat(16)));
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+
+    // A request over the limit closes the connection once the client is told, even
+    // when the client is still sending it.
+    too_large(
+        client.set("key".to_owned(), "v".repeat(4 * 1024 * 1024)),
+        "request over the limit of 1024 bytes",
+    );
+    assert!(client.ping().unwrap_err().is_connection_error());
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("key".to_owned()).unwrap(), Some("v".repeat(16)));
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn cli_limits() {
+    let addr = "127.0.0.1:5607";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "--max-key-size",
+            "4",
+            "--max-value-size",
+            "8",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    wait_for(addr.parse().unwrap());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", "12345678", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", "123456789", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains(
+            "Too large: value of 9 bytes, the limit is 8 bytes",
+        ));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key12", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Too large: key of 5 bytes, the limit is 4 bytes"));
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn requests_over_the_connection_rate_are_retried() {
+    let addr: SocketAddr = "127.0.0.1:5608".parse().unwrap();
+    let limits = ServerLimits {
+        connection_rate: Some(RateLimit {
+            per_second: 20.0,
+            burst: 2,
+        }),
+        ..ServerLimits::default()
+    };
+    let server = RunningServer::start(addr, limits);
+
+    let mut client = impatient_client(addr);
+    client.ping().unwrap();
+    client.ping().unwrap();
+    let retry_after = overloaded(client.ping());
+    assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_millis(50));
+
+    // Each connection has a bucket of its own.
+    let mut other = impatient_client(addr);
+    other.ping().unwrap();
+    other.ping().unwrap();
+
+    // A patient client waits for tokens instead of failing.
+    let mut client = KvsClient::connect(addr).unwrap();
+    let started = Instant::now();
+    for i in 0..6 {
+        client.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    assert!(started.elapsed() >= Duration::from_millis(150));
+    assert_eq!(
+        client.get("key5".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    server.stop();
+}
+
+#[test]
+fn requests_over_the_global_rate_are_refused() {
+    let addr: SocketAddr = "127.0.0.1:5609".parse().unwrap();
+    let limits = ServerLimits {
+        global_rate: Some(RateLimit {
+            per_second: 1.0,
+            burst: 3,
+        }),
+        ..ServerLimits::default()
+    };
+    let server = RunningServer::start(addr, limits);
+
+    let mut first = impatient_client(addr);
+    let mut second = impatient_client(addr);
+    first.ping().unwrap();
+    first.ping().unwrap();
+    second.ping().unwrap();
+    assert!(overloaded(second.ping()) > Duration::from_millis(500));
+    overloaded(first.ping());
+    server.stop();
+}
+
+// A request refused by the server leaves the tokens of its connection alone.
+#[test]
+fn requests_refused_globally_keep_connection_tokens() {
+    let addr: SocketAddr = "127.0.0.1:5612".parse().unwrap();
+    let limits = ServerLimits {
+        connection_rate: Some(RateLimit {
+            per_second: 0.01,
+            burst: 2,
+        }),
+        global_rate: Some(RateLimit {
+            per_second: 10.0,
+            burst: 1,
+        }),
+        ..ServerLimits::default()
+    };
+    let server = RunningServer::start(addr, limits);
+
+    let mut client = impatient_client(addr);
+    client.ping().unwrap();
+    overloaded(client.ping());
+    thread::sleep(Duration::from_millis(150));
+    client.ping().unwrap();
+    server.stop();
+}
+
+#[test]
+fn connections_over_the_limit_are_refused() {
+    let addr: SocketAddr = "127.0.0.1:5610".parse().unwrap();
+    let limits = ServerLimits {
+        max_connections: Some(1),
+        ..ServerLimits::default()
+    };
+    let server = RunningServer::start(addr, limits);
+
+    let mut first = KvsClient::connect(addr).unwrap();
+    first.set("key".to_owned(), "value".to_owned()).unwrap();
+    overloaded(impatient_client(addr).ping());
+
+    // A patient client connects again once the first connection is closed.
+    let closing = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        drop(first);
+    });
+    let mut second = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        second.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    closing.join().unwrap();
+    drop(second);
+    server.stop();
+}
+
+#[test]
+fn cli_rate_limits() {
+    let addr = "127.0.0.1:5611";
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--global-burst", "5"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("a global burst needs a global rate"));
+
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--connection-rate", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    wait_for(addr.parse().unwrap());
+    let mut client = impatient_client(addr.parse().unwrap());
+    client.ping().unwrap();
+    overloaded(client.ping());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--overload-retries", "0", "--addr", addr])
+        .assert()
+        .success();
+    drop(client);
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
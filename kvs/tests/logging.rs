@@ -0,0 +1,140 @@
+use assert_cmd::prelude::*;
+use kvs::KvsClient;
+use serde_json::Value;
+use std::fs::{self, File};
+use std::net::TcpStream;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Records of a JSON log file, one per line.
+fn records(path: &std::path::Path) -> Vec<Value> {
+    fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("log line is not JSON"))
+        .collect()
+}
+
+#[test]
+fn json_log_file_tags_requests_with_peer_and_id() {
+    let addr = "127.0.0.1:5201";
+    let temp_dir = TempDir::new().unwrap();
+    let log_path = temp_dir.path().join("server.log");
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "--log-level",
+            "debug",
+            "--log-format",
+            "json",
+        ])
+        .arg("--log-file")
+        .arg(&log_path)
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut client = KvsClient::connect(addr.parse().unwrap()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.get("key1".to_owned()).unwrap();
+    drop(client);
+    thread::sleep(Duration::from_millis(200));
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    let records = records(&log_path);
+    assert!(records.iter().any(|record| record["msg"]
+        .as_str()
+        .unwrap()
+        .contains(env!("CARGO_PKG_VERSION"))));
+    assert!(records.iter().all(|record| record["level"].is_string()));
+    let requests: Vec<&Value> = records
+        .iter()
+        .filter(|record| record["msg"].as_str().unwrap().starts_with("Received"))
+        .collect();
+    assert_eq!(requests.len(), 2);
+    for request in &requests {
+        assert!(request["peer"].as_str().unwrap().starts_with("127.0.0.1:"));
+        assert!(request["request_id"].is_u64());
+    }
+    assert_ne!(requests[0]["request_id"], requests[1]["request_id"]);
+    // Nothing goes to the standard error when logging to a file.
+    assert_eq!(fs::read_to_string(&stderr_path).unwrap(), "");
+}
+
+#[test]
+fn log_level_filters_records() {
+    let addr = "127.0.0.1:5202";
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--log-level", "error"])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    KvsClient::connect(addr.parse().unwrap())
+        .unwrap()
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap();
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    let content = fs::read_to_string(&stderr_path).unwrap();
+    assert!(!content.contains(env!("CARGO_PKG_VERSION")), "{}", content);
+}
+
+#[test]
+fn kvs_logs_to_stderr() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--log-level", "trace"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--log-format", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+#[test]
+fn invalid_log_options_are_rejected() {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ping", "--log-format", "xml"])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--log-level", "loud"])
+        .assert()
+        .failure();
+
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--log-file"])
+        .arg(temp_dir.path().join("missing").join("kvs.log"))
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
@@ -0,0 +1,242 @@
+use assert_cmd::prelude::*;
+use kvs::{ErrorKind, KvsClient, KvsEngine, LsmKvsEngine, LsmOptions, Result};
+use rand::prelude::*;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::TcpStream;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Options small enough for a few hundred writes to fill several levels.
+fn small() -> LsmOptions {
+    LsmOptions {
+        memtable_bytes: 2000,
+        level0_runs: 2,
+        level1_bytes: 4000,
+        level_ratio: 2,
+        ..LsmOptions::default()
+    }
+}
+
+fn assert_same(engine: &mut LsmKvsEngine, model: &BTreeMap<String, String>) -> Result<()> {
+    let expected: Vec<(String, String)> = model
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    assert_eq!(engine.scan("")?, expected);
+    let in_prefix: Vec<(String, String)> = expected
+        .iter()
+        .filter(|(key, _)| key.starts_with("key1"))
+        .cloned()
+        .collect();
+    assert_eq!(engine.scan("key1")?, in_prefix);
+    for key_id in 0..300 {
+        let key = format!("key{:03}", key_id);
+        assert_eq!(
+            engine.get(key.clone())?,
+            model.get(&key).cloned(),
+            "{}",
+            key
+        );
+    }
+    assert_eq!(engine.stats()?.keys, model.len() as u64);
+    Ok(())
+}
+
+// Random writes give the same keys as a map, across flushes, merges and reopening.
+#[test]
+fn lsm_matches_a_map() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = LsmKvsEngine::open_with(temp_dir.path(), small())?;
+    let mut model = BTreeMap::new();
+    let mut rng = SmallRng::from_seed([3; 16]);
+
+    for round in 0..10 {
+        for _ in 0..300 {
+            let key = format!("key{:03}", rng.gen_range(0, 300));
+            if rng.gen_range(0, 4) == 0 {
+                let removed = engine.remove(key.clone());
+                match model.remove(&key) {
+                    Some(_) => removed?,
+                    None => assert!(matches!(removed, Err(ErrorKind::KeyNotFound))),
+                }
+            } else {
+                let value = format!("{}-{}", round, "x".repeat(rng.gen_range(0, 20)));
+                engine.set(key.clone(), value.clone())?;
+                model.insert(key, value);
+            }
+        }
+        assert_same(&mut engine, &model)?;
+        drop(engine);
+        engine = LsmKvsEngine::open_with(temp_dir.path(), small())?;
+        assert_same(&mut engine, &model)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn runs_are_merged_into_levels() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = LsmKvsEngine::open_with(temp_dir.path(), small())?;
+    for i in 0..2000 {
+        engine.set(format!("key{}", i % 500), format!("value{}", i))?;
+    }
+    let stats = engine.stats()?;
+    assert!(stats.compactions > 0);
+    assert_eq!(stats.keys, 500);
+    assert_eq!(stats.disk_bytes, stats.live_bytes + stats.stale_bytes);
+
+    // Level 0 never holds as many runs as it may, and each deeper level holds one.
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(temp_dir.path().join("MANIFEST"))?)?;
+    assert!(manifest["level0"].as_array().unwrap().len() < 2);
+    let levels = manifest["levels"].as_array().unwrap();
+    assert!(levels.len() >= 2, "{}", manifest);
+    let runs = fs::read_dir(temp_dir.path().join("runs"))?.count();
+    let listed = manifest["level0"].as_array().unwrap().len()
+        + levels.iter().filter(|run| !run.is_null()).count();
+    assert_eq!(runs, listed);
+
+    assert_eq!(
+        engine.get("key499".to_owned())?,
+        Some("value1999".to_owned())
+    );
+    assert_eq!(engine.get("key500".to_owned())?, None);
+
+    // Live bytes kept as writes happen match those counted on opening.
+    drop(engine);
+    let engine = LsmKvsEngine::open_with(temp_dir.path(), small())?;
+    let reopened = engine.stats()?;
+    assert_eq!(reopened.keys, stats.keys);
+    assert_eq!(reopened.live_bytes, stats.live_bytes);
+    Ok(())
+}
+
+#[test]
+fn unlisted_runs_are_removed_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = LsmKvsEngine::open_with(temp_dir.path(), small())?;
+    for i in 0..100 {
+        engine.set(format!("key{}", i), "value".to_owned())?;
+    }
+    drop(engine);
+
+    // Left by a merge the process did not finish.
+    let stray = temp_dir.path().join("runs").join("99999999.run--new");
+    fs::write(&stray, "{\"key\":\"key1\",\"value\":\"stray\"}")?;
+    let mut engine = LsmKvsEngine::open_with(temp_dir.path(), small())?;
+    assert!(!stray.exists());
+    assert_eq!(engine.get("key1".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A crash while appending to the write-ahead log leaves a partial entry, which is cut off.
+#[test]
+fn torn_wal_tail_is_cut_off_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = LsmKvsEngine::open_with(temp_dir.path(), small())?;
+    for i in 0..100 {
+        engine.set(format!("key{}", i % 30), format!("value{}", i))?;
+    }
+    engine.remove("key3".to_owned())?;
+    let stats = engine.stats()?;
+    drop(engine);
+
+    let wal = temp_dir.path().join("wal.log");
+    let length = fs::metadata(&wal)?.len();
+    assert!(length > 0);
+    let mut torn = fs::read(&wal)?;
+    torn.extend_from_slice(b"{\"key\":\"key1\",\"val");
+    fs::write(&wal, torn)?;
+
+    let mut engine = LsmKvsEngine::open_with(temp_dir.path(), small())?;
+    assert_eq!(fs::metadata(&wal)?.len(), length);
+    assert_eq!(engine.get("key9".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    let reopened = engine.stats()?;
+    assert_eq!(reopened.keys, 29);
+    assert_eq!(reopened.keys, stats.keys);
+    assert_eq!(reopened.live_bytes, stats.live_bytes);
+
+    engine.set("key1".to_owned(), "after".to_owned())?;
+    drop(engine);
+    let mut engine = LsmKvsEngine::open_with(temp_dir.path(), small())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("after".to_owned()));
+    Ok(())
+}
+
+// An entry that cannot be read before the end of the log is corruption, not a torn
+// tail, and the entries after it are kept for inspection.
+#[test]
+fn corrupt_wal_entry_fails_open() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = LsmKvsEngine::open_with(temp_dir.path(), small())?;
+    for i in 0..10 {
+        engine.set(format!("key{}", i), "value".to_owned())?;
+    }
+    drop(engine);
+
+    let wal = temp_dir.path().join("wal.log");
+    let mut corrupt = fs::read(&wal)?;
+    let length = corrupt.len() as u64;
+    let entry = String::from_utf8_lossy(&corrupt)
+        .find("{\"key\":\"key4\"")
+        .unwrap();
+    corrupt[entry] = b'x';
+    fs::write(&wal, corrupt)?;
+
+    assert!(LsmKvsEngine::open_with(temp_dir.path(), small()).is_err());
+    assert_eq!(fs::metadata(&wal)?.len(), length);
+    Ok(())
+}
+
+#[test]
+fn namespaces_hold_their_own_keys() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = LsmKvsEngine::open(temp_dir.path())?;
+    engine.set("key".to_owned(), "default".to_owned())?;
+    let mut namespace = engine.open_namespace("team-a")?;
+    namespace.set("key".to_owned(), "team-a".to_owned())?;
+    assert_eq!(engine.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(namespace.get("key".to_owned())?, Some("team-a".to_owned()));
+    assert_eq!(engine.namespaces()?, vec!["team-a".to_owned()]);
+    engine.drop_namespace("team-a")?;
+    assert!(engine.namespaces()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn server_runs_on_lsm_engine() {
+    let addr = "127.0.0.1:5601";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "lsm", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut client = KvsClient::connect(addr.parse().unwrap()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    drop(client);
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("engine")).unwrap(),
+        "lsm"
+    );
+    let mut engine = LsmKvsEngine::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        engine.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
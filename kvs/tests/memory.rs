@@ -0,0 +1,127 @@
+use assert_cmd::prelude::*;
+use kvs::{ErrorKind, InMemoryEngine, KvsClient, KvsEngine, Result};
+use std::net::TcpStream;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn get_set_remove_and_scan() -> Result<()> {
+    let mut engine = InMemoryEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("other".to_owned(), "value3".to_owned())?;
+    engine.set("key1".to_owned(), "overwritten".to_owned())?;
+    assert_eq!(
+        engine.get("key1".to_owned())?,
+        Some("overwritten".to_owned())
+    );
+    assert_eq!(engine.get("missing".to_owned())?, None);
+    assert_eq!(
+        engine.scan("key")?,
+        vec![
+            ("key1".to_owned(), "overwritten".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(ErrorKind::KeyNotFound)
+    ));
+    let stats = engine.stats()?;
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.live_bytes, 21);
+    assert_eq!(stats.disk_bytes, 0);
+    Ok(())
+}
+
+#[test]
+fn least_recently_used_keys_are_evicted() -> Result<()> {
+    // Room for three keys of 4 bytes with values of 6 bytes.
+    let mut engine = InMemoryEngine::with_max_bytes(30);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.stats()?.evictions, 0);
+
+    // Reading key1 makes key2 the least recently used.
+    engine.get("key1".to_owned())?;
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // A larger value takes the room of two keys.
+    engine.set("key5".to_owned(), "a larger value".to_owned())?;
+    let pairs = engine.scan("")?;
+    let keys: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["key1", "key5"]);
+    let stats = engine.stats()?;
+    assert_eq!(stats.evictions, 3);
+    assert_eq!(stats.live_bytes, 28);
+
+    // A value that can never fit is evicted at once.
+    engine.set("key6".to_owned(), "v".repeat(40))?;
+    assert_eq!(engine.stats()?.keys, 0);
+    Ok(())
+}
+
+#[test]
+fn namespaces_share_their_keys_between_handles() -> Result<()> {
+    let mut engine = InMemoryEngine::new();
+    engine.set("key".to_owned(), "default".to_owned())?;
+    let mut first = engine.open_namespace("team-a")?;
+    first.set("key".to_owned(), "team-a".to_owned())?;
+    let mut second = engine.open_namespace("team-a")?;
+    assert_eq!(second.get("key".to_owned())?, Some("team-a".to_owned()));
+    assert_eq!(engine.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(engine.namespaces()?, vec!["team-a".to_owned()]);
+
+    engine.drop_namespace("team-a")?;
+    assert!(engine.namespaces()?.is_empty());
+    assert!(matches!(
+        engine.drop_namespace("team-a"),
+        Err(ErrorKind::NamespaceNotFound(_))
+    ));
+    assert!(matches!(
+        engine.open_namespace("bad name"),
+        Err(ErrorKind::InvalidNamespace(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn server_runs_on_memory_engine() {
+    let addr = "127.0.0.1:5602";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--max-memory", "30", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut client = KvsClient::connect(addr.parse().unwrap()).unwrap();
+    for i in 1..=4 {
+        client
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    assert_eq!(
+        client.get("key4".to_owned()).unwrap(),
+        Some("value4".to_owned())
+    );
+    assert_eq!(client.stats().unwrap().engine.evictions, 1);
+    drop(client);
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    // Nothing claims the data directory for another engine.
+    assert!(!temp_dir.path().join("engine").exists());
+}
@@ -0,0 +1,171 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, Result, ShutdownHandle};
+use predicates::str::contains;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::Command;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+struct RunningServer {
+    handle: ShutdownHandle,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl RunningServer {
+    fn start(mut server: KvsServer<KvStore>, addr: SocketAddr) -> Self {
+        let handle = server.shutdown_handle();
+        let thread = thread::spawn(move || server.listen());
+        while TcpStream::connect(addr).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        RunningServer { handle, thread }
+    }
+
+    fn stop(self) {
+        self.handle.shutdown();
+        self.thread.join().unwrap().unwrap();
+    }
+}
+
+fn scrape(addr: SocketAddr) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn stats_count_requests_and_describe_engines() {
+    let addr: SocketAddr = "127.0.0.1:5101".parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr).unwrap();
+    let server = RunningServer::start(server, addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value".to_owned()).unwrap();
+    client.remove("key2".to_owned()).unwrap();
+    assert!(client.remove("key2".to_owned()).is_err());
+    client.get("key1".to_owned()).unwrap();
+    client.create_namespace("team-a".to_owned()).unwrap();
+
+    let stats = client.stats().unwrap();
+    assert_eq!(stats.connections, 1);
+    let request = |kind: &str| {
+        stats
+            .requests
+            .iter()
+            .find(|request| request.kind == kind)
+            .unwrap()
+            .clone()
+    };
+    assert_eq!(request("set").latency.count(), 3);
+    assert_eq!(request("set").errors, 0);
+    assert_eq!(request("remove").latency.count(), 2);
+    assert_eq!(request("remove").errors, 1);
+    assert_eq!(request("get").latency.count(), 1);
+    assert!(request("get").latency.quantile(0.99) > Duration::default());
+
+    assert_eq!(stats.engine.keys, 1);
+    assert!(stats.engine.live_bytes > 0);
+    assert!(stats.engine.stale_bytes > 0);
+    assert_eq!(
+        stats.engine.disk_bytes,
+        stats.engine.live_bytes + stats.engine.stale_bytes
+    );
+    assert_eq!(stats.namespaces.len(), 1);
+    assert_eq!(stats.namespaces[0].0, "team-a");
+    assert_eq!(stats.namespaces[0].1.keys, 0);
+
+    // The stats request itself is counted from then on.
+    let stats = client.stats().unwrap();
+    assert!(stats.requests.iter().any(|request| request.kind == "stats"));
+    server.stop();
+}
+
+#[test]
+fn compactions_are_counted() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.stats().unwrap().compactions, 0);
+    for i in 0..1100 {
+        store.set("key".to_owned(), format!("value{}", i)).unwrap();
+    }
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.keys, 1);
+    assert!(stats.stale_bytes < stats.disk_bytes);
+}
+
+#[test]
+fn prometheus_endpoint() {
+    let addr: SocketAddr = "127.0.0.1:5102".parse().unwrap();
+    let metrics_addr: SocketAddr = "127.0.0.1:5103".parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr)
+        .unwrap()
+        .with_metrics_addr(metrics_addr);
+    let server = RunningServer::start(server, addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.get("missing".to_owned()).unwrap();
+
+    let response = scrape(metrics_addr);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+    assert!(response.contains("# TYPE kvs_requests_total counter\n"));
+    assert!(response.contains("kvs_requests_total{type=\"set\"} 1\n"));
+    assert!(response.contains("kvs_request_errors_total{type=\"get\"} 0\n"));
+    assert!(response.contains("kvs_request_duration_seconds_bucket{type=\"get\",le=\"+Inf\"} 1\n"));
+    assert!(response.contains("kvs_request_duration_seconds_count{type=\"set\"} 1\n"));
+    assert!(response.contains("kvs_engine_keys{namespace=\"\"} 1\n"));
+    assert!(response.contains("kvs_engine_compactions_total{namespace=\"\"} 0\n"));
+    assert!(response.contains("kvs_connections 1\n"));
+
+    drop(client);
+    server.stop();
+    assert!(TcpStream::connect(metrics_addr).is_err());
+}
+
+#[test]
+fn cli_stats() {
+    let addr = "127.0.0.1:5104";
+    let metrics_addr = "127.0.0.1:5105";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--metrics-addr", metrics_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("uptime_secs "))
+        .stdout(contains("request set count 1 errors 0 p50_us "))
+        .stdout(contains("engine keys 1 disk_bytes "));
+    assert!(
+        scrape(metrics_addr.parse().unwrap()).contains("kvs_requests_total{type=\"stats\"} 1\n")
+    );
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
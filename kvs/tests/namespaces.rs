@@ -0,0 +1,207 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    ClientOptions, ErrorKind, KvStore, KvsClient, KvsEngine, KvsServer, Result, SledKvsEngine,
+};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Keys of a namespace are kept apart from the engine's own and survive reopening it.
+fn namespaces_on_engine<E: KvsEngine>(open: impl Fn(&TempDir) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = open(&temp_dir)?;
+    assert!(engine.namespaces()?.is_empty());
+
+    let mut team_a = engine.open_namespace("team-a")?;
+    team_a.set("key".to_owned(), "a".to_owned())?;
+    engine.set("key".to_owned(), "default".to_owned())?;
+    engine.open_namespace("team_b")?;
+    assert_eq!(engine.namespaces()?, vec!["team-a", "team_b"]);
+    assert_eq!(team_a.get("key".to_owned())?, Some("a".to_owned()));
+    assert_eq!(engine.get("key".to_owned())?, Some("default".to_owned()));
+    team_a.flush()?;
+    engine.flush()?;
+    drop(team_a);
+    drop(engine);
+
+    let mut engine = open(&temp_dir)?;
+    let mut team_a = engine.open_namespace("team-a")?;
+    assert_eq!(team_a.get("key".to_owned())?, Some("a".to_owned()));
+    drop(team_a);
+
+    engine.drop_namespace("team-a")?;
+    assert_eq!(engine.namespaces()?, vec!["team_b"]);
+    assert!(engine
+        .open_namespace("team-a")?
+        .get("key".to_owned())?
+        .is_none());
+    engine.drop_namespace("team-a")?;
+    match engine.drop_namespace("missing") {
+        Err(ErrorKind::NamespaceNotFound(_)) => {}
+        other => panic!("expected namespace not found, got {:?}", other),
+    }
+
+    for name in &["", "../escape", "a/b", "_hidden", "white space"] {
+        match engine.open_namespace(name) {
+            Err(ErrorKind::InvalidNamespace(_)) => {}
+            Err(e) => panic!("expected invalid namespace {:?}, got {:?}", name, e),
+            Ok(_) => panic!("expected invalid namespace {:?}", name),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn kv_store_namespaces() -> Result<()> {
+    namespaces_on_engine(|dir| KvStore::open(dir.path()))
+}
+
+/// Open sled at `path`. Its background threads release the lock on the directory a
+/// little after the last handle of a previous database is dropped.
+fn open_sled(path: &Path) -> sled::Result<sled::Db> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(_)) if Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            result => return result,
+        }
+    }
+}
+
+#[test]
+fn sled_namespaces() -> Result<()> {
+    namespaces_on_engine(|dir| Ok(SledKvsEngine::new(open_sled(dir.path())?)))
+}
+
+fn connect(addr: SocketAddr, namespace: &str) -> KvsClient {
+    KvsClient::connect_with(
+        addr,
+        &ClientOptions {
+            namespace: Some(namespace.to_owned()),
+            ..ClientOptions::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn server_namespaces() {
+    let addr: SocketAddr = "127.0.0.1:4701".parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr).unwrap();
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.listen());
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut admin = KvsClient::connect(addr).unwrap();
+    admin.create_namespace("team-a".to_owned()).unwrap();
+    admin.create_namespace("team-b".to_owned()).unwrap();
+    match admin.create_namespace("team-a".to_owned()) {
+        Err(ErrorKind::Server(msg)) => assert!(msg.contains("already exists")),
+        other => panic!("expected an error, got {:?}", other),
+    }
+    assert!(admin.create_namespace("../escape".to_owned()).is_err());
+    assert_eq!(admin.namespaces().unwrap(), vec!["team-a", "team-b"]);
+
+    let mut team_a = connect(addr, "team-a");
+    let mut team_b = connect(addr, "team-b");
+    team_a.set("key".to_owned(), "a".to_owned()).unwrap();
+    team_b.set("key".to_owned(), "b".to_owned()).unwrap();
+    assert_eq!(team_a.get("key".to_owned()).unwrap(), Some("a".to_owned()));
+    assert_eq!(team_b.get("key".to_owned()).unwrap(), Some("b".to_owned()));
+    assert_eq!(admin.get("key".to_owned()).unwrap(), None);
+
+    admin.use_namespace(Some("team-b".to_owned()));
+    assert_eq!(admin.get("key".to_owned()).unwrap(), Some("b".to_owned()));
+    admin.use_namespace(None);
+
+    let mut missing = connect(addr, "missing");
+    match missing.get("key".to_owned()) {
+        Err(ErrorKind::Server(msg)) => assert!(msg.contains("Namespace not found")),
+        other => panic!("expected an error, got {:?}", other),
+    }
+
+    admin.drop_namespace("team-b".to_owned()).unwrap();
+    assert!(team_b.get("key".to_owned()).is_err());
+    assert!(admin.drop_namespace("team-b".to_owned()).is_err());
+    assert_eq!(admin.namespaces().unwrap(), vec!["team-a"]);
+
+    handle.shutdown();
+    server.join().unwrap().unwrap();
+
+    // Namespaces are opened again when the server restarts.
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr).unwrap();
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.listen());
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut team_a = connect(addr, "team-a");
+    assert_eq!(team_a.get("key".to_owned()).unwrap(), Some("a".to_owned()));
+    handle.shutdown();
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn cli_namespaces() {
+    let addr = "127.0.0.1:4702";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["namespace", "create", "team-a", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["namespace", "list", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("team-a\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", "a", "--addr", addr, "--namespace", "team-a"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", addr, "--namespace", "team-a"])
+        .assert()
+        .success()
+        .stdout("a\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["namespace", "drop", "team-a", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", addr, "--namespace", "team-a"])
+        .assert()
+        .failure();
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
@@ -0,0 +1,425 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    ClientOptions, ErrorKind, KvStore, KvsClient, KvsEngine, KvsServer, NodeId, RaftConfig,
+    RaftEngine, Result, ShutdownHandle, SimulatedNetwork, TcpTransport,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::process::Command;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Addresses that clients get redirected to. In-process nodes do not listen on them.
+fn peers(ports: &[u16]) -> HashMap<NodeId, SocketAddr> {
+    ports
+        .iter()
+        .enumerate()
+        .map(|(i, port)| (i as NodeId + 1, SocketAddr::from(([127, 0, 0, 1], *port))))
+        .collect()
+}
+
+fn config(id: NodeId, peers: &HashMap<NodeId, SocketAddr>, dir: &Path) -> RaftConfig {
+    let mut config = RaftConfig::new(id, peers.clone(), dir.join("raft"));
+    config.election_timeout = Duration::from_millis(100);
+    config.heartbeat_interval = Duration::from_millis(20);
+    config.propose_timeout = Duration::from_secs(1);
+    config
+}
+
+struct Cluster {
+    network: SimulatedNetwork,
+    dirs: Vec<TempDir>,
+    nodes: Vec<Option<RaftEngine<KvStore>>>,
+    peers: HashMap<NodeId, SocketAddr>,
+    snapshot_threshold: u64,
+}
+
+impl Cluster {
+    fn new(size: usize, snapshot_threshold: u64) -> Self {
+        let ports: Vec<u16> = (1..=size as u16).collect();
+        let mut cluster = Cluster {
+            network: SimulatedNetwork::new(),
+            dirs: (0..size).map(|_| TempDir::new().unwrap()).collect(),
+            nodes: (0..size).map(|_| None).collect(),
+            peers: peers(&ports),
+            snapshot_threshold,
+        };
+        for id in 1..=size as NodeId {
+            cluster.start(id);
+        }
+        cluster
+    }
+
+    fn start(&mut self, id: NodeId) {
+        let dir = self.dirs[id as usize - 1].path();
+        let mut config = config(id, &self.peers, dir);
+        config.snapshot_threshold = self.snapshot_threshold;
+        let engine = KvStore::open(dir.join("engine")).unwrap();
+        let node = RaftEngine::start(engine, config, self.network.transport()).unwrap();
+        self.network.register(id, node.inbox());
+        self.nodes[id as usize - 1] = Some(node);
+    }
+
+    fn stop(&mut self, id: NodeId) {
+        self.nodes[id as usize - 1] = None;
+    }
+
+    fn node(&self, id: NodeId) -> RaftEngine<KvStore> {
+        self.nodes[id as usize - 1].clone().unwrap()
+    }
+
+    /// Wait for a leader among the running nodes that are not isolated.
+    fn leader(&self, excluding: &[NodeId]) -> RaftEngine<KvStore> {
+        let mut leader = None;
+        eventually(|| {
+            leader = self
+                .nodes
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !excluding.contains(&(*i as NodeId + 1)))
+                .filter_map(|(_, node)| node.clone())
+                .find(|node| node.is_leader());
+            leader.is_some()
+        });
+        leader.unwrap()
+    }
+}
+
+// Poll until `condition` holds, failing the test after a few seconds.
+fn eventually(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "condition not met in time");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Set a key through whichever node leads, retrying while leadership changes.
+fn set_retrying(cluster: &Cluster, key: &str, value: &str, excluding: &[NodeId]) {
+    eventually(|| {
+        let mut leader = cluster.leader(excluding);
+        leader.set(key.to_owned(), value.to_owned()).is_ok()
+    });
+}
+
+fn get_retrying(cluster: &Cluster, key: &str, excluding: &[NodeId]) -> Option<String> {
+    let mut value = None;
+    eventually(|| match cluster.leader(excluding).get(key.to_owned()) {
+        Ok(v) => {
+            value = v;
+            true
+        }
+        Err(_) => false,
+    });
+    value
+}
+
+#[test]
+fn elects_a_single_leader_and_replicates_writes() {
+    let cluster = Cluster::new(3, 1000);
+    let mut leader = cluster.leader(&[]);
+    leader.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        leader.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    leader.remove("key1".to_owned()).unwrap();
+    assert_eq!(leader.get("key1".to_owned()).unwrap(), None);
+    leader.set("key2".to_owned(), "value2".to_owned()).unwrap();
+
+    // Followers point clients to the leader.
+    let leader_id = leader.leader().unwrap();
+    let leader_addr = cluster.peers[&leader_id];
+    for id in (1..=3).filter(|&id| id != leader_id) {
+        let mut follower = cluster.node(id);
+        eventually(|| follower.leader() == Some(leader_id));
+        assert_eq!(follower.term(), leader.term());
+        match follower.get("key2".to_owned()) {
+            Err(ErrorKind::NotLeader(Some(addr))) => assert_eq!(addr, leader_addr),
+            other => panic!("expected a redirect, got {:?}", other),
+        }
+        match follower.set("key3".to_owned(), "value3".to_owned()) {
+            Err(ErrorKind::NotLeader(Some(addr))) => assert_eq!(addr, leader_addr),
+            other => panic!("expected a redirect, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn new_leader_takes_over_after_partition() {
+    let cluster = Cluster::new(3, 1000);
+    let old = cluster.leader(&[]);
+    let old_id = old.leader().unwrap();
+    set_retrying(&cluster, "key1", "value1", &[]);
+
+    cluster.network.isolate(old_id);
+    let new = cluster.leader(&[old_id]);
+    assert!(new.term() > old.term() || new.leader() != Some(old_id));
+    assert_eq!(
+        get_retrying(&cluster, "key1", &[old_id]),
+        Some("value1".to_owned())
+    );
+    set_retrying(&cluster, "key2", "value2", &[old_id]);
+
+    // The isolated leader cannot commit, and steps down.
+    let mut isolated = old.clone();
+    assert!(isolated.set("lost".to_owned(), "write".to_owned()).is_err());
+    eventually(|| !old.is_leader());
+
+    // Once healed it catches up with the new leader and drops its uncommitted entry.
+    cluster.network.heal(old_id);
+    eventually(|| old.leader().is_some() && old.leader() != Some(old_id));
+    assert_eq!(
+        get_retrying(&cluster, "key2", &[]),
+        Some("value2".to_owned())
+    );
+    assert_eq!(get_retrying(&cluster, "lost", &[]), None);
+}
+
+// A leader cut off from the majority cannot confirm that it still leads, so it serves
+// no reads, which would be stale once another node took over.
+#[test]
+fn deposed_leader_refuses_reads() {
+    let cluster = Cluster::new(3, 1000);
+    set_retrying(&cluster, "key", "old", &[]);
+    let mut old = cluster.leader(&[]);
+    let old_id = old.leader().unwrap();
+    assert_eq!(old.get("key".to_owned()).unwrap(), Some("old".to_owned()));
+
+    cluster.network.isolate(old_id);
+    assert!(old.get("key".to_owned()).is_err());
+    set_retrying(&cluster, "key", "new", &[old_id]);
+    assert!(old.get("key".to_owned()).is_err());
+    assert!(old.scan("").is_err());
+    assert_eq!(
+        get_retrying(&cluster, "key", &[old_id]),
+        Some("new".to_owned())
+    );
+}
+
+#[test]
+fn increments_are_applied_once_on_every_node() {
+    let cluster = Cluster::new(3, 1000);
+    let mut leader = cluster.leader(&[]);
+    for expected in 1..=5 {
+        assert_eq!(leader.incr("counter".to_owned(), 1).unwrap(), expected);
+    }
+    assert_eq!(leader.incr("counter".to_owned(), -2).unwrap(), 3);
+    leader.set("text".to_owned(), "value".to_owned()).unwrap();
+    assert!(matches!(
+        leader.incr("text".to_owned(), 1),
+        Err(ErrorKind::NotAnInteger(key)) if key == "text"
+    ));
+
+    // A new leader has the same count.
+    let leader_id = leader.leader().unwrap();
+    cluster.network.isolate(leader_id);
+    assert_eq!(
+        get_retrying(&cluster, "counter", &[leader_id]),
+        Some("3".to_owned())
+    );
+}
+
+// Entries after the last snapshot are applied again on restart, and must not count twice.
+#[test]
+fn increments_are_not_applied_again_after_restart() {
+    let mut cluster = Cluster::new(3, 1000);
+    let mut leader = cluster.leader(&[]);
+    for expected in 1..=5 {
+        assert_eq!(leader.incr("counter".to_owned(), 1).unwrap(), expected);
+    }
+    drop(leader);
+    for id in 1..=3 {
+        cluster.stop(id);
+    }
+    thread::sleep(Duration::from_millis(50));
+
+    for id in 1..=3 {
+        cluster.start(id);
+    }
+    assert_eq!(get_retrying(&cluster, "counter", &[]), Some("5".to_owned()));
+}
+
+#[test]
+fn survives_a_lossy_network() {
+    let cluster = Cluster::new(5, 1000);
+    cluster.network.set_loss(0.2);
+    for i in 0..30 {
+        set_retrying(&cluster, &format!("key{}", i), &format!("value{}", i), &[]);
+    }
+    cluster.network.set_loss(0.0);
+    for i in 0..30 {
+        assert_eq!(
+            get_retrying(&cluster, &format!("key{}", i), &[]),
+            Some(format!("value{}", i))
+        );
+    }
+}
+
+#[test]
+fn lagging_node_catches_up_from_a_snapshot() {
+    let mut cluster = Cluster::new(3, 10);
+    let leader_id = cluster.leader(&[]).leader().unwrap();
+    let lagging = (1..=3).find(|&id| id != leader_id).unwrap();
+    cluster.network.isolate(lagging);
+    for i in 0..40 {
+        set_retrying(
+            &cluster,
+            &format!("key{}", i),
+            &format!("value{}", i),
+            &[lagging],
+        );
+    }
+    let leader_dir = cluster.dirs[leader_id as usize - 1].path().to_owned();
+    assert!(leader_dir.join("raft").join("snapshot.json").exists());
+
+    cluster.network.heal(lagging);
+    let lagging_dir = cluster.dirs[lagging as usize - 1].path().to_owned();
+    eventually(|| lagging_dir.join("raft").join("snapshot.json").exists());
+    let node = cluster.node(lagging);
+    eventually(|| node.leader().is_some());
+    drop(node);
+
+    // The snapshot was restored into the engine of the lagging node.
+    cluster.stop(lagging);
+    thread::sleep(Duration::from_millis(50));
+    let mut engine = KvStore::open(lagging_dir.join("engine")).unwrap();
+    assert_eq!(
+        engine.get("key0".to_owned()).unwrap(),
+        Some("value0".to_owned())
+    );
+}
+
+#[test]
+fn cluster_recovers_after_restart() {
+    let mut cluster = Cluster::new(3, 10);
+    for i in 0..25 {
+        set_retrying(&cluster, &format!("key{}", i), &format!("value{}", i), &[]);
+    }
+    for id in 1..=3 {
+        cluster.stop(id);
+    }
+    thread::sleep(Duration::from_millis(50));
+
+    for id in 1..=3 {
+        cluster.start(id);
+    }
+    for i in 0..25 {
+        assert_eq!(
+            get_retrying(&cluster, &format!("key{}", i), &[]),
+            Some(format!("value{}", i))
+        );
+    }
+    set_retrying(&cluster, "key25", "value25", &[]);
+}
+
+struct RunningServer {
+    handle: ShutdownHandle,
+    thread: JoinHandle<Result<()>>,
+}
+
+#[test]
+fn clients_are_redirected_to_the_leader() {
+    let peers = peers(&[4901, 4902, 4903]);
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let mut servers = Vec::new();
+    for (i, dir) in dirs.iter().enumerate() {
+        let id = i as NodeId + 1;
+        let addr = peers[&id];
+        let transport = TcpTransport::new(&peers, &ClientOptions::default());
+        let engine = KvStore::open(dir.path().join("engine")).unwrap();
+        let engine = RaftEngine::start(engine, config(id, &peers, dir.path()), transport).unwrap();
+        let inbox = engine.inbox();
+        let mut server = KvsServer::new(engine, addr).unwrap().with_raft(inbox);
+        let handle = server.shutdown_handle();
+        let thread = thread::spawn(move || server.listen());
+        while TcpStream::connect(addr).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        servers.push(RunningServer { handle, thread });
+    }
+
+    for id in 1..=3 {
+        let mut client = KvsClient::connect(peers[&id]).unwrap();
+        let key = format!("key{}", id);
+        eventually(|| client.set(key.clone(), "value".to_owned()).is_ok());
+        assert_eq!(client.get(key).unwrap(), Some("value".to_owned()));
+    }
+    let mut client = KvsClient::connect(peers[&1]).unwrap();
+    client.remove("key1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    assert!(client.create_namespace("team-a".to_owned()).is_err());
+
+    for server in servers {
+        server.handle.shutdown();
+        server.thread.join().unwrap().unwrap();
+    }
+}
+
+#[test]
+fn cli_cluster() {
+    let addrs = ["127.0.0.1:4904", "127.0.0.1:4905", "127.0.0.1:4906"];
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let mut children = Vec::new();
+    for (addr, dir) in addrs.iter().zip(&dirs) {
+        let peers = dir.path().join("peers.txt");
+        fs::write(&peers, format!("# cluster\n{}\n", addrs.join("\n"))).unwrap();
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr, "--cluster"])
+            .arg(&peers)
+            .current_dir(dir)
+            .spawn()
+            .unwrap();
+        children.push(child);
+    }
+    for addr in &addrs {
+        while TcpStream::connect(addr).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    eventually(|| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", "value1", "--addr", addrs[0]])
+            .output()
+            .unwrap()
+            .status
+            .success()
+    });
+    for addr in &addrs {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", addr])
+            .assert()
+            .success()
+            .stdout("value1\n");
+    }
+    assert!(dirs[0].path().join("raft").join("log.jsonl").exists());
+
+    // A server must be listed in the cluster file, and cannot also use --follow.
+    let peers = dirs[0].path().join("peers.txt");
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4907", "--cluster"])
+        .arg(&peers)
+        .current_dir(&dirs[0])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--follow", addrs[1], "--cluster"])
+        .arg(&peers)
+        .current_dir(&dirs[0])
+        .assert()
+        .failure();
+
+    for child in &mut children {
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
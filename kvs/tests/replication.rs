@@ -0,0 +1,255 @@
+use assert_cmd::prelude::*;
+use kvs::{ClientOptions, FollowerConfig, KvStore, KvsClient, KvsServer, Result, ShutdownHandle};
+use predicates::str::contains;
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::process::Command;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+struct RunningServer {
+    handle: ShutdownHandle,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl RunningServer {
+    fn start(mut server: KvsServer<KvStore>, addr: SocketAddr) -> Self {
+        let handle = server.shutdown_handle();
+        let thread = thread::spawn(move || server.listen());
+        while TcpStream::connect(addr).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        RunningServer { handle, thread }
+    }
+
+    fn stop(self) {
+        self.handle.shutdown();
+        self.thread.join().unwrap().unwrap();
+    }
+}
+
+fn start_leader(addr: SocketAddr, dir: &Path) -> RunningServer {
+    let server = KvsServer::new(KvStore::open(dir).unwrap(), addr)
+        .unwrap()
+        .with_replication_log(dir.join("replication.log"))
+        .unwrap();
+    RunningServer::start(server, addr)
+}
+
+fn start_follower(addr: SocketAddr, leader: SocketAddr, dir: &Path) -> RunningServer {
+    let server = KvsServer::new(KvStore::open(dir).unwrap(), addr)
+        .unwrap()
+        .following(FollowerConfig {
+            leader,
+            options: ClientOptions::default(),
+            position_file: dir.join("replication.pos"),
+        });
+    RunningServer::start(server, addr)
+}
+
+// Poll until `condition` holds, failing the test after a few seconds.
+fn eventually(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "condition not met in time");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn get(client: &mut KvsClient, key: &str) -> Option<String> {
+    client.get(key.to_owned()).unwrap()
+}
+
+#[test]
+fn follower_replicates_writes() {
+    let leader_addr: SocketAddr = "127.0.0.1:4801".parse().unwrap();
+    let follower_addr: SocketAddr = "127.0.0.1:4802".parse().unwrap();
+    let (leader_dir, follower_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let leader = start_leader(leader_addr, leader_dir.path());
+
+    let mut client = KvsClient::connect(leader_addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+
+    let follower = start_follower(follower_addr, leader_addr, follower_dir.path());
+    let mut reader = KvsClient::connect(follower_addr).unwrap();
+    eventually(|| get(&mut reader, "key2").is_some());
+    assert_eq!(get(&mut reader, "key1"), Some("value1".to_owned()));
+
+    client.remove("key1".to_owned()).unwrap();
+    client.create_namespace("team-a".to_owned()).unwrap();
+    client.use_namespace(Some("team-a".to_owned()));
+    client.set("key1".to_owned(), "a".to_owned()).unwrap();
+
+    reader.use_namespace(Some("team-a".to_owned()));
+    eventually(|| reader.get("key1".to_owned()).ok().flatten().is_some());
+    reader.use_namespace(None);
+    assert_eq!(get(&mut reader, "key1"), None);
+
+    // Followers serve reads only.
+    assert!(reader.set("key3".to_owned(), "value3".to_owned()).is_err());
+    assert!(reader.create_namespace("team-b".to_owned()).is_err());
+
+    let mut status = client.replication_status().unwrap();
+    eventually(|| {
+        status = client.replication_status().unwrap();
+        status.followers.len() == 1 && status.followers[0].lag == 0
+    });
+    assert_eq!(status.position, 5);
+    assert_eq!(status.followers[0].applied, 5);
+
+    follower.stop();
+    eventually(|| client.replication_status().unwrap().followers.is_empty());
+    leader.stop();
+}
+
+#[test]
+fn follower_resumes_after_restart() {
+    let leader_addr: SocketAddr = "127.0.0.1:4803".parse().unwrap();
+    let follower_addr: SocketAddr = "127.0.0.1:4804".parse().unwrap();
+    let (leader_dir, follower_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let leader = start_leader(leader_addr, leader_dir.path());
+    let follower = start_follower(follower_addr, leader_addr, follower_dir.path());
+
+    let mut client = KvsClient::connect(leader_addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let mut reader = KvsClient::connect(follower_addr).unwrap();
+    eventually(|| get(&mut reader, "key1").is_some());
+    follower.stop();
+
+    // Writes made while the follower is down are caught up on restart.
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    client.remove("key1".to_owned()).unwrap();
+    let follower = start_follower(follower_addr, leader_addr, follower_dir.path());
+    let mut reader = KvsClient::connect(follower_addr).unwrap();
+    eventually(|| get(&mut reader, "key2").is_some());
+    eventually(|| get(&mut reader, "key1").is_none());
+    eventually(|| fs::read_to_string(follower_dir.path().join("replication.pos")).unwrap() == "3");
+    follower.stop();
+    leader.stop();
+
+    // The leader continues its log where it left off.
+    let leader = start_leader(leader_addr, leader_dir.path());
+    let mut client = KvsClient::connect(leader_addr).unwrap();
+    assert_eq!(client.replication_status().unwrap().position, 3);
+    client.set("key3".to_owned(), "value3".to_owned()).unwrap();
+    assert_eq!(client.replication_status().unwrap().position, 4);
+
+    let follower = start_follower(follower_addr, leader_addr, follower_dir.path());
+    let mut reader = KvsClient::connect(follower_addr).unwrap();
+    eventually(|| get(&mut reader, "key3").is_some());
+    assert_eq!(get(&mut reader, "key2"), Some("value2".to_owned()));
+    follower.stop();
+    leader.stop();
+}
+
+#[test]
+fn leader_log_holds_only_applied_writes() {
+    let addr: SocketAddr = "127.0.0.1:4808".parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let log = temp_dir.path().join("replication.log");
+    let leader = start_leader(addr, temp_dir.path());
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("text".to_owned(), "value".to_owned()).unwrap();
+    assert!(client.remove("missing".to_owned()).is_err());
+    assert!(client.incr("text".to_owned(), 1).is_err());
+    assert!(client.create_namespace("not valid".to_owned()).is_err());
+    assert_eq!(client.replication_status().unwrap().position, 2);
+    leader.stop();
+
+    // An entry cut short by a crash is dropped.
+    let mut contents = fs::read(&log).unwrap();
+    contents.extend_from_slice(br#"{"position":3,"write":{"Set":{"#);
+    fs::write(&log, &contents).unwrap();
+    let leader = start_leader(addr, temp_dir.path());
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.replication_status().unwrap().position, 2);
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(client.replication_status().unwrap().position, 3);
+    leader.stop();
+
+    // An entry that cannot be read before the end of the log is not skipped.
+    let contents = fs::read_to_string(&log).unwrap();
+    let mut lines: Vec<&str> = contents.lines().collect();
+    lines[1] = "garbage";
+    fs::write(&log, lines.join("\n") + "\n").unwrap();
+    let server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr).unwrap();
+    assert!(server.with_replication_log(&log).is_err());
+}
+
+#[test]
+fn replication_requires_a_log() {
+    let addr: SocketAddr = "127.0.0.1:4805".parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr).unwrap();
+    let server = RunningServer::start(server, addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(client.replication_status().is_err());
+    client.ping().unwrap();
+    server.stop();
+}
+
+#[test]
+fn cli_replication() {
+    let leader_addr = "127.0.0.1:4806";
+    let follower_addr = "127.0.0.1:4807";
+    let (leader_dir, follower_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let leader = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", leader_addr, "--replicate"])
+        .current_dir(&leader_dir)
+        .spawn()
+        .unwrap();
+    let follower = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", follower_addr, "--follow", leader_addr])
+        .current_dir(&follower_dir)
+        .spawn()
+        .unwrap();
+    for addr in &[leader_addr, follower_addr] {
+        while TcpStream::connect(addr).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", leader_addr])
+        .assert()
+        .success();
+    eventually(|| {
+        let output = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", follower_addr])
+            .output()
+            .unwrap();
+        output.stdout == b"value1\n"
+    });
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", follower_addr])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["replication", "--addr", leader_addr])
+        .assert()
+        .success()
+        .stdout(contains("position 1\nfollower 127.0.0.1:"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--replicate", "--follow", leader_addr])
+        .current_dir(&follower_dir)
+        .assert()
+        .failure();
+
+    for child in &mut [leader, follower] {
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsClient, KvsServer, Result, ShardedKvsClient, ShutdownHandle};
+use predicates::str::contains;
+use std::net::{SocketAddr, TcpStream};
+use std::process::Command;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+struct RunningServer {
+    handle: ShutdownHandle,
+    thread: JoinHandle<Result<()>>,
+    _dir: TempDir,
+}
+
+impl RunningServer {
+    fn start(addr: SocketAddr) -> Self {
+        let dir = TempDir::new().unwrap();
+        let mut server = KvsServer::new(KvStore::open(dir.path()).unwrap(), addr).unwrap();
+        let handle = server.shutdown_handle();
+        let thread = thread::spawn(move || server.listen());
+        while TcpStream::connect(addr).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        RunningServer {
+            handle,
+            thread,
+            _dir: dir,
+        }
+    }
+
+    fn stop(self) {
+        self.handle.shutdown();
+        self.thread.join().unwrap().unwrap();
+    }
+}
+
+fn addrs(ports: &[u16]) -> Vec<SocketAddr> {
+    ports
+        .iter()
+        .map(|port| SocketAddr::from(([127, 0, 0, 1], *port)))
+        .collect()
+}
+
+/// Number of keys stored on the server itself.
+fn stored(addr: SocketAddr) -> usize {
+    KvsClient::connect(addr)
+        .unwrap()
+        .scan(String::new())
+        .unwrap()
+        .len()
+}
+
+#[test]
+fn keys_are_spread_across_servers() {
+    let servers = addrs(&[5001, 5002, 5003]);
+    let running: Vec<_> = servers
+        .iter()
+        .map(|addr| RunningServer::start(*addr))
+        .collect();
+
+    let mut client = ShardedKvsClient::new(servers.clone());
+    for i in 0..300 {
+        client
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    for i in 0..300 {
+        let key = format!("key{}", i);
+        assert_eq!(
+            client.get(key.clone()).unwrap(),
+            Some(format!("value{}", i))
+        );
+        // The key is stored on its owner only.
+        let mut owner = KvsClient::connect(client.owner(&key)).unwrap();
+        assert_eq!(owner.get(key).unwrap(), Some(format!("value{}", i)));
+    }
+    for addr in &servers {
+        let count = stored(*addr);
+        assert!(count > 50, "{} holds only {} keys", addr, count);
+    }
+
+    client.remove("key0".to_owned()).unwrap();
+    assert_eq!(client.get("key0".to_owned()).unwrap(), None);
+    assert!(client.remove("key0".to_owned()).is_err());
+
+    // Placement does not depend on the order of the servers.
+    let reordered = ShardedKvsClient::new(vec![servers[2], servers[0], servers[1]]);
+    for i in 0..300 {
+        let key = format!("key{}", i);
+        assert_eq!(reordered.owner(&key), client.owner(&key));
+    }
+
+    for server in running {
+        server.stop();
+    }
+}
+
+#[test]
+fn rebalance_moves_keys_to_added_and_from_drained_servers() {
+    let servers = addrs(&[5004, 5005, 5006, 5007]);
+    let running: Vec<_> = servers
+        .iter()
+        .map(|addr| RunningServer::start(*addr))
+        .collect();
+
+    let mut client = ShardedKvsClient::new(servers[..3].to_vec());
+    for i in 0..400 {
+        client
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+
+    // Only keys placed on the new server move, and one written there since it was
+    // added keeps its newer value.
+    let mut grown = ShardedKvsClient::new(servers.clone());
+    let newer = (0..400)
+        .map(|i| format!("key{}", i))
+        .find(|key| grown.owner(key) == servers[3])
+        .unwrap();
+    grown.set(newer.clone(), "newer".to_owned()).unwrap();
+    let moved = grown.rebalance(&[]).unwrap();
+    assert_eq!(moved as usize, stored(servers[3]));
+    assert!(moved > 40 && moved < 200, "moved {} keys", moved);
+    assert_eq!(grown.rebalance(&[]).unwrap(), 0);
+    for i in 0..400 {
+        let key = format!("key{}", i);
+        let expected = if key == newer {
+            "newer".to_owned()
+        } else {
+            format!("value{}", i)
+        };
+        assert_eq!(grown.get(key).unwrap(), Some(expected));
+    }
+
+    // Draining a server moves all of its keys away.
+    let drained = servers[0];
+    let held = stored(drained);
+    let mut shrunk = ShardedKvsClient::new(servers[1..].to_vec());
+    assert_eq!(shrunk.rebalance(&[drained]).unwrap() as usize, held);
+    assert_eq!(stored(drained), 0);
+    for i in 0..400 {
+        let key = format!("key{}", i);
+        let expected = if key == newer {
+            "newer".to_owned()
+        } else {
+            format!("value{}", i)
+        };
+        assert_eq!(shrunk.get(key).unwrap(), Some(expected));
+    }
+
+    for server in running {
+        server.stop();
+    }
+}
+
+#[test]
+fn cli_rebalance() {
+    let servers = addrs(&[5008, 5009]);
+    let running: Vec<_> = servers
+        .iter()
+        .map(|addr| RunningServer::start(*addr))
+        .collect();
+
+    let mut client = KvsClient::connect(servers[0]).unwrap();
+    for i in 0..50 {
+        client.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    let list = format!("{},{}", servers[0], servers[1]);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rebalance", "--servers", &list])
+        .assert()
+        .success()
+        .stdout(contains("Moved "));
+    let sharded = ShardedKvsClient::new(servers.clone());
+    for (key, _) in client.scan(String::new()).unwrap() {
+        assert_eq!(sharded.owner(&key), servers[0]);
+    }
+    assert_eq!(stored(servers[0]) + stored(servers[1]), 50);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rebalance", "--servers", &servers[1].to_string()])
+        .args(["--drain", &servers[0].to_string()])
+        .assert()
+        .success()
+        .stdout(contains("Moved "));
+    assert_eq!(stored(servers[1]), 50);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rebalance"])
+        .assert()
+        .failure();
+
+    for server in running {
+        server.stop();
+    }
+}
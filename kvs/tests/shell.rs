@@ -0,0 +1,142 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsClient, KvsServer, Result, ShutdownHandle};
+use predicates::prelude::*;
+use predicates::str::contains;
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::process::Command;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+struct RunningServer {
+    handle: ShutdownHandle,
+    thread: JoinHandle<Result<()>>,
+    _dir: TempDir,
+}
+
+impl RunningServer {
+    fn start(addr: SocketAddr) -> Self {
+        let dir = TempDir::new().unwrap();
+        let mut server = KvsServer::new(KvStore::open(dir.path()).unwrap(), addr).unwrap();
+        let handle = server.shutdown_handle();
+        let thread = thread::spawn(move || server.listen());
+        while TcpStream::connect(addr).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        RunningServer {
+            handle,
+            thread,
+            _dir: dir,
+        }
+    }
+
+    fn stop(self) {
+        self.handle.shutdown();
+        self.thread.join().unwrap().unwrap();
+    }
+}
+
+#[test]
+fn batch_reports_the_result_of_each_line() {
+    let addr: SocketAddr = "127.0.0.1:5401".parse().unwrap();
+    let server = RunningServer::start(addr);
+    let temp_dir = TempDir::new().unwrap();
+    let script = temp_dir.path().join("script");
+    let content = r#"# Quoted words may hold spaces and quotes
+set greeting "hello world"
+set 'quoted key' "say \"hi\""
+get greeting
+
+get missing
+rm missing
+frobnicate
+set onlykey
+get "unterminated
+scan
+ping
+"#;
+    fs::write(&script, content).unwrap();
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["shell", "--addr", &addr.to_string(), "--batch"])
+        .arg(&script)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let expected = r#"2: OK
+3: OK
+4: "hello world"
+6: Key not found
+7: error: Key not found
+8: error: unknown command "frobnicate", see help
+9: error: wrong number of arguments for set, see help
+10: error: unterminated " quote
+11: greeting "hello world"
+11: "quoted key" "say \"hi\""
+12: PONG
+"#;
+    assert_eq!(stdout, expected);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        client.get("quoted key".to_owned()).unwrap(),
+        Some("say \"hi\"".to_owned())
+    );
+    server.stop();
+}
+
+#[test]
+fn batch_succeeds_when_every_line_does() {
+    let addr: SocketAddr = "127.0.0.1:5402".parse().unwrap();
+    let server = RunningServer::start(addr);
+    let temp_dir = TempDir::new().unwrap();
+    let script = temp_dir.path().join("script");
+    fs::write(&script, "set a 1\nstats\nexit\nget a\n").unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["shell", "--addr", &addr.to_string(), "--batch"])
+        .arg(&script)
+        .assert()
+        .success()
+        .stdout(contains("1: OK\n"))
+        .stdout(contains("2: uptime_secs "))
+        .stdout(contains("2: request set count 1 errors 0"))
+        // Nothing runs after exit.
+        .stdout(contains("4:").not());
+    server.stop();
+}
+
+#[test]
+fn shell_keeps_one_connection() {
+    let addr: SocketAddr = "127.0.0.1:5403".parse().unwrap();
+    let server = RunningServer::start(addr);
+    let temp_dir = TempDir::new().unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["shell", "--addr", &addr.to_string()])
+        .env("HOME", temp_dir.path())
+        .with_stdin()
+        .buffer("set key1 value1\nget key1\nstats\nbogus\n")
+        .assert()
+        .success()
+        .stdout(contains("OK\nvalue1\n"))
+        .stdout(contains("request set count 1 errors 0"))
+        .stdout(contains("error: unknown command \"bogus\""));
+    server.stop();
+}
+
+#[test]
+fn shell_fails_without_server() {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["shell", "--addr", "127.0.0.1:5404"])
+        .with_stdin()
+        .buffer("ping\n")
+        .assert()
+        .failure();
+}
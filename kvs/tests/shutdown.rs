@@ -0,0 +1,198 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, SledKvsEngine};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn wait_for_server(addr: SocketAddr) {
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Open sled at `path`. Its background threads release the lock on the directory a
+/// little after the last handle of a previous database is dropped.
+fn open_sled(path: &Path) -> sled::Db {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(_)) if Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            result => return result.unwrap(),
+        }
+    }
+}
+
+// Writers keep setting keys until the server goes away. Every write the server
+// acknowledged must be readable after reopening the engine.
+fn no_acknowledged_write_lost<E, F>(addr: &str, open: F)
+where
+    E: KvsEngine + Send + 'static,
+    F: Fn(&TempDir) -> E,
+{
+    let addr: SocketAddr = addr.parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = KvsServer::new(open(&temp_dir), addr).unwrap();
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.listen());
+    wait_for_server(addr);
+
+    let writers: Vec<_> = (0..4)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut client = KvsClient::connect(addr).unwrap();
+                let mut acknowledged = Vec::new();
+                for j in 0.. {
+                    let key = format!("key{}_{}", i, j);
+                    if client.set(key.clone(), format!("value{}", j)).is_err() {
+                        break;
+                    }
+                    acknowledged.push((key, format!("value{}", j)));
+                }
+                acknowledged
+            })
+        })
+        .collect();
+
+    thread::sleep(Duration::from_millis(300));
+    handle.shutdown();
+    server.join().unwrap().unwrap();
+    let acknowledged: Vec<_> = writers
+        .into_iter()
+        .flat_map(|writer| writer.join().unwrap())
+        .collect();
+    assert!(!acknowledged.is_empty());
+
+    let mut engine = open(&temp_dir);
+    for (key, value) in acknowledged {
+        assert_eq!(engine.get(key).unwrap(), Some(value));
+    }
+}
+
+#[test]
+fn shutdown_kvs_engine_keeps_acknowledged_writes() {
+    no_acknowledged_write_lost("127.0.0.1:4301", |dir| KvStore::open(dir.path()).unwrap());
+}
+
+#[test]
+fn shutdown_sled_engine_keeps_acknowledged_writes() {
+    no_acknowledged_write_lost("127.0.0.1:4302", |dir| {
+        SledKvsEngine::new(open_sled(dir.path()))
+    });
+}
+
+#[test]
+fn shutdown_request_stops_server() {
+    let addr: SocketAddr = "127.0.0.1:4303".parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr).unwrap();
+    let server = thread::spawn(move || server.listen());
+    wait_for_server(addr);
+
+    // An idle connection must not keep the server alive.
+    let mut idle = KvsClient::connect(addr).unwrap();
+    idle.ping().unwrap();
+
+    KvsClient::connect(addr).unwrap().shutdown().unwrap();
+    server.join().unwrap().unwrap();
+    assert!(idle.ping().is_err());
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn shutdown_after_a_connection_panicked() {
+    let addr: SocketAddr = "127.0.0.1:4307".parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr).unwrap();
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.listen());
+    wait_for_server(addr);
+
+    // KvStore asserts that no value is its tombstone marker, with the engine locked.
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert!(client
+        .set("key".to_owned(), "__tombstone__".to_owned())
+        .is_err());
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    client.set("other".to_owned(), "value".to_owned()).unwrap();
+    drop(client);
+
+    handle.shutdown();
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn shutdown_before_listen() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4304".parse().unwrap();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr).unwrap();
+    server.shutdown_handle().shutdown();
+    server.listen().unwrap();
+}
+
+fn cli_graceful_shutdown(addr: &str, stop: impl Fn(&mut std::process::Child)) {
+    let temp_dir = TempDir::new().unwrap();
+    let start_server = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+
+    let mut child = start_server();
+    wait_for_server(addr.parse().unwrap());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    stop(&mut child);
+    assert!(child.wait().unwrap().success());
+
+    let mut child = start_server();
+    wait_for_server(addr.parse().unwrap());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    stop(&mut child);
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn cli_shutdown_on_sigterm() {
+    cli_graceful_shutdown("127.0.0.1:4305", |child| {
+        let status = Command::new("kill")
+            .args(["-TERM", &child.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    });
+}
+
+#[test]
+fn cli_shutdown_command() {
+    cli_graceful_shutdown("127.0.0.1:4306", |_| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["shutdown", "--addr", "127.0.0.1:4306"])
+            .assert()
+            .success();
+    });
+}
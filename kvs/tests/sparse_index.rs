@@ -0,0 +1,138 @@
+use kvs::{ErrorKind, IndexMode, KvStore, KvStoreOptions, KvsEngine, Result};
+use rand::prelude::*;
+use std::collections::BTreeMap;
+use tempfile::TempDir;
+
+fn sparse(compaction_threshold: u64) -> KvStoreOptions {
+    KvStoreOptions {
+        index: IndexMode::Sparse,
+        compaction_threshold,
+        ..KvStoreOptions::default()
+    }
+}
+
+fn assert_same(store: &mut KvStore, model: &BTreeMap<String, String>) -> Result<()> {
+    let expected: Vec<(String, String)> = model
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    assert_eq!(store.scan("")?, expected);
+    for key_id in 0..300 {
+        let key = format!("key{:03}", key_id);
+        assert_eq!(store.get(key.clone())?, model.get(&key).cloned(), "{}", key);
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, model.len() as u64);
+    assert_eq!(stats.disk_bytes, stats.live_bytes + stats.stale_bytes);
+    Ok(())
+}
+
+// Random writes give the same keys as a map, across merges and reopening.
+#[test]
+fn sparse_index_matches_a_map() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with(temp_dir.path(), sparse(50))?;
+    let mut model = BTreeMap::new();
+    let mut rng = SmallRng::from_seed([7; 16]);
+
+    for round in 0..10 {
+        for _ in 0..200 {
+            let key = format!("key{:03}", rng.gen_range(0, 300));
+            if rng.gen_range(0, 4) == 0 {
+                let removed = store.remove(key.clone());
+                match model.remove(&key) {
+                    Some(_) => removed?,
+                    None => assert!(matches!(removed, Err(ErrorKind::KeyNotFound))),
+                }
+            } else {
+                // Values long enough for the segment to span several blocks.
+                let value = format!("{}-{}", round, "x".repeat(rng.gen_range(0, 100)));
+                store.set(key.clone(), value.clone())?;
+                model.insert(key, value);
+            }
+        }
+        assert_same(&mut store, &model)?;
+        // Live bytes are kept as writes happen, and counted from the files on opening.
+        let live_bytes = store.stats()?.live_bytes;
+        drop(store);
+        store = KvStore::open_with(temp_dir.path(), sparse(50))?;
+        assert_same(&mut store, &model)?;
+        assert_eq!(store.stats()?.live_bytes, live_bytes);
+    }
+    assert!(temp_dir.path().join("data.sst").exists());
+    Ok(())
+}
+
+#[test]
+fn scan_reads_prefixes_from_the_segment_and_the_log() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with(temp_dir.path(), sparse(1000))?;
+    for i in 0..500 {
+        store.set(format!("b{:03}", i), "v".repeat(50))?;
+    }
+    store.set("a".to_owned(), "first".to_owned())?;
+    store.set("c".to_owned(), "last".to_owned())?;
+    store.compact()?;
+    store.set("b250x".to_owned(), "new".to_owned())?;
+    store.set("b100".to_owned(), "overwritten".to_owned())?;
+    store.remove("b101".to_owned())?;
+
+    let pairs = store.scan("b10")?;
+    let keys: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(
+        keys,
+        vec!["b100", "b102", "b103", "b104", "b105", "b106", "b107", "b108", "b109"]
+    );
+    assert_eq!(pairs[0].1, "overwritten");
+    assert_eq!(store.scan("b250")?.len(), 2);
+    assert_eq!(store.scan("a")?, vec![("a".to_owned(), "first".to_owned())]);
+    assert_eq!(store.scan("c")?, vec![("c".to_owned(), "last".to_owned())]);
+    assert_eq!(store.scan("d")?, Vec::new());
+    assert_eq!(store.scan("")?.len(), 502);
+    Ok(())
+}
+
+#[test]
+fn merges_bound_the_log() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with(temp_dir.path(), sparse(100))?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 9);
+    assert_eq!(stats.keys, 1000);
+    // Only the writes since the last merge are in the log.
+    let log_bytes = std::fs::metadata(temp_dir.path().join("data.log"))?.len();
+    assert!(log_bytes < stats.disk_bytes / 9, "{}", log_bytes);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn stores_convert_between_index_modes() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("hash{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_with(temp_dir.path(), sparse(1000))?;
+    store.compact()?;
+    store.set("key1".to_owned(), "sparse".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("data.sst").exists());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!temp_dir.path().join("data.sst").exists());
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("sparse".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("hash3".to_owned()));
+    assert_eq!(store.stats()?.keys, 98);
+    Ok(())
+}
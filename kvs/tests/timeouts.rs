@@ -0,0 +1,103 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    ClientTimeouts, ErrorKind, KvStore, KvsClient, KvsClientPool, KvsServer, ServerTimeouts,
+};
+use predicates::str::contains;
+use std::io::Read;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// A server that accepts connections and reads requests, but never answers.
+fn silent_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut connections = Vec::new();
+        for stream in listener.incoming() {
+            // Keep the connection open so the client does not see EOF.
+            connections.push(stream.unwrap());
+        }
+    });
+    addr
+}
+
+#[test]
+fn client_read_timeout_on_silent_server() {
+    let addr = silent_server();
+    let timeouts = ClientTimeouts {
+        read: Some(Duration::from_millis(200)),
+        ..ClientTimeouts::default()
+    };
+    let mut client = KvsClient::connect_with_timeouts(addr, timeouts).unwrap();
+
+    let start = Instant::now();
+    match client.get("key".to_owned()) {
+        Err(ErrorKind::Timeout) => {}
+        other => panic!("expected timeout, got {:?}", other),
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn pool_read_timeout_on_silent_server() {
+    let addr = silent_server();
+    let pool = KvsClientPool::new(addr, 1)
+        .with_retries(1, Duration::from_millis(1))
+        .with_timeouts(ClientTimeouts::all(Duration::from_millis(100)));
+
+    match pool.ping() {
+        Err(ErrorKind::Timeout) => {}
+        other => panic!("expected timeout, got {:?}", other),
+    }
+    assert_eq!(pool.open_connections(), 0);
+}
+
+#[test]
+fn client_cli_timeout_on_silent_server() {
+    let addr = silent_server().to_string();
+    let temp_dir = TempDir::new().unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", &addr, "--timeout", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("timed out"));
+}
+
+#[test]
+fn server_closes_idle_connections() {
+    let addr: SocketAddr = "127.0.0.1:4201".parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let timeouts = ServerTimeouts {
+        idle: Some(Duration::from_millis(200)),
+        ..ServerTimeouts::default()
+    };
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr)
+        .unwrap()
+        .with_timeouts(timeouts);
+    thread::spawn(move || server.listen().unwrap());
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut stalled = TcpStream::connect(addr).unwrap();
+    stalled
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(stalled.read(&mut buf).unwrap(), 0, "expected EOF");
+
+    // Active connections keep being served.
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
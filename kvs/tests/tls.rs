@@ -0,0 +1,288 @@
+use assert_cmd::prelude::*;
+use kvs::{ClientOptions, ClientTls, FollowerConfig, KvStore, KvsClient, KvsServer, ServerTls};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    KeyUsagePurpose,
+};
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// A CA and certificates signed by it, written as PEM files to a temporary directory.
+struct Pki {
+    dir: TempDir,
+    ca: Certificate,
+    ca_key: KeyPair,
+}
+
+impl Pki {
+    fn new() -> Self {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let pki = Pki {
+            dir: TempDir::new().unwrap(),
+            ca,
+            ca_key,
+        };
+        fs::write(pki.path("ca.pem"), pki.ca.pem()).unwrap();
+        pki
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    // Issue a certificate and write it to `<name>.pem` with its key in `<name>-key.pem`.
+    fn issue(&self, name: &str, sans: &[&str], usage: ExtendedKeyUsagePurpose) {
+        let key = KeyPair::generate().unwrap();
+        let sans: Vec<String> = sans.iter().map(|san| san.to_string()).collect();
+        let mut params = CertificateParams::new(sans).unwrap();
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+
+        fs::write(self.path(&format!("{}.pem", name)), cert.pem()).unwrap();
+        fs::write(self.path(&format!("{}-key.pem", name)), key.serialize_pem()).unwrap();
+    }
+}
+
+fn start_server(addr: &str, tls: ServerTls) -> (SocketAddr, TempDir) {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), addr)
+        .unwrap()
+        .with_tls(tls);
+    thread::spawn(move || server.listen().unwrap());
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    (addr, temp_dir)
+}
+
+fn client_options(tls: ClientTls) -> ClientOptions {
+    ClientOptions {
+        tls: Some(tls),
+        ..ClientOptions::default()
+    }
+}
+
+fn server_tls(pki: &Pki, client_ca: Option<&Path>) -> ServerTls {
+    pki.issue(
+        "server",
+        &["127.0.0.1", "localhost"],
+        ExtendedKeyUsagePurpose::ServerAuth,
+    );
+    ServerTls::from_pem_files(
+        &pki.path("server.pem"),
+        &pki.path("server-key.pem"),
+        client_ca,
+    )
+    .unwrap()
+}
+
+#[test]
+fn tls_round_trip() {
+    let pki = Pki::new();
+    let (addr, _temp_dir) = start_server("127.0.0.1:4501", server_tls(&pki, None));
+
+    let tls = ClientTls::from_pem_files(&pki.path("ca.pem"), None).unwrap();
+    let mut client = KvsClient::connect_with(addr, &client_options(tls.clone())).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    // The name in the certificate may be given explicitly.
+    let tls = tls.with_server_name("localhost").unwrap();
+    let mut client = KvsClient::connect_with(addr, &client_options(tls)).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn replication_over_tls() {
+    let pki = Pki::new();
+    let leader_addr: SocketAddr = "127.0.0.1:4506".parse().unwrap();
+    let follower_addr: SocketAddr = "127.0.0.1:4507".parse().unwrap();
+    let (leader_dir, follower_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let mut leader = KvsServer::new(KvStore::open(leader_dir.path()).unwrap(), leader_addr)
+        .unwrap()
+        .with_tls(server_tls(&pki, None))
+        .with_replication_log(leader_dir.path().join("replication.log"))
+        .unwrap();
+    thread::spawn(move || leader.listen().unwrap());
+    while TcpStream::connect(leader_addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let tls = ClientTls::from_pem_files(&pki.path("ca.pem"), None).unwrap();
+    let mut client = KvsClient::connect_with(leader_addr, &client_options(tls.clone())).unwrap();
+    client.set("key0".to_owned(), "value0".to_owned()).unwrap();
+
+    // The leader streams entries while it reads the acknowledgements of the follower
+    // over the same encrypted connection.
+    let mut follower = KvsServer::new(KvStore::open(follower_dir.path()).unwrap(), follower_addr)
+        .unwrap()
+        .following(FollowerConfig {
+            leader: leader_addr,
+            options: client_options(tls),
+            position_file: follower_dir.path().join("replication.pos"),
+        });
+    thread::spawn(move || follower.listen().unwrap());
+    while TcpStream::connect(follower_addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    for i in 1..100 {
+        client
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+
+    let mut reader = KvsClient::connect(follower_addr).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while reader.get("key99".to_owned()).unwrap().is_none() {
+        assert!(Instant::now() < deadline, "writes not replicated in time");
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(
+        reader.get("key0".to_owned()).unwrap(),
+        Some("value0".to_owned())
+    );
+}
+
+#[test]
+fn tls_rejects_untrusted_server() {
+    let pki = Pki::new();
+    let (addr, _temp_dir) = start_server("127.0.0.1:4502", server_tls(&pki, None));
+
+    let other_pki = Pki::new();
+    let tls = ClientTls::from_pem_files(&other_pki.path("ca.pem"), None).unwrap();
+    let mut client = KvsClient::connect_with(addr, &client_options(tls)).unwrap();
+    assert!(client.ping().is_err());
+
+    let tls = ClientTls::from_pem_files(&pki.path("ca.pem"), None)
+        .unwrap()
+        .with_server_name("example.com")
+        .unwrap();
+    let mut client = KvsClient::connect_with(addr, &client_options(tls)).unwrap();
+    assert!(client.ping().is_err());
+}
+
+#[test]
+fn tls_server_rejects_plaintext_client() {
+    let pki = Pki::new();
+    let (addr, _temp_dir) = start_server("127.0.0.1:4503", server_tls(&pki, None));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(client.ping().is_err());
+}
+
+#[test]
+fn mutual_tls() {
+    let pki = Pki::new();
+    let ca = pki.path("ca.pem");
+    let (addr, _temp_dir) = start_server("127.0.0.1:4504", server_tls(&pki, Some(&ca)));
+
+    let tls = ClientTls::from_pem_files(&ca, None).unwrap();
+    let mut client = KvsClient::connect_with(addr, &client_options(tls)).unwrap();
+    assert!(client.ping().is_err());
+
+    pki.issue("client", &["client"], ExtendedKeyUsagePurpose::ClientAuth);
+    let identity = (pki.path("client.pem"), pki.path("client-key.pem"));
+    let tls = ClientTls::from_pem_files(&ca, Some((&identity.0, &identity.1))).unwrap();
+    let mut client = KvsClient::connect_with(addr, &client_options(tls)).unwrap();
+    client.ping().unwrap();
+
+    // Certificates from another CA are not accepted.
+    let other_pki = Pki::new();
+    other_pki.issue("client", &["client"], ExtendedKeyUsagePurpose::ClientAuth);
+    let tls = ClientTls::from_pem_files(
+        &ca,
+        Some((
+            &other_pki.path("client.pem"),
+            &other_pki.path("client-key.pem"),
+        )),
+    )
+    .unwrap();
+    let mut client = KvsClient::connect_with(addr, &client_options(tls)).unwrap();
+    assert!(client.ping().is_err());
+}
+
+#[test]
+fn tls_invalid_pem_files() {
+    let pki = Pki::new();
+    fs::write(pki.path("empty.pem"), "").unwrap();
+
+    assert!(ClientTls::from_pem_files(&pki.path("empty.pem"), None).is_err());
+    assert!(ClientTls::from_pem_files(&pki.path("missing.pem"), None).is_err());
+    assert!(ServerTls::from_pem_files(&pki.path("ca.pem"), &pki.path("empty.pem"), None).is_err());
+}
+
+#[test]
+fn cli_tls() {
+    let pki = Pki::new();
+    server_tls(&pki, None);
+    let addr = "127.0.0.1:4505";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--tls-cert"])
+        .arg(pki.path("server.pem"))
+        .arg("--tls-key")
+        .arg(pki.path("server-key.pem"))
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--tls-ca"])
+        .arg(pki.path("ca.pem"))
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--tls-ca"])
+        .arg(pki.path("ca.pem"))
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .failure();
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_tls_requires_cert_and_key() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--tls-cert", "server.pem"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ping", "--tls-cert", "client.pem", "--tls-ca", "ca.pem"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}